@@ -0,0 +1,184 @@
+use crate::events::{Event, EventSender};
+use crate::{config, log, timestamp};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::interval;
+
+const ANALYTICS_CONFIG_PATH: &str = "./analytics_config.toml";
+const ANALYTICS_DIR: &str = "./analytics";
+const SALT_FILE: &str = "./analytics/salt";
+const HEADER: &str = "timestamp,time,event,subject,outcome\n";
+
+// Exports longer than this are refused so the attachment stays within discord's limits.
+pub(crate) const MAX_EXPORT_DAYS: i64 = 366;
+
+/// One row of a daily rollup. Subjects are salted hashes of the player's UUID, so rows can be joined across
+/// days without naming anyone.
+struct Row {
+    timestamp: u64,
+    event: &'static str,
+    subject: Option<String>,
+    outcome: &'static str,
+}
+
+/// Roll the event stream up into a CSV file per day, if enabled. Rows are buffered and written on an interval so
+/// the rest of the bot never waits on the disk.
+pub(crate) async fn start_analytics(events: EventSender) -> Result<()> {
+    let config = open_config()?;
+    if !config.enabled {
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(ANALYTICS_DIR)?;
+    let salt = load_salt()?;
+    let mut events = events.subscribe();
+    let mut flush = interval(Duration::from_secs(config.flush_interval_secs.max(1)));
+    let mut rows = Vec::new();
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => rows.push(row(&event, &salt)),
+                Err(RecvError::Lagged(skipped)) => log!("Analytics writer fell behind and skipped {skipped} events"),
+                Err(RecvError::Closed) => return write_rows(&rows),
+            },
+
+            _ = flush.tick() => {
+                if let Err(why) = write_rows(&rows) {
+                    log!("Error writing analytics rollup: {why:?}");
+                }
+                rows.clear();
+                if let Err(why) = prune(config.retention_days) {
+                    log!("Error pruning analytics rollups: {why:?}");
+                }
+            }
+        }
+    }
+}
+
+/// Combine the rollups for an inclusive range of days into one CSV. Days without a rollup are skipped.
+pub(crate) fn export_range(from: NaiveDate, to: NaiveDate) -> Result<Vec<u8>> {
+    let mut export = HEADER.as_bytes().to_vec();
+    for day in from.iter_days().take_while(|day| *day <= to) {
+        let Ok(rollup) = std::fs::read_to_string(rollup_path(day)) else {
+            continue;
+        };
+        export.extend(rollup.lines().skip(1).flat_map(|line| format!("{line}\n").into_bytes()));
+    }
+    Ok(export)
+}
+
+fn row(event: &Event, salt: &str) -> Row {
+    let (event_type, uuid, outcome) = match event {
+        Event::Approved { uuid, .. } => ("approval", Some(uuid), "approved"),
+        Event::PlayerJoined { uuid, .. } => ("presence", Some(uuid), "joined"),
+        Event::PlayerLeft { uuid, .. } => ("presence", Some(uuid), "left"),
+        Event::TicketOpened => ("ticket", None, "opened"),
+        Event::TicketClosed => ("ticket", None, "closed"),
+    };
+
+    Row {
+        timestamp: timestamp(),
+        event: event_type,
+        subject: uuid.map(|uuid| anonymize(salt, uuid)),
+        outcome,
+    }
+}
+
+// The first 16 bytes of a salted SHA-256, which is plenty to tell players apart.
+fn anonymize(salt: &str, uuid: &str) -> String {
+    digest(&SHA256, format!("{salt}{uuid}").as_bytes()).as_ref()[..16].iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn write_rows(rows: &[Row]) -> Result<()> {
+    for row in rows {
+        let time = DateTime::<Utc>::from_timestamp_millis(row.timestamp as i64).ok_or(anyhow!("Invalid timestamp {}!", row.timestamp))?;
+        let path = rollup_path(time.date_naive());
+        let new = !Path::new(&path).exists();
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        if new {
+            file.write_all(HEADER.as_bytes())?;
+        }
+        writeln!(file, "{},{},{},{},{}", row.timestamp, time.format("%Y-%m-%dT%H:%M:%SZ"), row.event, row.subject.as_deref().unwrap_or(""), row.outcome)?;
+    }
+    Ok(())
+}
+
+// Delete rollups older than the retention. Zero keeps them forever.
+fn prune(retention_days: u64) -> Result<()> {
+    if retention_days == 0 {
+        return Ok(());
+    }
+
+    let cutoff = Utc::now().date_naive() - chrono::Duration::days(retention_days as i64);
+    for entry in std::fs::read_dir(ANALYTICS_DIR)? {
+        let path = entry?.path();
+        let day = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| NaiveDate::parse_from_str(stem, "%Y-%m-%d").ok());
+        if path.extension().is_some_and(|extension| extension == "csv") && day.is_some_and(|day| day < cutoff) {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+fn rollup_path(day: NaiveDate) -> String {
+    format!("{ANALYTICS_DIR}/{}.csv", day.format("%Y-%m-%d"))
+}
+
+// The salt is made once and kept, otherwise the same player would hash differently after a restart.
+fn load_salt() -> Result<String> {
+    if let Ok(salt) = std::fs::read_to_string(SALT_FILE) {
+        return Ok(salt.trim().to_owned());
+    }
+
+    let salt = (0..32).map(|_| format!("{:02x}", rand::random::<u8>())).collect::<String>();
+    let mut file = File::create_new(SALT_FILE)?;
+    file.write_all(salt.as_bytes())?;
+    Ok(salt)
+}
+
+#[derive(Serialize, Deserialize)]
+struct AnalyticsConfig {
+    enabled: bool,
+    retention_days: u64,
+    flush_interval_secs: u64,
+}
+
+impl AnalyticsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            retention_days: 90,
+            flush_interval_secs: 60,
+        }
+    }
+}
+
+impl config::Documented for AnalyticsConfig {
+    const COMMENTS: &'static [(&'static str, &'static str)] = &[
+        ("enabled", "Roll the event stream up into a CSV file per day in ./analytics."),
+        ("retention_days", "Daily files older than this are deleted. Zero keeps them forever."),
+        ("flush_interval_secs", "How often buffered events are written out."),
+    ];
+}
+
+fn open_config() -> Result<AnalyticsConfig> {
+    config::open_config(ANALYTICS_CONFIG_PATH, AnalyticsConfig::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_setting_is_documented() {
+        assert_eq!(config::undocumented(&AnalyticsConfig::default()), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,80 @@
+use crate::timestamp;
+
+/// A step in a user's verification, posted to the audit channel when one is configured.
+#[derive(Debug)]
+pub(crate) struct AuditEntry {
+    pub(crate) action: AuditAction,
+    pub(crate) name: String,
+    pub(crate) uuid: String,
+    pub(crate) discord_id: Option<u64>,
+    pub(crate) actor: Actor,
+    // Milliseconds since the unix epoch.
+    pub(crate) at: u64,
+}
+
+impl AuditEntry {
+    pub(crate) fn new(action: AuditAction, name: &str, uuid: &str, discord_id: Option<u64>, actor: Actor) -> Self {
+        Self {
+            action,
+            name: name.to_owned(),
+            uuid: uuid.to_owned(),
+            discord_id,
+            actor,
+            at: timestamp(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum AuditAction {
+    CodeIssued,
+    CodeExpired,
+    Linked,
+    // The game server the approval is for, for bots shared between several.
+    Approved(Option<String>),
+    // The reason the moderator gave.
+    Denied(String),
+    Unlinked,
+    Banned,
+    Unbanned,
+    // Discord wouldn't deliver a status DM, so the user may not know about it.
+    DmFailed,
+    // Drift between the guild and the bot's records that reconciliation fixed, or would have in a dry run.
+    Corrected { fix: String, dry_run: bool },
+    // The linked guild an approved link was carried into.
+    Inherited(u64),
+}
+
+impl AuditAction {
+    pub(crate) fn title(&self) -> &'static str {
+        match self {
+            Self::CodeIssued => "Code issued",
+            Self::CodeExpired => "Code expired",
+            Self::Linked => "Linked",
+            Self::Approved(_) => "Approved",
+            Self::Denied(_) => "Denied",
+            Self::Unlinked => "Unlinked",
+            Self::Banned => "Banned",
+            Self::Unbanned => "Unbanned",
+            Self::DmFailed => "Couldn't DM",
+            Self::Corrected { dry_run: false, .. } => "Corrected",
+            Self::Corrected { dry_run: true, .. } => "Correction (dry run)",
+            Self::Inherited(_) => "Inherited",
+        }
+    }
+}
+
+/// Who set an audited action off.
+#[derive(Clone, Debug)]
+pub(crate) enum Actor {
+    // A discord user, either the player themselves or a moderator.
+    Discord(u64),
+    // A game server, named for bots shared between several.
+    GameServer(Option<String>),
+    // The user left the guild.
+    LeftGuild,
+    // Someone took the verified role off the user by hand.
+    RoleRemoved,
+    // The bot itself, such as when a code runs out.
+    Bot,
+}
@@ -0,0 +1,18 @@
+use chrono::DateTime;
+
+pub(crate) const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub(crate) const GIT_COMMIT: &str = env!("CCBOT_GIT_COMMIT");
+const BUILD_TIMESTAMP: &str = env!("CCBOT_BUILD_TIMESTAMP");
+
+pub(crate) fn build_time() -> String {
+    BUILD_TIMESTAMP
+        .parse()
+        .ok()
+        .and_then(|secs| DateTime::from_timestamp(secs, 0))
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+pub(crate) fn summary() -> String {
+    format!("ccbot {VERSION} (commit {GIT_COMMIT}, built {})", build_time())
+}
@@ -0,0 +1,257 @@
+use crate::{config, log};
+use anyhow::Result;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+const CHECKS_CONFIG_PATH: &str = "./checks_config.toml";
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// How a check participates in verification decisions.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum CheckMode {
+    /// The check is not run at all.
+    Off,
+    /// The check runs and its verdict is logged and counted, but never returned.
+    Shadow,
+    /// The check runs and a denial is returned to the user.
+    Enforce,
+}
+
+/// The point in the verification flow a check is being run at.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Stage {
+    Connect,
+    Link,
+}
+
+/// Everything a check is allowed to look at.
+pub(crate) struct Subject<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) uuid: &'a str,
+}
+
+pub(crate) enum Verdict {
+    Allow,
+    Deny(String),
+}
+
+/// A single verification check. Adding a new check only requires implementing this trait and
+/// registering it in `Checks::load`.
+pub(crate) trait Check: Send {
+    fn name(&self) -> &'static str;
+    fn mode(&self) -> CheckMode;
+    fn evaluate(&self, stage: Stage, subject: &Subject) -> Verdict;
+}
+
+#[derive(Default)]
+struct CheckStats {
+    evaluated: u64,
+    shadow_denials: u64,
+    enforced_denials: u64,
+}
+
+pub(crate) struct Checks {
+    checks: Vec<Box<dyn Check>>,
+    stats: HashMap<&'static str, CheckStats>,
+    last_summary: Instant,
+}
+
+impl Checks {
+    pub(crate) fn load() -> Result<Self> {
+        let config = open_config()?;
+        let checks: Vec<Box<dyn Check>> = vec![Box::new(NameFilterCheck::new(config.name_filter)?)];
+
+        for check in checks.iter().filter(|check| check.mode() != CheckMode::Off) {
+            log!("Verification check {} is running in {:?} mode", check.name(), check.mode());
+        }
+
+        Ok(Self {
+            checks,
+            stats: HashMap::new(),
+            last_summary: Instant::now(),
+        })
+    }
+
+    /// Run every enabled check, returning the reason of the first enforced denial. Shadow verdicts
+    /// are only logged and counted, so they can never change what the caller sends back.
+    pub(crate) fn run(&mut self, stage: Stage, subject: &Subject) -> Option<String> {
+        let mut denial = None;
+        for check in self.checks.iter().filter(|check| check.mode() != CheckMode::Off) {
+            let stats = self.stats.entry(check.name()).or_default();
+            stats.evaluated += 1;
+
+            let Verdict::Deny(reason) = check.evaluate(stage, subject) else { continue };
+            match check.mode() {
+                CheckMode::Shadow => {
+                    stats.shadow_denials += 1;
+                    log!("[shadow] Check {} would deny {} [{}] at {stage:?}: {reason}", check.name(), subject.name, subject.uuid);
+                }
+
+                CheckMode::Enforce => {
+                    stats.enforced_denials += 1;
+                    log!("Check {} denied {} [{}] at {stage:?}: {reason}", check.name(), subject.name, subject.uuid);
+                    denial.get_or_insert(reason);
+                }
+
+                CheckMode::Off => {}
+            }
+        }
+        denial
+    }
+
+    /// Log a summary of all check verdicts once per day.
+    pub(crate) fn summarize_if_due(&mut self) {
+        if self.last_summary.elapsed() < SUMMARY_INTERVAL {
+            return;
+        }
+        self.last_summary = Instant::now();
+
+        for (name, stats) in self.stats.drain() {
+            log!(
+                "Check {name} summary: {} evaluated, {} shadow denials, {} enforced denials",
+                stats.evaluated,
+                stats.shadow_denials,
+                stats.enforced_denials
+            );
+        }
+    }
+}
+
+/// Rejects Minecraft names matching any of the configured patterns.
+struct NameFilterCheck {
+    mode: CheckMode,
+    patterns: Vec<Regex>,
+}
+
+impl NameFilterCheck {
+    fn new(config: NameFilterConfig) -> Result<Self> {
+        Ok(Self {
+            mode: config.mode,
+            patterns: config.blocked_patterns.iter().map(|pattern| Regex::new(pattern)).collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+impl Check for NameFilterCheck {
+    fn name(&self) -> &'static str {
+        "name_filter"
+    }
+
+    fn mode(&self) -> CheckMode {
+        self.mode
+    }
+
+    fn evaluate(&self, _stage: Stage, subject: &Subject) -> Verdict {
+        match self.patterns.iter().find(|pattern| pattern.is_match(subject.name)) {
+            Some(_) => Verdict::Deny("Your Minecraft name is not allowed on this server.".to_owned()),
+            None => Verdict::Allow,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ChecksConfig {
+    name_filter: NameFilterConfig,
+}
+
+#[derive(Serialize, Deserialize)]
+struct NameFilterConfig {
+    mode: CheckMode,
+    blocked_patterns: Vec<String>,
+}
+
+impl ChecksConfig {
+    fn default() -> Self {
+        Self {
+            name_filter: NameFilterConfig {
+                mode: CheckMode::Off,
+                blocked_patterns: Vec::new(),
+            },
+        }
+    }
+}
+
+impl config::Documented for ChecksConfig {
+    const COMMENTS: &'static [(&'static str, &'static str)] = &[
+        ("name_filter", "Turns away Minecraft names matching any of a list of patterns."),
+        ("name_filter.mode", "\"off\", \"shadow\" to only log and count what would be denied, or \"enforce\"."),
+        ("name_filter.blocked_patterns", "Regular expressions matched against each name, such as \"(?i)griefer\"."),
+    ];
+}
+
+fn open_config() -> Result<ChecksConfig> {
+    config::open_config(CHECKS_CONFIG_PATH, ChecksConfig::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name_filter(mode: CheckMode, patterns: &[&str]) -> Box<dyn Check> {
+        Box::new(NameFilterCheck::new(NameFilterConfig { mode, blocked_patterns: patterns.iter().map(|pattern| (*pattern).to_owned()).collect() }).unwrap())
+    }
+
+    fn checks(checks: Vec<Box<dyn Check>>) -> Checks {
+        Checks { checks, stats: HashMap::new(), last_summary: Instant::now() }
+    }
+
+    const BLOCKED: Subject = Subject { name: "xX_Griefer_Xx", uuid: "069a79f4-44e9-4726-a5be-fca90e38aaf5" };
+    const ALLOWED: Subject = Subject { name: "Notch", uuid: "069a79f4-44e9-4726-a5be-fca90e38aaf5" };
+
+    #[test]
+    fn name_filter_denies_matching_names() {
+        let check = name_filter(CheckMode::Enforce, &["(?i)griefer", "^admin"]);
+        assert!(matches!(check.evaluate(Stage::Connect, &BLOCKED), Verdict::Deny(_)));
+        assert!(matches!(check.evaluate(Stage::Link, &ALLOWED), Verdict::Allow));
+        assert!(matches!(check.evaluate(Stage::Link, &Subject { name: "admin_bob", uuid: "" }), Verdict::Deny(_)));
+    }
+
+    #[test]
+    fn name_filter_refuses_invalid_patterns() {
+        assert!(NameFilterCheck::new(NameFilterConfig { mode: CheckMode::Enforce, blocked_patterns: vec!["(".to_owned()] }).is_err());
+    }
+
+    #[test]
+    fn off_checks_are_not_run() {
+        let mut checks = checks(vec![name_filter(CheckMode::Off, &["Griefer"])]);
+        assert_eq!(checks.run(Stage::Connect, &BLOCKED), None);
+        assert!(checks.stats.is_empty());
+    }
+
+    #[test]
+    fn shadow_denials_are_counted_but_never_returned() {
+        let mut checks = checks(vec![name_filter(CheckMode::Shadow, &["Griefer"])]);
+        for stage in [Stage::Connect, Stage::Link] {
+            assert_eq!(checks.run(stage, &BLOCKED), None);
+        }
+        assert_eq!(checks.run(Stage::Connect, &ALLOWED), None);
+
+        let stats = &checks.stats["name_filter"];
+        assert_eq!((stats.evaluated, stats.shadow_denials, stats.enforced_denials), (3, 2, 0));
+    }
+
+    #[test]
+    fn enforced_denials_are_returned() {
+        let mut checks = checks(vec![name_filter(CheckMode::Enforce, &["Griefer"])]);
+        assert_eq!(checks.run(Stage::Connect, &BLOCKED).as_deref(), Some("Your Minecraft name is not allowed on this server."));
+        assert_eq!(checks.run(Stage::Connect, &ALLOWED), None);
+        assert_eq!(checks.stats["name_filter"].enforced_denials, 1);
+    }
+
+    #[test]
+    fn shadow_checks_dont_change_enforced_results() {
+        let mut shadowed = checks(vec![name_filter(CheckMode::Shadow, &["Notch", "Griefer"]), name_filter(CheckMode::Enforce, &["Griefer"])]);
+        let mut plain = checks(vec![name_filter(CheckMode::Enforce, &["Griefer"])]);
+        for subject in [&BLOCKED, &ALLOWED] {
+            assert_eq!(shadowed.run(Stage::Connect, subject), plain.run(Stage::Connect, subject));
+        }
+    }
+
+    #[test]
+    fn every_setting_is_documented() {
+        assert_eq!(config::undocumented(&ChecksConfig::default()), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,41 @@
+use anyhow::{anyhow, Result};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An address range in CIDR notation. A bare address is a range containing just that address.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Cidr {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl Cidr {
+    pub(crate) fn contains(&self, addr: IpAddr) -> bool {
+        match (self.network, addr.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => prefix_matches(u32::from(network) as u128, u32::from(addr) as u128, 32, self.prefix),
+            (IpAddr::V6(network), IpAddr::V6(addr)) => prefix_matches(u128::from(network), u128::from(addr), 128, self.prefix),
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(network: u128, addr: u128, bits: u32, prefix: u32) -> bool {
+    let shift = bits - prefix;
+    // Shifting a u128 by 128 would overflow, and a /0 matches everything anyway.
+    shift >= 128 || network >> shift == addr >> shift
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let (address, prefix) = value.split_once('/').unwrap_or((value, ""));
+        let network = IpAddr::from_str(address)?.to_canonical();
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = if prefix.is_empty() { bits } else { u32::from_str(prefix)? };
+        if prefix > bits {
+            return Err(anyhow!("Prefix /{prefix} is too long for {address}"));
+        }
+        Ok(Self { network, prefix })
+    }
+}
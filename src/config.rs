@@ -0,0 +1,398 @@
+use crate::log;
+use anyhow::{anyhow, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::fs::File;
+use std::path::Path;
+use toml_edit::{DocumentMut, Item, Table};
+
+// Unknown keys further than this from every valid key get no suggestion.
+const MAX_SUGGESTION_DISTANCE: usize = 3;
+
+/// A config file's settings, each with the comment written above it in the file. Settings inside a table are named
+/// by their dotted path, such as `tls.cert_path`, and the table itself needs a comment too.
+pub(crate) trait Documented: Serialize + DeserializeOwned {
+    const COMMENTS: &'static [(&'static str, &'static str)];
+}
+
+/// Read a TOML config file, reporting a missing or broken file rather than replacing it. A JSON file of the same
+/// name from older versions is converted the first time. Keys the config doesn't have are warned about, and
+/// settings added since the file was written are appended with their defaults and comments so admins can see them.
+pub(crate) fn read_config<T: Documented>(path: &str, default: fn() -> T) -> Result<T> {
+    let legacy = Path::new(path).with_extension("json");
+    if !Path::new(path).exists() && legacy.exists() {
+        convert_json::<T>(path, &legacy)?;
+    }
+
+    let mut document = std::fs::read_to_string(path)?.parse::<DocumentMut>()?;
+    for (key, nearest) in unknown_keys(document.as_table(), T::COMMENTS) {
+        match nearest {
+            Some(nearest) => log!("Unknown setting {key} in {path}, did you mean {nearest}?"),
+            None => log!("Unknown setting {key} in {path}"),
+        }
+    }
+
+    let added = append_missing(document.as_table_mut(), commented(&default())?.as_table());
+    if !added.is_empty() {
+        log!("Adding new settings to {path} with their defaults: {}", added.join(", "));
+        write(path, &document)?;
+    }
+
+    Ok(toml_edit::de::from_document(document)?)
+}
+
+/// Read a config file, writing out the defaults if there isn't one yet. A file that can't be read is reported
+/// rather than replaced, as it may hold settings such as the bot token that can't be got back.
+pub(crate) fn open_config<T: Documented>(path: &str, default: fn() -> T) -> Result<T> {
+    if !Path::new(path).exists() && !Path::new(path).with_extension("json").exists() {
+        let config = default();
+        write(path, &commented(&config)?)?;
+        return Ok(config);
+    }
+
+    read_config(path, default).map_err(|why| refuse(path, why))
+}
+
+/// Read a JSON file of data, such as message templates, where keys are filled in rather than documented. Keys the
+/// defaults don't have are warned about, and a missing file is written out with the defaults. A file that can't be
+/// read is reported rather than replaced.
+pub(crate) fn open_json<T: Serialize + DeserializeOwned>(path: &str, default: fn() -> T) -> Result<T> {
+    if !Path::new(path).exists() {
+        let mut file = File::create_new(path)?;
+        let config = default();
+        serde_json::to_writer_pretty(&mut file, &config)?;
+        return Ok(config);
+    }
+
+    read_json(path, default).map_err(|why| refuse(path, why))
+}
+
+// Stop the bot over a file it couldn't read, leaving the file as it is for the admin to fix.
+fn refuse(path: &str, why: anyhow::Error) -> anyhow::Error {
+    log!("Couldn't read {path}: {why}");
+    anyhow!("{path} couldn't be read, so the bot won't start until it's fixed or removed: {why}")
+}
+
+fn read_json<T: Serialize + DeserializeOwned>(path: &str, default: fn() -> T) -> Result<T> {
+    let Value::Object(mut fields) = serde_json::from_reader(File::open(path)?)? else {
+        return Err(anyhow!("{path} is not a JSON object!"));
+    };
+    let Value::Object(defaults) = serde_json::to_value(default())? else {
+        return Err(anyhow!("Defaults for {path} are not a JSON object!"));
+    };
+
+    for key in fields.keys().filter(|key| !defaults.contains_key(*key)) {
+        match nearest_key(key, defaults.keys().map(String::as_str)) {
+            Some(nearest) => log!("Unknown field {key} in {path}, did you mean {nearest}?"),
+            None => log!("Unknown field {key} in {path}"),
+        }
+    }
+
+    let mut added = Vec::new();
+    for (key, value) in defaults {
+        if !fields.contains_key(&key) {
+            fields.insert(key.clone(), value);
+            added.push(key);
+        }
+    }
+
+    let fields = Value::Object(fields);
+    if !added.is_empty() {
+        log!("Adding new fields to {path} with their defaults: {}", added.join(", "));
+        let _ = std::fs::remove_file(path);
+        let mut file = File::create_new(path)?;
+        serde_json::to_writer_pretty(&mut file, &fields)?;
+    }
+
+    Ok(serde_json::from_value(fields)?)
+}
+
+// Write a JSON config from older versions out as TOML, keeping the JSON beside it as `.json.old` so it's only
+// converted once. Nulls are dropped, as TOML has no way to write them.
+fn convert_json<T: Documented>(path: &str, legacy: &Path) -> Result<()> {
+    let mut fields = serde_json::from_reader::<_, Value>(File::open(legacy)?)?;
+    if !fields.is_object() {
+        return Err(anyhow!("{} is not a JSON object!", legacy.display()));
+    }
+
+    drop_nulls(&mut fields);
+    let mut document = toml_edit::ser::to_string_pretty(&fields)?.parse::<DocumentMut>()?;
+    comment(document.as_table_mut(), T::COMMENTS, "");
+    write(path, &document)?;
+    std::fs::rename(legacy, legacy.with_extension("json.old"))?;
+    log!("Converted {} to {path}", legacy.display());
+    Ok(())
+}
+
+fn drop_nulls(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            fields.retain(|_, value| !value.is_null());
+            fields.values_mut().for_each(drop_nulls);
+        }
+        Value::Array(values) => values.iter_mut().for_each(drop_nulls),
+        _ => {}
+    }
+}
+
+fn write(path: &str, document: &DocumentMut) -> Result<()> {
+    Ok(std::fs::write(path, document.to_string().trim_start())?)
+}
+
+// The config as a TOML document with every setting's comment above it.
+fn commented<T: Documented>(config: &T) -> Result<DocumentMut> {
+    let mut document = toml_edit::ser::to_string_pretty(config)?.parse::<DocumentMut>()?;
+    comment(document.as_table_mut(), T::COMMENTS, "");
+    Ok(document)
+}
+
+fn comment(table: &mut Table, comments: &[(&str, &str)], prefix: &str) {
+    for (mut key, item) in table.iter_mut() {
+        let path = format!("{prefix}{}", key.get());
+        let text = comments.iter().find(|(name, _)| *name == path).map(|(_, text)| format!("\n# {text}\n"));
+        match item {
+            Item::Table(table) => {
+                if let Some(text) = text {
+                    table.decor_mut().set_prefix(text);
+                }
+                comment(table, comments, &format!("{path}."));
+            }
+            Item::ArrayOfTables(tables) => {
+                if let (Some(text), Some(first)) = (text, tables.get_mut(0)) {
+                    first.decor_mut().set_prefix(text);
+                }
+            }
+            _ => {
+                if let Some(text) = text {
+                    key.leaf_decor_mut().set_prefix(text);
+                }
+            }
+        }
+    }
+}
+
+// Every key without a comment, so not a setting, along with the nearest setting beside it.
+fn unknown_keys(table: &Table, comments: &[(&str, &str)]) -> Vec<(String, Option<String>)> {
+    let mut unknown = Vec::new();
+    find_unknown_keys(table, comments, "", &mut unknown);
+    unknown
+}
+
+fn find_unknown_keys(table: &Table, comments: &[(&str, &str)], prefix: &str, unknown: &mut Vec<(String, Option<String>)>) {
+    for (key, item) in table.iter() {
+        let path = format!("{prefix}{key}");
+        if !comments.iter().any(|(name, _)| *name == path) {
+            let siblings = comments.iter().filter_map(|(name, _)| name.strip_prefix(prefix)).filter(|name| !name.contains('.'));
+            unknown.push((path, nearest_key(key, siblings).map(|nearest| format!("{prefix}{nearest}"))));
+        } else if let Item::Table(table) = item {
+            find_unknown_keys(table, comments, &format!("{path}."), unknown);
+        }
+    }
+}
+
+// Copy over every default the file doesn't have, with its comment, returning the paths of those added.
+fn append_missing(table: &mut Table, defaults: &Table) -> Vec<String> {
+    let mut added = Vec::new();
+    append_missing_under(table, defaults, "", &mut added);
+    added
+}
+
+fn append_missing_under(table: &mut Table, defaults: &Table, prefix: &str, added: &mut Vec<String>) {
+    for (key, default) in defaults.iter() {
+        let path = format!("{prefix}{key}");
+        match (table.get_mut(key), default) {
+            (None, _) => {
+                let (key, default) = defaults.get_key_value(key).expect("Key was just iterated");
+                let mut default = default.clone();
+                // Tables keep their place in the defaults, which could land them among the file's own tables.
+                if let Item::Table(table) = &mut default {
+                    table.set_position(usize::MAX);
+                }
+                table.insert_formatted(key, default);
+                added.push(path);
+            }
+            (Some(Item::Table(table)), Item::Table(defaults)) => append_missing_under(table, defaults, &format!("{path}."), added),
+            _ => {}
+        }
+    }
+}
+
+fn nearest_key<'a>(key: &str, valid: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    valid
+        .map(|candidate| (edit_distance(key, candidate), candidate))
+        .filter(|(distance, _)| *distance <= MAX_SUGGESTION_DISTANCE)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+// Levenshtein distance, counting insertions, deletions and substitutions.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// The settings of a config that have no comment, for each config's tests to check there are none.
+#[cfg(test)]
+pub(crate) fn undocumented<T: Documented>(config: &T) -> Vec<String> {
+    let document = toml_edit::ser::to_string_pretty(config).unwrap().parse::<DocumentMut>().unwrap();
+    unknown_keys(document.as_table(), T::COMMENTS).into_iter().map(|(key, _)| key).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize)]
+    struct Example {
+        name: String,
+        port: u64,
+        #[serde(default)]
+        nickname: Option<String>,
+        tls: ExampleTls,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct ExampleTls {
+        cert_path: String,
+        key_path: String,
+    }
+
+    impl Documented for Example {
+        const COMMENTS: &'static [(&'static str, &'static str)] = &[
+            ("name", "What the server is called."),
+            ("port", "The port to listen on."),
+            ("nickname", "Shown instead of the name when set."),
+            ("tls", "Certificates for encrypted connections."),
+            ("tls.cert_path", "The certificate chain."),
+            ("tls.key_path", "The certificate's private key."),
+        ];
+    }
+
+    fn example() -> Example {
+        Example { name: "survival".to_owned(), port: 25575, nickname: None, tls: ExampleTls { cert_path: String::new(), key_path: String::new() } }
+    }
+
+    fn scratch_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("ccbot-config-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn defaults_have_a_comment_above_every_setting() {
+        let text = commented(&example()).unwrap().to_string();
+        let lines = text.lines().collect::<Vec<_>>();
+        for (line, comment) in [("name = ", "# What the server is called."), ("port = ", "# The port to listen on."), ("[tls]", "# Certificates for encrypted connections."), ("cert_path = ", "# The certificate chain."), ("key_path = ", "# The certificate's private key.")] {
+            let at = lines.iter().position(|candidate| candidate.starts_with(line)).unwrap();
+            assert_eq!(lines[at - 1], comment);
+        }
+        assert!(undocumented(&example()).is_empty());
+    }
+
+    #[test]
+    fn missing_settings_are_appended_with_their_comments() {
+        let mut document = "# Our survival server\nname = \"creative\"\n\n[tls]\n# Renewed by certbot\ncert_path = \"cert.pem\"\n".parse::<DocumentMut>().unwrap();
+        let added = append_missing(document.as_table_mut(), commented(&example()).unwrap().as_table());
+        assert_eq!(added, ["port", "tls.key_path"]);
+
+        let text = document.to_string();
+        assert!(text.contains("# Our survival server\nname = \"creative\"\n"));
+        assert!(text.contains("\n# The port to listen on.\nport = 25575\n"));
+        assert!(text.contains("# Renewed by certbot\ncert_path = \"cert.pem\"\n"));
+        assert!(text.contains("\n# The certificate's private key.\nkey_path = \"\"\n"));
+
+        let config = toml_edit::de::from_document::<Example>(document.clone()).unwrap();
+        assert_eq!((config.name.as_str(), config.port, config.tls.cert_path.as_str()), ("creative", 25575, "cert.pem"));
+        assert!(append_missing(document.as_table_mut(), commented(&example()).unwrap().as_table()).is_empty());
+    }
+
+    #[test]
+    fn missing_tables_are_appended_after_the_settings() {
+        let mut document = "name = \"creative\"\nport = 1\n".parse::<DocumentMut>().unwrap();
+        let added = append_missing(document.as_table_mut(), commented(&example()).unwrap().as_table());
+        assert_eq!(added, ["tls"]);
+        assert!(document.to_string().ends_with("\n# Certificates for encrypted connections.\n[tls]\n\n# The certificate chain.\ncert_path = \"\"\n\n# The certificate's private key.\nkey_path = \"\"\n"));
+    }
+
+    #[test]
+    fn unknown_keys_name_the_nearest_setting() {
+        let document = "nmae = \"creative\"\nport = 1\nnickname = \"c\"\nwhitelist = true\n\n[tls]\ncert_pth = \"cert.pem\"\nkey_path = \"\"\n\n[motd]\nport = 2\n".parse::<DocumentMut>().unwrap();
+        assert_eq!(unknown_keys(document.as_table(), Example::COMMENTS), [
+            ("nmae".to_owned(), Some("name".to_owned())),
+            ("whitelist".to_owned(), None),
+            ("tls.cert_pth".to_owned(), Some("tls.cert_path".to_owned())),
+            ("motd".to_owned(), Some("port".to_owned())),
+        ]);
+    }
+
+    #[test]
+    fn json_configs_are_converted_once() {
+        let dir = scratch_dir("convert");
+        let path = dir.join("example.toml");
+        let path = path.to_str().unwrap();
+        std::fs::write(dir.join("example.json"), r#"{"name": "creative", "nickname": null, "tls": {"cert_path": "cert.pem", "key_path": "key.pem"}}"#).unwrap();
+
+        let config = read_config(path, example).unwrap();
+        assert_eq!((config.name.as_str(), config.port, config.nickname, config.tls.key_path.as_str()), ("creative", 25575, None, "key.pem"));
+        assert!(!dir.join("example.json").exists());
+        assert!(dir.join("example.json.old").exists());
+
+        let text = std::fs::read_to_string(path).unwrap();
+        assert!(text.starts_with("# What the server is called.\nname = \"creative\"\n"));
+        assert!(text.contains("# The port to listen on.\nport = 25575\n"));
+        assert!(text.contains("# The certificate's private key.\nkey_path = \"key.pem\"\n"));
+
+        // A JSON file showing up again later is left alone.
+        std::fs::write(dir.join("example.json"), r#"{"name": "survival"}"#).unwrap();
+        assert_eq!(read_config(path, example).unwrap().name, "creative");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn missing_configs_are_written_with_their_defaults() {
+        let dir = scratch_dir("missing");
+        let path = dir.join("example.toml");
+        let path = path.to_str().unwrap();
+        assert!(read_config(path, example).is_err());
+
+        assert_eq!(open_config(path, example).unwrap().port, 25575);
+        assert_eq!(std::fs::read_to_string(path).unwrap(), commented(&example()).unwrap().to_string().trim_start());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn broken_configs_are_reported_and_left_alone() {
+        let dir = scratch_dir("broken");
+        let path = dir.join("example.toml");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "name = \"secret\"\nport = \n").unwrap();
+        let why = open_config(path, example).err().unwrap().to_string();
+        assert!(why.starts_with(&format!("{path} couldn't be read")), "{why}");
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "name = \"secret\"\nport = \n");
+
+        // Old JSON configs that can't be converted are left alone too.
+        let legacy = dir.join("legacy.toml");
+        std::fs::write(dir.join("legacy.json"), "{\"name\": ").unwrap();
+        assert!(open_config(legacy.to_str().unwrap(), example).is_err());
+        assert!(!legacy.exists());
+        assert_eq!(std::fs::read_to_string(dir.join("legacy.json")).unwrap(), "{\"name\": ");
+
+        let json = dir.join("messages.json");
+        std::fs::write(&json, "{\"title\": ").unwrap();
+        assert!(open_json(json.to_str().unwrap(), || serde_json::json!({ "title": "Verification" })).is_err());
+        assert_eq!(std::fs::read_to_string(&json).unwrap(), "{\"title\": ");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
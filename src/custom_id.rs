@@ -0,0 +1,197 @@
+use regex::Regex;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::LazyLock;
+
+static APPROVE_ACCOUNT_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^([0-9]+)-([0-9a-f]{8}-(?:[0-9a-f]{4}-){3}[0-9a-f]{12})(?:-([a-z0-9_]+))?$").unwrap());
+static ACCOUNT_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^([0-9]+)-([0-9a-f]{8}-(?:[0-9a-f]{4}-){3}[0-9a-f]{12})$").unwrap());
+static TICKET_RATING_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^([0-9]+)-([1-5])$").unwrap());
+
+/// Every component and modal ID the bot puts on messages. Messages posted by older versions keep their IDs for as
+/// long as the message exists, so a format may gain new variants but must keep parsing everything it ever issued.
+/// Approve buttons without a server are both the format from before the bot handled several servers and the
+/// current one for single server setups.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum CustomId {
+    MuteDms,
+    NotificationPreference,
+    CreateTicket,
+    NewCode,
+    // Ticket channel ID.
+    CloseTicket(u64),
+    ClosedTicket,
+    TicketRating { channel_id: u64, rating: u8 },
+    // Ticket channel ID, on both the button and the modal it opens.
+    TicketComment(u64),
+    ApproveAccount { discord_id: u64, uuid: String, server: Option<String> },
+    // On both the button and the modal asking for a reason.
+    DenyAccount { discord_id: u64, uuid: String },
+    BanAccount { discord_id: u64, uuid: String },
+    // On the ephemeral message asking the moderator to confirm the ban.
+    BanConfirm { discord_id: u64, uuid: String },
+    UnbanAccount { discord_id: u64, uuid: String },
+    UnlinkAccount(u64),
+    // On the ephemeral message asking the moderator to confirm the unlink.
+    UnlinkConfirm(u64),
+    UnlinkCancel(u64),
+    ForgetConfirm(u64),
+    WhitelistAddMissing,
+    WhitelistFlagUnknown,
+    // The /members page to show.
+    MembersPage(usize),
+}
+
+impl CustomId {
+    pub(crate) fn parse(id: &str) -> Option<Self> {
+        Some(match id {
+            "mute-dms" => Self::MuteDms,
+            "notification-preference" => Self::NotificationPreference,
+            "create-ticket" => Self::CreateTicket,
+            "new-code" => Self::NewCode,
+            "closed-ticket" => Self::ClosedTicket,
+            "whitelist-add-missing" => Self::WhitelistAddMissing,
+            "whitelist-flag-unknown" => Self::WhitelistFlagUnknown,
+            _ => {
+                if let Some(channel_id) = id.strip_prefix("close-ticket-") {
+                    Self::CloseTicket(u64::from_str(channel_id).ok()?)
+                } else if let Some(rest) = id.strip_prefix("ticket-rating-") {
+                    let captures = TICKET_RATING_REGEX.captures(rest)?;
+                    Self::TicketRating { channel_id: u64::from_str(&captures[1]).ok()?, rating: u8::from_str(&captures[2]).ok()? }
+                } else if let Some(channel_id) = id.strip_prefix("ticket-comment-") {
+                    Self::TicketComment(u64::from_str(channel_id).ok()?)
+                } else if let Some(rest) = id.strip_prefix("approve-account-") {
+                    let captures = APPROVE_ACCOUNT_REGEX.captures(rest)?;
+                    Self::ApproveAccount {
+                        discord_id: u64::from_str(&captures[1]).ok()?,
+                        uuid: captures[2].to_owned(),
+                        server: captures.get(3).map(|server| server.as_str().to_owned()),
+                    }
+                } else if let Some(rest) = id.strip_prefix("deny-account-") {
+                    let captures = ACCOUNT_REGEX.captures(rest)?;
+                    Self::DenyAccount { discord_id: u64::from_str(&captures[1]).ok()?, uuid: captures[2].to_owned() }
+                } else if let Some(rest) = id.strip_prefix("ban-account-") {
+                    let captures = ACCOUNT_REGEX.captures(rest)?;
+                    Self::BanAccount { discord_id: u64::from_str(&captures[1]).ok()?, uuid: captures[2].to_owned() }
+                } else if let Some(rest) = id.strip_prefix("ban-confirm-") {
+                    let captures = ACCOUNT_REGEX.captures(rest)?;
+                    Self::BanConfirm { discord_id: u64::from_str(&captures[1]).ok()?, uuid: captures[2].to_owned() }
+                } else if let Some(rest) = id.strip_prefix("unban-account-") {
+                    let captures = ACCOUNT_REGEX.captures(rest)?;
+                    Self::UnbanAccount { discord_id: u64::from_str(&captures[1]).ok()?, uuid: captures[2].to_owned() }
+                } else if let Some(discord_id) = id.strip_prefix("unlink-account-") {
+                    Self::UnlinkAccount(u64::from_str(discord_id).ok()?)
+                } else if let Some(discord_id) = id.strip_prefix("unlink-confirm-") {
+                    Self::UnlinkConfirm(u64::from_str(discord_id).ok()?)
+                } else if let Some(discord_id) = id.strip_prefix("unlink-cancel-") {
+                    Self::UnlinkCancel(u64::from_str(discord_id).ok()?)
+                } else if let Some(page) = id.strip_prefix("members-page-") {
+                    Self::MembersPage(usize::from_str(page).ok()?)
+                } else if let Some(discord_id) = id.strip_prefix("forget-confirm-") {
+                    Self::ForgetConfirm(u64::from_str(discord_id).ok()?)
+                } else {
+                    return None;
+                }
+            }
+        })
+    }
+}
+
+impl Display for CustomId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MuteDms => write!(f, "mute-dms"),
+            Self::NotificationPreference => write!(f, "notification-preference"),
+            Self::CreateTicket => write!(f, "create-ticket"),
+            Self::NewCode => write!(f, "new-code"),
+            Self::CloseTicket(channel_id) => write!(f, "close-ticket-{channel_id}"),
+            Self::ClosedTicket => write!(f, "closed-ticket"),
+            Self::TicketRating { channel_id, rating } => write!(f, "ticket-rating-{channel_id}-{rating}"),
+            Self::TicketComment(channel_id) => write!(f, "ticket-comment-{channel_id}"),
+            Self::ApproveAccount { discord_id, uuid, server: Some(server) } => write!(f, "approve-account-{discord_id}-{uuid}-{server}"),
+            Self::ApproveAccount { discord_id, uuid, server: None } => write!(f, "approve-account-{discord_id}-{uuid}"),
+            Self::DenyAccount { discord_id, uuid } => write!(f, "deny-account-{discord_id}-{uuid}"),
+            Self::BanAccount { discord_id, uuid } => write!(f, "ban-account-{discord_id}-{uuid}"),
+            Self::BanConfirm { discord_id, uuid } => write!(f, "ban-confirm-{discord_id}-{uuid}"),
+            Self::UnbanAccount { discord_id, uuid } => write!(f, "unban-account-{discord_id}-{uuid}"),
+            Self::UnlinkAccount(discord_id) => write!(f, "unlink-account-{discord_id}"),
+            Self::UnlinkConfirm(discord_id) => write!(f, "unlink-confirm-{discord_id}"),
+            Self::UnlinkCancel(discord_id) => write!(f, "unlink-cancel-{discord_id}"),
+            Self::ForgetConfirm(discord_id) => write!(f, "forget-confirm-{discord_id}"),
+            Self::WhitelistAddMissing => write!(f, "whitelist-add-missing"),
+            Self::WhitelistFlagUnknown => write!(f, "whitelist-flag-unknown"),
+            Self::MembersPage(page) => write!(f, "members-page-{page}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DISCORD_ID: u64 = 123456789012345678;
+    const UUID: &str = "069a79f4-44e9-4726-a5be-fca90e38aaf5";
+
+    // IDs as the first versions of the bot put them on member and ticket messages, which are still out there.
+    #[test]
+    fn ids_from_the_first_versions_still_parse() {
+        assert_eq!(CustomId::parse("create-ticket"), Some(CustomId::CreateTicket));
+        assert_eq!(CustomId::parse("close-ticket-1187654321098765432"), Some(CustomId::CloseTicket(1187654321098765432)));
+        assert_eq!(
+            CustomId::parse("approve-account-123456789012345678-069a79f4-44e9-4726-a5be-fca90e38aaf5"),
+            Some(CustomId::ApproveAccount { discord_id: DISCORD_ID, uuid: UUID.to_owned(), server: None })
+        );
+        assert_eq!(CustomId::parse("unlink-account-123456789012345678"), Some(CustomId::UnlinkAccount(DISCORD_ID)));
+    }
+
+    #[test]
+    fn every_id_round_trips() {
+        let uuid = || UUID.to_owned();
+        let ids = [
+            CustomId::MuteDms,
+            CustomId::NotificationPreference,
+            CustomId::CreateTicket,
+            CustomId::NewCode,
+            CustomId::CloseTicket(DISCORD_ID),
+            CustomId::ClosedTicket,
+            CustomId::TicketRating { channel_id: DISCORD_ID, rating: 5 },
+            CustomId::TicketComment(DISCORD_ID),
+            CustomId::ApproveAccount { discord_id: DISCORD_ID, uuid: uuid(), server: None },
+            CustomId::ApproveAccount { discord_id: DISCORD_ID, uuid: uuid(), server: Some("survival".to_owned()) },
+            CustomId::DenyAccount { discord_id: DISCORD_ID, uuid: uuid() },
+            CustomId::BanAccount { discord_id: DISCORD_ID, uuid: uuid() },
+            CustomId::BanConfirm { discord_id: DISCORD_ID, uuid: uuid() },
+            CustomId::UnbanAccount { discord_id: DISCORD_ID, uuid: uuid() },
+            CustomId::UnlinkAccount(DISCORD_ID),
+            CustomId::UnlinkConfirm(DISCORD_ID),
+            CustomId::UnlinkCancel(DISCORD_ID),
+            CustomId::ForgetConfirm(DISCORD_ID),
+            CustomId::WhitelistAddMissing,
+            CustomId::WhitelistFlagUnknown,
+            CustomId::MembersPage(3),
+        ];
+        for id in ids {
+            let text = id.to_string();
+            assert!(text.len() <= 100, "{text} is over discord's custom ID limit");
+            assert_eq!(CustomId::parse(&text), Some(id), "{text}");
+        }
+    }
+
+    #[test]
+    fn malformed_ids_are_refused() {
+        for id in [
+            "",
+            "close-ticket-",
+            "close-ticket-abc",
+            "ticket-rating-1-6",
+            "approve-account-123456789012345678-069A79F4-44E9-4726-A5BE-FCA90E38AAF5",
+            "approve-account-123456789012345678-069a79f444e94726a5befca90e38aaf5",
+            "approve-account-123456789012345678-069a79f4-44e9-4726-a5be-fca90e38aaf5-Survival",
+            "deny-account-123456789012345678-069a79f4-44e9-4726-a5be-fca90e38aaf5-survival",
+            "unlink-account--1",
+            "members-page-x",
+            "something-else",
+        ] {
+            assert_eq!(CustomId::parse(id), None, "{id}");
+        }
+    }
+}
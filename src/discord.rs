@@ -1,405 +1,3427 @@
-use crate::{log, ChannelPair, Packet};
+use crate::analytics;
+use crate::audit::{Actor, AuditAction, AuditEntry};
+use crate::custom_id::CustomId;
+use crate::enrichment::Enrichment;
+use crate::events::{Event, EventSender};
+use crate::external_cache::ExternalCache;
+use crate::messages::Messages;
+use crate::notifications::{Importance, NotificationLevel, NotificationPreferences};
+use crate::panics::{Outcome, PanicGuard};
+use crate::panels::{PanelKind, PanelRecord, Panels};
+use crate::permissions::{Capability, Gate};
+use crate::priority::{self, Priority, PriorityCondition, PriorityFacts, PriorityRule};
+use crate::sanitize::{escape_markdown, sanitize_display};
+use crate::shutdown::Shutdown;
+use crate::staff::{StaffActivity, StaffTotals};
+use crate::tickets::TicketHistory;
+use crate::transcripts;
+use crate::{build_info, config, log, timestamp, ChannelPair, LinkDetails, Packet, VerifyState, WhitelistDiff};
 use anyhow::{anyhow, Result};
+use chrono::{Datelike, Months, NaiveDate, NaiveTime, Utc};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use serenity::all::{ButtonStyle, ChannelId, ComponentInteraction, Context, CreateButton, CreateChannel, CreateEmbed, CreateInteractionResponse, CreateInteractionResponseMessage, CreateMessage, EditChannel, EventHandler, GatewayIntents, GuildId, Http, Interaction, Member, Message, PermissionOverwrite, PermissionOverwriteType, Permissions, RoleId, User, UserId};
+use serenity::all::{ActionRowComponent, ActivityData, AutoArchiveDuration, ButtonStyle, ChannelId, CommandDataOption, CommandDataOptionValue, CommandInteraction, CommandOptionType, CreateAttachment, CreateCommandOption, CreateInteractionResponseFollowup, ComponentInteraction, ComponentInteractionDataKind, Context, CreateActionRow, CreateAllowedMentions, CreateButton, CreateChannel, CreateCommand, CreateEmbed, CreateEmbedFooter, CreateInputText, CreateInteractionResponse, CreateInteractionResponseMessage, CreateMessage, CreateModal, CreateSelectMenu, CreateSelectMenuKind, CreateSelectMenuOption, CreateThread, EditChannel, EditInteractionResponse, EditMember, Embed, EmbedField, EditMessage, EditThread, EventHandler, GatewayIntents, GetMessages, GuildId, GuildMemberUpdateEvent, Http, InputTextStyle, Interaction, Member, Message, MessageId, MessageUpdateEvent, ModalInteraction, PermissionOverwrite, PermissionOverwriteType, Permissions, Ready, RoleId, User, UserId};
+use serenity::cache::Settings as CacheSettings;
+use serenity::gateway::GatewayError;
+use serenity::http::HttpError;
 use serenity::{async_trait, Client};
-use std::fs::File;
+use std::future::Future;
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::process::exit;
 use std::str::FromStr;
-use std::sync::Arc;
-use tokio::sync::mpsc::UnboundedSender;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::watch;
 
-const DISCORD_CONFIG_PATH: &str = "./discord_config.json";
-pub(crate) const PRIMARY_COLOR: u32 = 0x30F4B0;
+const DISCORD_CONFIG_PATH: &str = "./discord_config.toml";
+const PRIMARY_COLOR: u32 = 0x30F4B0;
 const SECONDARY_COLOR: u32 = 0x50F3F1;
 const ERROR_COLOR: u32 = 0xEF1E02;
+const SLO_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 5);
+const CHAT_BATCH_WINDOW: Duration = Duration::from_secs(1);
+// Discord rate limits presence updates, so the player count is shown at most this often.
+const PRESENCE_UPDATE_INTERVAL: Duration = Duration::from_secs(30);
+const MESSAGE_LIMIT: usize = 2000;
+const HOUR_MILLIS: u64 = 1000 * 60 * 60;
+const DAY_MILLIS: u64 = HOUR_MILLIS * 24;
+const SURVEY_WINDOW: u64 = HOUR_MILLIS * 48;
+const STAFF_REPORT_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// The error discord returns when a user has blocked the bot or doesn't accept DMs from the guild.
+const CANNOT_DM_CODE: isize = 50007;
+const DM_BLOCKED_FIELD: &str = "📵 Couldn't DM";
+// Names can change hands, so name lookups aren't trusted for long.
+const MOJANG_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+const STAFF_LEADERBOARD_SIZE: usize = 20;
+// Most users /pending lists before summarizing the rest.
+const PENDING_LIST_SIZE: usize = 25;
+// Members on each page of /members.
+const MEMBERS_PAGE_SIZE: usize = 25;
+// How long /whitelist diff waits for a game server to upload its whitelist.
+const WHITELIST_UPLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+const NO_PERMISSION: &str = "You don't have permission to do that.";
+const CODE_MIN: i32 = 100000;
+const CODE_MAX: i32 = 999999;
+// How many recent messages to look through for a panel posted before the bot kept records of them.
+const PANEL_SEARCH_DEPTH: u8 = 50;
+// How long a status posted in the verification channel for a user with closed DMs stays up.
+const STATUS_FALLBACK_LIFETIME: Duration = Duration::from_secs(60);
+// How long a verified role removal by the bot is expected to show up as a member update.
+const ROLE_REMOVAL_WINDOW: Duration = Duration::from_secs(30);
+// How many fields `member_embed` starts member messages with.
+const MEMBER_EMBED_FIELDS: usize = 6;
+const REMINDER_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 15);
+// The most guild members discord returns at once.
+const MEMBER_PAGE_SIZE: u64 = 1000;
 
 struct Handler {
     sender: UnboundedSender<ChannelPair<Packet>>,
-    config: DiscordConfig,
+    config: Arc<DiscordConfig>,
+    notifications: Mutex<NotificationPreferences>,
+    panels: Mutex<Panels>,
+    tasks_started: AtomicBool,
+    events: EventSender,
+    tickets: Arc<Mutex<TicketHistory>>,
+    shutdown: Arc<Shutdown>,
+    enrichment: Enrichment,
+    staff: Arc<Mutex<StaffActivity>>,
+    external_cache: Arc<ExternalCache>,
+    messages: Arc<Messages>,
+    // The last whitelist comparison, which its follow-up buttons act on.
+    last_whitelist_diff: Mutex<Option<WhitelistDiff>>,
+    panics: Arc<PanicGuard>,
+    role_removals: Arc<RoleRemovals>,
+    // Custom IDs of the buttons whose clicks are being handled.
+    in_flight: Mutex<HashSet<String>>,
+    // How many players are online, or None while no game server is known to be up.
+    online_players: watch::Receiver<Option<usize>>,
+}
+
+// Verified roles the bot took off members itself, so the member updates that follow aren't mistaken for a
+// moderator removing the role by hand. Entries run out, as no update comes when the member didn't have the role.
+#[derive(Default)]
+struct RoleRemovals(Mutex<HashMap<u64, Instant>>);
+
+impl RoleRemovals {
+    fn expect(&self, user_id: UserId) {
+        self.0.lock().unwrap().insert(user_id.get(), Instant::now());
+    }
+
+    // Whether the bot removed the user's role recently, which the caller is now handling.
+    fn take(&self, user_id: UserId) -> bool {
+        let mut removals = self.0.lock().unwrap();
+        removals.retain(|_, removed| removed.elapsed() < ROLE_REMOVAL_WINDOW);
+        removals.remove(&user_id.get()).is_some()
+    }
+}
+
+struct Claim<'a> {
+    in_flight: &'a Mutex<HashSet<String>>,
+    key: String,
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        self.in_flight.lock().unwrap().remove(&self.key);
+    }
+}
+
+// Take the verified role off the user in the main guild and every linked guild.
+async fn remove_verified_role(http: &Arc<Http>, config: &DiscordConfig, role_removals: &RoleRemovals, user_id: UserId) {
+    for guild_id in [None].into_iter().chain(config.linked_guilds.iter().map(|guild| Some(guild.guild_id))) {
+        remove_verified_role_in(http, config, role_removals, user_id, guild_id).await;
+    }
+}
+
+// Take the verified role off the user in the main guild (None) or a linked guild.
+async fn remove_verified_role_in(http: &Arc<Http>, config: &DiscordConfig, role_removals: &RoleRemovals, user_id: UserId, guild_id: Option<u64>) {
+    let Some((guild_id, role_id)) = config.verified_role(guild_id) else { return };
+    if guild_id == config.guild_id {
+        role_removals.expect(user_id);
+    }
+    let _ = http.remove_member_role(guild_id, user_id, role_id, None).await;
 }
 
 impl Handler {
-    fn new(sender: UnboundedSender<ChannelPair<Packet>>, config: DiscordConfig) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    fn new(sender: UnboundedSender<ChannelPair<Packet>>, config: Arc<DiscordConfig>, notifications: NotificationPreferences, panels: Panels, events: EventSender, tickets: TicketHistory, shutdown: Arc<Shutdown>, enrichment: Enrichment, staff: Arc<Mutex<StaffActivity>>, external_cache: Arc<ExternalCache>, messages: Arc<Messages>, role_removals: Arc<RoleRemovals>, online_players: watch::Receiver<Option<usize>>) -> Self {
+        let panics = Arc::new(PanicGuard::new(config.panic_limit, Duration::from_secs(config.panic_window_mins * 60)));
         Self {
             sender,
             config,
+            notifications: Mutex::new(notifications),
+            panels: Mutex::new(panels),
+            tasks_started: AtomicBool::new(false),
+            events,
+            tickets: Arc::new(Mutex::new(tickets)),
+            shutdown,
+            enrichment,
+            staff,
+            external_cache,
+            messages,
+            last_whitelist_diff: Mutex::new(None),
+            panics,
+            role_removals,
+            in_flight: Mutex::new(HashSet::new()),
+            online_players,
         }
     }
 
-    async fn handle_verify_message(&self, ctx: Context, msg: Message) -> Result<()> {
-        // Create a new message when told.
-        if msg.content == "!msg" && msg.author.has_role(&ctx.http, self.config.guild_id, self.config.staff_role_id).await? {
-            msg.channel_id.send_message(&ctx.http, CreateMessage::new()
-                .embed(CreateEmbed::new()
-                    .title("CloverCraft SMP")
-                    .description("Welcome to the CloverCraft SMP! To verify your account, please join the Minecraft server and type the code it gives you into this channel. You will not be able to play until you have verified your account and an admin has approved it. The bot will DM you in order to confirm your verification statuses.")
-                    .color(PRIMARY_COLOR)
-                ),
-            ).await?;
-        }
-
-        // Parse a code - we can't verify it here, so send it to the main thread.
-        if let Ok(code) = i32::from_str(&msg.content) && matches!(code, (100000..1000000)) {
-            let mut local_pair = ChannelPair::new();
-            self.sender.send(local_pair.entangle())?;
-
-            local_pair.sender.send(Packet::DiscordCode(code, msg.author.id.get()))?;
-
-            // Check if the code worked.
-            let packet = local_pair.receiver.recv().await.ok_or(anyhow!("Main thread did not reply to discord bot!"))?;
-            match packet {
-                // The code was valid - send the user a direct message and send the approval message in the members channel.
-                Packet::VerifyPending(uuid, name) => {
-                    let _ = msg.author.direct_message(&ctx.http, CreateMessage::new().embed(
-                        CreateEmbed::new()
-                            .title("CloverCraft SMP")
-                            .description("Your whitelist status has been updated.")
-                            .field("Status", "Pending", false)
-                            .color(SECONDARY_COLOR)
-                    )).await;
-
-                    let discord_id = msg.author.id.get();
-                    let message = self.add_user_verify(&ctx.http, &name, &uuid, discord_id).await?;
-                    local_pair.sender.send(Packet::LinkVerifyMessage(message.id.get()))?;
-                }
+    // Hold a button until the returned claim is dropped, or None if it's already held.
+    fn claim(&self, key: String) -> Option<Claim<'_>> {
+        self.in_flight.lock().unwrap().insert(key.clone()).then_some(Claim { in_flight: &self.in_flight, key })
+    }
 
-                // The code was invalid
-                Packet::VerifyCodeInvalid => {
-                    let _ = msg.author.direct_message(&ctx.http, CreateMessage::new().embed(
-                        CreateEmbed::new()
-                            .title("CloverCraft SMP")
-                            .description("You did not send a valid verification code. Please ensure that you have typed the code exactly as it appeared in Minecraft.")
-                            .color(ERROR_COLOR)
-                    )).await;
-                }
+    // Post a panel and remember it so it can be refreshed when its content changes.
+    async fn post_panel(&self, http: &Arc<Http>, channel_id: ChannelId, kind: PanelKind) -> Result<()> {
+        let (embed, buttons) = render_panel(kind, &self.config, &self.messages);
+        let hash = panel_hash(&embed, &buttons)?;
+        let message = channel_id.send_message(http, CreateMessage::new().embed(embed).components(buttons)).await?;
+        self.panels.lock().unwrap().set(kind, PanelRecord {
+            channel_id: channel_id.get(),
+            message_id: message.id.get(),
+            hash,
+        })
+    }
 
-                // The user is already verifying
-                Packet::AlreadyLinked => {
-                    let _ = msg.author.direct_message(&ctx.http, CreateMessage::new().embed(
-                        CreateEmbed::new()
-                            .title("CloverCraft SMP")
-                            .description("You cannot link more than one Minecraft account.")
-                            .color(ERROR_COLOR)
-                    )).await;
-                }
+    // Make sure each configured panel channel has its panel, so nobody has to run /setup. Panels posted before
+    // the bot kept records are adopted rather than duplicated, deleted ones are reposted, and any whose content no
+    // longer matches what we'd render now are edited.
+    async fn refresh_panels(&self, http: &Arc<Http>) -> Result<()> {
+        let bot_id = http.get_current_user().await?.id;
+        for (kind, channel_id) in [(PanelKind::Verification, self.config.verification_channel_id), (PanelKind::Ticket, self.config.ticket_channel_id)] {
+            if channel_id == 0 {
+                continue;
+            }
+            let channel_id = ChannelId::new(channel_id);
+            let (embed, buttons) = render_panel(kind, &self.config, &self.messages);
+            let hash = panel_hash(&embed, &buttons)?;
 
-                x => return Err(anyhow!("Unexpected packet {x:?} received on discord thread!")),
+            let recorded = self.panels.lock().unwrap().get(kind).filter(|record| record.channel_id == channel_id.get());
+            let record = match recorded {
+                Some(record) if channel_id.message(http, record.message_id).await.is_ok() => record,
+                recorded => match find_panel(http, channel_id, bot_id, kind, &self.messages).await? {
+                    // Never hashed, so it gets edited below.
+                    Some(message_id) => {
+                        log!("Found an unrecorded {kind:?} panel, adopting it");
+                        PanelRecord { channel_id: channel_id.get(), message_id, hash: 0 }
+                    }
+                    None => {
+                        log!("The {kind:?} panel is {}, posting it", if recorded.is_some() { "deleted" } else { "missing" });
+                        self.post_panel(http, channel_id, kind).await?;
+                        continue;
+                    }
+                },
+            };
+            if hash == record.hash {
+                continue;
             }
+
+            channel_id.edit_message(http, record.message_id, EditMessage::new().embed(embed).components(buttons)).await?;
+            self.panels.lock().unwrap().set(kind, PanelRecord { hash, ..record })?;
+            log!("Refreshed the {kind:?} panel with updated content");
         }
+        Ok(())
+    }
 
-        // Delete non-bot messages.
-        if !msg.author.bot {
-            msg.delete(&ctx.http).await?;
+    // DM a user, respecting their notification preferences. Every DM carries a button to mute the bot. Users who
+    // can't be DMed only get important DMs, which are mostly replies to something they just did, and the first
+    // one that gets through clears the flag again.
+    async fn send_dm(&self, http: &Arc<Http>, user_id: UserId, importance: Importance, embed: CreateEmbed) -> Result<()> {
+        self.send_dm_with_buttons(http, user_id, importance, embed, vec![]).await
+    }
+
+    async fn send_dm_with_buttons(&self, http: &Arc<Http>, user_id: UserId, importance: Importance, embed: CreateEmbed, buttons: Vec<CreateButton>) -> Result<()> {
+        if !self.notifications.lock().unwrap().should_send(user_id.get(), importance) {
+            return Ok(());
         }
 
+        let mut rows = vec![];
+        if !buttons.is_empty() {
+            rows.push(CreateActionRow::Buttons(buttons));
+        }
+        rows.push(CreateActionRow::Buttons(vec![CreateButton::new(CustomId::MuteDms.to_string()).label("Mute bot DMs").style(ButtonStyle::Secondary)]));
+
+        let result = user_id.direct_message(http, CreateMessage::new().embed(embed).components(rows)).await;
+        let blocked = match &result {
+            Ok(_) => Some(false),
+            Err(why) if cannot_dm(why) => Some(true),
+            Err(_) => None,
+        };
+        if let Some(blocked) = blocked && self.notifications.lock().unwrap().set_blocked(user_id.get(), blocked)? {
+            log!("{} DMs to user {user_id}", if blocked { "Discord is refusing" } else { "Discord is accepting" });
+        }
+        result?;
         Ok(())
     }
 
-    async fn handle_ticket_message(&self, ctx: Context, msg: Message) -> Result<()> {
-        // Create a new message when told.
-        if msg.content == "!msg" && msg.author.has_role(&ctx.http, self.config.guild_id, self.config.staff_role_id).await? {
-            msg.channel_id.send_message(&ctx.http, CreateMessage::new()
-                .embed(CreateEmbed::new()
-                    .title("CloverCraft Tickets")
-                    .description("If you need to discuss something in private with the team, this is the place. Simply press the 'Create Ticket' button below to open a new ticket. Be prepared to describe your issue once the ticket is open.")
-                    .color(PRIMARY_COLOR)
-                )
-                .button(CreateButton::new("create-ticket")
-                    .label("Create Ticket")
-                ),
-            ).await?;
+    // DM the user about their verification, falling back to a short-lived mention in the verification channel when
+    // discord won't deliver it. Fallbacks for a known account are noted in the audit log, since the user may not have
+    // seen them.
+    async fn send_status(&self, http: &Arc<Http>, user_id: UserId, importance: Importance, embed: CreateEmbed, subject: Option<(&str, &str)>) -> Result<()> {
+        if let Err(why) = self.send_dm(http, user_id, importance, embed.clone()).await {
+            log!("Error sending status DM to user {user_id}: {why:?}");
+        }
+        let (level, blocked) = {
+            let notifications = self.notifications.lock().unwrap();
+            (notifications.get(user_id.get()), notifications.is_blocked(user_id.get()))
+        };
+        if !level.allows(importance) || !blocked {
+            return Ok(());
         }
 
-        // Delete non-bot messages.
-        if !msg.author.bot {
-            msg.delete(&ctx.http).await?;
+        let channel_id = ChannelId::new(self.config.verification_channel_id);
+        let message = channel_id.send_message(http,
+            CreateMessage::new()
+                .content(self.messages.render("status.fallback", &[("discord_id", &user_id.to_string())]))
+                .embed(embed)
+                .allowed_mentions(CreateAllowedMentions::new().users(vec![user_id]))
+        ).await?;
+        let cleanup = http.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(STATUS_FALLBACK_LIFETIME).await;
+            let _ = message.delete(&cleanup).await;
+        });
+
+        if let Some((name, uuid)) = subject {
+            post_audit(http, &self.config, &AuditEntry::new(AuditAction::DmFailed, name, uuid, Some(user_id.get()), Actor::Bot)).await?;
         }
+        Ok(())
+    }
 
+    async fn set_notification_level(&self, http: &Arc<Http>, user_id: UserId, level: NotificationLevel, component: &ComponentInteraction) -> Result<()> {
+        self.notifications.lock().unwrap().set(user_id.get(), level)?;
+        component.create_response(http, CreateInteractionResponse::Message(
+            CreateInteractionResponseMessage::new()
+                .content(format!("Your bot DM preference is now: {}. You can change it at any time with /notifications.", level.label()))
+                .ephemeral(true)
+        )).await?;
         Ok(())
     }
 
-    async fn handle_member_message(&self, ctx: Context, msg: Message) -> Result<()> {
-        let regex = Regex::new(r"^!link ([a-zA-Z0-9_]+) <@([0-9]+)>$")?;
-        if let Some(captures) = regex.captures(&msg.content) {
-            let username = captures[1].to_owned();
-            let discord_id = u64::from_str(&captures[2])?;
-            let guild_id = GuildId::new(self.config.guild_id);
-            if guild_id.member(&ctx.http, discord_id).await.is_ok() && let Ok(uuid) = self.get_uuid(&username).await {
-                let mut pair = ChannelPair::new();
-                self.sender.send(pair.entangle())?;
-                pair.sender.send(Packet::UserQuery(uuid.clone(), discord_id))?;
+    async fn show_stats(&self, http: &Arc<Http>, command: &CommandInteraction) -> Result<()> {
+        let mut pair = ChannelPair::new();
+        self.sender.send(pair.entangle())?;
+        pair.sender.send(Packet::ApprovalTimesQuery(timestamp().saturating_sub(30 * DAY_MILLIS)))?;
+        let Some(Packet::ApprovalTimesResponse(times)) = pair.receiver.recv().await else { return Err(anyhow!("Main thread did not respond with approval times!")) };
+        let mut pair = ChannelPair::new();
+        self.sender.send(pair.entangle())?;
+        pair.sender.send(Packet::RoundTripQuery)?;
+        let Some(Packet::RoundTripResponse(round_trips)) = pair.receiver.recv().await else { return Err(anyhow!("Main thread did not respond with round trips!")) };
 
-                let Some(Packet::UserResponse(success)) = pair.receiver.recv().await else { return Err(anyhow!("Main thread did not respond with user response!")) };
-                if success {
-                    let message = self.add_user_verify(&ctx.http, &username, &uuid, discord_id).await?;
-                    pair.sender.send(Packet::AddUserManually(username, uuid, discord_id, message.id.get()))?;
-                }
-            }
+        let panics = self.panics.summary();
+        let ratings = self.tickets.lock().unwrap().records().iter().filter_map(|record| record.rating).collect::<Vec<u8>>();
+        let satisfaction = match ratings.len() {
+            0 => "N/A".to_owned(),
+            count => format!("{:.2}/5 from {count} ratings", ratings.iter().map(|rating| *rating as f64).sum::<f64>() / count as f64),
+        };
+
+        let target = self.config.approval_slo_hours * HOUR_MILLIS;
+        let compliance = if target == 0 || times.is_empty() {
+            "N/A".to_owned()
+        } else {
+            let within = times.iter().filter(|(pending_since, approved_at)| approved_at.saturating_sub(*pending_since) <= target).count();
+            format!("{:.1}% of {} approvals within {} hours", within as f64 * 100.0 / times.len() as f64, times.len(), self.config.approval_slo_hours)
+        };
+
+        command.create_response(http, CreateInteractionResponse::Message(
+            CreateInteractionResponseMessage::new()
+                .embed(CreateEmbed::new()
+                    .title(self.messages.get("stats.title"))
+                    .field("Version", build_info::summary(), false)
+                    .field("Approval SLO (30 days)", compliance, false)
+                    .field("Ticket satisfaction", satisfaction, false)
+                    .field("Connection round trips", round_trip_summary(&round_trips), false)
+                    .field("Handler panics", panics.unwrap_or_else(|| "None".to_owned()), false)
+                    .color(self.config.colors.primary)
+                )
+                .ephemeral(true)
+        )).await?;
+        Ok(())
+    }
+
+    // Attach the analytics rollups for a range of days as a single CSV.
+    async fn export_analytics(&self, http: &Arc<Http>, command: &CommandInteraction, option: &CommandDataOption) -> Result<()> {
+        if !self.allowed(http, &command.user, command.channel_id, Capability::ExportAnalytics).await? {
+            command.create_response(http, CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new().content("Only staff can export analytics.").ephemeral(true)
+            )).await?;
+            return Ok(());
         }
 
-        // Delete non-bot messages.
-        if !msg.author.bot {
-            msg.delete(&ctx.http).await?;
+        let CommandDataOptionValue::SubCommand(options) = &option.value else { return Err(anyhow!("export-range is not a subcommand!")) };
+        let day = |name: &str| options.iter().find(|option| option.name == name).and_then(|option| option.value.as_str()).and_then(|day| NaiveDate::parse_from_str(day, "%Y-%m-%d").ok());
+        let range = match (day("from"), day("to")) {
+            (Some(from), Some(to)) if from > to => Err("The first day must not be after the last day."),
+            (Some(from), Some(to)) if (to - from).num_days() >= analytics::MAX_EXPORT_DAYS => Err("Exports are limited to a year at a time."),
+            (Some(from), Some(to)) => Ok((from, to)),
+            _ => Err("Days must be given as YYYY-MM-DD."),
+        };
+        let (from, to) = match range {
+            Ok(range) => range,
+            Err(message) => {
+                command.create_response(http, CreateInteractionResponse::Message(
+                    CreateInteractionResponseMessage::new().content(message).ephemeral(true)
+                )).await?;
+                return Ok(());
+            }
+        };
+
+        let export = analytics::export_range(from, to)?;
+        command.create_response(http, CreateInteractionResponse::Message(
+            CreateInteractionResponseMessage::new()
+                .content(format!("Analytics from {from} to {to}."))
+                .add_file(CreateAttachment::bytes(export, format!("analytics-{from}-{to}.csv")))
+                .ephemeral(true)
+        )).await?;
+        Ok(())
+    }
+
+    async fn show_staff_activity(&self, http: &Arc<Http>, command: &CommandInteraction, option: &CommandDataOption) -> Result<()> {
+        if !self.allowed(http, &command.user, command.channel_id, Capability::ViewStaffActivity).await? {
+            command.create_response(http, CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new().content("Only staff can view the staff leaderboard.").ephemeral(true)
+            )).await?;
+            return Ok(());
         }
 
+        let CommandDataOptionValue::SubCommand(options) = &option.value else { return Err(anyhow!("staff is not a subcommand!")) };
+        let range = options.iter().find(|option| option.name == "range").and_then(|option| option.value.as_str()).unwrap_or("all");
+        let (from, title) = match range {
+            "7d" => (timestamp().saturating_sub(7 * DAY_MILLIS), "Staff activity (7 days)"),
+            "30d" => (timestamp().saturating_sub(30 * DAY_MILLIS), "Staff activity (30 days)"),
+            _ => (0, "Staff activity (all time)"),
+        };
+
+        let totals = staff_leaderboard(&self.sender, &self.staff, &self.tickets, from, u64::MAX).await?;
+        command.create_response(http, CreateInteractionResponse::Message(
+            CreateInteractionResponseMessage::new().embed(staff_embed(&self.config, title, &totals)).ephemeral(true)
+        )).await?;
         Ok(())
     }
 
-    async fn add_user_verify(&self, http: &Arc<Http>, name: &str, uuid: &str, discord_id: u64) -> Result<Message> {
-        Ok(ChannelId::new(self.config.member_channel_id).send_message(http,
-                                                                      CreateMessage::new()
-                                                                          .embed(
-                                                                              CreateEmbed::new()
-                                                                                  .thumbnail(format!("https://www.mc-heads.net/head/{uuid}.png"))
-                                                                                  .title("CloverCraft SMP")
-                                                                                  .field("Minecraft Name", name, true)
-                                                                                  .field("Minecraft UUID", uuid, true)
-                                                                                  .field("", "", true)
-                                                                                  .field("Discord User", format!("<@{discord_id}>"), true)
-                                                                                  .field("Discord ID", format!("{discord_id}"), true)
-                                                                                  .field("", "", true)
-                                                                                  .color(PRIMARY_COLOR)
-                                                                          )
-                                                                          .button(CreateButton::new(format!("approve-account-{discord_id}-{uuid}")).label("Approve")),
-        ).await?)
+    // Whether a user may use a capability from the given channel.
+    async fn allowed(&self, http: &Arc<Http>, user: &User, channel_id: ChannelId, capability: Capability) -> Result<bool> {
+        Ok(match capability.gate() {
+            Gate::StaffRole => self.is_staff(http, user.id).await?,
+            Gate::MemberChannel => channel_id.get() == self.config.member_channel_id,
+        })
     }
 
-    async fn get_uuid(&self, name: &str) -> Result<String> {
-        let response = reqwest::get(format!("https://api.minecraftservices.com/minecraft/profile/lookup/name/{name}")).await?;
-        if response.status().as_u16() == 200 {
-            let data: Value = serde_json::from_str(&response.text().await?)?;
-            let id = data["id"].as_str().ok_or(anyhow!("ID wasn't a string!"))?.to_owned();
-            let regex = Regex::new(r"^[0-9a-f]{32}$")?;
-            if !regex.is_match(&id) {
-                return Err(anyhow!("Invalid data returned from the API!"));
-            }
-            Ok(format!("{}-{}-{}-{}-{}", &id[0..8], &id[8..12], &id[12..16], &id[16..20], &id[20..32]))
-        } else {
-            Err(anyhow!("Invalid Minecraft account!"))
-        }
+    // Holding any of the staff roles makes a member staff.
+    async fn is_staff(&self, http: &Arc<Http>, user_id: UserId) -> Result<bool> {
+        let member = GuildId::new(self.config.guild_id).member(http, user_id).await?;
+        Ok(self.config.staff_roles().iter().any(|role| member.roles.contains(role)))
     }
 
-    async fn open_ticket(&self, http: &Arc<Http>, user: &User, component: &ComponentInteraction) -> Result<()> {
-        // Create the new ticket channel and give the creator permission to see it.
-        let ticket_channel = GuildId::new(self.config.guild_id).create_channel(http, CreateChannel::new(format!("ticket-{}", user.name)).category(self.config.active_ticket_category_id)).await?;
-        ticket_channel.create_permission(http, PermissionOverwrite {
-            allow: Permissions::VIEW_CHANNEL,
-            deny: Default::default(),
-            kind: PermissionOverwriteType::Member(user.id),
-        }).await?;
+    // Describe the permission model the bot enforces, with the staff roles resolved to their current members.
+    async fn show_permissions(&self, http: &Arc<Http>, command: &CommandInteraction) -> Result<()> {
+        if !self.allowed(http, &command.user, command.channel_id, Capability::ViewPermissions).await? {
+            command.create_response(http, CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new().content("Only staff can view the permission report.").ephemeral(true)
+            )).await?;
+            return Ok(());
+        }
+        command.defer_ephemeral(http).await?;
 
-        // Create the initial message / close ticket button
-        let initial_message = CreateMessage::new()
-            .content(
-                format!("<@{}> <@&{}>", user.id, self.config.staff_role_id)
-            )
-            .embed(
-                CreateEmbed::new()
-                    .title("CloverCraft Ticket")
-                    .description("Thank you for opening a ticket. Please describe your issue below. A staff member will reach out to help as soon as possible.")
-                    .color(PRIMARY_COLOR)
+        let guild_id = GuildId::new(self.config.guild_id);
+        let roles = guild_id.roles(http).await?;
+        let role_name = |id: u64| roles.get(&RoleId::new(id)).map(|role| role.name.clone()).unwrap_or("unknown role".to_owned());
+
+        let staff_roles = self.config.staff_roles();
+        let mut staff = vec![];
+        let mut after = None;
+        loop {
+            let page = guild_id.members(http, Some(MEMBER_PAGE_SIZE), after).await?;
+            after = page.last().map(|member| member.user.id);
+            staff.extend(page.iter().filter(|member| staff_roles.iter().any(|role| member.roles.contains(role))).map(|member| member.user.clone()));
+            if (page.len() as u64) < MEMBER_PAGE_SIZE {
+                break;
+            }
+        }
+
+        let mut report = format!("# Permission report\n\nGenerated {}\n\n", chrono::offset::Local::now().format("%Y-%m-%d %H:%M:%S"));
+        let names = staff_roles.iter().map(|role| format!("{} ({role})", role_name(role.get()))).collect::<Vec<_>>();
+        report.push_str(&format!("## Staff roles: {}\n\n", names.join(", ")));
+        for user in &staff {
+            report.push_str(&format!("- {} ({})\n", user.name, user.id));
+        }
+        report.push_str(&format!("\n## Members channel ({})\n\nAnyone discord lets see this channel.\n\n## Capabilities\n\n| Capability | Gate | Description |\n| --- | --- | --- |\n", self.config.member_channel_id));
+        for capability in Capability::ALL {
+            report.push_str(&format!("| {capability:?} | {:?} | {} |\n", capability.gate(), capability.description()));
+        }
+        if self.config.discussion_threads {
+            let bot = guild_id.member(http, http.get_current_user().await?.id).await?;
+            let channel = ChannelId::new(self.config.member_channel_id).to_channel(http).await?.guild().ok_or(anyhow!("Members channel is not a guild channel!"))?;
+            let permissions = guild_id.to_partial_guild(http).await?.user_permissions_in(&channel, &bot);
+            report.push_str("\n## Discussion threads\n\n");
+            for (name, permission) in [("Create Public Threads", Permissions::CREATE_PUBLIC_THREADS), ("Send Messages in Threads", Permissions::SEND_MESSAGES_IN_THREADS)] {
+                report.push_str(&format!("- {name}: {}\n", if permissions.contains(permission) { "granted" } else { "**missing**" }));
+            }
+        }
+        report.push_str("\n## Priority roles\n\n");
+        for rule in &self.config.priority_rules {
+            if let PriorityCondition::Role(role) = rule.condition {
+                report.push_str(&format!("- {} ({role}): {} priority\n", role_name(role), rule.level.label()));
+            }
+        }
+
+        let gated = |gate| Capability::ALL.iter().filter(|capability| capability.gate() == gate).map(|capability| format!("{capability:?}")).collect::<Vec<_>>().join(", ");
+        command.create_followup(http, CreateInteractionResponseFollowup::new()
+            .embed(CreateEmbed::new()
+                .title("Permission Report")
+                .field("Staff roles", format!("{} ({} members)", role_mentions(&staff_roles), staff.len()), false)
+                .field("Staff only", gated(Gate::StaffRole), false)
+                .field("Members channel", format!("<#{}>: {}", self.config.member_channel_id, gated(Gate::MemberChannel)), false)
+                .color(self.config.colors.primary)
             )
-            .button(
-                CreateButton::new(format!("close-ticket-{}", ticket_channel.id))
-                    .label("Close Ticket")
-            );
+            .add_file(CreateAttachment::bytes(sanitize_display(&report).into_bytes(), "permissions.md"))
+            .ephemeral(true)
+        ).await?;
+        Ok(())
+    }
 
-        ticket_channel.send_message(http, initial_message).await?;
-        component.create_response(http, CreateInteractionResponse::Acknowledge).await?;
+    // Ask for confirmation before erasing a user's data. Users can erase themselves, staff can erase anyone.
+    async fn request_forget(&self, http: &Arc<Http>, command: &CommandInteraction, target: UserId) -> Result<()> {
+        if target != command.user.id && !self.allowed(http, &command.user, command.channel_id, Capability::ForgetUsers).await? {
+            command.create_response(http, CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new().content("Only staff can erase another user's data.").ephemeral(true)
+            )).await?;
+            return Ok(());
+        }
+
+        command.create_response(http, CreateInteractionResponse::Message(
+            CreateInteractionResponseMessage::new()
+                .content(format!("This will unlink <@{target}>'s Minecraft account and delete everything the bot stores about them, including ticket history and notification preferences. This cannot be undone."))
+                .button(CreateButton::new(CustomId::ForgetConfirm(target.get()).to_string()).label("Erase data").style(ButtonStyle::Danger))
+                .ephemeral(true)
+        )).await?;
         Ok(())
     }
 
-    async fn close_ticket(&self, http: &Arc<Http>, id: &str, component: &ComponentInteraction) -> Result<()> {
-        let channel_id = ChannelId::new(u64::from_str(id.split_at(13).1)?);
-        let mut channel = channel_id.to_channel(http).await?.guild().ok_or(anyhow!("Channel was not a guild channel!"))?;
+    async fn confirm_forget(&self, http: &Arc<Http>, target: UserId, component: &ComponentInteraction) -> Result<()> {
+        if target != component.user.id && !self.allowed(http, &component.user, component.channel_id, Capability::ForgetUsers).await? {
+            return Ok(());
+        }
 
-        // Remove all custom permissions
-        for permission_overwrite in channel.permission_overwrites.iter()
-            .filter_map(|o| match o.kind {
-                PermissionOverwriteType::Member(_) => Some(o.kind),
-                _ => None,
-            }) {
-            channel.delete_permission(http, permission_overwrite).await?;
+        // Unlink the account and forget the approval history
+        let mut local_pair = ChannelPair::new();
+        self.sender.send(local_pair.entangle())?;
+        local_pair.sender.send(Packet::ForgetUser(target.get(), component.user.id.get()))?;
+        if let Some(Packet::RemoveMessages(messages)) = local_pair.receiver.recv().await {
+            for (guild_id, message_id) in messages {
+                if let Some(channel_id) = self.config.member_channel(guild_id) {
+                    let _ = channel_id.delete_message(http, message_id).await;
+                }
+            }
         }
+        remove_verified_role(http, &self.config, &self.role_removals, target).await;
 
-        // Move the ticket into the archived tickets category, disable the close ticket button
-        channel.edit(http, EditChannel::new().category(Some(ChannelId::new(self.config.archive_ticket_category_id)))).await?;
-        component.create_response(http, CreateInteractionResponse::UpdateMessage(CreateInteractionResponseMessage::new().button(CreateButton::new("closed-ticket").label("Ticket closed").disabled(true)))).await?;
+        self.notifications.lock().unwrap().forget(target.get())?;
+        self.tickets.lock().unwrap().forget(target.get())?;
+        self.staff.lock().unwrap().forget(target.get())?;
+
+        // The reference is random, so it can't be traced back to the user.
+        let reference = format!("erasure-{:016x}", rand::random::<u64>());
+        log!("Completed data erasure {reference}");
+
+        let _ = target.direct_message(http, CreateMessage::new().embed(
+            CreateEmbed::new()
+                .title(self.messages.get("title"))
+                .description(self.messages.render("erasure.done", &[("reference", &reference), ("discord_id", &target.to_string())]))
+                .color(self.config.colors.primary)
+        )).await;
+
+        post_log(http, &self.config, CreateMessage::new().embed(
+            CreateEmbed::new()
+                .title("Data erasure completed")
+                .description(format!("Reference `{reference}`"))
+                .color(self.config.colors.secondary)
+        )).await?;
+
+        component.create_response(http, CreateInteractionResponse::UpdateMessage(
+            CreateInteractionResponseMessage::new()
+                .content(format!("The data has been erased. Reference: `{reference}`"))
+                .components(vec![])
+        )).await?;
         Ok(())
     }
 
-    async fn approve_account(&self, http: &Arc<Http>, id: &str, component: &ComponentInteraction) -> Result<()> {
-        let regex = Regex::new(r"^approve-account-([0-9]+)-([0-9a-f]{8}-([0-9a-f]{4}-){3}[0-9a-f]{12})$")?;
-        let captures = regex.captures(id).ok_or(anyhow!("Invalid button id!"))?;
-        let discord_id = UserId::new(u64::from_str(&captures[1])?);
-        let uuid = captures[2].to_owned();
+    // List pending users for staff in the order they should be reviewed.
+    async fn show_pending(&self, http: &Arc<Http>, command: &CommandInteraction) -> Result<()> {
+        if !self.allowed(http, &command.user, command.channel_id, Capability::ViewQueue).await? {
+            command.create_response(http, CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new().content("Only staff can view the approval queue.").ephemeral(true)
+            )).await?;
+            return Ok(());
+        }
 
-        // Notify the main thread
         let mut pair = ChannelPair::new();
         self.sender.send(pair.entangle())?;
-        pair.sender.send(Packet::DiscordApproval(uuid.clone()))?;
+        pair.sender.send(Packet::ListPending)?;
+        let Some(Packet::PendingList(mut pending)) = pair.receiver.recv().await else { return Err(anyhow!("Main thread did not respond with pending users!")) };
+        pending.sort_by_key(|user| priority::queue_key(user.priority, user.pending_since));
 
-        // DM the user if it was successful
-        if let Packet::ApprovalSuccess = pair.receiver.recv().await.ok_or(anyhow!("Main thread did not acknowledge approval!"))? {
-            let _ = discord_id.direct_message(http, CreateMessage::new().embed(
-                CreateEmbed::new()
-                    .title("CloverCraft SMP")
-                    .description("Your whitelist status has been updated.")
-                    .field("Status", "Approved", false)
-                    .color(PRIMARY_COLOR)
-            )).await;
+        let mut description = String::new();
+        for (position, user) in pending.iter().enumerate() {
+            let line = format!(
+                "{}. {}{} [{}]{}{}{}\n",
+                position + 1,
+                if user.priority == Priority::Normal { String::new() } else { format!("**[{}]** ", user.priority.label()) },
+                escape_markdown(&sanitize_display(&user.name)),
+                user.uuid,
+                user.discord_id.map(|discord_id| format!(" <@{discord_id}>")).unwrap_or_default(),
+                user.pending_since.map(|since| format!(" - waiting since <t:{}:R>", since / 1000)).unwrap_or_default(),
+                user.verify_message.map(|message_id| format!(" - [request](https://discord.com/channels/{}/{}/{message_id})", self.config.guild_id, self.config.member_channel_id)).unwrap_or_default(),
+            );
+            if position == PENDING_LIST_SIZE || description.len() + line.len() > 4000 {
+                description.push_str(&format!("...and {} more", pending.len() - position));
+                break;
+            }
+            description.push_str(&line);
+        }
+        if description.is_empty() {
+            description.push_str("Nobody is waiting for approval.");
+        }
 
-            http.add_member_role(GuildId::new(self.config.guild_id), discord_id, RoleId::new(self.config.verified_role_id), None).await?;
-            component.create_response(http, CreateInteractionResponse::UpdateMessage(
-                CreateInteractionResponseMessage::new()
-                    .button(
-                        CreateButton::new(format!("unlink-account-{discord_id}"))
-                            .label("Unlink").style(ButtonStyle::Danger)
-                    )
+        command.create_response(http, CreateInteractionResponse::Message(
+            CreateInteractionResponseMessage::new()
+                .embed(CreateEmbed::new()
+                    .title("Approval Queue")
+                    .description(description)
+                    .color(self.config.colors.primary)
+                )
+                .ephemeral(true)
+        )).await?;
+        Ok(())
+    }
+
+    async fn show_members(&self, http: &Arc<Http>, command: &CommandInteraction) -> Result<()> {
+        if !self.allowed(http, &command.user, command.channel_id, Capability::ListMembers).await? {
+            command.create_response(http, CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new().content("Only staff can list the members.").ephemeral(true)
             )).await?;
+            return Ok(());
         }
 
+        let page = self.members_page(0).await?;
+        command.create_response(http, CreateInteractionResponse::Message(page.ephemeral(true))).await?;
         Ok(())
     }
 
-    async fn unlink_account(&self, http: &Arc<Http>, id: &str) -> Result<()> {
-        let user_id = UserId::new(u64::from_str(id.split_at(15).1)?);
-        self.handle_user_leave(http, user_id).await?;
+    async fn clear_lockout(&self, http: &Arc<Http>, command: &CommandInteraction) -> Result<()> {
+        if !self.allowed(http, &command.user, command.channel_id, Capability::ClearLockouts).await? {
+            command.create_response(http, CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new().content("Only staff can clear lockouts.").ephemeral(true)
+            )).await?;
+            return Ok(());
+        }
+        let Some(target) = command.data.options.first().and_then(|option| option.value.as_user_id()) else { return Ok(()) };
+
+        let mut pair = ChannelPair::new();
+        self.sender.send(pair.entangle())?;
+        pair.sender.send(Packet::ClearLockout(target.get()))?;
+        let Some(Packet::LockoutCleared(cleared)) = pair.receiver.recv().await else { return Err(anyhow!("Main thread did not respond to lockout clear!")) };
+        if cleared {
+            log!("{} cleared the code lockout of user {target}", command.user.name);
+        }
+
+        let content = match cleared {
+            true => format!("<@{target}> can enter codes again."),
+            false => format!("<@{target}> hasn't entered any invalid codes."),
+        };
+        command.create_response(http, CreateInteractionResponse::Message(
+            CreateInteractionResponseMessage::new().content(content).ephemeral(true)
+        )).await?;
         Ok(())
     }
 
-    async fn handle_user_leave(&self, http: &Arc<Http>, user_id: UserId) -> Result<()> {
-        // Tell the main thread to remove the user
-        let mut local_pair = ChannelPair::new();
-        self.sender.send(local_pair.entangle())?;
-        local_pair.sender.send(Packet::RemoveUser(user_id.get()))?;
-
-        // Remove the member message from the members channel
-        if let Some(Packet::RemoveMessage(message_id)) = local_pair.receiver.recv().await {
-            ChannelId::new(self.config.member_channel_id).delete_message(http, message_id).await?;
+    async fn turn_members_page(&self, http: &Arc<Http>, page: usize, component: &ComponentInteraction) -> Result<()> {
+        if !self.allowed(http, &component.user, component.channel_id, Capability::ListMembers).await? {
+            return refuse(http, component).await;
         }
 
-        // Try to remove their role
-        let _ = http.remove_member_role(GuildId::new(self.config.guild_id), user_id, RoleId::new(self.config.verified_role_id), None).await;
+        let page = self.members_page(page).await?;
+        component.create_response(http, CreateInteractionResponse::UpdateMessage(page)).await?;
         Ok(())
     }
-}
 
-#[async_trait]
-impl EventHandler for Handler {
-    async fn guild_member_removal(&self, ctx: Context, guild_id: GuildId, user: User, _member_data_if_available: Option<Member>) {
-        if guild_id == self.config.guild_id && let Err(why) = self.handle_user_leave(&ctx.http, user.id).await {
-            log!("Error handling user removal: {why:?}");
+    // The page number lives in the buttons, so pages can still be turned after a restart. Members are sorted by name
+    // to keep them on the same page between clicks.
+    async fn members_page(&self, page: usize) -> Result<CreateInteractionResponseMessage> {
+        let mut pair = ChannelPair::new();
+        self.sender.send(pair.entangle())?;
+        pair.sender.send(Packet::ListAll)?;
+        let Some(Packet::AllUsers(users)) = pair.receiver.recv().await else { return Err(anyhow!("Main thread did not respond with all users!")) };
+        let mut members = users.into_iter().filter(|user| user.verify_state == VerifyState::APPROVED).collect::<Vec<_>>();
+        members.sort_by_key(|member| member.name.to_lowercase());
+
+        if members.is_empty() {
+            return Ok(CreateInteractionResponseMessage::new().embed(CreateEmbed::new()
+                .title("Members")
+                .description("No members yet. Approved accounts will be listed here.")
+                .color(self.config.colors.secondary)
+            ));
         }
+
+        let pages = members.len().div_ceil(MEMBERS_PAGE_SIZE);
+        let page = page.min(pages - 1);
+        let description = members
+            .iter()
+            .skip(page * MEMBERS_PAGE_SIZE)
+            .take(MEMBERS_PAGE_SIZE)
+            .map(|member| format!(
+                "**{}**{}{}",
+                escape_markdown(&sanitize_display(&member.name)),
+                member.discord_id.map(|discord_id| format!(" <@{discord_id}>")).unwrap_or_default(),
+                member.approved_at.map(|approved_at| format!(" - approved <t:{}:D>", approved_at / 1000)).unwrap_or_default(),
+            ))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        Ok(CreateInteractionResponseMessage::new()
+            .embed(CreateEmbed::new()
+                .title("Members")
+                .description(description)
+                .footer(CreateEmbedFooter::new(format!("Page {} of {pages} - {} members", page + 1, members.len())))
+                .color(self.config.colors.primary)
+            )
+            .button(CreateButton::new(CustomId::MembersPage(page.saturating_sub(1)).to_string()).label("Previous").disabled(page == 0))
+            .button(CreateButton::new(CustomId::MembersPage(page + 1).to_string()).label("Next").disabled(page + 1 == pages)))
     }
 
-    async fn message(&self, ctx: Context, msg: Message) {
-        let verification_channel = self.config.verification_channel_id;
-        let ticket_channel = self.config.ticket_channel_id;
-        let member_channel = self.config.member_channel_id;
-        let channel_id = msg.channel_id.get();
+    // Find a user by their discord account or Minecraft name.
+    async fn lookup(&self, http: &Arc<Http>, command: &CommandInteraction) -> Result<()> {
+        if !self.allowed(http, &command.user, command.channel_id, Capability::LookupUsers).await? {
+            command.create_response(http, CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new().content("Only staff can look up users.").ephemeral(true)
+            )).await?;
+            return Ok(());
+        }
 
-        if channel_id == verification_channel {
-            if let Err(why) = self.handle_verify_message(ctx, msg).await {
-                log!("Error handling verification message: {why:?}");
-            }
-        } else if channel_id == ticket_channel {
-            if let Err(why) = self.handle_ticket_message(ctx, msg).await {
-                log!("Error handling ticket message: {why:?}");
+        let Some(option) = command.data.options.first() else { return Err(anyhow!("lookup has no subcommand!")) };
+        let CommandDataOptionValue::SubCommand(options) = &option.value else { return Err(anyhow!("{} is not a subcommand!", option.name)) };
+        let Some(value) = options.first().map(|option| &option.value) else { return Err(anyhow!("lookup {} has no value!", option.name)) };
+        let (query, searched) = match (option.name.as_str(), value) {
+            ("user", CommandDataOptionValue::User(user_id)) => (Packet::LookupByDiscord(user_id.get()), format!("<@{user_id}>")),
+            ("mcname", CommandDataOptionValue::String(name)) => (Packet::LookupByName(name.to_owned()), escape_markdown(&sanitize_display(name))),
+            _ => return Err(anyhow!("Unknown lookup {}!", option.name)),
+        };
+
+        let mut pair = ChannelPair::new();
+        self.sender.send(pair.entangle())?;
+        pair.sender.send(query)?;
+        let Some(Packet::LookupResult(details)) = pair.receiver.recv().await else { return Err(anyhow!("Main thread did not respond with a lookup!")) };
+
+        let embed = match details {
+            Some(details) => lookup_embed(&self.config, &details),
+            None => CreateEmbed::new()
+                .title("Not found")
+                .description(format!("Nobody linked or waiting to link matches {searched}."))
+                .color(self.config.colors.secondary),
+        };
+        command.create_response(http, CreateInteractionResponse::Message(
+            CreateInteractionResponseMessage::new().embed(embed).ephemeral(true)
+        )).await?;
+        Ok(())
+    }
+
+    // Compare a game server's whitelist against the approved users, with buttons to fix drift either way.
+    async fn show_whitelist_diff(&self, http: &Arc<Http>, command: &CommandInteraction) -> Result<()> {
+        if !self.allowed(http, &command.user, command.channel_id, Capability::AuditWhitelist).await? {
+            command.create_response(http, CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new().content("Only staff can audit the whitelist.").ephemeral(true)
+            )).await?;
+            return Ok(());
+        }
+
+        // Game servers can take a while to read and upload a large whitelist.
+        command.defer_ephemeral(http).await?;
+        let mut pair = ChannelPair::new();
+        self.sender.send(pair.entangle())?;
+        pair.sender.send(Packet::WhitelistDiffQuery)?;
+        let diff = match tokio::time::timeout(WHITELIST_UPLOAD_TIMEOUT, pair.receiver.recv()).await {
+            Ok(Some(Packet::WhitelistDiffResponse(Some(diff)))) => diff,
+            Ok(Some(Packet::WhitelistDiffResponse(None))) => {
+                command.edit_response(http, EditInteractionResponse::new().content("No game server is connected to ask.")).await?;
+                return Ok(());
             }
-        } else if channel_id == member_channel {
-            if let Err(why) = self.handle_member_message(ctx, msg).await {
-                log!("Error handling member message: {why:?}");
+            Ok(_) => return Err(anyhow!("Main thread did not respond with a whitelist diff!")),
+            Err(_) => {
+                command.edit_response(http, EditInteractionResponse::new().content("No game server uploaded its whitelist in time.")).await?;
+                return Ok(());
             }
+        };
+
+        let mut details = String::from("Approved but not whitelisted:\n");
+        for (name, uuid) in &diff.missing {
+            details.push_str(&format!("{name} {uuid}\n"));
         }
+        details.push_str("\nWhitelisted but not approved:\n");
+        for uuid in &diff.unknown {
+            details.push_str(&format!("{uuid}\n"));
+        }
+
+        let embed = CreateEmbed::new()
+            .title(format!("Whitelist Diff{}", diff.server.as_deref().map(|server| format!(" ({server})")).unwrap_or_default()))
+            .field("Approved but not whitelisted", diff.missing.len().to_string(), true)
+            .field("Whitelisted but not approved", diff.unknown.len().to_string(), true)
+            .field("Matching", diff.matching.to_string(), true)
+            .color(if diff.missing.is_empty() && diff.unknown.is_empty() { self.config.colors.primary } else { self.config.colors.error });
+        let buttons = vec![
+            CreateButton::new(CustomId::WhitelistAddMissing.to_string()).label("Whitelist missing").style(ButtonStyle::Success).disabled(diff.missing.is_empty()),
+            CreateButton::new(CustomId::WhitelistFlagUnknown.to_string()).label("Flag unknown for review").style(ButtonStyle::Danger).disabled(diff.unknown.is_empty()),
+        ];
+        command.edit_response(http, EditInteractionResponse::new()
+            .embed(embed)
+            .new_attachment(CreateAttachment::bytes(details, "whitelist-diff.txt"))
+            .components(vec![CreateActionRow::Buttons(buttons)])
+        ).await?;
+        *self.last_whitelist_diff.lock().unwrap() = Some(diff);
+        Ok(())
     }
 
-    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
-        if let Interaction::Component(component) = interaction {
-            let id = &component.data.custom_id;
+    // Act on the last whitelist diff: push the missing users to the game servers, or flag the unknown ones in the log channel.
+    async fn fix_whitelist(&self, http: &Arc<Http>, add_missing: bool, component: &ComponentInteraction) -> Result<()> {
+        if !self.allowed(http, &component.user, component.channel_id, Capability::AuditWhitelist).await? {
+            component.create_response(http, CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new().content("Only staff can fix the whitelist.").ephemeral(true)
+            )).await?;
+            return Ok(());
+        }
 
-            if id == "create-ticket" && let Err(why) = self.open_ticket(&ctx.http, &component.user, &component).await {
-                log!("Error opening ticket: {why:?}");
-            }
+        let Some(diff) = self.last_whitelist_diff.lock().unwrap().clone() else {
+            component.create_response(http, CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new().content("This diff is out of date, run /whitelist diff again.").ephemeral(true)
+            )).await?;
+            return Ok(());
+        };
 
-            if id.starts_with("close-ticket-") && let Err(why) = self.close_ticket(&ctx.http, &id, &component).await {
-                log!("Error closing ticket: {why:?}");
+        let content = if add_missing {
+            let mut pair = ChannelPair::new();
+            self.sender.send(pair.entangle())?;
+            pair.sender.send(Packet::PushWhitelist(diff.missing.clone()))?;
+            let Some(Packet::PushedWhitelist(reached)) = pair.receiver.recv().await else { return Err(anyhow!("Main thread did not respond to whitelist push!")) };
+            log!("{} pushed {} missing players to {reached} game servers", component.user.name, diff.missing.len());
+            format!("Sent {} players to {reached} game servers to whitelist.", diff.missing.len())
+        } else {
+            let mut description = String::new();
+            for uuid in &diff.unknown {
+                if description.len() + uuid.len() > 4000 {
+                    description.push_str("...see the attached diff for the rest");
+                    break;
+                }
+                description.push_str(&format!("{uuid}\n"));
             }
+            post_log(http, &self.config, CreateMessage::new().embed(CreateEmbed::new()
+                .title(format!("Whitelisted but not approved{}", diff.server.as_deref().map(|server| format!(" on {server}")).unwrap_or_default()))
+                .description(description)
+                .field("Flagged by", component.user.name.to_owned(), false)
+                .color(self.config.colors.error)
+            )).await?;
+            format!("Flagged {} whitelisted players for review in the log channel.", diff.unknown.len())
+        };
 
-            if id.starts_with("approve-account-") && let Err(why) = self.approve_account(&ctx.http, &id, &component).await {
-                log!("Error approving account: {why:?}");
-            }
+        component.create_response(http, CreateInteractionResponse::Message(
+            CreateInteractionResponseMessage::new().content(content).ephemeral(true)
+        )).await?;
+        Ok(())
+    }
 
-            if id.starts_with("unlink-account-") && let Err(why) = self.unlink_account(&ctx.http, &id).await {
-                log!("Error unlinking account: {why:?}");
-            }
+    // Send a message from staff to the chat of every connected game server.
+    async fn announce(&self, http: &Arc<Http>, command: &CommandInteraction) -> Result<()> {
+        if !self.allowed(http, &command.user, command.channel_id, Capability::Announce).await? {
+            command.create_response(http, CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new().content("Only staff can make announcements.").ephemeral(true)
+            )).await?;
+            return Ok(());
         }
+
+        let Some(text) = command.data.options.first().and_then(|option| option.value.as_str()) else { return Err(anyhow!("announce has no text!")) };
+        let text = sanitize_display(text);
+        let mut pair = ChannelPair::new();
+        self.sender.send(pair.entangle())?;
+        pair.sender.send(Packet::Announce(text.clone()))?;
+        let Some(Packet::Announced(reached)) = pair.receiver.recv().await else { return Err(anyhow!("Main thread did not respond to announcement!")) };
+
+        let content = if reached == 0 {
+            "No servers connected, so nothing was announced.".to_owned()
+        } else {
+            log!("{} announced to {reached} game servers: {text}", command.user.name);
+            post_log(http, &self.config, CreateMessage::new().embed(CreateEmbed::new()
+                .title("Announcement")
+                .description(escape_markdown(&text))
+                .field("Announced by", command.user.name.to_owned(), false)
+                .color(self.config.colors.secondary)
+            )).await?;
+            format!("Announced to {reached} game servers.")
+        };
+        command.create_response(http, CreateInteractionResponse::Message(
+            CreateInteractionResponseMessage::new().content(content).ephemeral(true)
+        )).await?;
+        Ok(())
     }
-}
 
-pub async fn start_discord(discord_tx: UnboundedSender<ChannelPair<Packet>>) -> Result<()> {
-    let intents = GatewayIntents::GUILD_MESSAGES | GatewayIntents::GUILD_MEMBERS | GatewayIntents::DIRECT_MESSAGES | GatewayIntents::MESSAGE_CONTENT;
+    // Post a panel in the channel the command was run in.
+    async fn setup(&self, http: &Arc<Http>, command: &CommandInteraction) -> Result<()> {
+        if !self.allowed(http, &command.user, command.channel_id, Capability::PostPanels).await? {
+            command.create_response(http, CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new().content("Only staff can post panels.").ephemeral(true)
+            )).await?;
+            return Ok(());
+        }
 
-    let config = open_config()?;
-    if config.token.is_empty() {
-        log!("Please complete the discord config before starting the discord bot program.");
-        exit(0);
+        let kind = match command.data.options.first().and_then(|option| option.value.as_str()) {
+            Some("verification") => PanelKind::Verification,
+            Some("ticket") => PanelKind::Ticket,
+            _ => return Err(anyhow!("setup has no panel!")),
+        };
+        // Codes typed anywhere else are never read, so a verification panel elsewhere would only mislead.
+        let content = if kind == PanelKind::Verification && command.channel_id.get() != self.config.verification_channel_id {
+            format!("The verification panel can only be posted in <#{}>.", self.config.verification_channel_id)
+        } else {
+            self.post_panel(http, command.channel_id, kind).await?;
+            log!("{} posted the {kind:?} panel in {}", command.user.name, command.channel_id);
+            "Panel posted.".to_owned()
+        };
+        command.create_response(http, CreateInteractionResponse::Message(
+            CreateInteractionResponseMessage::new().content(content).ephemeral(true)
+        )).await?;
+        Ok(())
     }
 
-    let mut client = Client::builder(config.token.clone(), intents)
-        .event_handler(Handler::new(discord_tx, config))
-        .await
-        .expect("Error creating client!");
+    async fn show_notification_menu(&self, http: &Arc<Http>, command: &CommandInteraction) -> Result<()> {
+        let current = self.notifications.lock().unwrap().get(command.user.id.get());
+        let options = [("all", NotificationLevel::All), ("important_only", NotificationLevel::ImportantOnly), ("none", NotificationLevel::None)]
+            .into_iter()
+            .map(|(id, level)| CreateSelectMenuOption::new(level.label(), id).default_selection(level == current))
+            .collect();
 
-    log!("Starting discord client...");
+        command.create_response(http, CreateInteractionResponse::Message(
+            CreateInteractionResponseMessage::new()
+                .content("Choose which direct messages you would like to receive from the bot.")
+                .components(vec![CreateActionRow::SelectMenu(CreateSelectMenu::new(CustomId::NotificationPreference.to_string(), CreateSelectMenuKind::String { options }))])
+                .ephemeral(true)
+        )).await?;
+        Ok(())
+    }
 
-    client.start().await?;
+    // Handle a message in the verification channel of the main guild, or of the given linked guild.
+    async fn handle_verify_message(&self, ctx: Context, msg: Message, linked_guild: Option<&LinkedGuild>) -> Result<()> {
+        self.handle_code(&ctx.http, msg.author.id, &msg.content, linked_guild).await?;
 
-    Ok(())
-}
+        // Delete non-bot messages.
+        if !msg.author.bot {
+            msg.delete(&ctx.http).await?;
+        }
 
-#[derive(Serialize, Deserialize)]
-struct DiscordConfig {
-    token: String,
-    guild_id: u64,
-    verified_role_id: u64,
-    staff_role_id: u64,
-    verification_channel_id: u64,
-    member_channel_id: u64,
-    ticket_channel_id: u64,
-    active_ticket_category_id: u64,
-    archive_ticket_category_id: u64,
-}
+        Ok(())
+    }
+
+    // A code DMed to the bot gets its answer in the same DM, whatever the user's DM preference, since they asked. The
+    // approval request still goes to the configured member channel. Anything that isn't a code is left alone.
+    async fn handle_direct_message(&self, ctx: Context, msg: Message) -> Result<()> {
+        if msg.author.bot {
+            return Ok(());
+        }
+        let Some(code) = i32::from_str(msg.content.trim()).ok().filter(|code| is_code(*code)) else { return Ok(()) };
+
+        let (_, embed, _) = self.submit_code(&ctx.http, msg.author.id, code, None).await?;
+        msg.channel_id.send_message(&ctx.http, CreateMessage::new().embed(embed)).await?;
+        Ok(())
+    }
+
+    // Parse a code - we can't verify it here, so send it to the main thread.
+    async fn handle_code(&self, http: &Arc<Http>, user_id: UserId, content: &str, linked_guild: Option<&LinkedGuild>) -> Result<()> {
+        if let Ok(code) = i32::from_str(content) && is_code(code) {
+            // Approved users verifying in a linked guild get their link carried over instead, when links are global.
+            if let Some(guild) = linked_guild && let Some(name) = inherit_link(&self.sender, http, &self.config, &self.messages, user_id, guild).await? {
+                let _ = self.send_dm(http, user_id, Importance::Important, self.inherited_embed(&name, user_id)).await;
+            } else {
+                let (importance, embed, linked) = self.submit_code(http, user_id, code, linked_guild.map(|guild| guild.guild_id)).await?;
+                let Some(importance) = importance else { return Ok(()) };
+                if let Err(why) = self.send_status(http, user_id, importance, embed, linked.as_ref().map(|(name, uuid)| (name.as_str(), uuid.as_str()))).await {
+                    log!("Error telling user {user_id} about their code: {why:?}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // DM a fresh code to a user whose code ran out, once they've entered the expired one. Anyone else is told how
+    // codes work, as they have no attempt to renew.
+    async fn regenerate_code(&self, http: &Arc<Http>, component: &ComponentInteraction) -> Result<()> {
+        let user_id = component.user.id;
+        let mut pair = ChannelPair::new();
+        self.sender.send(pair.entangle())?;
+        pair.sender.send(Packet::RegenerateCode(user_id.get()))?;
+        let Some(Packet::RegeneratedCode(regenerated)) = pair.receiver.recv().await else { return Err(anyhow!("Main thread did not respond to code regeneration!")) };
+
+        let content = match regenerated {
+            Some((name, code)) => {
+                let text = self.messages.render("code.regenerated", &[
+                    ("name", &escape_markdown(&sanitize_display(&name))),
+                    ("code", &code.to_string()),
+                    ("discord_id", &user_id.to_string()),
+                    ("channel_id", &self.config.verification_channel_id.to_string()),
+                ]);
+                let embed = CreateEmbed::new().title(self.messages.get("title")).description(&text).color(self.config.colors.primary);
+                // The user asked for this one, so it's sent whatever their DM preference. The reply is only shown to
+                // them, so it can carry the code when the DM can't be delivered.
+                match user_id.direct_message(http, CreateMessage::new().embed(embed)).await {
+                    Ok(_) => "I've sent you a fresh code in your DMs.".to_owned(),
+                    Err(_) => text,
+                }
+            }
+            None => self.messages.get("code.how_to"),
+        };
+        component.create_response(http, CreateInteractionResponse::Message(
+            CreateInteractionResponseMessage::new().content(content).ephemeral(true)
+        )).await?;
+        Ok(())
+    }
+
+    // Messages edited after posting would otherwise slip past the cleanup, along with any code edited into them.
+    async fn handle_edit(&self, ctx: Context, event: MessageUpdateEvent) -> Result<()> {
+        let Some(author) = &event.author else { return Ok(()) };
+        if author.bot {
+            return Ok(());
+        }
+        let linked_guild = self.config.linked_guilds.iter().find(|guild| guild.verification_channel_id == event.channel_id.get());
+        if (event.channel_id.get() == self.config.verification_channel_id || linked_guild.is_some()) && let Some(content) = &event.content {
+            self.handle_code(&ctx.http, author.id, content, linked_guild).await?;
+        }
+        event.channel_id.delete_message(&ctx.http, event.id).await?;
+        Ok(())
+    }
+
+    // Hand a verification code to the main thread, returning what to tell the user about it, along with the name and
+    // uuid of the account if the code linked one. Links made in a linked guild carry its id, and are reviewed in the
+    // main guild like any other. There's no importance when the user shouldn't be DMed about it again.
+    async fn submit_code(&self, http: &Arc<Http>, user_id: UserId, code: i32, guild_id: Option<u64>) -> Result<(Option<Importance>, CreateEmbed, Option<(String, String)>)> {
+        let mut local_pair = ChannelPair::new();
+        self.sender.send(local_pair.entangle())?;
+
+        local_pair.sender.send(Packet::DiscordCode(code, user_id.get(), self.config.max_accounts_per_discord, guild_id))?;
+
+        // Check if the code worked.
+        let packet = local_pair.receiver.recv().await.ok_or(anyhow!("Main thread did not reply to discord bot!"))?;
+        let embed = CreateEmbed::new().title(self.messages.get("title"));
+        let code = code.to_string();
+        let discord_id = user_id.to_string();
+        let mut linked = None;
+        let (importance, embed) = match packet {
+            // The code was valid - send the approval message in the members channel.
+            Packet::VerifyPending(uuid, name, previously_approved, server, others, guild_id) => {
+                let priority = self.priority_for(http, user_id.get(), previously_approved).await;
+                let message = self.add_user_verify(http, &name, &uuid, user_id.get(), priority, server.as_deref(), &others, guild_id).await?;
+                local_pair.sender.send(Packet::LinkVerifyMessage(message.id.get(), priority))?;
+                let description = self.messages.render("status.updated", &[("name", &name), ("uuid", &uuid), ("discord_id", &discord_id), ("code", &code)]);
+                linked = Some((name, uuid));
+                (Some(Importance::Optional), embed
+                    .description(description)
+                    .field("Status", "Pending", false)
+                    .color(self.config.colors.secondary))
+            }
+
+            // The code was invalid
+            Packet::VerifyCodeInvalid => (Some(Importance::Important), embed
+                .description(self.messages.render("code.invalid", &[("discord_id", &discord_id), ("code", &code)]))
+                .color(self.config.colors.error)),
+
+            // The code was valid but ran out before it was used
+            Packet::VerifyCodeExpired(expired) => (Some(Importance::Important), embed
+                .description(self.messages.render("code.expired", &[("expired", &format!("<t:{}:R>", expired / 1000)), ("discord_id", &discord_id), ("code", &code)]))
+                .color(self.config.colors.error)),
+
+            // A verification check refused the link
+            Packet::LinkRejected(reason) => (Some(Importance::Important), embed
+                .description(sanitize_display(&reason))
+                .color(self.config.colors.error)),
+
+            // The user is already verifying
+            Packet::AlreadyLinked => (Some(Importance::Important), embed
+                .description(self.messages.render("code.already_linked", &[("discord_id", &discord_id), ("code", &code)]))
+                .color(self.config.colors.error)),
+
+            // Too many invalid codes. Users are only DMed once per lockout, however often they try.
+            Packet::LockedOut(locked_until, notify) => (notify.then_some(Importance::Important), embed
+                .description(self.messages.render("code.locked_out", &[("until", &format!("<t:{}:R>", locked_until / 1000)), ("discord_id", &discord_id), ("code", &code)]))
+                .color(self.config.colors.error)),
+
+            x => return Err(anyhow!("Unexpected packet {x:?} received on discord thread!")),
+        };
+        Ok((importance, embed, linked))
+    }
+
+    // Tell a user entering a code in a linked guild that their approved link was carried over instead.
+    fn inherited_embed(&self, name: &str, user_id: UserId) -> CreateEmbed {
+        CreateEmbed::new()
+            .title(self.messages.get("title"))
+            .description(self.messages.render("link.inherited", &[("name", &escape_markdown(&sanitize_display(name))), ("discord_id", &user_id.to_string())]))
+            .color(self.config.colors.primary)
+    }
+
+    // /verify takes the code privately, rather than it showing in the verification channel until it's deleted.
+    async fn verify_command(&self, http: &Arc<Http>, command: &CommandInteraction) -> Result<()> {
+        let code = command.data.options.first().and_then(|option| option.value.as_i64()).and_then(|code| i32::try_from(code).ok()).filter(|code| is_code(*code));
+        let Some(code) = code else {
+            command.create_response(http, CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new().content("Verification codes are 6 digit numbers.").ephemeral(true)
+            )).await?;
+            return Ok(());
+        };
+
+        // Posting the approval request can take longer than discord waits for a response.
+        command.defer_ephemeral(http).await?;
+
+        // Approved users verifying in a linked guild get their link carried over instead, when links are global.
+        let linked_guild = command.guild_id.and_then(|guild_id| self.config.linked_guild(guild_id.get()));
+        if let Some(guild) = linked_guild && let Some(name) = inherit_link(&self.sender, http, &self.config, &self.messages, command.user.id, guild).await? {
+            command.edit_response(http, EditInteractionResponse::new().embed(self.inherited_embed(&name, command.user.id))).await?;
+            return Ok(());
+        }
+
+        let (_, embed, _) = self.submit_code(http, command.user.id, code, linked_guild.map(|guild| guild.guild_id)).await?;
+        command.edit_response(http, EditInteractionResponse::new().embed(embed)).await?;
+        Ok(())
+    }
+
+    async fn handle_ticket_message(&self, ctx: Context, msg: Message) -> Result<()> {
+        // Delete non-bot messages.
+        if !msg.author.bot {
+            msg.delete(&ctx.http).await?;
+        }
+
+        Ok(())
+    }
+
+    // The first staff member to answer in a ticket claims it, which also times how long the opener waited.
+    async fn handle_ticket_reply(&self, ctx: Context, msg: Message) -> Result<()> {
+        if msg.author.bot || !self.is_staff(&ctx.http, msg.author.id).await? {
+            return Ok(());
+        }
+        self.tickets.lock().unwrap().claim(msg.channel_id.get(), msg.author.id.get(), timestamp())?;
+        self.staff.lock().unwrap().remember(msg.author.id.get(), &msg.author.name)
+    }
+
+    async fn handle_member_message(&self, ctx: Context, msg: Message) -> Result<()> {
+        let regex = Regex::new(r"^!link ([a-zA-Z0-9_]+) <@([0-9]+)>$")?;
+        if let Some(captures) = regex.captures(&msg.content) && self.allowed(&ctx.http, &msg.author, msg.channel_id, Capability::LinkAccounts).await? {
+            let username = captures[1].to_owned();
+            let discord_id = u64::from_str(&captures[2])?;
+            let guild_id = GuildId::new(self.config.guild_id);
+            if guild_id.member(&ctx.http, discord_id).await.is_ok() && let Ok(uuid) = self.get_uuid(&username).await {
+                let mut pair = ChannelPair::new();
+                self.sender.send(pair.entangle())?;
+                pair.sender.send(Packet::UserQuery(uuid.clone(), discord_id, self.config.max_accounts_per_discord))?;
+
+                let Some(Packet::UserResponse(success, previously_approved, others)) = pair.receiver.recv().await else { return Err(anyhow!("Main thread did not respond with user response!")) };
+                if success {
+                    let priority = self.priority_for(&ctx.http, discord_id, previously_approved).await;
+                    let message = self.add_user_verify(&ctx.http, &username, &uuid, discord_id, priority, None, &others, None).await?;
+                    pair.sender.send(Packet::AddUserManually(username, uuid, discord_id, message.id.get(), priority, msg.author.id.get()))?;
+                }
+            }
+        }
+
+        // Delete non-bot messages.
+        if !msg.author.bot {
+            msg.delete(&ctx.http).await?;
+        }
+
+        Ok(())
+    }
+
+    // Work out a pending user's priority from the configured rules. Members that can't be fetched
+    // are treated as having no roles.
+    async fn priority_for(&self, http: &Arc<Http>, discord_id: u64, previously_approved: bool) -> Priority {
+        let roles = match GuildId::new(self.config.guild_id).member(http, discord_id).await {
+            Ok(member) => member.roles.iter().map(|role| role.get()).collect(),
+            Err(_) => vec![],
+        };
+        priority::evaluate(&self.config.priority_rules, &PriorityFacts { previously_approved, roles: &roles })
+    }
+
+    // Others are the names of the accounts the user already linked, which this one is an additional link to. Links for
+    // linked guilds are reviewed here in the main guild too, marked with the guild they're for.
+    #[allow(clippy::too_many_arguments)]
+    async fn add_user_verify(&self, http: &Arc<Http>, name: &str, uuid: &str, discord_id: u64, priority: Priority, server: Option<&str>, others: &[String], guild_id: Option<u64>) -> Result<Message> {
+        let mut embed = member_embed(&self.config, &self.messages, name, uuid, discord_id);
+        if let Some(guild_id) = guild_id {
+            embed = embed.field("For guild", guild_id.to_string(), false);
+        }
+        if !others.is_empty() {
+            embed = embed.field("Additional account of", others.iter().map(|other| escape_markdown(&sanitize_display(other))).collect::<Vec<_>>().join(", "), false);
+        }
+        if priority != Priority::Normal {
+            embed = embed.field("Priority", priority.label(), true);
+        }
+        if let Some(server) = server {
+            embed = embed.field("Server", server, true);
+        }
+        for (field, value) in self.enrichment.warnings(name, uuid).await {
+            embed = embed.field(field, value, false);
+        }
+
+        let channel_id = ChannelId::new(self.config.member_channel_id);
+        let message = channel_id.send_message(http,
+            CreateMessage::new()
+                .embed(embed)
+                .button(approve_button(discord_id, uuid, server))
+                .button(deny_button(discord_id, uuid))
+                .button(ban_button(discord_id, uuid)),
+        ).await?;
+
+        // A missing thread shouldn't stop the request from being reviewed.
+        if self.config.discussion_threads && let Err(why) = open_discussion(http, &self.config, channel_id, message.id, name, discord_id).await {
+            log!("Error opening discussion thread for {name}: {why:?}");
+        }
+        Ok(message)
+    }
+
+    async fn get_uuid(&self, name: &str) -> Result<String> {
+        let uuid = self.external_cache.get("mojang_uuid", &name.to_lowercase(), MOJANG_CACHE_TTL, || async {
+            let response = reqwest::get(format!("https://api.minecraftservices.com/minecraft/profile/lookup/name/{name}")).await?;
+            if response.status().as_u16() == 200 {
+                let data: Value = serde_json::from_str(&response.text().await?)?;
+                let id = data["id"].as_str().ok_or(anyhow!("ID wasn't a string!"))?.to_owned();
+                let regex = Regex::new(r"^[0-9a-f]{32}$")?;
+                if !regex.is_match(&id) {
+                    return Err(anyhow!("Invalid data returned from the API!"));
+                }
+                Ok(Value::String(format!("{}-{}-{}-{}-{}", &id[0..8], &id[8..12], &id[12..16], &id[16..20], &id[20..32])))
+            } else {
+                Err(anyhow!("Invalid Minecraft account!"))
+            }
+        }).await?;
+        Ok(uuid.as_str().ok_or(anyhow!("Cached UUID wasn't a string!"))?.to_owned())
+    }
+
+    async fn open_ticket(&self, http: &Arc<Http>, user: &User, component: &ComponentInteraction) -> Result<()> {
+        // Create the new ticket channel and give the creator permission to see it.
+        let ticket_channel = GuildId::new(self.config.guild_id).create_channel(http, CreateChannel::new(format!("ticket-{}", sanitize_display(&user.name))).category(self.config.active_ticket_category_id)).await?;
+        ticket_channel.create_permission(http, PermissionOverwrite {
+            allow: Permissions::VIEW_CHANNEL,
+            deny: Default::default(),
+            kind: PermissionOverwriteType::Member(user.id),
+        }).await?;
+
+        // Create the initial message / close ticket button
+        let initial_message = CreateMessage::new()
+            .content(
+                format!("<@{}> {}", user.id, role_mentions(&self.config.ticket_ping_roles()))
+            )
+            .embed(
+                CreateEmbed::new()
+                    .title(self.messages.get("ticket.title"))
+                    .description(self.messages.render("ticket.description", &[("discord_id", &user.id.to_string())]))
+                    .color(self.config.colors.primary)
+            )
+            .button(
+                CreateButton::new(CustomId::CloseTicket(ticket_channel.id.get()).to_string())
+                    .label("Close Ticket")
+            );
+
+        ticket_channel.send_message(http, initial_message).await?;
+        self.tickets.lock().unwrap().open(ticket_channel.id.get(), user.id.get(), timestamp())?;
+        component.create_response(http, CreateInteractionResponse::Acknowledge).await?;
+        let _ = self.events.send(Event::TicketOpened);
+        Ok(())
+    }
+
+    async fn close_ticket(&self, http: &Arc<Http>, channel_id: ChannelId, component: &ComponentInteraction) -> Result<()> {
+        let mut channel = channel_id.to_channel(http).await?.guild().ok_or(anyhow!("Channel was not a guild channel!"))?;
+
+        // The ticket opener is the member that was given access to the channel.
+        let opener = channel.permission_overwrites.iter().find_map(|o| match o.kind {
+            PermissionOverwriteType::Member(user_id) => Some(user_id),
+            _ => None,
+        });
+        if opener != Some(component.user.id) && !self.allowed(http, &component.user, component.channel_id, Capability::CloseTickets).await? {
+            return refuse(http, component).await;
+        }
+
+        // Remove all custom permissions
+        for permission_overwrite in channel.permission_overwrites.iter()
+            .filter_map(|o| match o.kind {
+                PermissionOverwriteType::Member(_) => Some(o.kind),
+                _ => None,
+            }) {
+            channel.delete_permission(http, permission_overwrite).await?;
+        }
+
+        // Move the ticket into the archived tickets category, disable the close ticket button
+        channel.edit(http, EditChannel::new().category(Some(ChannelId::new(self.config.archive_ticket_category_id)))).await?;
+        component.create_response(http, CreateInteractionResponse::UpdateMessage(CreateInteractionResponseMessage::new().button(CreateButton::new(CustomId::ClosedTicket.to_string()).label("Ticket closed").disabled(true)))).await?;
+        let _ = self.events.send(Event::TicketClosed);
+
+        // Transcripts of long tickets take a while, so they're written in the background.
+        if self.config.transcript_channel_id != 0 {
+            tokio::spawn(transcripts::start(http.clone(), channel_id, ChannelId::new(self.config.transcript_channel_id)));
+        }
+
+        if let Some(opener) = opener {
+            self.tickets.lock().unwrap().close(channel_id.get(), opener.get(), component.user.id.get(), timestamp())?;
+            if opener != component.user.id {
+                self.staff.lock().unwrap().remember(component.user.id.get(), &component.user.name)?;
+            }
+
+            // Ask the opener how it went. Openers with DMs closed are skipped.
+            if self.config.ticket_survey {
+                let buttons = (1..=5).map(|rating| CreateButton::new(CustomId::TicketRating { channel_id: channel_id.get(), rating }.to_string()).label(format!("{rating}"))).collect();
+                let _ = self.send_dm_with_buttons(http, opener, Importance::Optional,
+                    CreateEmbed::new()
+                        .title(self.messages.get("survey.title"))
+                        .description(self.messages.render("survey.description", &[("discord_id", &opener.to_string())]))
+                        .color(self.config.colors.secondary),
+                    buttons,
+                ).await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn rate_ticket(&self, http: &Arc<Http>, channel_id: u64, rating: u8, component: &ComponentInteraction) -> Result<()> {
+        let error = {
+            let mut tickets = self.tickets.lock().unwrap();
+            match tickets.get_mut(channel_id) {
+                Some(record) if record.opener_id != component.user.id.get() => Some("This survey isn't for you."),
+                Some(record) if timestamp().saturating_sub(record.closed_at) > SURVEY_WINDOW => Some("This survey has closed."),
+                Some(record) if record.rating.is_some() => Some("You have already rated this ticket."),
+                Some(record) => {
+                    record.rating = Some(rating);
+                    tickets.save()?;
+                    None
+                }
+                None => Some("This ticket could not be found."),
+            }
+        };
+
+        let response = match error {
+            Some(error) => CreateInteractionResponse::Message(CreateInteractionResponseMessage::new().content(error).ephemeral(true)),
+            None => CreateInteractionResponse::UpdateMessage(
+                CreateInteractionResponseMessage::new()
+                    .content(format!("Thank you for your feedback! You rated this ticket {rating}/5."))
+                    .button(CreateButton::new(CustomId::TicketComment(channel_id).to_string()).label("Add a comment"))
+            ),
+        };
+        component.create_response(http, response).await?;
+        Ok(())
+    }
+
+    async fn open_ticket_comment(&self, http: &Arc<Http>, channel_id: u64, component: &ComponentInteraction) -> Result<()> {
+        component.create_response(http, CreateInteractionResponse::Modal(
+            CreateModal::new(CustomId::TicketComment(channel_id).to_string(), "Ticket feedback").components(vec![
+                CreateActionRow::InputText(CreateInputText::new(InputTextStyle::Paragraph, "Comment", "comment").max_length(1000)),
+            ])
+        )).await?;
+        Ok(())
+    }
+
+    async fn submit_ticket_comment(&self, http: &Arc<Http>, channel_id: u64, modal: &ModalInteraction) -> Result<()> {
+        let comment = modal_text(modal);
+
+        let error = {
+            let mut tickets = self.tickets.lock().unwrap();
+            match tickets.get_mut(channel_id) {
+                Some(record) if record.opener_id != modal.user.id.get() => Some("This survey isn't for you."),
+                Some(record) if timestamp().saturating_sub(record.closed_at) > SURVEY_WINDOW => Some("This survey has closed."),
+                Some(record) if record.comment.is_some() => Some("You have already commented on this ticket."),
+                Some(record) => {
+                    record.comment = Some(comment);
+                    tickets.save()?;
+                    None
+                }
+                None => Some("This ticket could not be found."),
+            }
+        };
+
+        modal.create_response(http, CreateInteractionResponse::Message(
+            CreateInteractionResponseMessage::new()
+                .content(error.unwrap_or("Thank you, your comment has been recorded."))
+                .ephemeral(true)
+        )).await?;
+        Ok(())
+    }
+
+    async fn approve_account(&self, http: &Arc<Http>, discord_id: UserId, uuid: String, server: Option<String>, component: &ComponentInteraction) -> Result<()> {
+        if !self.allowed(http, &component.user, component.channel_id, Capability::ApproveAccounts).await? {
+            return refuse(http, component).await;
+        }
+        // The main thread and discord can both be slow, so acknowledge the click before discord gives up on it. The
+        // member message is edited through the interaction from here on.
+        component.create_response(http, CreateInteractionResponse::Acknowledge).await?;
+
+        // Hold off until enough distinct moderators have pressed approve.
+        let required = self.config.required_approvals as usize;
+        if required > 1 {
+            let mut pair = ChannelPair::new();
+            self.sender.send(pair.entangle())?;
+            pair.sender.send(Packet::RecordApproval(uuid.clone(), component.user.id.get()))?;
+            let Some(Packet::Approvals(approvals)) = pair.receiver.recv().await else { return Err(anyhow!("Main thread did not acknowledge approval!")) };
+            if approvals.is_empty() {
+                return Ok(());
+            }
+            if approvals.len() < required {
+                let mut update = EditInteractionResponse::new();
+                if let Some(embed) = component.message.embeds.first() {
+                    let mut embed = embed.clone();
+                    embed.fields.retain(|field| field.name != "Approvals");
+                    let moderators = approvals.iter().map(|moderator| format!("<@{moderator}>")).collect::<Vec<_>>().join(", ");
+                    embed.fields.push(EmbedField::new("Approvals", format!("{}/{required}: {moderators}", approvals.len()), false));
+                    update = update.embed(CreateEmbed::from(embed));
+                }
+                component.edit_response(http, update).await?;
+                return Ok(());
+            }
+        }
+
+        // Notify the main thread
+        let mut pair = ChannelPair::new();
+        self.sender.send(pair.entangle())?;
+        pair.sender.send(Packet::DiscordApproval(uuid.clone(), server.clone(), component.user.id.get()))?;
+
+        // Someone may have denied or banned the account in the meantime.
+        let Packet::ApprovalSuccess(slo_breached, name, newly_approved, guild_id) = pair.receiver.recv().await.ok_or(anyhow!("Main thread did not acknowledge approval!"))? else {
+            component.create_followup(http, CreateInteractionResponseFollowup::new().content("This account can no longer be approved.").ephemeral(true)).await?;
+            return Ok(());
+        };
+
+        // Give the role straight away, as an approved user without it looks like one whose role was removed. Links for a
+        // linked guild get that guild's role instead.
+        let (guild_id, role_id) = self.config.verified_role(guild_id).ok_or(anyhow!("The guild {uuid} was linked for is no longer configured!"))?;
+        http.add_member_role(guild_id, discord_id, role_id, None).await?;
+        // Discord won't let the bot rename members above it, such as admins, which is no reason to fail the approval.
+        if self.config.sync_nicknames && let Err(why) = guild_id.edit_member(http, discord_id, EditMember::new().nickname(&name)).await {
+            log!("Couldn't set the nickname of user {discord_id} to {name}: {why:?}");
+        }
+        if newly_approved {
+            self.inherit_into_linked_guilds(http, discord_id).await;
+        }
+        let mut embed = CreateEmbed::new()
+            .title(self.messages.get("title"))
+            .description(self.messages.render("status.updated", &[("name", &name), ("uuid", &uuid), ("discord_id", &discord_id.to_string())]))
+            .field("Status", "Approved", false)
+            .color(self.config.colors.primary);
+        if let Some(server) = &server {
+            embed = embed.field("Server", server, false);
+        }
+        if let Err(why) = self.send_status(http, discord_id, Importance::Important, embed, Some((&name, &uuid))).await {
+            log!("Error telling user {discord_id} about their approval: {why:?}");
+        }
+        self.staff.lock().unwrap().remember(component.user.id.get(), &component.user.name)?;
+        if let Err(why) = close_discussion(http, &self.config, component.message.id.get(), &format!("Approved by {}.", component.user.name)).await {
+            log!("Error closing discussion thread for {uuid}: {why:?}");
+        }
+        if newly_approved && let Err(why) = self.welcome(http, discord_id, &name, &uuid).await {
+            log!("Error welcoming user {discord_id}: {why:?}");
+        }
+
+        if slo_breached {
+            log!("Approval SLO alert for {uuid} resolved");
+            post_log(http, &self.config, CreateMessage::new().embed(
+                CreateEmbed::new()
+                    .title("Approval SLO resolved")
+                    .description(format!("<@{discord_id}> [{uuid}] has now been approved."))
+                    .color(self.config.colors.primary)
+            )).await?;
+        }
+
+        // Let staff see when the user never got told.
+        let blocked = self.notifications.lock().unwrap().is_blocked(discord_id.get());
+        let mut update = EditInteractionResponse::new();
+        if let Some(embed) = component.message.embeds.first() {
+            let mut embed = embed.clone();
+            embed.fields.retain(|field| field.name != "Approved by");
+            embed.fields.push(EmbedField::new("Approved by", format!("<@{}> on <t:{}:f>", component.user.id, timestamp() / 1000), false));
+            update = update.embed(with_dm_indicator(embed, blocked));
+        }
+
+        component.edit_response(http, update.components(vec![CreateActionRow::Buttons(vec![
+            CreateButton::new(CustomId::UnlinkAccount(discord_id.get()).to_string())
+                .label("Unlink").style(ButtonStyle::Danger),
+            ban_button(discord_id.get(), &uuid),
+        ])])).await?;
+        Ok(())
+    }
+
+    // Carry an approved link into every linked guild the user is already in. Nothing is carried when links are per
+    // guild, or into guilds the user already has it in.
+    async fn inherit_into_linked_guilds(&self, http: &Arc<Http>, discord_id: UserId) {
+        for guild in &self.config.linked_guilds {
+            if GuildId::new(guild.guild_id).member(http, discord_id).await.is_err() {
+                continue;
+            }
+            if let Err(why) = inherit_link(&self.sender, http, &self.config, &self.messages, discord_id, guild).await {
+                log!("Error carrying the link of user {discord_id} into guild {}: {why:?}", guild.guild_id);
+            }
+        }
+    }
+
+    // Greet a newly approved member in public.
+    async fn welcome(&self, http: &Arc<Http>, discord_id: UserId, name: &str, uuid: &str) -> Result<()> {
+        if self.config.welcome_channel_id == 0 {
+            return Ok(());
+        }
+        let text = self.config.welcome_template
+            .replace("{mention}", &format!("<@{discord_id}>"))
+            .replace("{name}", &escape_markdown(&sanitize_display(name)));
+        ChannelId::new(self.config.welcome_channel_id).send_message(http, CreateMessage::new().embed(
+            CreateEmbed::new()
+                .thumbnail(format!("https://www.mc-heads.net/head/{uuid}.png"))
+                .description(text)
+                .color(self.config.colors.primary)
+        )).await?;
+        Ok(())
+    }
+
+    // Ask the moderator why, before denying anything.
+    async fn open_denial(&self, http: &Arc<Http>, discord_id: UserId, uuid: String, component: &ComponentInteraction) -> Result<()> {
+        if !self.allowed(http, &component.user, component.channel_id, Capability::ApproveAccounts).await? {
+            return refuse(http, component).await;
+        }
+
+        component.create_response(http, CreateInteractionResponse::Modal(
+            CreateModal::new(CustomId::DenyAccount { discord_id: discord_id.get(), uuid }.to_string(), "Deny account").components(vec![
+                CreateActionRow::InputText(CreateInputText::new(InputTextStyle::Paragraph, "Reason", "reason").max_length(1000)),
+            ])
+        )).await?;
+        Ok(())
+    }
+
+    async fn deny_account(&self, http: &Arc<Http>, discord_id: UserId, uuid: String, modal: &ModalInteraction) -> Result<()> {
+        if !self.allowed(http, &modal.user, modal.channel_id, Capability::ApproveAccounts).await? {
+            modal.create_response(http, CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new().content(NO_PERMISSION).ephemeral(true)
+            )).await?;
+            return Ok(());
+        }
+
+        let reason = sanitize_display(&modal_text(modal));
+        let mut pair = ChannelPair::new();
+        self.sender.send(pair.entangle())?;
+        pair.sender.send(Packet::DiscordDenial(uuid.clone(), reason.clone(), modal.user.id.get()))?;
+        let Some(Packet::DenialResult(denied)) = pair.receiver.recv().await else { return Err(anyhow!("Main thread did not acknowledge denial!")) };
+        if !denied {
+            modal.create_response(http, CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new().content("This account is no longer pending.").ephemeral(true)
+            )).await?;
+            return Ok(());
+        }
+        self.staff.lock().unwrap().remember(modal.user.id.get(), &modal.user.name)?;
+
+        let name = modal.message.as_ref().and_then(|message| member_name(&message.embeds)).unwrap_or_default();
+        let args = [("name", name.as_str()), ("uuid", &uuid), ("discord_id", &discord_id.to_string()), ("channel_id", &self.config.verification_channel_id.to_string())];
+        let embed = CreateEmbed::new()
+            .title(self.messages.get("title"))
+            .description(self.messages.render("status.updated", &args))
+            .field("Status", "Denied", false)
+            .field("Reason", &reason, false)
+            .field("Trying again", self.messages.render("status.trying_again", &args), false)
+            .color(self.config.colors.error);
+        if let Err(why) = self.send_status(http, discord_id, Importance::Important, embed, Some((&name, &uuid))).await {
+            log!("Error telling user {discord_id} about their denial: {why:?}");
+        }
+        let outcome = format!("Denied by {}: {}", modal.user.name, reason.chars().take(900).collect::<String>());
+        if let Some(message) = &modal.message && let Err(why) = close_discussion(http, &self.config, message.id.get(), &outcome).await {
+            log!("Error closing discussion thread for {uuid}: {why:?}");
+        }
+
+        // Leave the request in the members channel with the outcome, and nothing left to press.
+        let blocked = self.notifications.lock().unwrap().is_blocked(discord_id.get());
+        let mut update = CreateInteractionResponseMessage::new();
+        if let Some(embed) = modal.message.as_ref().and_then(|message| message.embeds.first()) {
+            update = update.embed(with_dm_indicator(embed.clone(), blocked).field("Outcome", escape_markdown(&outcome), false));
+        }
+        let buttons = vec![
+            approve_button(discord_id.get(), &uuid, None).disabled(true),
+            deny_button(discord_id.get(), &uuid).disabled(true),
+        ];
+        modal.create_response(http, CreateInteractionResponse::UpdateMessage(update.components(vec![CreateActionRow::Buttons(buttons)]))).await?;
+        Ok(())
+    }
+
+    // Bans can't be taken back by the banned user, so make the moderator confirm first.
+    async fn request_ban(&self, http: &Arc<Http>, discord_id: UserId, uuid: String, component: &ComponentInteraction) -> Result<()> {
+        if !self.allowed(http, &component.user, component.channel_id, Capability::BanUsers).await? {
+            return refuse(http, component).await;
+        }
+
+        component.create_response(http, CreateInteractionResponse::Message(
+            CreateInteractionResponseMessage::new()
+                .content(format!("This will ban <@{discord_id}> [{uuid}] from the Minecraft server. Their account stays linked, so they can't link another one until a moderator unbans them."))
+                .button(CreateButton::new(CustomId::BanConfirm { discord_id: discord_id.get(), uuid }.to_string()).label("Ban").style(ButtonStyle::Danger))
+                .ephemeral(true)
+        )).await?;
+        Ok(())
+    }
+
+    async fn confirm_ban(&self, http: &Arc<Http>, discord_id: UserId, uuid: String, component: &ComponentInteraction) -> Result<()> {
+        if !self.allowed(http, &component.user, component.channel_id, Capability::BanUsers).await? {
+            return refuse(http, component).await;
+        }
+
+        let mut pair = ChannelPair::new();
+        self.sender.send(pair.entangle())?;
+        pair.sender.send(Packet::BanUser(uuid.clone(), component.user.id.get()))?;
+        let Some(Packet::BanResult(message_id)) = pair.receiver.recv().await else { return Err(anyhow!("Main thread did not acknowledge ban!")) };
+        let Some(message_id) = message_id else {
+            component.create_response(http, CreateInteractionResponse::UpdateMessage(
+                CreateInteractionResponseMessage::new().content("This account can no longer be banned.").components(Vec::new())
+            )).await?;
+            return Ok(());
+        };
+
+        // The confirmation is its own message, so the member message is edited by ID.
+        let channel_id = ChannelId::new(self.config.member_channel_id);
+        let message = channel_id.message(http, message_id).await?;
+        let mut edit = EditMessage::new().components(vec![CreateActionRow::Buttons(vec![unban_button(discord_id.get(), &uuid)])]);
+        if let Some(mut embed) = message.embeds.into_iter().next() {
+            embed.fields.retain(|field| !matches!(field.name.as_str(), "Approvals" | "Banned by"));
+            embed.fields.push(EmbedField::new("Banned by", format!("<@{}> on <t:{}:f>", component.user.id, timestamp() / 1000), false));
+            edit = edit.embed(CreateEmbed::from(embed));
+        }
+        channel_id.edit_message(http, message_id, edit).await?;
+
+        remove_verified_role(http, &self.config, &self.role_removals, discord_id).await;
+        if let Err(why) = close_discussion(http, &self.config, message_id, &format!("Banned by {}.", component.user.name)).await {
+            log!("Error closing discussion thread for {uuid}: {why:?}");
+        }
+        component.create_response(http, CreateInteractionResponse::UpdateMessage(
+            CreateInteractionResponseMessage::new().content(format!("Banned <@{discord_id}>.")).components(Vec::new())
+        )).await?;
+        Ok(())
+    }
+
+    // Unbanned users are back in the queue, so the member message gets its approval buttons back.
+    async fn unban_account(&self, http: &Arc<Http>, discord_id: UserId, uuid: String, component: &ComponentInteraction) -> Result<()> {
+        if !self.allowed(http, &component.user, component.channel_id, Capability::BanUsers).await? {
+            return refuse(http, component).await;
+        }
+
+        let mut pair = ChannelPair::new();
+        self.sender.send(pair.entangle())?;
+        pair.sender.send(Packet::UnbanUser(uuid.clone(), component.user.id.get()))?;
+        let Some(Packet::BanResult(unbanned)) = pair.receiver.recv().await else { return Err(anyhow!("Main thread did not acknowledge unban!")) };
+        if unbanned.is_none() {
+            component.create_response(http, CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new().content("This account is no longer banned.").ephemeral(true)
+            )).await?;
+            return Ok(());
+        }
+
+        let mut update = CreateInteractionResponseMessage::new();
+        if let Some(embed) = component.message.embeds.first() {
+            let mut embed = embed.clone();
+            embed.fields.retain(|field| !matches!(field.name.as_str(), "Approved by" | "Banned by"));
+            update = update.embed(CreateEmbed::from(embed));
+        }
+        let buttons = vec![
+            approve_button(discord_id.get(), &uuid, None),
+            deny_button(discord_id.get(), &uuid),
+            ban_button(discord_id.get(), &uuid),
+        ];
+        component.create_response(http, CreateInteractionResponse::UpdateMessage(update.components(vec![CreateActionRow::Buttons(buttons)]))).await?;
+        Ok(())
+    }
+
+    // Unlinking can't be undone, so ask first, naming the account so the moderator can tell they clicked the right message.
+    async fn request_unlink(&self, http: &Arc<Http>, user_id: UserId, component: &ComponentInteraction) -> Result<()> {
+        if !self.allowed(http, &component.user, component.channel_id, Capability::UnlinkAccounts).await? {
+            return refuse(http, component).await;
+        }
+
+        let name = member_name(&component.message.embeds).map(|name| format!("**{}** ", escape_markdown(&name))).unwrap_or_default();
+        component.create_response(http, CreateInteractionResponse::Message(
+            CreateInteractionResponseMessage::new()
+                .content(format!("This will unlink {name}(<@{user_id}>), removing them from the whitelist and deleting their member message. This cannot be undone."))
+                .button(CreateButton::new(CustomId::UnlinkConfirm(user_id.get()).to_string()).label("Unlink").style(ButtonStyle::Danger))
+                .button(CreateButton::new(CustomId::UnlinkCancel(user_id.get()).to_string()).label("Cancel").style(ButtonStyle::Secondary))
+                .ephemeral(true)
+        )).await?;
+        Ok(())
+    }
+
+    async fn unlink_account(&self, http: &Arc<Http>, user_id: UserId, component: &ComponentInteraction) -> Result<()> {
+        if !self.allowed(http, &component.user, component.channel_id, Capability::UnlinkAccounts).await? {
+            return refuse(http, component).await;
+        }
+
+        self.handle_user_leave(http, user_id, Actor::Discord(component.user.id.get())).await?;
+        component.create_response(http, CreateInteractionResponse::UpdateMessage(
+            CreateInteractionResponseMessage::new().content(format!("Unlinked <@{user_id}>.")).components(Vec::new())
+        )).await?;
+        Ok(())
+    }
+
+    // Dismiss the confirmation without doing anything.
+    async fn cancel_unlink(&self, http: &Arc<Http>, component: &ComponentInteraction) -> Result<()> {
+        component.create_response(http, CreateInteractionResponse::Acknowledge).await?;
+        component.delete_response(http).await?;
+        Ok(())
+    }
+
+    // Member updates don't say which roles changed, so any approved user without the verified role lost it by hand.
+    // Pending and banned users never have it.
+    async fn handle_role_removal(&self, http: &Arc<Http>, user_id: UserId) -> Result<()> {
+        let mut pair = ChannelPair::new();
+        self.sender.send(pair.entangle())?;
+        pair.sender.send(Packet::LookupByDiscord(user_id.get()))?;
+        let Some(Packet::LookupResult(details)) = pair.receiver.recv().await else { return Err(anyhow!("Main thread did not respond to lookup!")) };
+        if details.is_some_and(|details| details.verify_state == VerifyState::APPROVED) {
+            log!("The verified role was removed from user {user_id}, unlinking them");
+            self.handle_user_leave(http, user_id, Actor::RoleRemoved).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_user_leave(&self, http: &Arc<Http>, user_id: UserId, actor: Actor) -> Result<()> {
+        unlink_user(&self.sender, http, &self.config, &self.role_removals, user_id, actor).await
+    }
+}
+
+// The bodies of the gateway events, which the EventHandler impl runs with their panics caught.
+impl Handler {
+    async fn on_ready(&self, ctx: Context) {
+        if let Err(why) = GuildId::new(self.config.guild_id).set_commands(&ctx.http, commands()).await {
+            log!("Error registering slash commands: {why:?}");
+        }
+        // Linked guilds share the main guild's staff, so their members only need a way to verify.
+        for guild in &self.config.linked_guilds {
+            if let Err(why) = GuildId::new(guild.guild_id).set_commands(&ctx.http, vec![verify_definition()]).await {
+                log!("Error registering slash commands in guild {}: {why:?}", guild.guild_id);
+            }
+        }
+
+        if let Err(why) = self.refresh_panels(&ctx.http).await {
+            log!("Error refreshing panels: {why:?}");
+        }
+
+        // Ready fires again on reconnects, so only start the periodic tasks once.
+        if !self.tasks_started.swap(true, Ordering::SeqCst) {
+            tokio::spawn(show_online_players(ctx.clone(), self.config.clone(), self.online_players.clone()));
+            if self.config.approval_slo_hours > 0 {
+                tokio::spawn(slo_monitor(self.sender.clone(), ctx.http.clone(), self.config.clone(), self.panics.clone()));
+            }
+            if self.config.transcript_channel_id != 0 {
+                tokio::spawn(transcripts::resume_all(ctx.http.clone(), ChannelId::new(self.config.transcript_channel_id)));
+            }
+            if self.config.staff_report_channel_id != 0 {
+                tokio::spawn(staff_report_monitor(self.sender.clone(), ctx.http.clone(), self.config.clone(), self.staff.clone(), self.tickets.clone(), self.panics.clone()));
+            }
+            if self.config.reminder_after_hours > 0 {
+                tokio::spawn(reminder_monitor(self.sender.clone(), ctx.http.clone(), self.config.clone(), self.panics.clone()));
+            }
+            if self.config.reconcile_interval_mins > 0 {
+                tokio::spawn(reconcile_monitor(self.sender.clone(), ctx.http.clone(), self.config.clone(), self.panics.clone(), self.role_removals.clone()));
+            }
+            if self.config.role_sweep_interval_mins > 0 {
+                tokio::spawn(role_sweep_monitor(self.sender.clone(), ctx.http.clone(), self.config.clone(), self.messages.clone(), self.panics.clone(), self.role_removals.clone()));
+            }
+        }
+    }
+
+    // Approved users joining a linked guild inherit their link there.
+    async fn on_member_addition(&self, ctx: Context, member: Member) {
+        let Some(guild) = self.config.linked_guild(member.guild_id.get()) else { return };
+        if let Err(why) = inherit_link(&self.sender, &ctx.http, &self.config, &self.messages, member.user.id, guild).await {
+            log!("Error carrying the link of user {} into guild {}: {why:?}", member.user.id, guild.guild_id);
+        }
+    }
+
+    async fn on_member_removal(&self, ctx: Context, guild_id: GuildId, user: User) {
+        // Linked guilds need the member intent too, so these arrive even when leaving is meant to be ignored.
+        if !self.config.handle_member_leave {
+            return;
+        }
+        if guild_id == self.config.guild_id && let Err(why) = self.handle_user_leave(&ctx.http, user.id, Actor::LeftGuild).await {
+            log!("Error handling user removal: {why:?}");
+        }
+    }
+
+    async fn on_member_update(&self, ctx: Context, event: GuildMemberUpdateEvent) {
+        if !self.config.handle_member_leave || event.guild_id != self.config.guild_id || event.roles.contains(&RoleId::new(self.config.verified_role_id)) || self.role_removals.take(event.user.id) {
+            return;
+        }
+        if let Err(why) = self.handle_role_removal(&ctx.http, event.user.id).await {
+            log!("Error handling verified role removal: {why:?}");
+        }
+    }
+
+    async fn on_message(&self, ctx: Context, msg: Message) {
+        let Some(_guard) = self.shutdown.enter() else {
+            return;
+        };
+        let verification_channel = self.config.verification_channel_id;
+        let ticket_channel = self.config.ticket_channel_id;
+        let member_channel = self.config.member_channel_id;
+        let channel_id = msg.channel_id.get();
+
+        let linked_guild = msg.guild_id.and_then(|guild_id| self.config.linked_guild(guild_id.get()));
+
+        // Codes can be DMed to the bot by users who'd rather not post them in the verification channel.
+        if msg.guild_id.is_none() {
+            if let Err(why) = self.handle_direct_message(ctx, msg).await {
+                log!("Error handling direct message: {why:?}");
+            }
+        } else if channel_id == verification_channel {
+            if let Err(why) = self.handle_verify_message(ctx, msg, None).await {
+                log!("Error handling verification message: {why:?}");
+            }
+        } else if let Some(guild) = linked_guild && channel_id == guild.verification_channel_id {
+            if let Err(why) = self.handle_verify_message(ctx, msg, Some(guild)).await {
+                log!("Error handling verification message in guild {}: {why:?}", guild.guild_id);
+            }
+        } else if channel_id == ticket_channel {
+            if let Err(why) = self.handle_ticket_message(ctx, msg).await {
+                log!("Error handling ticket message: {why:?}");
+            }
+        } else if self.tickets.lock().unwrap().claimable(channel_id, msg.author.id.get()) {
+            if let Err(why) = self.handle_ticket_reply(ctx, msg).await {
+                log!("Error handling ticket reply: {why:?}");
+            }
+        } else if channel_id == member_channel && let Err(why) = self.handle_member_message(ctx, msg).await {
+            log!("Error handling member message: {why:?}");
+        }
+    }
+
+    async fn on_message_update(&self, ctx: Context, event: MessageUpdateEvent) {
+        let Some(_guard) = self.shutdown.enter() else {
+            return;
+        };
+        // Discord also sends updates when it unfurls links, which aren't edits and may be for messages already deleted.
+        let channel_id = event.channel_id.get();
+        let linked_verification = self.config.linked_guilds.iter().any(|guild| guild.verification_channel_id == channel_id);
+        if event.edited_timestamp.is_none() || (channel_id != self.config.verification_channel_id && channel_id != self.config.ticket_channel_id && !linked_verification) {
+            return;
+        }
+
+        if let Err(why) = self.handle_edit(ctx, event).await {
+            log!("Error handling edited message: {why:?}");
+        }
+    }
+
+    async fn on_interaction(&self, ctx: Context, interaction: Interaction) {
+        let Some(_guard) = self.shutdown.enter() else {
+            let response = CreateInteractionResponse::Message(CreateInteractionResponseMessage::new().content("The bot is restarting, please try again shortly.").ephemeral(true));
+            let _ = match &interaction {
+                Interaction::Command(command) => command.create_response(&ctx.http, response).await,
+                Interaction::Component(component) => component.create_response(&ctx.http, response).await,
+                Interaction::Modal(modal) => modal.create_response(&ctx.http, response).await,
+                _ => Ok(()),
+            };
+            return;
+        };
+
+        if let Interaction::Command(command) = &interaction {
+            let result = match command.data.name.as_str() {
+                "notifications" => self.show_notification_menu(&ctx.http, command).await,
+                "stats" => match command.data.options.first() {
+                    Some(option) if option.name == "export-range" => self.export_analytics(&ctx.http, command, option).await,
+                    Some(option) if option.name == "staff" => self.show_staff_activity(&ctx.http, command, option).await,
+                    _ => self.show_stats(&ctx.http, command).await,
+                },
+                "pending" => self.show_pending(&ctx.http, command).await,
+                "members" => self.show_members(&ctx.http, command).await,
+                "clearlockout" => self.clear_lockout(&ctx.http, command).await,
+                "whitelist" => self.show_whitelist_diff(&ctx.http, command).await,
+                "announce" => self.announce(&ctx.http, command).await,
+                "setup" => self.setup(&ctx.http, command).await,
+                "verify" => self.verify_command(&ctx.http, command).await,
+                "lookup" => self.lookup(&ctx.http, command).await,
+                "permissions" => self.show_permissions(&ctx.http, command).await,
+                "forget-me" => self.request_forget(&ctx.http, command, command.user.id).await,
+                "forget" => match command.data.options.first().and_then(|option| option.value.as_user_id()) {
+                    Some(target) => self.request_forget(&ctx.http, command, target).await,
+                    None => Ok(()),
+                },
+                _ => Ok(()),
+            };
+
+            if let Err(why) = result {
+                log!("Error handling /{} command: {why:?}", command.data.name);
+            }
+        }
+
+        if let Interaction::Modal(modal) = &interaction {
+            let (action, result) = match CustomId::parse(&modal.data.custom_id) {
+                Some(CustomId::TicketComment(channel_id)) => ("recording ticket comment", self.submit_ticket_comment(&ctx.http, channel_id, modal).await),
+                Some(CustomId::DenyAccount { discord_id, uuid }) => ("denying account", self.deny_account(&ctx.http, UserId::new(discord_id), uuid, modal).await),
+                _ => {
+                    log!("Ignoring modal with unrecognized id {}", modal.data.custom_id);
+                    return;
+                }
+            };
+
+            if let Err(why) = result {
+                log!("Error {action}: {why:?}");
+            }
+        }
+
+        if let Interaction::Component(component) = interaction {
+            let Some(id) = CustomId::parse(&component.data.custom_id) else {
+                log!("Ignoring component with unrecognized id {}", component.data.custom_id);
+                return;
+            };
+
+            // A second click on a button whose first click is still being handled would do everything twice. Everyone
+            // shares the create ticket button, so it's only held for the user who clicked it.
+            let key = match &id {
+                CustomId::ApproveAccount { .. } | CustomId::CloseTicket(_) => Some(component.data.custom_id.clone()),
+                CustomId::CreateTicket => Some(format!("{}-{}", component.data.custom_id, component.user.id)),
+                _ => None,
+            };
+            let _claim = match key.map(|key| self.claim(key)) {
+                Some(None) => {
+                    let response = CreateInteractionResponse::Message(CreateInteractionResponseMessage::new().content("This is already being processed.").ephemeral(true));
+                    if let Err(why) = component.create_response(&ctx.http, response).await {
+                        log!("Error refusing a repeated click: {why:?}");
+                    }
+                    return;
+                }
+                claim => claim.flatten(),
+            };
+
+            let (action, result) = match id {
+                CustomId::TicketRating { channel_id, rating } => ("rating ticket", self.rate_ticket(&ctx.http, channel_id, rating, &component).await),
+                CustomId::TicketComment(channel_id) => ("opening ticket comment", self.open_ticket_comment(&ctx.http, channel_id, &component).await),
+                CustomId::MuteDms => ("muting bot DMs", self.set_notification_level(&ctx.http, component.user.id, NotificationLevel::None, &component).await),
+                CustomId::NotificationPreference => {
+                    let level = match &component.data.kind {
+                        ComponentInteractionDataKind::StringSelect { values } => values.first().and_then(|value| NotificationLevel::from_id(value)),
+                        _ => None,
+                    };
+                    match level {
+                        Some(level) => ("updating notification preferences", self.set_notification_level(&ctx.http, component.user.id, level, &component).await),
+                        None => return,
+                    }
+                }
+                CustomId::NewCode => ("regenerating code", self.regenerate_code(&ctx.http, &component).await),
+                CustomId::CreateTicket => ("opening ticket", self.open_ticket(&ctx.http, &component.user, &component).await),
+                CustomId::CloseTicket(channel_id) => ("closing ticket", self.close_ticket(&ctx.http, ChannelId::new(channel_id), &component).await),
+                CustomId::ApproveAccount { discord_id, uuid, server } => ("approving account", self.approve_account(&ctx.http, UserId::new(discord_id), uuid, server, &component).await),
+                CustomId::DenyAccount { discord_id, uuid } => ("opening denial", self.open_denial(&ctx.http, UserId::new(discord_id), uuid, &component).await),
+                CustomId::BanAccount { discord_id, uuid } => ("requesting ban", self.request_ban(&ctx.http, UserId::new(discord_id), uuid, &component).await),
+                CustomId::BanConfirm { discord_id, uuid } => ("banning account", self.confirm_ban(&ctx.http, UserId::new(discord_id), uuid, &component).await),
+                CustomId::UnbanAccount { discord_id, uuid } => ("unbanning account", self.unban_account(&ctx.http, UserId::new(discord_id), uuid, &component).await),
+                CustomId::ForgetConfirm(target) => ("erasing user data", self.confirm_forget(&ctx.http, UserId::new(target), &component).await),
+                CustomId::UnlinkAccount(user_id) => ("requesting unlink", self.request_unlink(&ctx.http, UserId::new(user_id), &component).await),
+                CustomId::UnlinkConfirm(user_id) => ("unlinking account", self.unlink_account(&ctx.http, UserId::new(user_id), &component).await),
+                CustomId::UnlinkCancel(_) => ("cancelling unlink", self.cancel_unlink(&ctx.http, &component).await),
+                CustomId::WhitelistAddMissing => ("fixing whitelist", self.fix_whitelist(&ctx.http, true, &component).await),
+                CustomId::WhitelistFlagUnknown => ("fixing whitelist", self.fix_whitelist(&ctx.http, false, &component).await),
+                CustomId::MembersPage(page) => ("turning members page", self.turn_members_page(&ctx.http, page, &component).await),
+                // Disabled, so discord never sends it.
+                CustomId::ClosedTicket => return,
+            };
+
+            if let Err(why) = result {
+                log!("Error {action}: {why:?}");
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl EventHandler for Handler {
+    async fn ready(&self, ctx: Context, _ready: Ready) {
+        let http = ctx.http.clone();
+        isolate(&self.panics, &http, &self.config, "ready", self.on_ready(ctx)).await;
+    }
+
+    async fn guild_member_addition(&self, ctx: Context, new_member: Member) {
+        let http = ctx.http.clone();
+        isolate(&self.panics, &http, &self.config, "member addition", self.on_member_addition(ctx, new_member)).await;
+    }
+
+    async fn guild_member_removal(&self, ctx: Context, guild_id: GuildId, user: User, _member_data_if_available: Option<Member>) {
+        let http = ctx.http.clone();
+        isolate(&self.panics, &http, &self.config, "member removal", self.on_member_removal(ctx, guild_id, user)).await;
+    }
+
+    async fn guild_member_update(&self, ctx: Context, _old_if_available: Option<Member>, _new: Option<Member>, event: GuildMemberUpdateEvent) {
+        let http = ctx.http.clone();
+        isolate(&self.panics, &http, &self.config, "member update", self.on_member_update(ctx, event)).await;
+    }
+
+    async fn message(&self, ctx: Context, msg: Message) {
+        let http = ctx.http.clone();
+        isolate(&self.panics, &http, &self.config, "message", self.on_message(ctx, msg)).await;
+    }
+
+    async fn message_update(&self, ctx: Context, _old_if_available: Option<Message>, _new: Option<Message>, event: MessageUpdateEvent) {
+        let http = ctx.http.clone();
+        isolate(&self.panics, &http, &self.config, "message update", self.on_message_update(ctx, event)).await;
+    }
+
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        let http = ctx.http.clone();
+        isolate(&self.panics, &http, &self.config, "interaction", self.on_interaction(ctx, interaction)).await;
+    }
+}
+
+pub async fn start_discord(discord_tx: UnboundedSender<ChannelPair<Packet>>, notifications_rx: UnboundedReceiver<Packet>, requests_rx: UnboundedReceiver<ChannelPair<Packet>>, events: EventSender, shutdown: Arc<Shutdown>, external_cache: Arc<ExternalCache>, messages: Arc<Messages>) -> Result<()> {
+    let config = Arc::new(open_config()?);
+    if config.token.is_empty() {
+        log!("Please complete the discord config before starting the discord bot program.");
+        exit(0);
+    }
+
+    let (intents, cache_settings) = gateway_profile(&config);
+    log!("Requesting gateway intents {intents:?}, caching users: {}", cache_settings.cache_users);
+
+    let notifications = NotificationPreferences::load()?;
+    let panels = Panels::load()?;
+    let tickets = TicketHistory::load()?;
+    let enrichment = Enrichment::load(external_cache.clone())?;
+    let staff = Arc::new(Mutex::new(StaffActivity::load()?));
+    let role_removals = Arc::new(RoleRemovals::default());
+    let (online_players_tx, online_players) = watch::channel(None);
+
+    let grace = Duration::from_secs(config.shutdown_grace_secs);
+    let shutdown_coordinator = shutdown.clone();
+    tokio::spawn(async move {
+        if let Err(why) = shutdown_coordinator.run(grace).await {
+            log!("Error waiting for shutdown signals: {why:?}");
+        }
+    });
+
+    let mut client = Client::builder(config.token.clone(), intents)
+        .cache_settings(cache_settings)
+        .event_handler(Handler::new(discord_tx, config.clone(), notifications, panels, events, tickets, shutdown, enrichment, staff, external_cache, messages.clone(), role_removals.clone(), online_players))
+        .await
+        .expect("Error creating client!");
+
+    tokio::spawn(handle_notifications(notifications_rx, client.http.clone(), config, messages, role_removals, online_players_tx));
+    tokio::spawn(handle_requests(requests_rx, client.http.clone()));
+
+    log!("Starting discord client...");
+
+    match client.start().await {
+        Err(serenity::Error::Gateway(GatewayError::DisallowedGatewayIntents)) => {
+            log!(
+                "Discord refused the privileged intents {:?}. Enable them for the application in the developer portal, or set handle_member_leave to false and interaction_only to true in the discord config.",
+                intents & (GatewayIntents::GUILD_MEMBERS | GatewayIntents::MESSAGE_CONTENT)
+            );
+            Err(anyhow!("The bot application hasn't been granted its privileged gateway intents!"))
+        }
+        result => Ok(result?),
+    }
+}
+
+// Only ask discord for the events the enabled features act on. GUILD_MEMBERS and MESSAGE_CONTENT are
+// privileged, and member events are what fill the user cache, so leaving them out keeps memory down.
+fn gateway_profile(config: &DiscordConfig) -> (GatewayIntents, CacheSettings) {
+    let mut intents = GatewayIntents::empty();
+    if config.handle_member_leave || !config.linked_guilds.is_empty() {
+        intents |= GatewayIntents::GUILD_MEMBERS;
+    }
+    if !config.interaction_only {
+        intents |= GatewayIntents::GUILD_MESSAGES | GatewayIntents::DIRECT_MESSAGES | GatewayIntents::MESSAGE_CONTENT;
+    }
+
+    // Nothing reads cached messages back, and users are only worth keeping when member events arrive.
+    let mut cache_settings = CacheSettings::default();
+    cache_settings.max_messages = 0;
+    cache_settings.cache_users = intents.contains(GatewayIntents::GUILD_MEMBERS);
+    (intents, cache_settings)
+}
+
+// Handle notifications pushed from the main thread.
+async fn handle_notifications(mut receiver: UnboundedReceiver<Packet>, http: Arc<Http>, config: Arc<DiscordConfig>, messages: Arc<Messages>, role_removals: Arc<RoleRemovals>, online_players: watch::Sender<Option<usize>>) {
+    let (chat_tx, chat_rx) = unbounded_channel();
+    if config.chat_channel_id != 0 {
+        tokio::spawn(relay_chat(chat_rx, http.clone(), ChannelId::new(config.chat_channel_id)));
+    }
+
+    // Uuids of the players online, so a repeated join or leave doesn't throw the count off.
+    let mut online = HashSet::new();
+    while let Some(packet) = receiver.recv().await {
+        let result = match packet {
+            Packet::PlayerPresence(name, uuid, discord_id, joined) => {
+                match joined {
+                    true => online.insert(uuid.clone()),
+                    false => online.remove(&uuid),
+                };
+                online_players.send_if_modified(|count| count.replace(online.len()) != Some(online.len()));
+                post_presence(&http, &config, &name, &uuid, discord_id, joined).await
+            }
+            Packet::ServerDisconnected => {
+                online.clear();
+                online_players.send_if_modified(|count| count.take().is_some());
+                Ok(())
+            }
+            Packet::UnlinkMember(discord_id, messages, lost) => unlink_member(&http, &config, &role_removals, UserId::new(discord_id), messages, lost).await,
+            Packet::UpdatePlaytime(message_id, seconds) => update_playtime(&http, &config, message_id, seconds).await,
+            Packet::RenameMember(message_id, discord_id, name, uuid) => rename_member(&http, &config, &messages, message_id, discord_id, &name, &uuid).await,
+            Packet::Audit(entry) => post_audit(&http, &config, &entry).await,
+            Packet::RequestServerAccess(message_id, discord_id, uuid, server) => request_server_access(&http, &config, message_id, discord_id, &uuid, &server).await,
+            Packet::RelayChat(name, message) => {
+                // The receiver is gone when the chat channel isn't configured.
+                let _ = chat_tx.send(format!("**{}**: {}", escape_markdown(&sanitize_display(&name)), escape_markdown(&sanitize_display(&message))));
+                Ok(())
+            }
+            x => Err(anyhow!("Unexpected packet {x:?} received on discord notification channel!")),
+        };
+
+        if let Err(why) = result {
+            log!("Error handling discord notification: {why:?}");
+        }
+    }
+}
+
+// Answer the main loop's questions about discord, each on its own task so a slow API call doesn't hold up the
+// others.
+async fn handle_requests(mut receiver: UnboundedReceiver<ChannelPair<Packet>>, http: Arc<Http>) {
+    while let Some(mut pair) = receiver.recv().await {
+        let http = http.clone();
+        tokio::spawn(async move {
+            let reply = match pair.receiver.recv().await {
+                Some(Packet::DiscordUserQuery(discord_id)) => match http.get_user(UserId::new(discord_id)).await {
+                    Ok(user) => Packet::DiscordUserResponse(Some(user.name)),
+                    Err(why) => {
+                        log!("Error fetching discord user {discord_id}: {why:?}");
+                        Packet::DiscordUserResponse(None)
+                    }
+                },
+                Some(x) => {
+                    log!("Unexpected packet {x:?} received on discord request channel!");
+                    return;
+                }
+                None => return,
+            };
+            let _ = pair.sender.send(reply);
+        });
+    }
+}
+
+async fn unlink_user(sender: &UnboundedSender<ChannelPair<Packet>>, http: &Arc<Http>, config: &DiscordConfig, role_removals: &RoleRemovals, user_id: UserId, actor: Actor) -> Result<()> {
+    // Tell the main thread to remove the user
+    let mut local_pair = ChannelPair::new();
+    sender.send(local_pair.entangle())?;
+    local_pair.sender.send(Packet::RemoveUser(user_id.get(), actor))?;
+
+    // Remove the member message of each account from the members channel, and of each link inherited from it
+    if let Some(Packet::RemoveMessages(messages)) = local_pair.receiver.recv().await {
+        delete_member_messages(http, config, user_id, messages, "Unlinked.").await?;
+    }
+
+    // Try to remove their role
+    remove_verified_role(http, config, role_removals, user_id).await;
+    Ok(())
+}
+
+// Clean up after an account was unlinked from in-game, taking the verified role away in the guilds the user has no
+// other approved account for.
+async fn unlink_member(http: &Arc<Http>, config: &DiscordConfig, role_removals: &RoleRemovals, user_id: UserId, messages: Vec<(Option<u64>, u64)>, lost: Vec<Option<u64>>) -> Result<()> {
+    delete_member_messages(http, config, user_id, messages, "Unlinked from in-game.").await?;
+    for guild_id in lost {
+        remove_verified_role_in(http, config, role_removals, user_id, guild_id).await;
+    }
+    Ok(())
+}
+
+// Delete member messages from the member channel of the main guild (None) or the linked guild each was posted in.
+// Only the main guild's have discussion threads.
+async fn delete_member_messages(http: &Arc<Http>, config: &DiscordConfig, user_id: UserId, messages: Vec<(Option<u64>, u64)>, outcome: &str) -> Result<()> {
+    for (guild_id, message_id) in messages {
+        if guild_id.is_none() && let Err(why) = close_discussion(http, config, message_id, outcome).await {
+            log!("Error closing discussion thread for {user_id}: {why:?}");
+        }
+        let Some(channel_id) = config.member_channel(guild_id) else { continue };
+        channel_id.delete_message(http, message_id).await?;
+    }
+    Ok(())
+}
+
+// Carry a user's approved link into a linked guild without a code: give them the guild's verified role and post a
+// member message there. Returns the name of the account carried over, None if there was nothing to carry, as when
+// links are per guild or the user already has a link there.
+async fn inherit_link(sender: &UnboundedSender<ChannelPair<Packet>>, http: &Arc<Http>, config: &DiscordConfig, messages: &Messages, discord_id: UserId, guild: &LinkedGuild) -> Result<Option<String>> {
+    let mut pair = ChannelPair::new();
+    sender.send(pair.entangle())?;
+    pair.sender.send(Packet::InheritLink(discord_id.get(), guild.guild_id))?;
+    let Some(Packet::Inheritable(inheritable)) = pair.receiver.recv().await else { return Err(anyhow!("Main thread did not respond to inheriting a link!")) };
+    let Some((name, uuid)) = inheritable else { return Ok(None) };
+
+    http.add_member_role(GuildId::new(guild.guild_id), discord_id, RoleId::new(guild.verified_role_id), Some("Approved in the main guild")).await?;
+    let embed = member_embed(config, messages, &name, &uuid, discord_id.get()).field("Status", "Approved in the main guild", false);
+    let message = ChannelId::new(guild.member_channel_id).send_message(http, CreateMessage::new().embed(embed)).await?;
+    pair.sender.send(Packet::InheritedMessage(message.id.get()))?;
+    log!("Carried the link of {name} [{uuid}] into guild {}", guild.guild_id);
+    Ok(Some(name))
+}
+
+// Start a thread on a pending member message for staff to discuss the applicant in, and note it in the log channel.
+async fn open_discussion(http: &Arc<Http>, config: &DiscordConfig, channel_id: ChannelId, message_id: MessageId, name: &str, discord_id: u64) -> Result<()> {
+    let thread = channel_id.create_thread_from_message(http, message_id,
+        CreateThread::new(name).auto_archive_duration(AutoArchiveDuration::OneWeek)
+    ).await?;
+    log!("Opened discussion thread {} for {name}", thread.id);
+    post_log(http, config, CreateMessage::new().embed(
+        CreateEmbed::new()
+            .title("Discussion opened")
+            .description(format!("<#{}> for {} (<@{discord_id}>)", thread.id, escape_markdown(&sanitize_display(name))))
+            .color(config.colors.secondary)
+    ).allowed_mentions(CreateAllowedMentions::new())).await
+}
+
+// Post the outcome into a member message's discussion thread and archive it. The thread is kept so the
+// discussion can still be read after the member message is gone. Threads started from a message share its ID.
+async fn close_discussion(http: &Arc<Http>, config: &DiscordConfig, message_id: u64, outcome: &str) -> Result<()> {
+    if !config.discussion_threads {
+        return Ok(());
+    }
+    let thread = ChannelId::new(message_id);
+    thread.send_message(http, CreateMessage::new().content(outcome).allowed_mentions(CreateAllowedMentions::new())).await?;
+    thread.edit_thread(http, EditThread::new().archived(true)).await?;
+    Ok(())
+}
+
+// Ask the moderators to let an approved user onto another server by adding an approve button to their member message.
+async fn request_server_access(http: &Arc<Http>, config: &DiscordConfig, message_id: u64, discord_id: u64, uuid: &str, server: &str) -> Result<()> {
+    let channel_id = ChannelId::new(config.member_channel_id);
+    let message = channel_id.message(http, message_id).await?;
+    let mut embed = message.embeds.into_iter().next().ok_or(anyhow!("Member message has no embed!"))?;
+    embed.fields.retain(|field| field.name != "Server");
+    embed.fields.push(EmbedField::new("Server", server, true));
+
+    let buttons = vec![
+        CreateButton::new(CustomId::UnlinkAccount(discord_id).to_string()).label("Unlink").style(ButtonStyle::Danger),
+        approve_button(discord_id, uuid, Some(server)).label(format!("Approve for {server}")),
+    ];
+    channel_id.edit_message(http, message_id, EditMessage::new().embed(CreateEmbed::from(embed)).components(vec![CreateActionRow::Buttons(buttons)])).await?;
+    Ok(())
+}
+
+// The server is only part of the ID for bots shared between several game servers.
+fn approve_button(discord_id: u64, uuid: &str, server: Option<&str>) -> CreateButton {
+    CreateButton::new(CustomId::ApproveAccount { discord_id, uuid: uuid.to_owned(), server: server.map(str::to_owned) }.to_string()).label("Approve")
+}
+
+// Tell someone who pressed a button they aren't allowed to use, rather than leaving the interaction hanging.
+async fn refuse(http: &Arc<Http>, component: &ComponentInteraction) -> Result<()> {
+    component.create_response(http, CreateInteractionResponse::Message(
+        CreateInteractionResponseMessage::new().content(NO_PERMISSION).ephemeral(true)
+    )).await?;
+    Ok(())
+}
+
+fn deny_button(discord_id: u64, uuid: &str) -> CreateButton {
+    CreateButton::new(CustomId::DenyAccount { discord_id, uuid: uuid.to_owned() }.to_string()).label("Deny").style(ButtonStyle::Danger)
+}
+
+fn ban_button(discord_id: u64, uuid: &str) -> CreateButton {
+    CreateButton::new(CustomId::BanAccount { discord_id, uuid: uuid.to_owned() }.to_string()).label("Ban").style(ButtonStyle::Danger)
+}
+
+fn unban_button(discord_id: u64, uuid: &str) -> CreateButton {
+    CreateButton::new(CustomId::UnbanAccount { discord_id, uuid: uuid.to_owned() }.to_string()).label("Unban").style(ButtonStyle::Secondary)
+}
+
+// The text typed into a modal's first input.
+fn modal_text(modal: &ModalInteraction) -> String {
+    modal.data.components.iter()
+        .flat_map(|row| row.components.iter())
+        .find_map(|component| match component {
+            ActionRowComponent::InputText(input) => input.value.clone(),
+            _ => None,
+        })
+        .unwrap_or_default()
+}
+
+// Show a player's total playtime on their member message, replacing the previous total.
+async fn update_playtime(http: &Arc<Http>, config: &DiscordConfig, message_id: u64, seconds: u64) -> Result<()> {
+    let channel_id = ChannelId::new(config.member_channel_id);
+    let message = channel_id.message(http, message_id).await?;
+    let mut embed = message.embeds.into_iter().next().ok_or(anyhow!("Member message has no embed!"))?;
+    embed.fields.retain(|field| field.name != "Playtime");
+    embed.fields.push(EmbedField::new("Playtime", format_duration(seconds), true));
+    channel_id.edit_message(http, message_id, EditMessage::new().embed(CreateEmbed::from(embed))).await?;
+    Ok(())
+}
+
+fn cannot_dm(why: &serenity::Error) -> bool {
+    matches!(why, serenity::Error::Http(HttpError::UnsuccessfulRequest(response)) if response.error.code == CANNOT_DM_CODE)
+}
+
+// Flag a member message whose user couldn't be DMed, or unflag it once they can be again.
+fn with_dm_indicator(mut embed: Embed, blocked: bool) -> CreateEmbed {
+    embed.fields.retain(|field| field.name != DM_BLOCKED_FIELD);
+    if blocked {
+        embed.fields.push(EmbedField::new(DM_BLOCKED_FIELD, "They may have blocked the bot or closed their DMs.", false));
+    }
+    CreateEmbed::from(embed)
+}
+
+fn role_mentions(roles: &[RoleId]) -> String {
+    roles.iter().map(|role| format!("<@&{role}>")).collect::<Vec<_>>().join(" ")
+}
+
+// The Minecraft name shown on a member message.
+fn member_name(embeds: &[Embed]) -> Option<String> {
+    embeds.first()?.fields.iter().find(|field| field.name == "Minecraft Name").map(|field| field.value.clone())
+}
+
+// The fields every member message starts with. Renames rebuild these too, so a renamed member's message looks
+// just like one posted under the new name.
+fn member_embed(config: &DiscordConfig, messages: &Messages, name: &str, uuid: &str, discord_id: u64) -> CreateEmbed {
+    CreateEmbed::new()
+        .thumbnail(format!("https://www.mc-heads.net/head/{uuid}.png"))
+        .title(messages.get("title"))
+        .field("Minecraft Name", sanitize_display(name), true)
+        .field("Minecraft UUID", uuid, true)
+        .field("", "", true)
+        .field("Discord User", format!("<@{discord_id}>"), true)
+        .field("Discord ID", format!("{discord_id}"), true)
+        .field("", "", true)
+        .color(config.colors.primary)
+}
+
+// Show the online player count as the bot's activity, or the idle message while no game server is up. Changes
+// that come in while waiting out the rate limit are folded into the next update.
+async fn show_online_players(ctx: Context, config: Arc<DiscordConfig>, mut online_players: watch::Receiver<Option<usize>>) {
+    loop {
+        let activity = match *online_players.borrow_and_update() {
+            Some(count) => ActivityData::playing(format!("with {count} player{} online", if count == 1 { "" } else { "s" })),
+            None => ActivityData::custom(&config.idle_activity),
+        };
+        ctx.set_activity(Some(activity));
+        tokio::time::sleep(PRESENCE_UPDATE_INTERVAL).await;
+        if online_players.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+// Rebuild the start of the member message under the new name, keeping everything added to it since.
+async fn rename_member(http: &Arc<Http>, config: &DiscordConfig, messages: &Messages, message_id: u64, discord_id: u64, name: &str, uuid: &str) -> Result<()> {
+    let channel_id = ChannelId::new(config.member_channel_id);
+    let message = channel_id.message(http, message_id).await?;
+    let old = message.embeds.into_iter().next().ok_or(anyhow!("Member message has no embed!"))?;
+    let mut embed = member_embed(config, messages, name, uuid, discord_id);
+    for field in old.fields.into_iter().skip(MEMBER_EMBED_FIELDS) {
+        embed = embed.field(field.name, field.value, field.inline);
+    }
+    if let Some(colour) = old.colour {
+        embed = embed.colour(colour);
+    }
+    channel_id.edit_message(http, message_id, EditMessage::new().embed(embed)).await?;
+    Ok(())
+}
+
+fn format_duration(seconds: u64) -> String {
+    let (days, hours, minutes) = (seconds / 86400, seconds / 3600 % 24, seconds / 60 % 60);
+    match (days, hours) {
+        (0, 0) => format!("{minutes}m"),
+        (0, _) => format!("{hours}h {minutes}m"),
+        _ => format!("{days}d {hours}h {minutes}m"),
+    }
+}
+
+async fn post_presence(http: &Arc<Http>, config: &DiscordConfig, name: &str, uuid: &str, discord_id: Option<u64>, joined: bool) -> Result<()> {
+    if config.presence_channel_id == 0 {
+        return Ok(());
+    }
+
+    let mut embed = CreateEmbed::new()
+        .thumbnail(format!("https://www.mc-heads.net/head/{uuid}.png"))
+        .description(format!("**{}** {} the server", sanitize_display(name), if joined { "joined" } else { "left" }))
+        .color(if joined { config.colors.primary } else { config.colors.secondary });
+    if let Some(discord_id) = discord_id {
+        embed = embed.field("Discord User", format!("<@{discord_id}>"), true);
+    }
+
+    ChannelId::new(config.presence_channel_id).send_message(http, CreateMessage::new().embed(embed)).await?;
+    Ok(())
+}
+
+// Post relayed chat lines, batching everything that arrives within a short window into as few messages as
+// possible so bursts of chat stay under discord's rate limits.
+async fn relay_chat(mut receiver: UnboundedReceiver<String>, http: Arc<Http>, channel_id: ChannelId) {
+    while let Some(first) = receiver.recv().await {
+        tokio::time::sleep(CHAT_BATCH_WINDOW).await;
+        let mut lines = vec![first];
+        while let Ok(line) = receiver.try_recv() {
+            lines.push(line);
+        }
+
+        let mut batches = vec![String::new()];
+        for line in lines {
+            let line: String = line.chars().take(MESSAGE_LIMIT - 1).collect();
+            let batch = batches.last_mut().unwrap();
+            if !batch.is_empty() && batch.len() + line.len() + 1 > MESSAGE_LIMIT {
+                batches.push(line);
+            } else {
+                if !batch.is_empty() {
+                    batch.push('\n');
+                }
+                batch.push_str(&line);
+            }
+        }
+
+        for batch in batches {
+            if let Err(why) = channel_id.send_message(&http, CreateMessage::new().content(batch).allowed_mentions(CreateAllowedMentions::new())).await {
+                log!("Error relaying chat: {why:?}");
+            }
+        }
+    }
+}
+
+// Run a handler with its panics caught, telling the log channel when one panics often enough to be switched off.
+// None when it panicked or is switched off.
+async fn isolate<T>(panics: &PanicGuard, http: &Arc<Http>, config: &DiscordConfig, handler: &'static str, future: impl Future<Output = T>) -> Option<T> {
+    match panics.run(handler, future).await {
+        Outcome::Finished(value) => Some(value),
+        Outcome::Panicked { reference, message, tripped: true } => {
+            let message = message.chars().take(500).collect::<String>();
+            let embed = CreateEmbed::new()
+                .title("Handler disabled")
+                .description(format!("The {handler} handler kept panicking and is disabled until the bot restarts.\nLast panic [{reference}]: {}", escape_markdown(&message)))
+                .color(config.colors.error);
+            if let Err(why) = post_log(http, config, CreateMessage::new().embed(embed)).await {
+                log!("Error posting disabled handler alert: {why:?}");
+            }
+            None
+        }
+        Outcome::Panicked { .. } | Outcome::Disabled => None,
+    }
+}
+
+// One compact embed per verification step, for moderators to trace who did what.
+async fn post_audit(http: &Arc<Http>, config: &DiscordConfig, entry: &AuditEntry) -> Result<()> {
+    if config.audit_channel_id == 0 {
+        return Ok(());
+    }
+
+    let mut description = format!("{} [{}]", escape_markdown(&sanitize_display(&entry.name)), entry.uuid);
+    if let Some(discord_id) = entry.discord_id {
+        description.push_str(&format!(" <@{discord_id}>"));
+    }
+    let actor = match &entry.actor {
+        Actor::Discord(discord_id) => format!("<@{discord_id}>"),
+        Actor::GameServer(Some(server)) => format!("game server {server}"),
+        Actor::GameServer(None) => "the game server".to_owned(),
+        Actor::LeftGuild => "leaving the guild".to_owned(),
+        Actor::RoleRemoved => "removing the verified role".to_owned(),
+        Actor::Bot => "the bot".to_owned(),
+    };
+    description.push_str(&format!("\nBy {actor} <t:{}:f>", entry.at / 1000));
+    let color = match &entry.action {
+        AuditAction::Approved(server) => {
+            if let Some(server) = server {
+                description.push_str(&format!("\nFor {server}"));
+            }
+            config.colors.primary
+        }
+        AuditAction::Denied(reason) => {
+            description.push_str(&format!("\nReason: {}", escape_markdown(reason)));
+            config.colors.error
+        }
+        AuditAction::Inherited(guild_id) => {
+            description.push_str(&format!("\nInto guild {guild_id}"));
+            config.colors.primary
+        }
+        AuditAction::Corrected { fix, .. } => {
+            description.push_str(&format!("\n{fix}"));
+            config.colors.secondary
+        }
+        AuditAction::Unlinked | AuditAction::Banned | AuditAction::DmFailed => config.colors.error,
+        AuditAction::CodeIssued | AuditAction::CodeExpired | AuditAction::Linked | AuditAction::Unbanned => config.colors.secondary,
+    };
+
+    let embed = CreateEmbed::new().title(entry.action.title()).description(description).color(color);
+    ChannelId::new(config.audit_channel_id).send_message(http, CreateMessage::new().embed(embed)).await?;
+    Ok(())
+}
+
+// Post a message to the log channel, if one is configured.
+async fn post_log(http: &Arc<Http>, config: &DiscordConfig, message: CreateMessage) -> Result<()> {
+    if config.log_channel_id != 0 {
+        ChannelId::new(config.log_channel_id).send_message(http, message).await?;
+    }
+    Ok(())
+}
+
+// Post last month's staff leaderboard once a new month starts.
+async fn staff_report_monitor(sender: UnboundedSender<ChannelPair<Packet>>, http: Arc<Http>, config: Arc<DiscordConfig>, staff: Arc<Mutex<StaffActivity>>, tickets: Arc<Mutex<TicketHistory>>, panics: Arc<PanicGuard>) {
+    let mut interval = tokio::time::interval(STAFF_REPORT_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Some(Err(why)) = isolate(&panics, &http, &config, "staff report", post_staff_report(&sender, &http, &config, &staff, &tickets)).await {
+            log!("Error posting the staff leaderboard: {why:?}");
+        }
+    }
+}
+
+async fn post_staff_report(sender: &UnboundedSender<ChannelPair<Packet>>, http: &Arc<Http>, config: &DiscordConfig, staff: &Mutex<StaffActivity>, tickets: &Mutex<TicketHistory>) -> Result<()> {
+    let now = Utc::now();
+    let month = now.format("%Y-%m").to_string();
+    let last_report = staff.lock().unwrap().last_report().map(str::to_owned);
+    if last_report.as_deref() == Some(month.as_str()) {
+        return Ok(());
+    }
+
+    // The first run only remembers the month, rather than posting a report for a month it may not have seen.
+    if last_report.is_some() {
+        let this_month = now.date_naive().with_day(1).ok_or(anyhow!("Invalid date!"))?;
+        let last_month = this_month.checked_sub_months(Months::new(1)).ok_or(anyhow!("Invalid date!"))?;
+        let millis = |day: NaiveDate| day.and_time(NaiveTime::MIN).and_utc().timestamp_millis() as u64;
+        let totals = staff_leaderboard(sender, staff, tickets, millis(last_month), millis(this_month)).await?;
+        let title = format!("Staff activity for {}", last_month.format("%B %Y"));
+        ChannelId::new(config.staff_report_channel_id).send_message(http, CreateMessage::new().embed(staff_embed(config, &title, &totals))).await?;
+    }
+    staff.lock().unwrap().set_last_report(month)
+}
+
+// Rank staff over a range, from the approvals and denials the main thread keeps and the ticket history.
+async fn staff_leaderboard(sender: &UnboundedSender<ChannelPair<Packet>>, staff: &Mutex<StaffActivity>, tickets: &Mutex<TicketHistory>, from: u64, to: u64) -> Result<Vec<StaffTotals>> {
+    let mut pair = ChannelPair::new();
+    sender.send(pair.entangle())?;
+    pair.sender.send(Packet::DecisionsQuery(from, to))?;
+    let Some(Packet::DecisionsResponse(decisions)) = pair.receiver.recv().await else { return Err(anyhow!("Main thread did not respond to decisions query!")) };
+    let tickets = tickets.lock().unwrap();
+    Ok(staff.lock().unwrap().leaderboard(&decisions, tickets.records(), from, to))
+}
+
+fn lookup_embed(config: &DiscordConfig, details: &LinkDetails) -> CreateEmbed {
+    let status = match details.verify_state {
+        VerifyState::NEW => "Waiting for their code",
+        VerifyState::PENDING => "Pending approval",
+        VerifyState::APPROVED => "Approved",
+        VerifyState::BANNED => "Banned",
+    };
+    let mut embed = CreateEmbed::new()
+        .title("Linked account")
+        .field("Minecraft Name", escape_markdown(&sanitize_display(&details.name)), true)
+        .field("UUID", &details.uuid, true)
+        .field("Discord", details.discord_id.map(|discord_id| format!("<@{discord_id}>")).unwrap_or_else(|| "Not linked".to_owned()), true)
+        .field("Status", status, true)
+        .color(config.colors.primary);
+    if !details.servers.is_empty() {
+        embed = embed.field("Servers", details.servers.join(", "), true);
+    }
+    if let Some(pending_since) = details.pending_since {
+        embed = embed.field("Requested", format!("<t:{}:f>", pending_since / 1000), true);
+    }
+    if let Some(approved_at) = details.approved_at {
+        let approver = details.approved_by.map(|approver| format!(" by <@{approver}>")).unwrap_or_default();
+        embed = embed.field("Approved", format!("<t:{}:f>{approver}", approved_at / 1000), true);
+    }
+    embed
+}
+
+// Verification codes are always 6 digits.
+fn is_code(code: i32) -> bool {
+    (CODE_MIN..=CODE_MAX).contains(&code)
+}
+
+// One line per connection that answers pings, cut short to fit in an embed field.
+fn round_trip_summary(round_trips: &[(String, Duration)]) -> String {
+    if round_trips.is_empty() {
+        return "N/A".to_owned();
+    }
+    let mut summary = String::new();
+    for (position, (label, round_trip)) in round_trips.iter().enumerate() {
+        let line = format!("{}: {} ms\n", escape_markdown(&sanitize_display(label)), round_trip.as_millis());
+        if summary.len() + line.len() > 1000 {
+            summary.push_str(&format!("...and {} more", round_trips.len() - position));
+            break;
+        }
+        summary.push_str(&line);
+    }
+    summary
+}
+
+// Moderators are listed by their stored name so those who left the guild still show up properly.
+fn staff_embed(config: &DiscordConfig, title: &str, totals: &[StaffTotals]) -> CreateEmbed {
+    let mut description = totals
+        .iter()
+        .take(STAFF_LEADERBOARD_SIZE)
+        .enumerate()
+        .map(|(rank, totals)| {
+            let first_response = totals.average_first_response.map(|millis| format!(", first response in {} on average", format_duration(millis / 1000))).unwrap_or_default();
+            format!(
+                "**{}.** {} (<@{}>): {} approvals, {} denials, {} tickets claimed, {} tickets closed{first_response}",
+                rank + 1, escape_markdown(&sanitize_display(&totals.name)), totals.moderator_id, totals.approvals, totals.denials, totals.tickets_claimed, totals.tickets_closed,
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    if description.is_empty() {
+        description = "No staff activity in this range.".to_owned();
+    }
+    CreateEmbed::new().title(title).description(description).color(config.colors.primary)
+}
+
+async fn slo_monitor(sender: UnboundedSender<ChannelPair<Packet>>, http: Arc<Http>, config: Arc<DiscordConfig>, panics: Arc<PanicGuard>) {
+    let mut interval = tokio::time::interval(SLO_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Some(Err(why)) = isolate(&panics, &http, &config, "SLO check", check_slo(&sender, &http, &config)).await {
+            log!("Error checking approval SLO: {why:?}");
+        }
+    }
+}
+
+async fn reminder_monitor(sender: UnboundedSender<ChannelPair<Packet>>, http: Arc<Http>, config: Arc<DiscordConfig>, panics: Arc<PanicGuard>) {
+    let mut interval = tokio::time::interval(REMINDER_CHECK_INTERVAL);
+    let mut last_digest = None;
+    loop {
+        interval.tick().await;
+        if let Some(Err(why)) = isolate(&panics, &http, &config, "pending reminder", remind_pending(&sender, &http, &config, &mut last_digest)).await {
+            log!("Error reminding staff of pending approvals: {why:?}");
+        }
+    }
+}
+
+// Post one digest of the approvals that have waited too long, pinging staff. Each user is listed at most once per
+// reminder interval, which is remembered across restarts, and digests themselves go out at most that often.
+async fn remind_pending(sender: &UnboundedSender<ChannelPair<Packet>>, http: &Arc<Http>, config: &DiscordConfig, last_digest: &mut Option<u64>) -> Result<()> {
+    let now = timestamp();
+    let interval = config.reminder_interval_hours * HOUR_MILLIS;
+    if last_digest.is_some_and(|last| now.saturating_sub(last) < interval) {
+        return Ok(());
+    }
+
+    let mut pair = ChannelPair::new();
+    sender.send(pair.entangle())?;
+    pair.sender.send(Packet::ListPending)?;
+    let Some(Packet::PendingList(mut pending)) = pair.receiver.recv().await else { return Err(anyhow!("Main thread did not respond with pending users!")) };
+    let threshold = config.reminder_after_hours * HOUR_MILLIS;
+    pending.retain(|user| {
+        user.pending_since.is_some_and(|since| now.saturating_sub(since) >= threshold)
+            && user.reminded_at.is_none_or(|reminded| now.saturating_sub(reminded) >= interval)
+    });
+    if pending.is_empty() {
+        return Ok(());
+    }
+    pending.sort_by_key(|user| priority::queue_key(user.priority, user.pending_since));
+
+    let mut description = String::new();
+    for (position, user) in pending.iter().enumerate() {
+        let line = format!(
+            "- {}{}{}{}\n",
+            escape_markdown(&sanitize_display(&user.name)),
+            user.discord_id.map(|discord_id| format!(" <@{discord_id}>")).unwrap_or_default(),
+            user.pending_since.map(|since| format!(" - waiting since <t:{}:R>", since / 1000)).unwrap_or_default(),
+            user.verify_message.map(|message_id| format!(" - [request](https://discord.com/channels/{}/{}/{message_id})", config.guild_id, config.member_channel_id)).unwrap_or_default(),
+        );
+        if position == PENDING_LIST_SIZE || description.len() + line.len() > 4000 {
+            description.push_str(&format!("...and {} more", pending.len() - position));
+            break;
+        }
+        description.push_str(&line);
+    }
+
+    log!("Reminding staff of {} pending approvals", pending.len());
+    ChannelId::new(config.member_channel_id).send_message(http, CreateMessage::new()
+        .content(role_mentions(&config.staff_roles()))
+        .embed(CreateEmbed::new()
+            .title(format!("{} approvals waiting over {} hours", pending.len(), config.reminder_after_hours))
+            .description(description)
+            .color(config.colors.secondary))
+    ).await?;
+    *last_digest = Some(now);
+
+    let mut pair = ChannelPair::new();
+    sender.send(pair.entangle())?;
+    pair.sender.send(Packet::Reminded(pending.into_iter().map(|user| user.uuid).collect()))?;
+    Ok(())
+}
+
+async fn reconcile_monitor(sender: UnboundedSender<ChannelPair<Packet>>, http: Arc<Http>, config: Arc<DiscordConfig>, panics: Arc<PanicGuard>, role_removals: Arc<RoleRemovals>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.reconcile_interval_mins * 60));
+    loop {
+        interval.tick().await;
+        if let Some(Err(why)) = isolate(&panics, &http, &config, "reconciliation", reconcile(&sender, &http, &config, &role_removals)).await {
+            log!("Error reconciling users with the guild: {why:?}");
+        }
+    }
+}
+
+// Fix what the member events missed, such as users leaving while the bot was offline or roles edited by hand.
+// Approved users in the guild get back a missing verified role, and linked users who left are unlinked. Dry runs
+// only report what they would fix.
+async fn reconcile(sender: &UnboundedSender<ChannelPair<Packet>>, http: &Arc<Http>, config: &DiscordConfig, role_removals: &RoleRemovals) -> Result<()> {
+    let guild_id = GuildId::new(config.guild_id);
+    let members: HashMap<_, _> = guild_members(http, guild_id).await?.into_iter().map(|member| (member.user.id.get(), member.roles)).collect();
+
+    let mut pair = ChannelPair::new();
+    sender.send(pair.entangle())?;
+    pair.sender.send(Packet::ListAll)?;
+    let Some(Packet::AllUsers(users)) = pair.receiver.recv().await else { return Err(anyhow!("Main thread did not respond with all users!")) };
+
+    let verified_role = RoleId::new(config.verified_role_id);
+    // Links made for a linked guild don't depend on membership of the main one.
+    for user in users.into_iter().filter(|user| user.guild_id.is_none()) {
+        let Some(discord_id) = user.discord_id else { continue };
+        // Banned users are kept whether or not they are still around.
+        let fix = match members.get(&discord_id) {
+            None if user.verify_state != VerifyState::BANNED => "Unlinked, as they are no longer in the guild",
+            Some(roles) if user.verify_state == VerifyState::APPROVED && !roles.contains(&verified_role) => "Granted the missing verified role",
+            _ => continue,
+        };
+
+        log!("Reconciliation{} for {} [{}]: {fix}", if config.reconcile_dry_run { " (dry run)" } else { "" }, user.name, user.uuid);
+        post_audit(http, config, &AuditEntry::new(AuditAction::Corrected { fix: fix.to_owned(), dry_run: config.reconcile_dry_run }, &user.name, &user.uuid, Some(discord_id), Actor::Bot)).await?;
+        if config.reconcile_dry_run {
+            continue;
+        }
+        let user_id = UserId::new(discord_id);
+        if members.contains_key(&discord_id) {
+            http.add_member_role(guild_id, user_id, verified_role, None).await?;
+        } else {
+            unlink_user(sender, http, config, role_removals, user_id, Actor::LeftGuild).await?;
+        }
+    }
+    Ok(())
+}
+
+// Every member of the guild, fetched a page at a time.
+async fn guild_members(http: &Arc<Http>, guild_id: GuildId) -> Result<Vec<Member>> {
+    let mut members = vec![];
+    let mut after = None;
+    loop {
+        let page = guild_id.members(http, Some(MEMBER_PAGE_SIZE), after).await?;
+        let last_page = (page.len() as u64) < MEMBER_PAGE_SIZE;
+        after = page.last().map(|member| member.user.id);
+        members.extend(page);
+        if last_page {
+            break;
+        }
+    }
+    // The bot itself is a member, so an empty list means the fetch went wrong rather than that everyone left.
+    if members.is_empty() {
+        return Err(anyhow!("Discord returned no guild members!"));
+    }
+    Ok(members)
+}
+
+async fn role_sweep_monitor(sender: UnboundedSender<ChannelPair<Packet>>, http: Arc<Http>, config: Arc<DiscordConfig>, messages: Arc<Messages>, panics: Arc<PanicGuard>, role_removals: Arc<RoleRemovals>) {
+    // The first tick is immediate, so members are swept on startup too.
+    let mut interval = tokio::time::interval(Duration::from_secs(config.role_sweep_interval_mins * 60));
+    loop {
+        interval.tick().await;
+        if let Some(Err(why)) = isolate(&panics, &http, &config, "role sweep", sweep_roles(&sender, &http, &config, &messages, &role_removals)).await {
+            log!("Error sweeping verified roles: {why:?}");
+        }
+    }
+}
+
+// Take the verified role off members with no approved account, such as those whose role removal lost a race with
+// their unlinking, and tell them how to verify again.
+async fn sweep_roles(sender: &UnboundedSender<ChannelPair<Packet>>, http: &Arc<Http>, config: &DiscordConfig, messages: &Messages, role_removals: &RoleRemovals) -> Result<()> {
+    let guild_id = GuildId::new(config.guild_id);
+    let verified_role = RoleId::new(config.verified_role_id);
+    for member in guild_members(http, guild_id).await? {
+        if member.user.bot || !member.roles.contains(&verified_role) {
+            continue;
+        }
+        let mut pair = ChannelPair::new();
+        sender.send(pair.entangle())?;
+        pair.sender.send(Packet::ApprovedQuery(member.user.id.get()))?;
+        let Some(Packet::ApprovedResponse(approved)) = pair.receiver.recv().await else { return Err(anyhow!("Main thread did not respond to approval query!")) };
+        if approved {
+            continue;
+        }
+
+        log!("Removing the verified role from {} ({}), who has no approved account", member.user.name, member.user.id);
+        role_removals.expect(member.user.id);
+        http.remove_member_role(guild_id, member.user.id, verified_role, Some("No approved Minecraft account is linked")).await?;
+        let embed = CreateEmbed::new()
+            .title(messages.get("title"))
+            .description(messages.render("role.reverify", &[("discord_id", &member.user.id.to_string()), ("channel_id", &config.verification_channel_id.to_string())]))
+            .color(config.colors.error);
+        if let Err(why) = member.user.id.direct_message(http, CreateMessage::new().embed(embed)).await {
+            log!("Error telling user {} to verify again: {why:?}", member.user.id);
+        }
+    }
+    Ok(())
+}
+
+// Alert once when a pending user passes the SLO target, and again at twice the target.
+async fn check_slo(sender: &UnboundedSender<ChannelPair<Packet>>, http: &Arc<Http>, config: &DiscordConfig) -> Result<()> {
+    let mut pair = ChannelPair::new();
+    sender.send(pair.entangle())?;
+    pair.sender.send(Packet::ListPending)?;
+    let Some(Packet::PendingList(pending)) = pair.receiver.recv().await else { return Err(anyhow!("Main thread did not respond with pending users!")) };
+
+    let target = config.approval_slo_hours * HOUR_MILLIS;
+    let now = timestamp();
+    for user in pending {
+        let Some(pending_since) = user.pending_since else { continue };
+        let waited = now.saturating_sub(pending_since);
+        let level = match waited {
+            x if x >= target * 2 => 2,
+            x if x >= target => 1,
+            _ => 0,
+        };
+        if level <= user.slo_alerts {
+            continue;
+        }
+
+        log!("User {} [{}] has been pending for {} hours, breaching the approval SLO", user.name, user.uuid, waited / HOUR_MILLIS);
+        let mut description = format!("{} [{}] has been waiting for approval for {} hours.", sanitize_display(&user.name), user.uuid, waited / HOUR_MILLIS);
+        if user.priority != Priority::Normal {
+            description.push_str(&format!("\nPriority: {}", user.priority.label()));
+        }
+        if let Some(discord_id) = user.discord_id {
+            description.push_str(&format!("\nDiscord User: <@{discord_id}>"));
+        }
+        if let Some(message_id) = user.verify_message {
+            description.push_str(&format!("\nhttps://discord.com/channels/{}/{}/{message_id}", config.guild_id, config.member_channel_id));
+        }
+
+        let mut message = CreateMessage::new().embed(
+            CreateEmbed::new()
+                .title(if level == 1 { "Approval SLO breached" } else { "Approval SLO breached twice over" })
+                .description(description)
+                .color(config.colors.error)
+        );
+        if config.slo_ping_staff {
+            message = message.content(role_mentions(&config.staff_roles()));
+        }
+        post_log(http, config, message).await?;
+
+        let mut pair = ChannelPair::new();
+        sender.send(pair.entangle())?;
+        pair.sender.send(Packet::SloAlerted(user.uuid, level))?;
+    }
+    Ok(())
+}
+
+// Panels are told apart from the bot's other messages by their title.
+fn panel_title(kind: PanelKind, messages: &Messages) -> String {
+    match kind {
+        PanelKind::Verification => messages.get("panel.verification.title"),
+        PanelKind::Ticket => messages.get("panel.ticket.title"),
+    }
+}
+
+fn render_panel(kind: PanelKind, config: &DiscordConfig, messages: &Messages) -> (CreateEmbed, Vec<CreateActionRow>) {
+    match kind {
+        PanelKind::Verification => (
+            CreateEmbed::new()
+                .title(panel_title(kind, messages))
+                .description(messages.get("panel.verification.description"))
+                .color(config.colors.primary),
+            vec![CreateActionRow::Buttons(vec![CreateButton::new(CustomId::NewCode.to_string()).label("I need a new code").style(ButtonStyle::Secondary)])],
+        ),
+
+        PanelKind::Ticket => (
+            CreateEmbed::new()
+                .title(panel_title(kind, messages))
+                .description(messages.get("panel.ticket.description"))
+                .color(config.colors.primary),
+            vec![CreateActionRow::Buttons(vec![CreateButton::new(CustomId::CreateTicket.to_string()).label("Create Ticket")])],
+        ),
+    }
+}
+
+// Look through the channel's recent history for a panel the bot posted. Status messages in the verification channel
+// share the panel's title, but always carry a mention.
+async fn find_panel(http: &Arc<Http>, channel_id: ChannelId, bot_id: UserId, kind: PanelKind, messages: &Messages) -> Result<Option<u64>> {
+    let title = panel_title(kind, messages);
+    let messages = channel_id.messages(http, GetMessages::new().limit(PANEL_SEARCH_DEPTH)).await?;
+    Ok(messages
+        .iter()
+        .find(|message| {
+            message.author.id == bot_id
+                && message.content.is_empty()
+                && message.embeds.first().and_then(|embed| embed.title.as_deref()) == Some(title.as_str())
+        })
+        .map(|message| message.id.get()))
+}
+
+fn panel_hash(embed: &CreateEmbed, buttons: &[CreateActionRow]) -> Result<u64> {
+    let mut hasher = DefaultHasher::new();
+    serde_json::to_string(embed)?.hash(&mut hasher);
+    serde_json::to_string(buttons)?.hash(&mut hasher);
+    Ok(hasher.finish())
+}
+
+fn verify_definition() -> CreateCommand {
+    CreateCommand::new("verify").description("Link your Minecraft account with the code shown when you joined")
+        .add_option(CreateCommandOption::new(CommandOptionType::Integer, "code", "Your 6 digit verification code").required(true).min_int_value(CODE_MIN as u64).max_int_value(CODE_MAX as u64))
+}
+
+fn commands() -> Vec<CreateCommand> {
+    vec![
+        verify_definition(),
+        CreateCommand::new("notifications").description("Choose which direct messages the bot sends you"),
+        CreateCommand::new("stats").description("Show bot statistics")
+            .add_option(CreateCommandOption::new(CommandOptionType::SubCommand, "summary", "Show bot version and approval statistics"))
+            .add_option(CreateCommandOption::new(CommandOptionType::SubCommand, "export-range", "Download the analytics rollups for a range of days")
+                .add_sub_option(CreateCommandOption::new(CommandOptionType::String, "from", "First day, as YYYY-MM-DD").required(true))
+                .add_sub_option(CreateCommandOption::new(CommandOptionType::String, "to", "Last day, as YYYY-MM-DD").required(true)))
+            .add_option(CreateCommandOption::new(CommandOptionType::SubCommand, "staff", "Rank staff by approvals and closed tickets")
+                .add_sub_option(CreateCommandOption::new(CommandOptionType::String, "range", "How far back to look").required(true)
+                    .add_string_choice("7 days", "7d")
+                    .add_string_choice("30 days", "30d")
+                    .add_string_choice("All time", "all"))),
+        CreateCommand::new("pending").description("Show the approval queue in review order"),
+        CreateCommand::new("members").description("List the approved members"),
+        CreateCommand::new("clearlockout").description("Let a user who entered too many invalid codes try again")
+            .add_option(CreateCommandOption::new(CommandOptionType::User, "user", "The user to let back in").required(true)),
+        CreateCommand::new("permissions").description("Audit who the bot lets do what")
+            .add_option(CreateCommandOption::new(CommandOptionType::SubCommand, "report", "Show the permission model the bot enforces")),
+        CreateCommand::new("whitelist").description("Audit the game server whitelist")
+            .add_option(CreateCommandOption::new(CommandOptionType::SubCommand, "diff", "Compare the game server whitelist with approved users")),
+        CreateCommand::new("setup").description("Post a panel in this channel")
+            .add_option(CreateCommandOption::new(CommandOptionType::String, "panel", "Which panel to post").required(true)
+                .add_string_choice("Verification", "verification")
+                .add_string_choice("Ticket", "ticket")),
+        CreateCommand::new("announce").description("Post a message in game server chat")
+            .add_option(CreateCommandOption::new(CommandOptionType::String, "text", "What to announce").required(true).max_length(1000)),
+        CreateCommand::new("lookup").description("Find a linked account")
+            .add_option(CreateCommandOption::new(CommandOptionType::SubCommand, "user", "Look up by discord user")
+                .add_sub_option(CreateCommandOption::new(CommandOptionType::User, "user", "The discord user").required(true)))
+            .add_option(CreateCommandOption::new(CommandOptionType::SubCommand, "mcname", "Look up by Minecraft name")
+                .add_sub_option(CreateCommandOption::new(CommandOptionType::String, "name", "The Minecraft name").required(true))),
+        CreateCommand::new("forget-me").description("Delete everything the bot stores about you"),
+        CreateCommand::new("forget").description("Delete everything the bot stores about a user")
+            .add_option(CreateCommandOption::new(CommandOptionType::User, "user", "The user to forget").required(true)),
+    ]
+}
+
+#[derive(Serialize, Deserialize)]
+struct DiscordConfig {
+    token: String,
+    guild_id: u64,
+    verified_role_id: u64,
+    // The one staff role older configs have, still counted alongside staff_role_ids. Zero for none.
+    staff_role_id: u64,
+    // Every role whose members count as staff, such as separate admin and helper roles.
+    #[serde(default)]
+    staff_role_ids: Vec<u64>,
+    // The staff roles pinged when a ticket is opened. Empty pings all of them.
+    #[serde(default)]
+    ticket_ping_role_ids: Vec<u64>,
+    verification_channel_id: u64,
+    member_channel_id: u64,
+    ticket_channel_id: u64,
+    active_ticket_category_id: u64,
+    archive_ticket_category_id: u64,
+    #[serde(default)]
+    log_channel_id: u64,
+    // Where every code, link, approval, denial and unlink is recorded. Zero turns the audit log off.
+    #[serde(default)]
+    audit_channel_id: u64,
+    #[serde(default)]
+    approval_slo_hours: u64,
+    #[serde(default)]
+    slo_ping_staff: bool,
+    #[serde(default)]
+    presence_channel_id: u64,
+    #[serde(default)]
+    chat_channel_id: u64,
+    #[serde(default)]
+    ticket_survey: bool,
+    #[serde(default)]
+    priority_rules: Vec<PriorityRule>,
+    // Where the staff leaderboard is posted at the start of each month. Zero turns it off.
+    #[serde(default)]
+    staff_report_channel_id: u64,
+    // Where transcripts of closed tickets are posted. Zero turns transcripts off.
+    #[serde(default)]
+    transcript_channel_id: u64,
+    #[serde(default = "default_shutdown_grace_secs")]
+    shutdown_grace_secs: u64,
+    // Unlink members who leave the guild or have the verified role taken off them by hand. Needs the privileged
+    // GUILD_MEMBERS intent.
+    #[serde(default = "default_handle_member_leave")]
+    handle_member_leave: bool,
+    // Ignore channel messages and only work through slash commands and panels, which drops the
+    // privileged MESSAGE_CONTENT intent.
+    #[serde(default)]
+    interaction_only: bool,
+    // Open a thread on each pending member message for staff to discuss the applicant in.
+    #[serde(default)]
+    discussion_threads: bool,
+    // Distinct moderators who must press approve before an account is approved.
+    #[serde(default = "default_required_approvals")]
+    required_approvals: u8,
+    // A handler that panics more than this many times within the window is switched off until restart.
+    #[serde(default = "default_panic_limit")]
+    panic_limit: usize,
+    #[serde(default = "default_panic_window_mins")]
+    panic_window_mins: u64,
+    // Remind staff of approvals that have been pending this long. Zero turns reminders off.
+    #[serde(default)]
+    reminder_after_hours: u64,
+    // The least time between reminder digests, and before a user is listed in one again.
+    #[serde(default = "default_reminder_interval_hours")]
+    reminder_interval_hours: u64,
+    // How often to check the guild's members against the linked users and fix any drift. Zero turns it off. Needs
+    // the privileged GUILD_MEMBERS intent.
+    #[serde(default)]
+    reconcile_interval_mins: u64,
+    // Only report what reconciliation would fix, to the log and the audit channel.
+    #[serde(default = "default_reconcile_dry_run")]
+    reconcile_dry_run: bool,
+    // How often to take the verified role off members with no approved account, starting at startup. Zero turns it
+    // off. Needs the privileged GUILD_MEMBERS intent.
+    #[serde(default = "default_role_sweep_interval_mins")]
+    role_sweep_interval_mins: u64,
+    // Set approved members' discord nicknames to their Minecraft names.
+    #[serde(default)]
+    sync_nicknames: bool,
+    // Where newly approved members are welcomed. Zero turns welcomes off.
+    #[serde(default)]
+    welcome_channel_id: u64,
+    // The welcome text, with {mention} standing in for the member and {name} for their Minecraft name.
+    #[serde(default = "default_welcome_template")]
+    welcome_template: String,
+    // How many Minecraft accounts each discord user may link, so members can whitelist an alt.
+    #[serde(default = "default_max_accounts_per_discord")]
+    max_accounts_per_discord: u8,
+    // The bot's status while no game server is connected.
+    #[serde(default = "default_idle_activity")]
+    idle_activity: String,
+    // Embed colours as hex strings such as "#30F4B0", so the bot can match a server's branding. Leaving one out
+    // keeps the built-in colour.
+    #[serde(default)]
+    primary_color: Option<String>,
+    #[serde(default)]
+    secondary_color: Option<String>,
+    #[serde(default)]
+    error_color: Option<String>,
+    // Further guilds served alongside the main one. Whether a link made in one reaches the others is up to link_scope
+    // in ./link_scope_config.toml. Linking members needs the privileged GUILD_MEMBERS intent.
+    #[serde(default)]
+    linked_guilds: Vec<LinkedGuild>,
+    // The colours above, parsed when the config is opened.
+    #[serde(skip)]
+    colors: Colors,
+}
+
+/// A guild served alongside the main one. Its members enter codes in its own verification channel, staff review its
+/// links in the main guild's member channel, and its own member channel only lists the links it has.
+#[derive(Serialize, Deserialize)]
+struct LinkedGuild {
+    guild_id: u64,
+    verified_role_id: u64,
+    verification_channel_id: u64,
+    member_channel_id: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Colors {
+    primary: u32,
+    secondary: u32,
+    error: u32,
+}
+
+impl Default for Colors {
+    fn default() -> Self {
+        Self { primary: PRIMARY_COLOR, secondary: SECONDARY_COLOR, error: ERROR_COLOR }
+    }
+}
+
+fn default_max_accounts_per_discord() -> u8 {
+    1
+}
+
+fn default_idle_activity() -> String {
+    "Waiting for the server".to_owned()
+}
+
+fn default_welcome_template() -> String {
+    "Welcome {mention} to the SMP as {name}!".to_owned()
+}
+
+fn default_reminder_interval_hours() -> u64 {
+    24
+}
+
+fn default_reconcile_dry_run() -> bool {
+    true
+}
+
+fn default_role_sweep_interval_mins() -> u64 {
+    60
+}
+
+fn default_required_approvals() -> u8 {
+    1
+}
+
+fn default_panic_limit() -> usize {
+    5
+}
+
+fn default_panic_window_mins() -> u64 {
+    10
+}
+
+fn default_handle_member_leave() -> bool {
+    true
+}
+
+fn default_shutdown_grace_secs() -> u64 {
+    10
+}
 
 impl DiscordConfig {
+    fn staff_roles(&self) -> Vec<RoleId> {
+        let mut roles = self.staff_role_ids.iter().chain([&self.staff_role_id]).filter(|id| **id != 0).map(|id| RoleId::new(*id)).collect::<Vec<_>>();
+        roles.sort_unstable();
+        roles.dedup();
+        roles
+    }
+
+    fn ticket_ping_roles(&self) -> Vec<RoleId> {
+        match self.ticket_ping_role_ids.is_empty() {
+            true => self.staff_roles(),
+            false => self.ticket_ping_role_ids.iter().map(|id| RoleId::new(*id)).collect(),
+        }
+    }
+
+    fn linked_guild(&self, guild_id: u64) -> Option<&LinkedGuild> {
+        self.linked_guilds.iter().find(|guild| guild.guild_id == guild_id)
+    }
+
+    // The member channel of the main guild (None) or of a linked guild.
+    fn member_channel(&self, guild_id: Option<u64>) -> Option<ChannelId> {
+        match guild_id {
+            None => Some(ChannelId::new(self.member_channel_id)),
+            Some(guild_id) => self.linked_guild(guild_id).map(|guild| ChannelId::new(guild.member_channel_id)),
+        }
+    }
+
+    // The verified role of the main guild (None) or of a linked guild, with the guild it's in.
+    fn verified_role(&self, guild_id: Option<u64>) -> Option<(GuildId, RoleId)> {
+        match guild_id {
+            None => Some((GuildId::new(self.guild_id), RoleId::new(self.verified_role_id))),
+            Some(guild_id) => self.linked_guild(guild_id).map(|guild| (GuildId::new(guild.guild_id), RoleId::new(guild.verified_role_id))),
+        }
+    }
+
     fn default() -> Self {
         Self {
             token: String::new(),
             guild_id: 0,
             verified_role_id: 0,
             staff_role_id: 0,
+            staff_role_ids: Vec::new(),
+            ticket_ping_role_ids: Vec::new(),
             verification_channel_id: 0,
             member_channel_id: 0,
             ticket_channel_id: 0,
             active_ticket_category_id: 0,
             archive_ticket_category_id: 0,
+            log_channel_id: 0,
+            audit_channel_id: 0,
+            approval_slo_hours: 0,
+            slo_ping_staff: false,
+            presence_channel_id: 0,
+            chat_channel_id: 0,
+            ticket_survey: false,
+            priority_rules: Vec::new(),
+            staff_report_channel_id: 0,
+            transcript_channel_id: 0,
+            shutdown_grace_secs: default_shutdown_grace_secs(),
+            handle_member_leave: default_handle_member_leave(),
+            interaction_only: false,
+            discussion_threads: false,
+            required_approvals: default_required_approvals(),
+            panic_limit: default_panic_limit(),
+            panic_window_mins: default_panic_window_mins(),
+            reminder_after_hours: 0,
+            reminder_interval_hours: default_reminder_interval_hours(),
+            reconcile_interval_mins: 0,
+            reconcile_dry_run: default_reconcile_dry_run(),
+            role_sweep_interval_mins: default_role_sweep_interval_mins(),
+            sync_nicknames: false,
+            welcome_channel_id: 0,
+            welcome_template: default_welcome_template(),
+            max_accounts_per_discord: default_max_accounts_per_discord(),
+            idle_activity: default_idle_activity(),
+            primary_color: None,
+            secondary_color: None,
+            error_color: None,
+            linked_guilds: Vec::new(),
+            colors: Colors::default(),
         }
     }
 }
 
+impl config::Documented for DiscordConfig {
+    const COMMENTS: &'static [(&'static str, &'static str)] = &[
+        ("token", "The bot token from the discord developer portal."),
+        ("guild_id", "The guild the bot verifies members of."),
+        ("verified_role_id", "The role given to members once their Minecraft account is approved."),
+        ("staff_role_id", "The one staff role of older configs, still counted alongside staff_role_ids. Zero for none."),
+        ("staff_role_ids", "Every role whose members count as staff, such as separate admin and helper roles."),
+        ("ticket_ping_role_ids", "The staff roles pinged when a ticket is opened. Empty pings all of them."),
+        ("verification_channel_id", "Where members enter their verification codes."),
+        ("member_channel_id", "Where staff are shown each linked account to approve or deny."),
+        ("ticket_channel_id", "Where the ticket panel is posted."),
+        ("active_ticket_category_id", "The category open tickets are created in."),
+        ("archive_ticket_category_id", "The category closed tickets are moved to."),
+        ("log_channel_id", "Where warnings for staff, such as breached approval targets, are posted. Zero turns them off."),
+        ("audit_channel_id", "Where every code, link, approval, denial and unlink is recorded. Zero turns the audit log off."),
+        ("approval_slo_hours", "How soon linked accounts should be approved or denied, with breaches posted to the log channel. Zero turns it off."),
+        ("slo_ping_staff", "Ping the staff roles when an approval target is breached."),
+        ("presence_channel_id", "Where approved members joining and leaving the game server are posted. Zero turns it off."),
+        ("chat_channel_id", "Where game chat is relayed. Zero turns the relay off."),
+        ("ticket_survey", "Ask members to rate the help they got when their ticket is closed."),
+        ("priority_rules", "Rules raising the review priority of pending users, each a condition such as { role = 123 } or \"previously_approved\" with a level of \"elevated\" or \"high\"."),
+        ("staff_report_channel_id", "Where the staff leaderboard is posted at the start of each month. Zero turns it off."),
+        ("transcript_channel_id", "Where transcripts of closed tickets are posted. Zero turns transcripts off."),
+        ("shutdown_grace_secs", "How long to let work in progress finish when the bot is stopped."),
+        ("handle_member_leave", "Unlink members who leave the guild or have the verified role taken off them by hand. Needs the privileged GUILD_MEMBERS intent."),
+        ("interaction_only", "Ignore channel messages and only work through slash commands and panels, which drops the privileged MESSAGE_CONTENT intent."),
+        ("discussion_threads", "Open a thread on each pending member message for staff to discuss the applicant in."),
+        ("required_approvals", "Distinct moderators who must press approve before an account is approved."),
+        ("panic_limit", "A handler that panics more than this many times within panic_window_mins is switched off until restart."),
+        ("panic_window_mins", "How far back panics are counted towards panic_limit."),
+        ("reminder_after_hours", "Remind staff of approvals that have been pending this long. Zero turns reminders off."),
+        ("reminder_interval_hours", "The least time between reminder digests, and before a user is listed in one again."),
+        ("reconcile_interval_mins", "How often to check the guild's members against the linked users and fix any drift. Zero turns it off. Needs the privileged GUILD_MEMBERS intent."),
+        ("reconcile_dry_run", "Only report what reconciliation would fix, to the log and the audit channel."),
+        ("role_sweep_interval_mins", "How often to take the verified role off members with no approved account, starting at startup. Zero turns it off. Needs the privileged GUILD_MEMBERS intent."),
+        ("sync_nicknames", "Set approved members' discord nicknames to their Minecraft names."),
+        ("welcome_channel_id", "Where newly approved members are welcomed. Zero turns welcomes off."),
+        ("welcome_template", "The welcome text, with {mention} standing in for the member and {name} for their Minecraft name."),
+        ("max_accounts_per_discord", "How many Minecraft accounts each discord user may link, so members can whitelist an alt."),
+        ("idle_activity", "The bot's status while no game server is connected."),
+        ("primary_color", "The main embed colour as a hex string such as \"#30F4B0\". Leave it out to keep the built-in colour."),
+        ("secondary_color", "The embed colour for listings and notices, as a hex string. Leave it out to keep the built-in colour."),
+        ("error_color", "The embed colour for errors and denials, as a hex string. Leave it out to keep the built-in colour."),
+        ("linked_guilds", "Further guilds served alongside the main one, each { guild_id = 123, verified_role_id = 456, verification_channel_id = 789, member_channel_id = 1011 }. Whether a link made in one reaches the others is set by link_scope in ./link_scope_config.toml. Linking members needs the privileged GUILD_MEMBERS intent."),
+    ];
+}
+
 fn open_config() -> Result<DiscordConfig> {
-    if let Ok(file) = File::open(DISCORD_CONFIG_PATH) {
-        if let Ok(config) = serde_json::from_reader(file) {
-            return Ok(config);
+    let mut config = config::open_config(DISCORD_CONFIG_PATH, DiscordConfig::default)?;
+    config.colors = Colors {
+        primary: parse_color("primary_color", config.primary_color.as_deref(), PRIMARY_COLOR)?,
+        secondary: parse_color("secondary_color", config.secondary_color.as_deref(), SECONDARY_COLOR)?,
+        error: parse_color("error_color", config.error_color.as_deref(), ERROR_COLOR)?,
+    };
+    // Zero ids would panic when turned into discord ids.
+    if let Some(guild) = config.linked_guilds.iter().find(|guild| guild.guild_id == 0 || guild.verified_role_id == 0 || guild.verification_channel_id == 0 || guild.member_channel_id == 0) {
+        return Err(anyhow!("The linked guild {} in {DISCORD_CONFIG_PATH} needs a guild_id, verified_role_id, verification_channel_id and member_channel_id", guild.guild_id));
+    }
+    Ok(config)
+}
+
+fn parse_color(key: &str, value: Option<&str>, default: u32) -> Result<u32> {
+    let Some(value) = value else { return Ok(default) };
+    let digits = value.strip_prefix('#').unwrap_or(value);
+    match digits.len() == 6 && digits.chars().all(|digit| digit.is_ascii_hexdigit()) {
+        true => Ok(u32::from_str_radix(digits, 16)?),
+        false => Err(anyhow!("Invalid {key} {value:?} in {DISCORD_CONFIG_PATH}, expected a hex colour such as \"#30F4B0\"")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    async fn http_error(status: u16, code: isize) -> serenity::Error {
+        let body = json!({ "code": code, "message": "error" }).to_string();
+        let response = serenity_reqwest::Response::from(http::Response::builder().status(status).body(body).unwrap());
+        let response = serenity::http::ErrorResponse::from_response(response, serenity_reqwest::Method::POST).await;
+        serenity::Error::Http(HttpError::UnsuccessfulRequest(response))
+    }
+
+    // A request the bot made to the mock discord: method, path and json body.
+    type Request = (String, String, Value);
+
+    // A stand-in for discord's API that records every request, answering thread and channel requests with a channel
+    // and everything else with a message.
+    async fn mock_discord() -> (Arc<Http>, Arc<Mutex<Vec<Request>>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve_mock(stream, recorded.clone()));
+            }
+        });
+        let http = serenity::http::HttpBuilder::new("token").proxy(format!("http://{address}")).ratelimiter_disabled(true).build();
+        (Arc::new(http), requests)
+    }
+
+    async fn serve_mock(stream: tokio::net::TcpStream, requests: Arc<Mutex<Vec<Request>>>) {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+        let mut stream = BufReader::new(stream);
+        loop {
+            let mut request_line = String::new();
+            if stream.read_line(&mut request_line).await.unwrap_or(0) == 0 {
+                return;
+            }
+            let mut parts = request_line.split_whitespace();
+            let (method, path) = (parts.next().unwrap().to_owned(), parts.next().unwrap().to_owned());
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                stream.read_line(&mut header).await.unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':') && name.eq_ignore_ascii_case("content-length") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            stream.read_exact(&mut body).await.unwrap();
+
+            let id = path.trim_end_matches("/threads").rsplit('/').find(|segment| segment.parse::<u64>().is_ok()).unwrap_or("1").to_owned();
+            let reply = if path.ends_with("/threads") || method == "PATCH" {
+                json!({ "id": id, "type": 11, "guild_id": "1", "name": "thread" })
+            } else {
+                json!({
+                    "id": "99", "channel_id": id, "content": "", "timestamp": "2025-01-01T00:00:00+00:00", "edited_timestamp": null,
+                    "author": { "id": "1", "username": "bot", "discriminator": "0000", "avatar": null },
+                    "tts": false, "mention_everyone": false, "mentions": [], "mention_roles": [], "attachments": [], "embeds": [], "pinned": false, "type": 0
+                })
+            }.to_string();
+            requests.lock().unwrap().push((method, path, serde_json::from_slice(&body).unwrap_or(Value::Null)));
+            let response = format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{reply}", reply.len());
+            stream.get_mut().write_all(response.as_bytes()).await.unwrap();
         }
     }
 
-    let _ = std::fs::remove_file(DISCORD_CONFIG_PATH);
-    let mut file = File::create_new(DISCORD_CONFIG_PATH)?;
-    let config = DiscordConfig::default();
-    serde_json::to_writer_pretty(&mut file, &config)?;
-    Ok(config)
-}
\ No newline at end of file
+    fn discussion_config(discussion_threads: bool) -> DiscordConfig {
+        DiscordConfig { discussion_threads, member_channel_id: 10, log_channel_id: 5, ..DiscordConfig::default() }
+    }
+
+    #[tokio::test]
+    async fn discussion_threads_are_opened_then_archived() {
+        let (http, requests) = mock_discord().await;
+        let config = discussion_config(true);
+
+        open_discussion(&http, &config, ChannelId::new(10), MessageId::new(20), "Notch", 30).await.unwrap();
+        {
+            let requests = requests.lock().unwrap();
+            let [(create_method, create_path, create), (log_method, log_path, log)] = requests.as_slice() else { panic!("{requests:?}") };
+            assert_eq!((create_method.as_str(), create_path.as_str()), ("POST", "/api/v10/channels/10/messages/20/threads"));
+            assert_eq!(create["name"], "Notch");
+            assert_eq!(create["auto_archive_duration"], 10080);
+            assert_eq!((log_method.as_str(), log_path.as_str()), ("POST", "/api/v10/channels/5/messages"));
+            assert_eq!(log["embeds"][0]["description"], "<#20> for Notch (<@30>)");
+        }
+
+        requests.lock().unwrap().clear();
+        close_discussion(&http, &config, 20, "Approved by a moderator.").await.unwrap();
+        let requests = requests.lock().unwrap();
+        let [(post_method, post_path, post), (archive_method, archive_path, archive)] = requests.as_slice() else { panic!("{requests:?}") };
+        assert_eq!((post_method.as_str(), post_path.as_str()), ("POST", "/api/v10/channels/20/messages"));
+        assert_eq!(post["content"], "Approved by a moderator.");
+        // Threads are archived rather than deleted, so the discussion can still be read.
+        assert_eq!((archive_method.as_str(), archive_path.as_str()), ("PATCH", "/api/v10/channels/20"));
+        assert_eq!(archive["archived"], true);
+    }
+
+    #[tokio::test]
+    async fn nothing_is_closed_with_discussion_threads_off() {
+        let (http, requests) = mock_discord().await;
+        close_discussion(&http, &discussion_config(false), 20, "Unlinked.").await.unwrap();
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    fn field_names(embed: CreateEmbed) -> Vec<String> {
+        let embed = serde_json::to_value(embed).unwrap();
+        embed["fields"].as_array().map(|fields| fields.iter().map(|field| field["name"].as_str().unwrap().to_owned()).collect()).unwrap_or_default()
+    }
+
+    #[test]
+    fn gateway_profile_follows_the_enabled_features() {
+        let privileged = GatewayIntents::GUILD_MEMBERS | GatewayIntents::MESSAGE_CONTENT;
+        let messages = GatewayIntents::GUILD_MESSAGES | GatewayIntents::DIRECT_MESSAGES | GatewayIntents::MESSAGE_CONTENT;
+        let cases = [
+            (true, false, GatewayIntents::GUILD_MEMBERS | messages),
+            (false, false, messages),
+            (true, true, GatewayIntents::GUILD_MEMBERS),
+            (false, true, GatewayIntents::empty()),
+        ];
+        for (handle_member_leave, interaction_only, expected) in cases {
+            let config = DiscordConfig { handle_member_leave, interaction_only, ..DiscordConfig::default() };
+            let (intents, cache_settings) = gateway_profile(&config);
+            assert_eq!(intents, expected, "{handle_member_leave} {interaction_only}");
+            assert_eq!(cache_settings.max_messages, 0);
+            assert_eq!(cache_settings.cache_users, handle_member_leave);
+            assert_eq!((intents & privileged).is_empty(), !handle_member_leave && interaction_only);
+        }
+
+        // Members joining a linked guild are only seen with GUILD_MEMBERS.
+        let config = DiscordConfig { handle_member_leave: false, interaction_only: true, linked_guilds: vec![linked_guild()], ..DiscordConfig::default() };
+        assert_eq!(gateway_profile(&config).0, GatewayIntents::GUILD_MEMBERS);
+    }
+
+    fn linked_guild() -> LinkedGuild {
+        LinkedGuild { guild_id: 7, verified_role_id: 8, verification_channel_id: 11, member_channel_id: 9 }
+    }
+
+    #[tokio::test]
+    async fn inheriting_grants_the_linked_role_and_posts_there() {
+        let (http, requests) = mock_discord().await;
+        let config = DiscordConfig { guild_id: 1, verified_role_id: 2, member_channel_id: 10, linked_guilds: vec![linked_guild()], ..DiscordConfig::default() };
+        let (sender, mut receiver) = unbounded_channel::<ChannelPair<Packet>>();
+        let main = tokio::spawn(async move {
+            let mut pair = receiver.recv().await.unwrap();
+            let Some(Packet::InheritLink(30, 7)) = pair.receiver.recv().await else { panic!("Expected an inherit request") };
+            pair.sender.send(Packet::Inheritable(Some(("Notch".to_owned(), "notch-uuid".to_owned())))).unwrap();
+            let Some(Packet::InheritedMessage(message_id)) = pair.receiver.recv().await else { panic!("Expected the inherited message") };
+            message_id
+        });
+
+        let name = inherit_link(&sender, &http, &config, &Messages::built_in(), UserId::new(30), &config.linked_guilds[0]).await.unwrap();
+        assert_eq!(name.as_deref(), Some("Notch"));
+        assert_eq!(main.await.unwrap(), 99);
+        let requests = requests.lock().unwrap();
+        let [(role_method, role_path, _), (post_method, post_path, post)] = requests.as_slice() else { panic!("{requests:?}") };
+        assert_eq!((role_method.as_str(), role_path.as_str()), ("PUT", "/api/v10/guilds/7/members/30/roles/8"));
+        assert_eq!((post_method.as_str(), post_path.as_str()), ("POST", "/api/v10/channels/9/messages"));
+        assert_eq!(post["embeds"][0]["fields"][0]["value"], "Notch");
+    }
+
+    #[tokio::test]
+    async fn nothing_is_inherited_without_an_approved_global_link() {
+        let (http, requests) = mock_discord().await;
+        let config = DiscordConfig { linked_guilds: vec![linked_guild()], ..DiscordConfig::default() };
+        let (sender, mut receiver) = unbounded_channel::<ChannelPair<Packet>>();
+        tokio::spawn(async move {
+            let mut pair = receiver.recv().await.unwrap();
+            pair.receiver.recv().await.unwrap();
+            pair.sender.send(Packet::Inheritable(None)).unwrap();
+        });
+
+        assert_eq!(inherit_link(&sender, &http, &config, &Messages::built_in(), UserId::new(30), &config.linked_guilds[0]).await.unwrap(), None);
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_the_cannot_dm_error_means_blocked() {
+        assert!(cannot_dm(&http_error(403, CANNOT_DM_CODE).await));
+        assert!(!cannot_dm(&http_error(403, 50013).await));
+        assert!(!cannot_dm(&http_error(500, 0).await));
+    }
+
+    #[test]
+    fn dm_indicator_is_added_once_and_removed() {
+        let embed: Embed = serde_json::from_value(json!({ "fields": [{ "name": "Status", "value": "Approved", "inline": false }] })).unwrap();
+        let flagged = with_dm_indicator(embed.clone(), true);
+        assert_eq!(field_names(flagged), ["Status", DM_BLOCKED_FIELD]);
+
+        let mut reflagged = embed.clone();
+        reflagged.fields.push(EmbedField::new(DM_BLOCKED_FIELD, "", false));
+        assert_eq!(field_names(with_dm_indicator(reflagged.clone(), true)), ["Status", DM_BLOCKED_FIELD]);
+        assert_eq!(field_names(with_dm_indicator(reflagged, false)), ["Status"]);
+    }
+
+    #[test]
+    fn every_setting_is_documented() {
+        assert_eq!(config::undocumented(&DiscordConfig::default()), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,184 @@
+use crate::checks::CheckMode;
+use crate::external_cache::ExternalCache;
+use crate::sanitize::sanitize_display;
+use crate::{config, log};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::Duration;
+
+const ENRICHMENT_CONFIG_PATH: &str = "./enrichment_config.toml";
+
+/// Looks pending accounts up in external services and warns moderators about anything suspicious on the approval
+/// embed. Like the verification checks it can run in shadow mode, where warnings are only logged, so a new
+/// service can be trialed before moderators see it. Nothing here ever blocks an approval.
+pub(crate) struct Enrichment {
+    config: EnrichmentConfig,
+    client: reqwest::Client,
+    cache: Arc<ExternalCache>,
+}
+
+impl Enrichment {
+    pub(crate) fn load(cache: Arc<ExternalCache>) -> Result<Self> {
+        let config = open_config()?;
+        if config.mode != CheckMode::Off {
+            log!("Account enrichment is running in {:?} mode", config.mode);
+        }
+
+        Ok(Self {
+            client: reqwest::Client::builder().timeout(Duration::from_secs(config.timeout_secs)).build()?,
+            config,
+            cache,
+        })
+    }
+
+    /// Warning fields for the approval embed. Services that fail or time out are reported as unavailable.
+    pub(crate) async fn warnings(&self, name: &str, uuid: &str) -> Vec<(String, String)> {
+        if self.config.mode == CheckMode::Off {
+            return vec![];
+        }
+
+        let mut warnings = vec![];
+        if !self.config.ban_list.url.is_empty() {
+            match self.lookup("ban_list", &self.config.ban_list, uuid).await {
+                Ok(hits) if is_hit(&hits) => warnings.push(("Ban list warning".to_owned(), describe(&hits))),
+                Ok(_) => {}
+                Err(why) => {
+                    log!("Ban list lookup for {uuid} failed: {why:?}");
+                    warnings.push(("Ban list warning".to_owned(), "Check unavailable".to_owned()));
+                }
+            }
+        }
+
+        if !self.config.account_created.url.is_empty() {
+            match self.lookup("account_created", &self.config.account_created, uuid).await.and_then(|created| parse_time(&created)) {
+                Ok(created) => {
+                    let age = Utc::now().signed_duration_since(created);
+                    if age.num_days() < self.config.new_account_days as i64 {
+                        warnings.push(("New account warning".to_owned(), format!("Created <t:{}:R>", created.timestamp())));
+                    }
+                }
+                Err(why) => {
+                    log!("Account age lookup for {uuid} failed: {why:?}");
+                    warnings.push(("Account age warning".to_owned(), "Check unavailable".to_owned()));
+                }
+            }
+        }
+
+        if self.config.mode == CheckMode::Shadow {
+            for (field, value) in warnings.drain(..) {
+                log!("[shadow] Enrichment would warn about {name} [{uuid}]: {field}: {value}");
+            }
+        }
+        warnings
+    }
+
+    async fn lookup(&self, service: &'static str, lookup: &LookupConfig, uuid: &str) -> Result<Value> {
+        self.cache.get(service, uuid, Duration::from_secs(self.config.cache_ttl_secs), || async {
+            let mut request = self.client.get(lookup.url.replace("{uuid}", uuid));
+            if !lookup.token.is_empty() {
+                request = request.bearer_auth(&lookup.token);
+            }
+            let response: Value = serde_json::from_str(&request.send().await?.error_for_status()?.text().await?)?;
+            Ok(response.pointer(&lookup.path).cloned().unwrap_or(Value::Null))
+        }).await
+    }
+}
+
+// Anything but null, false, zero or an empty string, list or object counts as a hit.
+fn is_hit(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(hit) => *hit,
+        Value::Number(count) => count.as_f64().is_some_and(|count| count != 0.0),
+        Value::String(text) => !text.is_empty(),
+        Value::Array(hits) => !hits.is_empty(),
+        Value::Object(hits) => !hits.is_empty(),
+    }
+}
+
+fn describe(value: &Value) -> String {
+    match value {
+        Value::Array(hits) => format!("{} entries reported", hits.len()),
+        Value::String(text) => sanitize_display(&text.chars().take(200).collect::<String>()),
+        Value::Number(count) => format!("{count} reported"),
+        _ => "Reported".to_owned(),
+    }
+}
+
+// Creation times may be unix seconds or an RFC 3339 timestamp.
+fn parse_time(value: &Value) -> Result<DateTime<Utc>> {
+    match value {
+        Value::Number(seconds) => seconds.as_i64().and_then(|seconds| DateTime::from_timestamp(seconds, 0)).ok_or(anyhow!("Invalid timestamp {seconds}!")),
+        Value::String(time) => Ok(DateTime::parse_from_rfc3339(time)?.to_utc()),
+        _ => Err(anyhow!("No creation time in the response!")),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct EnrichmentConfig {
+    mode: CheckMode,
+    timeout_secs: u64,
+    cache_ttl_secs: u64,
+    ban_list: LookupConfig,
+    account_created: LookupConfig,
+    // Accounts created fewer than this many days ago get a warning.
+    new_account_days: u64,
+}
+
+/// An external JSON API. An empty URL skips it.
+#[derive(Serialize, Deserialize)]
+struct LookupConfig {
+    // `{uuid}` is replaced with the player's dashed UUID.
+    url: String,
+    // Sent as a bearer token if not empty.
+    token: String,
+    // JSON pointer to the interesting part of the response, such as `/bans`.
+    path: String,
+}
+
+impl EnrichmentConfig {
+    fn default() -> Self {
+        Self {
+            mode: CheckMode::Off,
+            timeout_secs: 5,
+            cache_ttl_secs: 60 * 60,
+            ban_list: LookupConfig { url: String::new(), token: String::new(), path: String::new() },
+            account_created: LookupConfig { url: String::new(), token: String::new(), path: String::new() },
+            new_account_days: 30,
+        }
+    }
+}
+
+impl config::Documented for EnrichmentConfig {
+    const COMMENTS: &'static [(&'static str, &'static str)] = &[
+        ("mode", "\"off\", \"shadow\" to only log the warnings, or \"enforce\" to show them to staff on approval."),
+        ("timeout_secs", "How long each lookup may take."),
+        ("cache_ttl_secs", "How long lookups are reused for."),
+        ("ban_list", "A ban list API, checked for prior bans of the Minecraft account."),
+        ("ban_list.url", "`{uuid}` is replaced with the player's dashed UUID. Leave empty to skip it."),
+        ("ban_list.token", "Sent as a bearer token if not empty."),
+        ("ban_list.path", "JSON pointer to the bans in the response, such as `/bans`."),
+        ("account_created", "An API giving when the Minecraft account was created."),
+        ("account_created.url", "`{uuid}` is replaced with the player's dashed UUID. Leave empty to skip it."),
+        ("account_created.token", "Sent as a bearer token if not empty."),
+        ("account_created.path", "JSON pointer to the creation time in the response, as unix seconds or an RFC 3339 timestamp."),
+        ("new_account_days", "Accounts created fewer than this many days ago get a warning."),
+    ];
+}
+
+fn open_config() -> Result<EnrichmentConfig> {
+    config::open_config(ENRICHMENT_CONFIG_PATH, EnrichmentConfig::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_setting_is_documented() {
+        assert_eq!(config::undocumented(&EnrichmentConfig::default()), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,82 @@
+use tokio::sync::broadcast;
+
+pub(crate) type EventSender = broadcast::Sender<Event>;
+
+/// Things that happened, published for anything that wants to follow along.
+#[derive(Clone, Debug)]
+pub(crate) enum Event {
+    Approved { name: String, uuid: String },
+    PlayerJoined { name: String, uuid: String },
+    PlayerLeft { name: String, uuid: String },
+    TicketOpened,
+    TicketClosed,
+}
+
+pub(crate) fn channel() -> EventSender {
+    broadcast::channel(256).0
+}
+
+/// An event as observers see it: a kind plus the player involved, if they're allowed to know.
+pub(crate) struct ObserverEvent {
+    pub(crate) kind: u8,
+    pub(crate) player: Option<(String, String)>,
+}
+
+/// Decide what read-only observers may see of an event. This is the only place that makes that decision:
+/// player names and UUIDs are stripped unless the listener config allows them, and anything not listed here
+/// is never sent.
+pub(crate) fn observer_view(event: &Event, include_identifiers: bool) -> Option<ObserverEvent> {
+    let (kind, player) = match event {
+        Event::Approved { name, uuid } => (0, Some((name, uuid))),
+        Event::PlayerJoined { name, uuid } => (1, Some((name, uuid))),
+        Event::PlayerLeft { name, uuid } => (2, Some((name, uuid))),
+        Event::TicketOpened => (3, None),
+        Event::TicketClosed => (4, None),
+    };
+
+    Some(ObserverEvent {
+        kind,
+        player: player
+            .filter(|_| include_identifiers)
+            .map(|(name, uuid)| (name.to_owned(), uuid.to_owned())),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn every_event() -> Vec<Event> {
+        let player = || ("Notch".to_owned(), "069a79f4-44e9-4726-a5be-fca90e38aaf5".to_owned());
+        vec![
+            Event::Approved { name: player().0, uuid: player().1 },
+            Event::PlayerJoined { name: player().0, uuid: player().1 },
+            Event::PlayerLeft { name: player().0, uuid: player().1 },
+            Event::TicketOpened,
+            Event::TicketClosed,
+        ]
+    }
+
+    #[test]
+    fn identifiers_are_stripped_by_default() {
+        for event in every_event() {
+            let view = observer_view(&event, false).unwrap();
+            assert!(view.player.is_none(), "{event:?}");
+        }
+    }
+
+    #[test]
+    fn identifiers_are_only_sent_when_allowed() {
+        for event in every_event() {
+            let view = observer_view(&event, true).unwrap();
+            let has_player = matches!(event, Event::Approved { .. } | Event::PlayerJoined { .. } | Event::PlayerLeft { .. });
+            assert_eq!(view.player.is_some(), has_player, "{event:?}");
+        }
+    }
+
+    #[test]
+    fn each_event_has_its_own_kind() {
+        let kinds: Vec<u8> = every_event().iter().map(|event| observer_view(event, false).unwrap().kind).collect();
+        assert_eq!(kinds, [0, 1, 2, 3, 4]);
+    }
+}
@@ -0,0 +1,289 @@
+use crate::{config, log, timestamp};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const EXTERNAL_CACHE_CONFIG_PATH: &str = "./external_cache_config.toml";
+const EXTERNAL_CACHE_FILE: &str = "./external_cache.json";
+
+// The service looked up and what it was asked about.
+type CacheKey = (String, String);
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    service: String,
+    key: String,
+    value: Value,
+    // Unix milliseconds after which the entry is stale.
+    expires: u64,
+    // Bumped on every hit, so the least recently used entry can be evicted.
+    #[serde(skip)]
+    last_used: u64,
+}
+
+/// Successful answers from external APIs, shared by everything that makes lookups so the same player isn't
+/// looked up again on every reconnect or embed rebuild. Each caller picks how long its answers stay fresh.
+/// Failures are never cached.
+pub(crate) struct ExternalCache {
+    config: ExternalCacheConfig,
+    entries: Mutex<HashMap<CacheKey, Entry>>,
+    // One lock per key being fetched, so concurrent lookups for it wait for a single upstream call.
+    inflight: Mutex<HashMap<CacheKey, Arc<tokio::sync::Mutex<()>>>>,
+    uses: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    waits: AtomicU64,
+}
+
+impl ExternalCache {
+    /// Start warm from the entries saved at the last shutdown, dropping any that have gone stale since.
+    pub(crate) fn load() -> Result<Self> {
+        let config = open_config()?;
+        let mut saved = vec![];
+        if config.persist && let Ok(mut file) = File::open(EXTERNAL_CACHE_FILE) {
+            saved = serde_json::from_reader(&mut file)?;
+        }
+        let cache = Self::new(config, saved);
+        if cache.config.persist {
+            log!("Loaded {} external lookups from {EXTERNAL_CACHE_FILE}", cache.entries.lock().unwrap().len());
+        }
+        Ok(cache)
+    }
+
+    fn new(config: ExternalCacheConfig, saved: Vec<Entry>) -> Self {
+        let now = timestamp();
+        let entries = saved.into_iter().filter(|entry| entry.expires > now).take(config.capacity).map(|entry| ((entry.service.clone(), entry.key.clone()), entry)).collect();
+        Self {
+            config,
+            entries: Mutex::new(entries),
+            inflight: Mutex::new(HashMap::new()),
+            uses: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            waits: AtomicU64::new(0),
+        }
+    }
+
+    /// Answer from the cache if there's a fresh entry, otherwise run `fetch` and keep what it returns for `ttl`.
+    pub(crate) async fn get<F, Fut>(&self, service: &str, key: &str, ttl: Duration, fetch: F) -> Result<Value>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Value>>,
+    {
+        let cache_key = (service.to_owned(), key.to_owned());
+        if let Some(value) = self.cached(&cache_key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(value);
+        }
+
+        let lock = self.inflight.lock().unwrap().entry(cache_key.clone()).or_default().clone();
+        let _fetching = match lock.try_lock() {
+            Ok(guard) => guard,
+            Err(_) => {
+                self.waits.fetch_add(1, Ordering::Relaxed);
+                lock.lock().await
+            }
+        };
+
+        // Whoever held the lock before us may have just filled the entry in.
+        if let Some(value) = self.cached(&cache_key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(value);
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let result = fetch().await;
+        if let Ok(value) = &result {
+            self.insert(cache_key.clone(), value.clone(), ttl);
+        }
+        self.inflight.lock().unwrap().remove(&cache_key);
+        result
+    }
+
+    fn cached(&self, cache_key: &CacheKey) -> Option<Value> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(cache_key).filter(|entry| entry.expires > timestamp())?;
+        entry.last_used = self.uses.fetch_add(1, Ordering::Relaxed);
+        Some(entry.value.clone())
+    }
+
+    fn insert(&self, (service, key): CacheKey, value: Value, ttl: Duration) {
+        let now = timestamp();
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| entry.expires > now);
+        while entries.len() >= self.config.capacity.max(1) {
+            let Some(oldest) = entries.iter().min_by_key(|(_, entry)| entry.last_used).map(|(cache_key, _)| cache_key.clone()) else { break };
+            entries.remove(&oldest);
+        }
+
+        let last_used = self.uses.fetch_add(1, Ordering::Relaxed);
+        let expires = now + ttl.as_millis() as u64;
+        entries.insert((service.clone(), key.clone()), Entry { service, key, value, expires, last_used });
+    }
+
+    /// Hits, misses and lookups that waited on someone else's, since startup.
+    pub(crate) fn summary(&self) -> String {
+        format!(
+            "{} hits, {} misses, {} waited on an inflight lookup, {} entries",
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+            self.waits.load(Ordering::Relaxed),
+            self.entries.lock().unwrap().len()
+        )
+    }
+
+    /// Write the fresh entries out so the next start is warm. Does nothing unless persistence is enabled.
+    pub(crate) fn save(&self) -> Result<()> {
+        log!("External lookup cache: {}", self.summary());
+        if !self.config.persist {
+            return Ok(());
+        }
+
+        let _ = std::fs::remove_file(EXTERNAL_CACHE_FILE);
+        self.write(File::create_new(EXTERNAL_CACHE_FILE)?)
+    }
+
+    fn write(&self, writer: impl Write) -> Result<()> {
+        let now = timestamp();
+        let entries = self.entries.lock().unwrap();
+        serde_json::to_writer_pretty(writer, &entries.values().filter(|entry| entry.expires > now).collect::<Vec<_>>())?;
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExternalCacheConfig {
+    // The most lookups kept at once across every service.
+    capacity: usize,
+    // Save the cache on shutdown and load it on startup.
+    persist: bool,
+}
+
+impl ExternalCacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1000,
+            persist: true,
+        }
+    }
+}
+
+impl config::Documented for ExternalCacheConfig {
+    const COMMENTS: &'static [(&'static str, &'static str)] = &[
+        ("capacity", "The most lookups kept at once across every service."),
+        ("persist", "Save the cache on shutdown and load it on startup."),
+    ];
+}
+
+fn open_config() -> Result<ExternalCacheConfig> {
+    config::open_config(EXTERNAL_CACHE_CONFIG_PATH, ExternalCacheConfig::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use futures_util::future::join_all;
+    use serde_json::json;
+
+    const SERVICE: &str = "mojang";
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn cache(capacity: usize) -> ExternalCache {
+        ExternalCache::new(ExternalCacheConfig { capacity, persist: true }, vec![])
+    }
+
+    // A lookup that counts how often it reaches upstream.
+    async fn lookup(cache: &ExternalCache, key: &str, ttl: Duration, calls: &AtomicU64) -> Result<Value> {
+        cache.get(SERVICE, key, ttl, || async {
+            calls.fetch_add(1, Ordering::Relaxed);
+            Ok(json!(key))
+        }).await
+    }
+
+    #[tokio::test]
+    async fn fresh_answers_are_reused_until_they_expire() {
+        let cache = cache(10);
+        let calls = AtomicU64::new(0);
+        for _ in 0..3 {
+            assert_eq!(lookup(&cache, "Notch", TTL, &calls).await.unwrap(), json!("Notch"));
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        lookup(&cache, "jeb_", Duration::from_millis(20), &calls).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        lookup(&cache, "jeb_", Duration::from_millis(20), &calls).await.unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn failures_are_not_cached() {
+        let cache = cache(10);
+        assert!(cache.get(SERVICE, "Notch", TTL, || async { Err(anyhow!("Upstream is down")) }).await.is_err());
+        let calls = AtomicU64::new(0);
+        lookup(&cache, "Notch", TTL, &calls).await.unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn concurrent_lookups_share_one_upstream_call() {
+        let cache = cache(10);
+        let calls = AtomicU64::new(0);
+        let lookups = (0..10).map(|_| cache.get(SERVICE, "Notch", TTL, || async {
+            calls.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok(json!("Notch"))
+        }));
+        for value in join_all(lookups).await {
+            assert_eq!(value.unwrap(), json!("Notch"));
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert_eq!(cache.waits.load(Ordering::Relaxed), 9);
+        assert!(cache.inflight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn the_least_recently_used_entry_is_evicted() {
+        let cache = cache(2);
+        let calls = AtomicU64::new(0);
+        lookup(&cache, "Notch", TTL, &calls).await.unwrap();
+        lookup(&cache, "jeb_", TTL, &calls).await.unwrap();
+        lookup(&cache, "Notch", TTL, &calls).await.unwrap();
+        lookup(&cache, "Dinnerbone", TTL, &calls).await.unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+
+        lookup(&cache, "Notch", TTL, &calls).await.unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+        lookup(&cache, "jeb_", TTL, &calls).await.unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 4);
+    }
+
+    #[tokio::test]
+    async fn saved_entries_start_the_next_run_warm() {
+        let cache = cache(10);
+        let calls = AtomicU64::new(0);
+        lookup(&cache, "Notch", TTL, &calls).await.unwrap();
+        lookup(&cache, "jeb_", Duration::from_millis(20), &calls).await.unwrap();
+        let mut saved = vec![];
+        cache.write(&mut saved).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // Only entries still fresh when the cache is loaded again are kept.
+        let restarted = ExternalCache::new(ExternalCacheConfig { capacity: 10, persist: true }, serde_json::from_slice(&saved).unwrap());
+        assert_eq!(restarted.entries.lock().unwrap().len(), 1);
+        lookup(&restarted, "Notch", TTL, &calls).await.unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn every_setting_is_documented() {
+        assert_eq!(config::undocumented(&ExternalCacheConfig::default()), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,126 @@
+use crate::{config, log, UserState, USERS_FILE};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+
+const LINK_SCOPE_CONFIG_PATH: &str = "./link_scope_config.toml";
+
+/// How far a discord user's link reaches when the bot serves linked guilds alongside its main guild.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum LinkScope {
+    /// One link covers every guild. Once approved, it's inherited by each linked guild the user is in, without
+    /// another code.
+    Global,
+    /// Each guild has its own links, so a user may link a different account in each.
+    PerGuild,
+}
+
+impl Display for LinkScope {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Global => "global",
+            Self::PerGuild => "per_guild",
+        })
+    }
+}
+
+pub(crate) fn load() -> Result<LinkScope> {
+    let scope = config::open_config(LINK_SCOPE_CONFIG_PATH, LinkScopeConfig::default)?.link_scope;
+    log!("Links are {scope} across guilds");
+    Ok(scope)
+}
+
+/// Refuse links made under the other scope, which this one would read wrongly. Per guild links would be treated as
+/// links to the main guild, and inherited links as nothing at all, leaving their roles and member messages behind.
+pub(crate) fn check(user_states: &[UserState], scope: LinkScope) -> Result<()> {
+    let (other, conflicting) = match scope {
+        LinkScope::Global => (LinkScope::PerGuild, user_states.iter().filter(|state| state.guild_id.is_some()).collect::<Vec<_>>()),
+        LinkScope::PerGuild => (LinkScope::Global, user_states.iter().filter(|state| !state.inherited.is_empty()).collect()),
+    };
+    let Some(example) = conflicting.first() else { return Ok(()) };
+    let (found, those) = match conflicting.len() {
+        1 => (format!("The account {} [{}] in {USERS_FILE} was", example.name, example.uuid), "that account"),
+        count => (format!("{count} accounts in {USERS_FILE}, such as {} [{}], were", example.name, example.uuid), "those accounts"),
+    };
+    Err(anyhow!(
+        "{found} linked under link_scope = \"{other}\" and can't be read as \"{scope}\". To switch, set link_scope back to \
+        \"{other}\", unlink {those} with the Unlink button on their member messages, then set it to \"{scope}\" again."
+    ))
+}
+
+#[derive(Serialize, Deserialize)]
+struct LinkScopeConfig {
+    // "global" for one Minecraft account per discord user across every guild, inherited by linked guilds once
+    // approved, or "per_guild" to let users link a different account in each guild.
+    link_scope: LinkScope,
+}
+
+impl LinkScopeConfig {
+    fn default() -> Self {
+        Self {
+            link_scope: LinkScope::Global,
+        }
+    }
+}
+
+impl config::Documented for LinkScopeConfig {
+    const COMMENTS: &'static [(&'static str, &'static str)] = &[
+        ("link_scope", "\"global\" for one Minecraft account per discord user across every guild, inherited by linked guilds once approved, or \"per_guild\" to let users link a different account in each guild."),
+    ];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InheritedLink, Priority, VerifyState};
+
+    fn approved(name: &str, discord_id: u64) -> UserState {
+        let mut state = UserState::complete(name, &format!("{name}-uuid"), discord_id, discord_id * 10, Priority::Normal);
+        state.verify_state = VerifyState::APPROVED;
+        state
+    }
+
+    fn inherited(name: &str, discord_id: u64) -> UserState {
+        let mut state = approved(name, discord_id);
+        state.inherited.push(InheritedLink { guild_id: 7, verify_message: 70, inherited_at: 0 });
+        state
+    }
+
+    #[test]
+    fn links_from_before_linked_guilds_suit_either_scope() {
+        let states = [approved("Notch", 1), approved("jeb_", 2)];
+        assert!(check(&states, LinkScope::Global).is_ok());
+        assert!(check(&states, LinkScope::PerGuild).is_ok());
+    }
+
+    #[test]
+    fn inherited_links_refuse_a_switch_to_per_guild() {
+        let states = [approved("Notch", 1), inherited("jeb_", 2)];
+        assert!(check(&states, LinkScope::Global).is_ok());
+
+        let refusal = check(&states, LinkScope::PerGuild).unwrap_err().to_string();
+        assert!(refusal.starts_with("The account jeb_ [jeb_-uuid] in ./users.json was linked under link_scope = \"global\""), "{refusal}");
+        assert!(refusal.contains("set link_scope back to \"global\", unlink that account"), "{refusal}");
+    }
+
+    #[test]
+    fn per_guild_links_refuse_a_switch_to_global() {
+        let mut elsewhere = approved("Dinnerbone", 1);
+        elsewhere.guild_id = Some(7);
+        let states = [approved("Notch", 1), elsewhere];
+        assert!(check(&states, LinkScope::PerGuild).is_ok());
+
+        let refusal = check(&states, LinkScope::Global).unwrap_err().to_string();
+        assert!(refusal.contains("Dinnerbone [Dinnerbone-uuid] in ./users.json was linked under link_scope = \"per_guild\""), "{refusal}");
+        assert!(refusal.ends_with("then set it to \"global\" again."), "{refusal}");
+    }
+
+    #[test]
+    fn several_conflicting_links_are_counted() {
+        let states = [inherited("Notch", 1), inherited("jeb_", 2)];
+        let refusal = check(&states, LinkScope::PerGuild).unwrap_err().to_string();
+        assert!(refusal.starts_with("2 accounts in ./users.json, such as Notch [Notch-uuid], were linked"), "{refusal}");
+        assert!(refusal.contains("unlink those accounts"), "{refusal}");
+    }
+}
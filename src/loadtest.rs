@@ -0,0 +1,113 @@
+use crate::wire::{format_uuid, Buffer, ClientPacket, ServerPacket, HELLO_ACCEPTED};
+use anyhow::{anyhow, Result};
+use std::time::{Duration, Instant};
+use tokio::net::{lookup_host, TcpStream};
+
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+const PROTOCOL_VERSION: u8 = 2;
+
+struct Options {
+    target: String,
+    clients: usize,
+    requests: usize,
+    force: bool,
+}
+
+impl Options {
+    // --loadtest <host:port> [--clients N] [--requests N] [--force]
+    fn parse(args: &[String]) -> Result<Self> {
+        let mut options = Self {
+            target: String::new(),
+            clients: 20,
+            requests: 10,
+            force: false,
+        };
+
+        let mut args = args.iter().skip_while(|arg| *arg != "--loadtest").skip(1);
+        options.target = args.next().ok_or(anyhow!("Usage: --loadtest <host:port> [--clients N] [--requests N] [--force]"))?.to_owned();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--clients" => options.clients = args.next().ok_or(anyhow!("--clients needs a value"))?.parse()?,
+                "--requests" => options.requests = args.next().ok_or(anyhow!("--requests needs a value"))?.parse()?,
+                "--force" => options.force = true,
+                x => return Err(anyhow!("Unknown load test option {x}")),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Drive connect queries from many synthetic clients at once and report throughput, latency and errors.
+/// Synthetic players never link a discord account, so their codes expire without being saved.
+pub(crate) async fn run(args: &[String]) -> Result<()> {
+    let options = Options::parse(args)?;
+
+    // Only local instances can be hammered without asking.
+    let addresses = lookup_host(&options.target).await?.collect::<Vec<_>>();
+    if !options.force && !addresses.iter().all(|address| address.ip().is_loopback()) {
+        return Err(anyhow!("Refusing to load test {}, which is not a local address. Pass --force to run anyway.", options.target));
+    }
+
+    println!("Load testing {} with {} clients sending {} connect queries each", options.target, options.clients, options.requests);
+    let started = Instant::now();
+    let tasks = (0..options.clients)
+        .map(|_| tokio::spawn(run_client(options.target.clone(), options.requests)))
+        .collect::<Vec<_>>();
+
+    let mut latencies = vec![];
+    let mut errors = 0;
+    for task in tasks {
+        match task.await? {
+            Ok(client_latencies) => latencies.extend(client_latencies),
+            Err(why) => {
+                println!("Client failed: {why}");
+                errors += 1;
+            }
+        }
+    }
+    let elapsed = started.elapsed();
+
+    latencies.sort();
+    let percentile = |p: usize| latencies.get((latencies.len() * p / 100).min(latencies.len().saturating_sub(1))).copied().unwrap_or_default();
+    println!("Completed {} connect queries in {:.2}s ({:.1}/s)", latencies.len(), elapsed.as_secs_f64(), latencies.len() as f64 / elapsed.as_secs_f64());
+    println!("Failed clients: {errors}/{}", options.clients);
+    println!("Latency p50 {:?}, p95 {:?}, p99 {:?}, max {:?}", percentile(50), percentile(95), percentile(99), latencies.last().copied().unwrap_or_default());
+    Ok(())
+}
+
+// Say hello, then send connect queries for fresh random players, timing each round trip.
+async fn run_client(target: String, requests: usize) -> Result<Vec<Duration>> {
+    let mut stream = TcpStream::connect(&target).await?;
+    let ServerPacket::Hello { status, message, .. } = exchange(&mut stream, ClientPacket::Hello { version: PROTOCOL_VERSION, server: None }).await? else {
+        return Err(anyhow!("Unexpected reply to hello!"));
+    };
+    if status != HELLO_ACCEPTED {
+        return Err(anyhow!("Hello refused: {message}"));
+    }
+
+    let mut latencies = vec![];
+    for _ in 0..requests {
+        let uuid = format_uuid(rand::random::<u128>());
+        let name = format!("load{:06}", rand::random_range(0..1000000));
+
+        let started = Instant::now();
+        match exchange(&mut stream, ClientPacket::ConnectQuery { uuid, name, locale: None }).await? {
+            ServerPacket::ConnectResult { .. } => latencies.push(started.elapsed()),
+            ServerPacket::Error { message, .. } => return Err(anyhow!("Server returned an error: {message}")),
+            packet => return Err(anyhow!("Unexpected reply {packet:?} to connect query!")),
+        }
+    }
+    Ok(latencies)
+}
+
+async fn exchange(stream: &mut TcpStream, packet: ClientPacket) -> Result<ServerPacket> {
+    let mut buf = Buffer::new(MAX_FRAME_SIZE);
+    packet.encode(&mut buf, PROTOCOL_VERSION)?;
+    buf.write_to_tcp(stream, IO_TIMEOUT, None).await?;
+    if !buf.read_from_tcp(stream, IO_TIMEOUT).await? {
+        return Err(anyhow!("Server closed the connection!"));
+    }
+    buf.decompress(PROTOCOL_VERSION)?;
+    ServerPacket::decode(&mut buf, PROTOCOL_VERSION)
+}
@@ -0,0 +1,153 @@
+use crate::{config, log};
+use anyhow::Result;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::sync::LazyLock;
+
+const LOCALE_CONFIG_PATH: &str = "./locale_config.toml";
+const LOCALES_DIR: &str = "./locales";
+
+// Messages used when neither the player's locale nor the default locale has a translation.
+const BUILT_IN: &[(&str, &str)] = &[
+    ("connect.code_prompt", "Please type the following code into the #verification channel:\n{code}"),
+    ("connect.pending", "Your account is currently pending admin approval (position {position} in the queue). Please try again later."),
+    ("connect.server_pending", "Your account is waiting for admin approval to join {server}. Please try again later."),
+    ("connect.denied", "{reason}"),
+    ("connect.banned", "You have been banned from this server."),
+];
+
+static LOCALE_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^([a-zA-Z]{2,3})(?:[-_]([a-zA-Z]{2}|[0-9]{3}))?$").unwrap());
+
+/// Normalize a locale tag such as `en_us` to `en-US`, or None if it isn't a locale tag at all.
+pub(crate) fn normalize_locale(locale: &str) -> Option<String> {
+    let captures = LOCALE_REGEX.captures(locale)?;
+    let language = captures[1].to_lowercase();
+    Some(match captures.get(2) {
+        Some(region) => format!("{language}-{}", region.as_str().to_uppercase()),
+        None => language,
+    })
+}
+
+/// Message templates for each locale, loaded from `./locales/<locale>.json` files mapping message keys to
+/// templates. Placeholders are written as `{name}`.
+pub(crate) struct Localizer {
+    default_locale: String,
+    locales: HashMap<String, HashMap<String, String>>,
+}
+
+impl Localizer {
+    pub(crate) fn load() -> Result<Self> {
+        let config = open_config()?;
+        let mut locales = HashMap::new();
+        if let Ok(entries) = std::fs::read_dir(LOCALES_DIR) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                let Some(locale) = path.file_stem().and_then(|stem| stem.to_str()).and_then(normalize_locale) else { continue };
+                match serde_json::from_reader::<_, HashMap<String, String>>(File::open(&path)?) {
+                    Ok(messages) => {
+                        locales.insert(locale, messages);
+                    }
+                    Err(why) => log!("Skipping invalid locale file {}: {why}", path.display()),
+                }
+            }
+        }
+
+        let default_locale = normalize_locale(&config.default_locale).unwrap_or("en-US".to_owned());
+        log!("Loaded {} locales, defaulting to {default_locale}", locales.len());
+        Ok(Self { default_locale, locales })
+    }
+
+    /// Render a message in the requested locale. Unknown locales and missing translations fall back to the
+    /// language without its region, then to the default locale, then to the built-in English text.
+    pub(crate) fn render(&self, locale: Option<&str>, key: &str, args: &[(&str, &str)]) -> String {
+        let requested = locale.and_then(normalize_locale);
+        let language = requested.as_deref().and_then(|locale| locale.split('-').next()).map(str::to_owned);
+        let template = [requested, language, Some(self.default_locale.clone())]
+            .into_iter()
+            .flatten()
+            .find_map(|locale| self.locales.get(&locale)?.get(key).cloned())
+            .or_else(|| BUILT_IN.iter().find(|(built_in, _)| *built_in == key).map(|(_, template)| (*template).to_owned()))
+            .unwrap_or(key.to_owned());
+
+        args.iter().fold(template, |message, (name, value)| message.replace(&format!("{{{name}}}"), value))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct LocaleConfig {
+    default_locale: String,
+}
+
+impl LocaleConfig {
+    fn default() -> Self {
+        Self {
+            default_locale: "en-US".to_owned(),
+        }
+    }
+}
+
+impl config::Documented for LocaleConfig {
+    const COMMENTS: &'static [(&'static str, &'static str)] = &[
+        ("default_locale", "The locale used for players whose game sends none, or one without a file in ./locales."),
+    ];
+}
+
+fn open_config() -> Result<LocaleConfig> {
+    config::open_config(LOCALE_CONFIG_PATH, LocaleConfig::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn localizer() -> Localizer {
+        let locale = |messages: &[(&str, &str)]| messages.iter().map(|(key, template)| (key.to_string(), template.to_string())).collect();
+        Localizer {
+            default_locale: "en-US".to_owned(),
+            locales: HashMap::from([
+                ("de".to_owned(), locale(&[("connect.code_prompt", "Bitte gib diesen Code im #verification Kanal ein:\n{code}")])),
+                ("fr-CA".to_owned(), locale(&[("connect.code_prompt", "Entre ce code dans le salon #verification :\n{code}")])),
+                ("en-US".to_owned(), locale(&[("connect.banned", "Banned.")])),
+            ]),
+        }
+    }
+
+    #[test]
+    fn locale_tags_are_normalized() {
+        assert_eq!(normalize_locale("en_us").as_deref(), Some("en-US"));
+        assert_eq!(normalize_locale("EN-gb").as_deref(), Some("en-GB"));
+        assert_eq!(normalize_locale("es_419").as_deref(), Some("es-419"));
+        assert_eq!(normalize_locale("DE").as_deref(), Some("de"));
+        for invalid in ["", "e", "english", "en-", "en_usa", "../en"] {
+            assert_eq!(normalize_locale(invalid), None, "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn the_code_prompt_is_rendered_in_the_players_locale() {
+        let localizer = localizer();
+        let args = [("code", "123456")];
+        assert_eq!(localizer.render(Some("fr_ca"), "connect.code_prompt", &args), "Entre ce code dans le salon #verification :\n123456");
+        assert_eq!(localizer.render(Some("de_de"), "connect.code_prompt", &args), "Bitte gib diesen Code im #verification Kanal ein:\n123456");
+    }
+
+    #[test]
+    fn unknown_locales_fall_back() {
+        let localizer = localizer();
+        let args = [("code", "123456")];
+        let built_in = "Please type the following code into the #verification channel:\n123456";
+        assert_eq!(localizer.render(Some("ja_jp"), "connect.code_prompt", &args), built_in);
+        assert_eq!(localizer.render(Some("not a locale"), "connect.code_prompt", &args), built_in);
+        assert_eq!(localizer.render(None, "connect.code_prompt", &args), built_in);
+        // The default locale is used before the built-in text, and keys no one knows are shown as they are.
+        assert_eq!(localizer.render(Some("de"), "connect.banned", &[]), "Banned.");
+        assert_eq!(localizer.render(Some("de"), "connect.unknown", &[]), "connect.unknown");
+    }
+
+    #[test]
+    fn every_setting_is_documented() {
+        assert_eq!(config::undocumented(&LocaleConfig::default()), Vec::<String>::new());
+    }
+}
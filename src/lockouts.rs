@@ -0,0 +1,81 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+
+const LOCKOUTS_FILE: &str = "./lockouts.json";
+// Invalid codes a user can enter before the first lockout.
+const FAILURES_BEFORE_LOCKOUT: u32 = 3;
+// How long each lockout lasts. Every failure past the last step gets the last one again.
+const LOCKOUT_STEPS: [u64; 3] = [1000 * 60, 1000 * 60 * 10, 1000 * 60 * 60];
+// Users who haven't entered an invalid code for this long start over.
+const FAILURE_MEMORY: u64 = 1000 * 60 * 60 * 24;
+
+#[derive(Default, Serialize, Deserialize)]
+struct Record {
+    failures: u32,
+    last_failure: u64,
+    locked_until: u64,
+    // Whether the user has been told about the current lockout.
+    notified: bool,
+}
+
+/// Invalid verification codes entered by each discord user. Past a few failures, every invalid code locks the user
+/// out for longer, so codes can't be guessed. Saved on every change, so restarting the bot doesn't lift a lockout.
+#[derive(Default, Serialize, Deserialize)]
+pub(crate) struct Lockouts {
+    records: HashMap<u64, Record>,
+}
+
+impl Lockouts {
+    pub(crate) fn load() -> Result<Self> {
+        match File::open(LOCKOUTS_FILE) {
+            Ok(mut file) => Ok(serde_json::from_reader(&mut file)?),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// When the user's lockout ends if they're locked out, and whether they still need telling about it. They're
+    /// only told once per lockout.
+    pub(crate) fn check(&mut self, discord_id: u64, now: u64) -> Result<Option<(u64, bool)>> {
+        let Some(record) = self.records.get_mut(&discord_id).filter(|record| record.locked_until > now) else { return Ok(None) };
+        let notify = !record.notified;
+        let locked_until = record.locked_until;
+        if notify {
+            record.notified = true;
+            self.save()?;
+        }
+        Ok(Some((locked_until, notify)))
+    }
+
+    /// Count an invalid code, returning when the lockout it started ends. The caller tells the user about it.
+    pub(crate) fn fail(&mut self, discord_id: u64, now: u64) -> Result<Option<u64>> {
+        self.records.retain(|_, record| record.last_failure + FAILURE_MEMORY > now || record.locked_until > now);
+        let record = self.records.entry(discord_id).or_default();
+        record.failures += 1;
+        record.last_failure = now;
+        let locked_until = record.failures.checked_sub(FAILURES_BEFORE_LOCKOUT).map(|step| {
+            record.locked_until = now + LOCKOUT_STEPS[(step as usize).min(LOCKOUT_STEPS.len() - 1)];
+            record.notified = true;
+            record.locked_until
+        });
+        self.save()?;
+        Ok(locked_until)
+    }
+
+    /// Forget the user's invalid codes, lifting any lockout. Returns whether there was anything to forget.
+    pub(crate) fn clear(&mut self, discord_id: u64) -> Result<bool> {
+        let cleared = self.records.remove(&discord_id).is_some();
+        if cleared {
+            self.save()?;
+        }
+        Ok(cleared)
+    }
+
+    fn save(&self) -> Result<()> {
+        let _ = std::fs::remove_file(LOCKOUTS_FILE);
+        let mut file = File::create_new(LOCKOUTS_FILE)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        Ok(())
+    }
+}
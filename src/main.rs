@@ -1,21 +1,70 @@
 extern crate core;
 
+mod analytics;
+mod audit;
+mod build_info;
+mod checks;
+mod cidr;
+mod config;
+mod custom_id;
 mod discord;
+mod enrichment;
+mod events;
+mod external_cache;
+mod link_scope;
+mod loadtest;
+mod locale;
+mod lockouts;
+mod messages;
+mod notifications;
+mod panels;
+mod panics;
+mod permissions;
+mod priority;
+mod proxy_protocol;
+mod rate_limit;
+mod sanitize;
+mod shutdown;
+mod staff;
 mod tcp;
+mod tickets;
+mod tls;
+mod transcripts;
+mod websocket;
+mod wire;
 
 use anyhow::{anyhow, Result};
+use audit::{Actor, AuditAction, AuditEntry};
+use checks::{Checks, Stage, Subject};
+use events::{Event, EventSender};
+use external_cache::ExternalCache;
+use link_scope::LinkScope;
+use locale::Localizer;
+use lockouts::Lockouts;
+use messages::Messages;
+use priority::{ApprovalHistory, Decision, Priority};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
+use shutdown::Shutdown;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
 use std::fs::File;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tcp::{Outbound, Sessions};
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 
 const USERS_FILE: &str = "./users.json";
 
+// How long a code lasts once it's been handed out.
+const CODE_LIFETIME: u128 = 1000 * 30;
+// How long an expired code is remembered so it can be reported as expired rather than invalid.
+const EXPIRED_CODE_MEMORY: u128 = 1000 * 60 * 10;
+
 macro_rules! log {
     ($($arg:tt)*) => {{
         let time = chrono::offset::Local::now();
-        println!("{} {}", time.format("[%Y-%m-%d %H:%M:%S]"), format!($($arg)*));
+        println!("{} {}", time.format("[%Y-%m-%d %H:%M:%S]"), crate::sanitize::sanitize_display(&format!($($arg)*)));
     }};
 }
 
@@ -23,20 +72,59 @@ pub(crate) use log;
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    if std::env::args().any(|arg| arg == "--version") {
+        println!("{}", build_info::summary());
+        return Ok(());
+    }
+
+    let args = std::env::args().collect::<Vec<_>>();
+    if args.iter().any(|arg| arg == "--loadtest") {
+        return loadtest::run(&args).await;
+    }
+
+    log!("Starting {}", build_info::summary());
+    log!("Storage backend: json file {USERS_FILE}");
+
     let mut random = rand::rng();
 
     let (main_tx, mut main_rx) = unbounded_channel();
 
+    // Notifications the main loop pushes to the discord thread without expecting a reply.
+    let (discord_notify, discord_notifications) = unbounded_channel();
+    // Questions the main loop asks the discord thread. The answers are awaited off the main loop, since the
+    // discord thread may itself be waiting on the main loop.
+    let (discord_requests, discord_request_rx) = unbounded_channel();
+
+    let events = events::channel();
+    let shutdown = Arc::new(Shutdown::default());
+    let external_cache = Arc::new(ExternalCache::load()?);
+    let messages = Arc::new(Messages::load()?);
+
     let discord_tx = main_tx.clone();
+    let discord_events = events.clone();
+    let discord_shutdown = shutdown.clone();
+    let discord_external_cache = external_cache.clone();
+    let discord_messages = messages.clone();
     tokio::spawn(async move {
-        if let Err(why) = discord::start_discord(discord_tx).await {
+        if let Err(why) = discord::start_discord(discord_tx, discord_notifications, discord_request_rx, discord_events, discord_shutdown, discord_external_cache, discord_messages).await {
             log!("Error in discord handler: {why:?}")
         }
     });
 
-    let tcp_tx = main_tx.clone();
+    let analytics_events = events.clone();
     tokio::spawn(async move {
-        if let Err(why) = tcp::start_tcp(tcp_tx).await {
+        if let Err(why) = analytics::start_analytics(analytics_events).await {
+            log!("Error in analytics writer: {why:?}");
+        }
+    });
+
+    let tcp_tx = main_tx.clone();
+    let tcp_events = events.clone();
+    let sessions = Sessions::default();
+    let tcp_sessions = sessions.clone();
+    let tcp_shutdown = shutdown.clone();
+    let tcp_task = tokio::spawn(async move {
+        if let Err(why) = tcp::start_tcp(tcp_tx, tcp_events, tcp_sessions, tcp_shutdown).await {
             log!("Error in tcp handler: {why:?}");
         }
     });
@@ -46,9 +134,26 @@ async fn main() -> Result<()> {
     } else {
         Vec::<UserState>::new()
     };
+    let link_scope = link_scope::load()?;
+    link_scope::check(&user_states, link_scope)?;
+
+    let mut checks = Checks::load()?;
+    let localizer = Localizer::load()?;
+    let mut approval_history = ApprovalHistory::load()?;
+    approval_history.backfill(user_states.iter().filter_map(|state| Some((state.uuid.to_owned(), state.approved_by?, state.approved_at?))))?;
+    let mut lockouts = Lockouts::load()?;
+
+    // Recently expired codes, with when they expired and the name and uuid they were for
+    let mut expired_codes = HashMap::<i32, (u128, String, String)>::new();
+    // The name and uuid of the expired code each discord user last entered, and when, which entitles them to a
+    // fresh code for that account.
+    let mut code_attempts = HashMap::<u64, (String, String, u128)>::new();
 
     let mut dirty = true;
 
+    // Callers waiting for a game server to upload its whitelist.
+    let mut whitelist_waiters = Vec::<ChannelPair<Packet>>::new();
+
     log!("Waiting for clients...");
 
     loop {
@@ -60,55 +165,36 @@ async fn main() -> Result<()> {
                 .ok_or(anyhow!("Main packet channel closed!"))?;
 
             match packet {
-                Packet::ConnectQuery(name, uuid) => {
-                    // Insert a new code if there isn't one already
-                    if !user_states.iter().any(|state| state.uuid == uuid) {
-                        let mut code;
-                        loop {
-                            code = random.random_range(100000..1000000);
-                            if !user_states
-                                .iter()
-                                .any(|state| state.verify_code == Some(code))
-                            {
-                                break;
-                            }
-                        }
-                        user_states.push(UserState::new(&name, &uuid, code));
-                    }
+                // The tcp side stops waiting on connect queries after a couple of seconds and has the player retry, so
+                // ones it gave up on are skipped rather than handing out codes nobody will see.
+                Packet::ConnectQuery(..) | Packet::ConnectQueryBatch(..) if channel.sender.is_closed() => {}
 
-                    // Send the verification message back. If the user is verified, send nothing.
-                    let state = user_states.iter().find(|state| state.uuid == uuid).unwrap();
-                    match state.verify_state {
-                        VerifyState::NEW => {
-                            let code = state.verify_code.unwrap();
-                            let response = format!(
-                                "Please type the following code into the #verification channel:\n{code}"
-                            );
-                            log!("Disconnecting user {name} [{uuid}]: {response}");
-                            channel.sender.send(Packet::ConnectResponse(response))?;
-                        }
-
-                        VerifyState::PENDING => {
-                            let response = "Your account is currently pending admin approval. Please try again later.".to_owned();
-                            log!("Disconnecting user {name} [{uuid}]: {response}");
-                            channel.sender.send(Packet::ConnectResponse(response))?;
-                        }
+                Packet::ConnectQuery(name, uuid, locale, server) => {
+                    let (result, response, code) = connect_query(&mut user_states, &mut checks, &localizer, &mut random, &discord_notify, &mut dirty, &name, &uuid, locale.as_deref(), server.as_deref())?;
+                    let _ = channel.sender.send(Packet::ConnectResponse(result, response, code));
+                }
 
-                        VerifyState::APPROVED => {
-                            log!("User {name} [{uuid}] is verified.");
-                            channel
-                                .sender
-                                .send(Packet::ConnectResponse(String::new()))?;
-                        }
+                // Answer a game server's whole list of reconnecting players in one pass.
+                Packet::ConnectQueryBatch(queries, server) => {
+                    let mut responses = Vec::with_capacity(queries.len());
+                    for (name, uuid, locale) in queries {
+                        responses.push(connect_query(&mut user_states, &mut checks, &localizer, &mut random, &discord_notify, &mut dirty, &name, &uuid, locale.as_deref(), server.as_deref())?);
                     }
+                    let _ = channel.sender.send(Packet::ConnectBatchResponse(responses));
                 }
 
-                Packet::DiscordCode(code, user) => {
-                    // Prevent duplicate registrations per discord user
-                    if user_states
-                        .iter()
-                        .any(|state| state.discord_id == Some(user))
-                    {
+                Packet::DiscordCode(code, user, max_accounts, guild_id) => {
+                    // Users who keep entering invalid codes are locked out for longer and longer, so codes can't be guessed.
+                    if let Some((locked_until, notify)) = lockouts.check(user, timestamp())? {
+                        channel.sender.send(Packet::LockedOut(locked_until, notify))?;
+                        continue;
+                    }
+
+                    // Limit how many accounts each discord user registers, in the guild the code was entered in when
+                    // each guild has its own links. Global links all belong to the main guild.
+                    let guild_id = guild_id.filter(|_| link_scope == LinkScope::PerGuild);
+                    let others = linked_names(&user_states, user, link_scope, guild_id);
+                    if others.len() >= max_accounts as usize {
                         channel.sender.send(Packet::AlreadyLinked)?;
                         continue;
                     }
@@ -118,22 +204,44 @@ async fn main() -> Result<()> {
                         state.verify_code == Some(code) && state.verify_state == VerifyState::NEW
                     }) {
                         Some(state) => {
+                            if let Some(reason) = checks.run(Stage::Link, &Subject { name: &state.name, uuid: &state.uuid }) {
+                                channel.sender.send(Packet::LinkRejected(reason))?;
+                                continue;
+                            }
+
+                            let next = match state.verify_state.transition(VerifyAction::Link) {
+                                Ok(next) => next,
+                                Err(why) => {
+                                    log!("Refusing to link user {} [{}]: {why}", state.name, state.uuid);
+                                    channel.sender.send(Packet::LinkRejected(why.to_string()))?;
+                                    continue;
+                                }
+                            };
+
                             log!(
                                 "User {} [{}] is linking to discord account with ID {user}",
                                 state.name,
                                 state.uuid
                             );
                             state.discord_id = Some(user);
-                            state.verify_state = VerifyState::PENDING;
+                            state.guild_id = guild_id;
+                            state.verify_state = next;
+                            discord_notify.send(Packet::Audit(AuditEntry::new(AuditAction::Linked, &state.name, &state.uuid, Some(user), Actor::Discord(user))))?;
                             state.verify_code = None;
                             state.code_expires = None;
+                            state.pending_since = Some(timestamp());
                             channel.sender.send(Packet::VerifyPending(
                                 state.uuid.to_owned(),
                                 state.name.to_owned(),
+                                approval_history.contains(&state.uuid),
+                                state.server.to_owned(),
+                                others,
+                                guild_id,
                             ))?;
+                            lockouts.clear(user)?;
 
                             // Read verification message ID that got created
-                            let Packet::LinkVerifyMessage(message_id) =
+                            let Packet::LinkVerifyMessage(message_id, priority) =
                                 channel.receiver.recv().await.ok_or(anyhow!(
                                     "Thread did not send linked verify message id!"
                                 ))?
@@ -143,95 +251,664 @@ async fn main() -> Result<()> {
                                 ));
                             };
                             state.verify_message = Some(message_id);
+                            state.priority = priority;
 
                             dirty = true;
                         }
 
-                        None => {
-                            channel.sender.send(Packet::VerifyCodeInvalid)?;
+                        // Tell the user if the code was real but ran out, so they know to reconnect.
+                        None => match expired_codes.get(&code) {
+                            Some((expired, name, uuid)) => {
+                                code_attempts.insert(user, (name.to_owned(), uuid.to_owned(), *expired));
+                                channel.sender.send(Packet::VerifyCodeExpired(*expired as u64))?;
+                            }
+                            None => match lockouts.fail(user, timestamp())? {
+                                Some(locked_until) => {
+                                    log!("Locking out discord account with ID {user} for entering invalid codes");
+                                    channel.sender.send(Packet::LockedOut(locked_until, true))?;
+                                }
+                                None => channel.sender.send(Packet::VerifyCodeInvalid)?,
+                            },
+                        },
+                    }
+                }
+
+                Packet::ClearLockout(discord_id) => channel.sender.send(Packet::LockoutCleared(lockouts.clear(discord_id)?))?,
+
+                // Hand out a fresh code for the account whose expired code the user entered. Only the player was
+                // shown that code, so nobody can get a code for someone else's account this way.
+                Packet::RegenerateCode(discord_id) => {
+                    let Some((name, uuid, _)) = code_attempts.remove(&discord_id) else {
+                        channel.sender.send(Packet::RegeneratedCode(None))?;
+                        continue;
+                    };
+                    let code = new_code(&user_states, &mut random);
+                    match user_states.iter_mut().find(|state| state.uuid == uuid) {
+                        Some(state) => {
+                            // Refused for users who linked some other way since.
+                            let next = match state.verify_state.transition(VerifyAction::RegenerateCode) {
+                                Ok(next) => next,
+                                Err(why) => {
+                                    log!("Refusing to regenerate the code of user {} [{}]: {why}", state.name, state.uuid);
+                                    channel.sender.send(Packet::RegeneratedCode(None))?;
+                                    continue;
+                                }
+                            };
+                            state.verify_state = next;
+                            state.verify_code = Some(code);
+                            state.code_expires = Some(timestamp() as u128 + CODE_LIFETIME);
                         }
+                        None => user_states.push(UserState::new(&name, &uuid, code, None)),
                     }
+                    log!("Regenerated the code of user {name} [{uuid}] for discord account with ID {discord_id}");
+                    discord_notify.send(Packet::Audit(AuditEntry::new(AuditAction::CodeIssued, &name, &uuid, None, Actor::Discord(discord_id))))?;
+                    channel.sender.send(Packet::RegeneratedCode(Some((name, code))))?;
+                    dirty = true;
                 }
 
                 // Set state to approved.
-                Packet::DiscordApproval(uuid) => {
-                    if let Some(state) = user_states.iter_mut().find(|state| state.uuid == uuid) {
+                Packet::DiscordApproval(uuid, server, approver) => match user_states.iter_mut().find(|state| state.uuid == uuid) {
+                    // An approved user being let onto another server.
+                    Some(state) if state.verify_state == VerifyState::APPROVED => {
+                        if let Some(server) = server && !state.servers.contains(&server) {
+                            log!("User {} [{}] was approved for server {server} by {approver}", state.name, state.uuid);
+                            discord_notify.send(Packet::Audit(AuditEntry::new(AuditAction::Approved(Some(server.clone())), &state.name, &state.uuid, state.discord_id, Actor::Discord(approver))))?;
+                            approval_history.record(&state.uuid, approver, timestamp())?;
+                            state.servers.push(server);
+                        }
+                        state.server = None;
+                        state.approvals.clear();
+                        channel.sender.send(Packet::ApprovalSuccess(false, state.name.to_owned(), false, state.guild_id))?;
+                        dirty = true;
+                    }
+
+                    Some(state) => {
+                        let next = match state.verify_state.transition(VerifyAction::Approve) {
+                            Ok(next) => next,
+                            Err(why) => {
+                                log!("Refusing to approve user {} [{}]: {why}", state.name, state.uuid);
+                                channel.sender.send(Packet::ApprovalFailure)?;
+                                continue;
+                            }
+                        };
+
                         log!(
-                            "Successfully linked user {} [{}] to discord account with ID {}",
+                            "Successfully linked user {} [{}] to discord account with ID {}, approved by {approver}",
                             state.name,
                             state.uuid,
                             state.discord_id.unwrap()
                         );
-                        channel.sender.send(Packet::ApprovalSuccess)?;
-                        state.verify_state = VerifyState::APPROVED;
+                        channel.sender.send(Packet::ApprovalSuccess(state.slo_alerts > 0, state.name.to_owned(), true, state.guild_id))?;
+                        let now = timestamp();
+                        state.verify_state = next;
+                        state.approved_at = Some(now);
+                        state.approved_by = Some(approver);
+                        discord_notify.send(Packet::Audit(AuditEntry::new(AuditAction::Approved(server.clone()), &state.name, &state.uuid, state.discord_id, Actor::Discord(approver))))?;
+                        approval_history.record(&state.uuid, approver, now)?;
+                        let _ = events.send(Event::Approved {
+                            name: state.name.to_owned(),
+                            uuid: state.uuid.to_owned(),
+                        });
+                        state.servers.extend(server);
+                        state.server = None;
+                        state.approvals.clear();
                         dirty = true;
-                    } else {
-                        channel.sender.send(Packet::ApprovalFailure)?;
                     }
+
+                    None => channel.sender.send(Packet::ApprovalFailure)?,
+                },
+
+                // Carry an approved link into a linked guild without a code, once discord has posted the member message
+                // there. Discord may give up first, such as when the member message can't be posted.
+                Packet::InheritLink(discord_id, guild_id) => {
+                    let Some(state) = inheritable(&mut user_states, link_scope, discord_id, guild_id) else {
+                        channel.sender.send(Packet::Inheritable(None))?;
+                        continue;
+                    };
+                    channel.sender.send(Packet::Inheritable(Some((state.name.to_owned(), state.uuid.to_owned()))))?;
+
+                    let Some(Packet::InheritedMessage(message_id)) = channel.receiver.recv().await else {
+                        log!("Discord did not carry the link of user {} [{}] into guild {guild_id}", state.name, state.uuid);
+                        continue;
+                    };
+                    log!("User {} [{}] inherited their link into guild {guild_id}", state.name, state.uuid);
+                    state.inherited.push(InheritedLink { guild_id, verify_message: message_id, inherited_at: timestamp() });
+                    discord_notify.send(Packet::Audit(AuditEntry::new(AuditAction::Inherited(guild_id), &state.name, &state.uuid, Some(discord_id), Actor::Bot)))?;
+                    dirty = true;
                 }
 
+                // A moderator's sign-off on an account that needs several. Each moderator only counts once.
+                Packet::RecordApproval(uuid, moderator) => match user_states.iter_mut().find(|state| state.uuid == uuid) {
+                    Some(state) => {
+                        if !state.approvals.contains(&moderator) {
+                            state.approvals.push(moderator);
+                            dirty = true;
+                        }
+                        channel.sender.send(Packet::Approvals(state.approvals.clone()))?;
+                    }
+                    None => channel.sender.send(Packet::Approvals(Vec::new()))?,
+                },
+
+                // Forget a denied user, so the next time they join they get a fresh code and start over.
+                Packet::DiscordDenial(uuid, reason, moderator) => match user_states.iter().position(|state| state.uuid == uuid) {
+                    Some(index) => {
+                        if let Err(why) = user_states[index].verify_state.transition(VerifyAction::Deny) {
+                            log!("Refusing to deny user {} [{}]: {why}", user_states[index].name, user_states[index].uuid);
+                            channel.sender.send(Packet::DenialResult(false))?;
+                            continue;
+                        }
+                        let state = user_states.remove(index);
+                        log!("Denied user {} [{}]: {reason}", state.name, state.uuid);
+                        discord_notify.send(Packet::Audit(AuditEntry::new(AuditAction::Denied(reason), &state.name, &state.uuid, state.discord_id, Actor::Discord(moderator))))?;
+                        approval_history.record_denial(&state.uuid, moderator, timestamp())?;
+                        channel.sender.send(Packet::DenialResult(true))?;
+                        dirty = true;
+                    }
+                    None => channel.sender.send(Packet::DenialResult(false))?,
+                },
+
+                // Banned users keep their entry, so the ban holds however they come back.
+                Packet::BanUser(uuid, moderator) => match user_states.iter_mut().find(|state| state.uuid == uuid) {
+                    Some(state) => {
+                        let next = match state.verify_state.transition(VerifyAction::Ban) {
+                            Ok(next) => next,
+                            Err(why) => {
+                                log!("Refusing to ban user {} [{}]: {why}", state.name, state.uuid);
+                                channel.sender.send(Packet::BanResult(None))?;
+                                continue;
+                            }
+                        };
+
+                        log!("Banned user {} [{}], banned by {moderator}", state.name, state.uuid);
+                        state.verify_state = next;
+                        state.server = None;
+                        state.approvals.clear();
+                        discord_notify.send(Packet::Audit(AuditEntry::new(AuditAction::Banned, &state.name, &state.uuid, state.discord_id, Actor::Discord(moderator))))?;
+                        let reached = sessions.broadcast(Outbound::KickPlayer(state.uuid.to_owned(), localizer.render(None, "connect.banned", &[])));
+                        log!("Sent kick for {} to {reached} game servers", state.uuid);
+                        channel.sender.send(Packet::BanResult(state.verify_message))?;
+                        dirty = true;
+                    }
+                    None => channel.sender.send(Packet::BanResult(None))?,
+                },
+
+                // Unbanned users go back to the queue rather than straight back in.
+                Packet::UnbanUser(uuid, moderator) => match user_states.iter_mut().find(|state| state.uuid == uuid) {
+                    Some(state) => {
+                        let next = match state.verify_state.transition(VerifyAction::Unban) {
+                            Ok(next) => next,
+                            Err(why) => {
+                                log!("Refusing to unban user {} [{}]: {why}", state.name, state.uuid);
+                                channel.sender.send(Packet::BanResult(None))?;
+                                continue;
+                            }
+                        };
+
+                        log!("Unbanned user {} [{}], unbanned by {moderator}", state.name, state.uuid);
+                        state.verify_state = next;
+                        state.pending_since = Some(timestamp());
+                        state.approved_at = None;
+                        state.approved_by = None;
+                        state.slo_alerts = 0;
+                        state.reminded_at = None;
+                        state.servers.clear();
+                        discord_notify.send(Packet::Audit(AuditEntry::new(AuditAction::Unbanned, &state.name, &state.uuid, state.discord_id, Actor::Discord(moderator))))?;
+                        channel.sender.send(Packet::BanResult(state.verify_message))?;
+                        dirty = true;
+                    }
+                    None => channel.sender.send(Packet::BanResult(None))?,
+                },
+
                 // Remove the verification message
-                Packet::RemoveUser(id) => {
-                    if let Some(state) = user_states
-                        .iter()
-                        .filter(|state| state.discord_id == Some(id))
-                        .next()
-                    {
-                        log!(
-                            "Unlinking user {} [{}] from discord account with ID {}",
-                            state.name,
-                            state.uuid,
-                            state.discord_id.unwrap()
-                        );
-                        channel
-                            .sender
-                            .send(Packet::RemoveMessage(state.verify_message.unwrap()))?;
+                Packet::RemoveUser(id, actor) => {
+                    let removed = remove_user(&mut user_states, &sessions, &discord_notify, &messages, id, None, actor)?;
+                    channel.sender.send(Packet::RemoveMessages(removed.into_iter().flat_map(|unlinked| unlinked.messages).collect()))?;
+                    dirty = true;
+                }
+
+                // Remove a player by UUID on behalf of an in-game admin.
+                Packet::RemovePlayer(uuid, server) => {
+                    let Some(state) = user_states.iter().find(|state| state.uuid == uuid) else {
+                        channel.sender.send(Packet::RemovePlayerResult(false))?;
+                        continue;
+                    };
+
+                    match state.discord_id {
+                        // Only this account is unlinked, so the user keeps the role in each guild where another of
+                        // theirs is approved.
+                        Some(discord_id) => {
+                            for unlinked in remove_user(&mut user_states, &sessions, &discord_notify, &messages, discord_id, Some(&uuid), Actor::GameServer(server))? {
+                                let lost = unlinked.guilds.into_iter().filter(|guild_id| !holds_role(&user_states, discord_id, *guild_id)).collect();
+                                discord_notify.send(Packet::UnlinkMember(discord_id, unlinked.messages, lost))?;
+                            }
+                        }
+
+                        // Players that haven't linked yet only have a code to throw away.
+                        None => user_states.retain(|state| state.uuid != uuid),
                     }
+                    channel.sender.send(Packet::RemovePlayerResult(true))?;
+                    dirty = true;
+                }
 
-                    user_states.retain(|state| state.discord_id != Some(id));
+                // Unlink the user and forget they were ever approved. Forgetting someone twice does nothing.
+                Packet::ForgetUser(id, requester) => {
+                    let removed = remove_user(&mut user_states, &sessions, &discord_notify, &messages, id, None, Actor::Discord(requester))?;
+                    for unlinked in &removed {
+                        approval_history.forget(&unlinked.uuid)?;
+                    }
+                    approval_history.forget_moderator(id)?;
+                    channel.sender.send(Packet::RemoveMessages(removed.into_iter().flat_map(|unlinked| unlinked.messages).collect()))?;
                     dirty = true;
                 }
 
-                Packet::UserQuery(uuid, id) => {
-                    let success = !user_states.iter().any(|state| state.uuid == uuid || state.discord_id == Some(id));
-                    channel.sender.send(Packet::UserResponse(success))?;
+                Packet::UserQuery(uuid, id, max_accounts) => {
+                    // Accounts are linked by hand in the main guild.
+                    let others = linked_names(&user_states, id, link_scope, None);
+                    let success = others.len() < max_accounts as usize && !user_states.iter().any(|state| state.uuid == uuid);
+                    channel.sender.send(Packet::UserResponse(success, approval_history.contains(&uuid), others))?;
                     if success {
-                        let Some(Packet::AddUserManually(name, uuid, discord_id, message_id)) = channel.receiver.recv().await else { return Err(anyhow!("Thread did not reply to add user manually!")) };
-                        user_states.push(UserState::complete(&name, &uuid, discord_id, message_id));
+                        let Some(Packet::AddUserManually(name, uuid, discord_id, message_id, priority, moderator)) = channel.receiver.recv().await else { return Err(anyhow!("Thread did not reply to add user manually!")) };
+                        discord_notify.send(Packet::Audit(AuditEntry::new(AuditAction::Linked, &name, &uuid, Some(discord_id), Actor::Discord(moderator))))?;
+                        user_states.push(UserState::complete(&name, &uuid, discord_id, message_id, priority));
+                        dirty = true;
+                    }
+                }
+
+                Packet::ApprovedQuery(id) => channel.sender.send(Packet::ApprovedResponse(holds_role(&user_states, id, None)))?,
+
+                Packet::ListAll => channel.sender.send(Packet::AllUsers(user_states.iter().map(LinkDetails::of).collect()))?,
+
+                Packet::ListPending => {
+                    let pending = user_states
+                        .iter()
+                        .filter(|state| state.verify_state == VerifyState::PENDING)
+                        .map(|state| PendingUser {
+                            name: state.name.to_owned(),
+                            uuid: state.uuid.to_owned(),
+                            discord_id: state.discord_id,
+                            verify_message: state.verify_message,
+                            pending_since: state.pending_since,
+                            slo_alerts: state.slo_alerts,
+                            reminded_at: state.reminded_at,
+                            priority: state.priority,
+                        })
+                        .collect();
+                    channel.sender.send(Packet::PendingList(pending))?;
+                }
+
+                // Users with several accounts are looked up by their approved one, if they have one.
+                Packet::LookupByDiscord(id) => {
+                    let mut linked = user_states.iter().filter(|state| state.discord_id == Some(id));
+                    let details = linked.clone().find(|state| state.verify_state == VerifyState::APPROVED).or(linked.next()).map(LinkDetails::of);
+                    channel.sender.send(Packet::LookupResult(details))?;
+                }
+
+                Packet::LookupByName(name) => {
+                    let details = user_states.iter().find(|state| state.name.eq_ignore_ascii_case(&name)).map(LinkDetails::of);
+                    channel.sender.send(Packet::LookupResult(details))?;
+                }
+
+                // Remember which SLO alerts went out so they aren't repeated after a restart.
+                Packet::SloAlerted(uuid, level) => {
+                    if let Some(state) = user_states.iter_mut().find(|state| state.uuid == uuid) {
+                        state.slo_alerts = level;
                         dirty = true;
                     }
                 }
 
+                Packet::Reminded(uuids) => {
+                    let now = timestamp();
+                    for state in user_states.iter_mut().filter(|state| uuids.contains(&state.uuid)) {
+                        state.reminded_at = Some(now);
+                    }
+                    dirty = true;
+                }
+
+                // Send back how long each approval since the given time took.
+                Packet::ApprovalTimesQuery(since) => {
+                    let times = user_states
+                        .iter()
+                        .filter_map(|state| Some((state.pending_since?, state.approved_at?)))
+                        .filter(|(_, approved_at)| *approved_at >= since)
+                        .collect();
+                    channel.sender.send(Packet::ApprovalTimesResponse(times))?;
+                }
+
+                Packet::DecisionsQuery(from, to) => channel.sender.send(Packet::DecisionsResponse(approval_history.decisions(from, to)))?,
+
+                // Codes are only pruned once per loop, so one that has just run out reports zero rather than going negative.
+                Packet::CodeTtlQuery(uuid) => {
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_millis();
+                    let remaining = user_states
+                        .iter()
+                        .find(|state| state.uuid == uuid && state.verify_code.is_some())
+                        .and_then(|state| state.code_expires)
+                        .map(|expires| expires.saturating_sub(now) as u64);
+                    channel.sender.send(Packet::CodeTtlResponse(remaining))?;
+                }
+
+                // Linked players are answered once discord has looked up their username.
+                Packet::WhoisQuery(uuid) => {
+                    let whois = user_states.iter().find(|state| state.uuid == uuid).map(|state| (state.verify_state, state.discord_id));
+                    match whois {
+                        Some((verify_state, Some(discord_id))) => {
+                            let discord_requests = discord_requests.clone();
+                            tokio::spawn(async move {
+                                let discord_name = discord_username(&discord_requests, discord_id).await.unwrap_or_else(|why| {
+                                    log!("Error looking up discord user {discord_id}: {why:?}");
+                                    None
+                                });
+                                let _ = channel.sender.send(Packet::WhoisResponse(Some((verify_state, Some(discord_id), discord_name))));
+                            });
+                        }
+                        whois => channel.sender.send(Packet::WhoisResponse(whois.map(|(verify_state, _)| (verify_state, None, None))))?,
+                    }
+                }
+
+                Packet::RoundTripQuery => channel.sender.send(Packet::RoundTripResponse(sessions.round_trips()))?,
+
+                // Relay players joining and leaving the Minecraft server to discord.
+                Packet::PlayerJoined(uuid) => relay_presence(&user_states, &discord_notify, &events, &uuid, true)?,
+                Packet::PlayerLeft(uuid) => relay_presence(&user_states, &discord_notify, &events, &uuid, false)?,
+                Packet::ServerDisconnected => discord_notify.send(Packet::ServerDisconnected)?,
+
+                // Add a finished session to the player's playtime and refresh their member message.
+                Packet::SessionEnd(uuid, seconds) => {
+                    let Some(state) = user_states.iter_mut().find(|state| state.uuid == uuid) else {
+                        log!("Ignoring session end for unknown player {uuid}");
+                        continue;
+                    };
+
+                    state.playtime_seconds = state.playtime_seconds.saturating_add(seconds);
+                    if let Some(message_id) = state.verify_message {
+                        discord_notify.send(Packet::UpdatePlaytime(message_id, state.playtime_seconds))?;
+                    }
+                    dirty = true;
+                }
+
+                // Relay chat from approved players to discord.
+                Packet::ChatMessage(uuid, message) => {
+                    if let Some(state) = user_states.iter().find(|state| state.uuid == uuid && state.verify_state == VerifyState::APPROVED) {
+                        discord_notify.send(Packet::RelayChat(state.name.to_owned(), message))?;
+                    }
+                }
+
+                // Send back every approved user for the game server to whitelist.
+                Packet::WhitelistQuery => {
+                    let users = user_states
+                        .iter()
+                        .filter(|state| state.verify_state == VerifyState::APPROVED)
+                        .map(|state| (state.name.to_owned(), state.uuid.to_owned()))
+                        .collect();
+                    channel.sender.send(Packet::WhitelistResponse(users))?;
+                }
+
+                // Ask the game servers for their whitelists. The answer arrives later as an upload.
+                Packet::WhitelistDiffQuery => {
+                    let reached = sessions.broadcast(Outbound::RequestWhitelist);
+                    if reached == 0 {
+                        channel.sender.send(Packet::WhitelistDiffResponse(None))?;
+                    } else {
+                        whitelist_waiters.push(channel);
+                    }
+                }
+
+                Packet::WhitelistUpload(uuids, server) => {
+                    let diff = whitelist_diff(&user_states, uuids, server);
+                    log!(
+                        "Whitelist of {} has {} missing and {} unknown players",
+                        diff.server.as_deref().unwrap_or("the game server"),
+                        diff.missing.len(),
+                        diff.unknown.len()
+                    );
+                    // Whoever asked may have given up waiting already.
+                    for waiter in whitelist_waiters.drain(..) {
+                        let _ = waiter.sender.send(Packet::WhitelistDiffResponse(Some(diff.clone())));
+                    }
+                }
+
+                Packet::PushWhitelist(users) => {
+                    let reached = sessions.broadcast(Outbound::AddToWhitelist(users));
+                    channel.sender.send(Packet::PushedWhitelist(reached))?;
+                }
+
+                Packet::Announce(message) => {
+                    let reached = sessions.broadcast(Outbound::Announce(message));
+                    channel.sender.send(Packet::Announced(reached))?;
+                }
+
                 x => return Err(anyhow!("Unexpected packet {x:?} received in main loop!")),
             }
         }
 
+        checks.summarize_if_due();
+
         // Remove expired codes
         let time = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("Time went backwards")
             .as_millis();
+        for state in user_states.iter() {
+            if let (Some(code), Some(expires)) = (state.verify_code, state.code_expires) && expires <= time {
+                expired_codes.insert(code, (expires, state.name.to_owned(), state.uuid.to_owned()));
+                discord_notify.send(Packet::Audit(AuditEntry::new(AuditAction::CodeExpired, &state.name, &state.uuid, None, Actor::Bot)))?;
+            }
+        }
         user_states.retain(|state| state.code_expires.is_none_or(|expired| expired > time));
+        expired_codes.retain(|_, (expired, _, _)| *expired + EXPIRED_CODE_MEMORY > time);
+        code_attempts.retain(|_, (_, _, expired)| *expired + EXPIRED_CODE_MEMORY > time);
 
         // Update the config file
         if dirty {
-            let _ = std::fs::remove_file(USERS_FILE);
-            let mut file = File::create_new(USERS_FILE)?;
-            serde_json::to_writer_pretty(
-                &mut file,
-                &user_states
-                    .iter()
-                    .filter(|state| {
-                        matches!(
-                            state.verify_state,
-                            VerifyState::PENDING | VerifyState::APPROVED
-                        )
-                    })
-                    .collect::<Vec<&UserState>>(),
-            )?;
+            save_users(&user_states)?;
             dirty = false;
         }
+
+        // Handlers and tcp clients have drained by now, so stop the listener, write the users one last time and exit.
+        if shutdown.is_finished() {
+            let _ = tcp_task.await;
+            save_users(&user_states)?;
+            if let Err(why) = external_cache.save() {
+                log!("Error saving the external lookup cache: {why:?}");
+            }
+            log!("Shutdown complete");
+            return Ok(());
+        }
+    }
+}
+
+// Decide whether a player may join, giving players the bot hasn't seen before a code. Single and batched connect
+// queries both go through here.
+#[allow(clippy::too_many_arguments)]
+fn connect_query(
+    user_states: &mut Vec<UserState>,
+    checks: &mut Checks,
+    localizer: &Localizer,
+    random: &mut impl Rng,
+    discord_notify: &UnboundedSender<Packet>,
+    dirty: &mut bool,
+    name: &str,
+    uuid: &str,
+    locale: Option<&str>,
+    server: Option<&str>,
+) -> Result<(ConnectResult, String, Option<i32>)> {
+    if let Some(reason) = checks.run(Stage::Connect, &Subject { name, uuid }) {
+        let response = localizer.render(locale, "connect.denied", &[("reason", &reason)]);
+        log!("Disconnecting user {name} [{uuid}]: {response}");
+        return Ok((ConnectResult::Denied, response, None));
+    }
+
+    // Insert a new code if there isn't one already
+    if !user_states.iter().any(|state| state.uuid == uuid) {
+        let code = new_code(user_states, random);
+        user_states.push(UserState::new(name, uuid, code, server.map(str::to_owned)));
+        discord_notify.send(Packet::Audit(AuditEntry::new(AuditAction::CodeIssued, name, uuid, None, Actor::GameServer(server.map(str::to_owned)))))?;
+    }
+
+    // Players who changed their Minecraft name show up asking under the new one.
+    if let Some(state) = user_states.iter_mut().find(|state| state.uuid == uuid) && state.name != name {
+        log!("User {} [{uuid}] is now called {name}", state.name);
+        state.name = name.to_owned();
+        if let (Some(message_id), Some(discord_id)) = (state.verify_message, state.discord_id) {
+            discord_notify.send(Packet::RenameMember(message_id, discord_id, name.to_owned(), uuid.to_owned()))?;
+        }
+        *dirty = true;
+    }
+
+    // Send the verification message back. If the user is verified, send nothing.
+    let state = user_states.iter().find(|state| state.uuid == uuid).unwrap();
+    Ok(match state.verify_state {
+        VerifyState::NEW => {
+            let code = state.verify_code.unwrap();
+            let response = localizer.render(locale, "connect.code_prompt", &[("code", &code.to_string())]);
+            log!("Disconnecting user {name} [{uuid}]: {response}");
+            (ConnectResult::NeedsCode, response, Some(code))
+        }
+
+        VerifyState::PENDING => {
+            let key = priority::queue_key(state.priority, state.pending_since);
+            let position = 1 + user_states
+                .iter()
+                .filter(|other| other.verify_state == VerifyState::PENDING)
+                .filter(|other| priority::queue_key(other.priority, other.pending_since) < key)
+                .count();
+            let response = localizer.render(locale, "connect.pending", &[("position", &position.to_string())]);
+            log!("Disconnecting user {name} [{uuid}]: {response}");
+            (ConnectResult::Pending, response, None)
+        }
+
+        // Approved on another server, so ask the moderators about this one too.
+        VerifyState::APPROVED if let Some(server) = server && !state.approved_for(server) => {
+            let state = user_states.iter_mut().find(|state| state.uuid == uuid).unwrap();
+            if state.server.is_none() && let (Some(discord_id), Some(message_id)) = (state.discord_id, state.verify_message) {
+                log!("User {name} [{uuid}] is requesting access to server {server}");
+                state.server = Some(server.to_owned());
+                discord_notify.send(Packet::RequestServerAccess(message_id, discord_id, uuid.to_owned(), server.to_owned()))?;
+                *dirty = true;
+            }
+
+            let response = localizer.render(locale, "connect.server_pending", &[("server", server)]);
+            log!("Disconnecting user {name} [{uuid}]: {response}");
+            (ConnectResult::Pending, response, None)
+        }
+
+        VerifyState::APPROVED => {
+            log!("User {name} [{uuid}] is verified.");
+            (ConnectResult::Allowed, String::new(), None)
+        }
+
+        VerifyState::BANNED => {
+            let response = localizer.render(locale, "connect.banned", &[]);
+            log!("Disconnecting banned user {name} [{uuid}]: {response}");
+            (ConnectResult::Denied, response, None)
+        }
+    })
+}
+
+// An account that was unlinked, with its member messages by the linked guild whose member channel each is in (None
+// for the main guild's), and the guilds it gave the verified role in.
+struct Unlinked {
+    uuid: String,
+    messages: Vec<(Option<u64>, u64)>,
+    guilds: Vec<Option<u64>>,
+}
+
+// Remove every account linked to a discord account, or only the one with the given uuid.
+fn remove_user(user_states: &mut Vec<UserState>, sessions: &Sessions, discord_notify: &UnboundedSender<Packet>, messages: &Messages, id: u64, only: Option<&str>, actor: Actor) -> Result<Vec<Unlinked>> {
+    let mut removed = Vec::new();
+    for state in user_states.iter().filter(|state| state.discord_id == Some(id) && only.is_none_or(|uuid| state.uuid == uuid)) {
+        // A ban has to outlive unlinking and leaving the guild, or it could be shaken off. Unban first.
+        if state.verify_state == VerifyState::BANNED {
+            log!("Not unlinking banned user {} [{}]", state.name, state.uuid);
+            continue;
+        }
+        log!(
+            "Unlinking user {} [{}] from discord account with ID {}",
+            state.name,
+            state.uuid,
+            id
+        );
+
+        // Kick the player now rather than waiting for them to relog.
+        let reached = sessions.broadcast(Outbound::KickPlayer(
+            state.uuid.to_owned(),
+            messages.render("kick.unlinked", &[("name", &state.name), ("uuid", &state.uuid), ("discord_id", &id.to_string())]),
+        ));
+        log!("Sent kick for {} to {reached} game servers", state.uuid);
+
+        discord_notify.send(Packet::Audit(AuditEntry::new(AuditAction::Unlinked, &state.name, &state.uuid, Some(id), actor.clone())))?;
+        let messages = [(None, state.verify_message.unwrap())]
+            .into_iter()
+            .chain(state.inherited.iter().map(|inherited| (Some(inherited.guild_id), inherited.verify_message)))
+            .collect();
+        let guilds = [state.guild_id].into_iter().chain(state.inherited.iter().map(|inherited| Some(inherited.guild_id))).collect();
+        removed.push(Unlinked { uuid: state.uuid.to_owned(), messages, guilds });
     }
+    user_states.retain(|state| !removed.iter().any(|unlinked| unlinked.uuid == state.uuid));
+    Ok(removed)
+}
+
+// The Minecraft names of the accounts a discord user has linked, only counting those in the given guild (None for
+// the main guild) when each guild has its own links.
+fn linked_names(user_states: &[UserState], id: u64, scope: LinkScope, guild_id: Option<u64>) -> Vec<String> {
+    user_states
+        .iter()
+        .filter(|state| state.discord_id == Some(id) && (scope == LinkScope::Global || state.guild_id == guild_id))
+        .map(|state| state.name.to_owned())
+        .collect()
+}
+
+// Whether a discord user has an approved account giving them the verified role in a guild, None for the main guild.
+fn holds_role(user_states: &[UserState], id: u64, guild_id: Option<u64>) -> bool {
+    user_states.iter().any(|state| {
+        state.discord_id == Some(id)
+            && state.verify_state == VerifyState::APPROVED
+            && (state.guild_id == guild_id || state.inherited.iter().any(|inherited| Some(inherited.guild_id) == guild_id))
+    })
+}
+
+// The approved account a discord user's link is carried into a linked guild from under the global link scope, their
+// first if they have several. None when links are per guild, or the user has nothing approved or has already
+// inherited into the guild.
+fn inheritable(user_states: &mut [UserState], scope: LinkScope, id: u64, guild_id: u64) -> Option<&mut UserState> {
+    if scope != LinkScope::Global || holds_role(user_states, id, Some(guild_id)) {
+        return None;
+    }
+    user_states.iter_mut().find(|state| state.discord_id == Some(id) && state.verify_state == VerifyState::APPROVED)
+}
+
+fn save_users(user_states: &[UserState]) -> Result<()> {
+    let _ = std::fs::remove_file(USERS_FILE);
+    let mut file = File::create_new(USERS_FILE)?;
+    serde_json::to_writer_pretty(
+        &mut file,
+        &user_states
+            .iter()
+            .filter(|state| {
+                matches!(
+                    state.verify_state,
+                    VerifyState::PENDING | VerifyState::APPROVED | VerifyState::BANNED
+                )
+            })
+            .collect::<Vec<&UserState>>(),
+    )?;
+    Ok(())
+}
+
+fn relay_presence(user_states: &[UserState], discord_notify: &UnboundedSender<Packet>, events: &EventSender, uuid: &str, joined: bool) -> Result<()> {
+    let Some(state) = user_states.iter().find(|state| state.uuid == uuid) else {
+        log!("Ignoring presence update for unknown player {uuid}");
+        return Ok(());
+    };
+
+    let (name, uuid) = (state.name.to_owned(), state.uuid.to_owned());
+    let _ = events.send(if joined {
+        Event::PlayerJoined { name: name.clone(), uuid: uuid.clone() }
+    } else {
+        Event::PlayerLeft { name: name.clone(), uuid: uuid.clone() }
+    });
+    discord_notify.send(Packet::PlayerPresence(name, uuid, state.discord_id, joined))?;
+    Ok(())
 }
 
 struct ChannelPair<T> {
@@ -253,6 +930,33 @@ impl<T> ChannelPair<T> {
     }
 }
 
+// Ask the discord thread for a user's name, None if discord doesn't know them.
+async fn discord_username(discord_requests: &UnboundedSender<ChannelPair<Packet>>, discord_id: u64) -> Result<Option<String>> {
+    let mut pair = ChannelPair::new();
+    discord_requests.send(pair.entangle())?;
+    pair.sender.send(Packet::DiscordUserQuery(discord_id))?;
+    let Some(Packet::DiscordUserResponse(name)) = pair.receiver.recv().await else { return Err(anyhow!("Discord thread did not respond with a username!")) };
+    Ok(name)
+}
+
+// A code no one else currently has.
+fn new_code(user_states: &[UserState], random: &mut impl Rng) -> i32 {
+    loop {
+        let code = random.random_range(100000..1000000);
+        if !user_states.iter().any(|state| state.verify_code == Some(code)) {
+            return code;
+        }
+    }
+}
+
+// Milliseconds since the unix epoch.
+fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as u64
+}
+
 #[derive(Serialize, Deserialize)]
 struct UserState {
     name: String,
@@ -260,6 +964,39 @@ struct UserState {
     discord_id: Option<u64>,
     verify_state: VerifyState,
     verify_message: Option<u64>,
+    #[serde(default)]
+    pending_since: Option<u64>,
+    #[serde(default)]
+    approved_at: Option<u64>,
+    // Discord ID of the moderator who approved the account.
+    #[serde(default)]
+    approved_by: Option<u64>,
+    // Moderators who have signed off so far, when approvals need more than one.
+    #[serde(default)]
+    approvals: Vec<u64>,
+    #[serde(default)]
+    slo_alerts: u8,
+    // When the user was last listed in a reminder digest, in milliseconds since the unix epoch.
+    #[serde(default)]
+    reminded_at: Option<u64>,
+    #[serde(default)]
+    priority: Priority,
+    #[serde(default)]
+    playtime_seconds: u64,
+    // The game server the user's latest request came from, while it waits for approval.
+    #[serde(default)]
+    server: Option<String>,
+    // Game servers the user is approved for. Empty means every server, as for users approved before the bot
+    // handled several servers.
+    #[serde(default)]
+    servers: Vec<String>,
+    // The linked guild the account was linked for, whose verified role it gives, when each guild has its own links.
+    // None for the main guild.
+    #[serde(default)]
+    guild_id: Option<u64>,
+    // Linked guilds the approval was carried into when links are global.
+    #[serde(default)]
+    inherited: Vec<InheritedLink>,
 
     #[serde(skip_serializing, skip_deserializing)]
     verify_code: Option<i32>,
@@ -267,60 +1004,457 @@ struct UserState {
     code_expires: Option<u128>,
 }
 
+// A linked guild's copy of an approval, made without a code, and the member message posted there for it.
+#[derive(Serialize, Deserialize)]
+struct InheritedLink {
+    guild_id: u64,
+    verify_message: u64,
+    inherited_at: u64,
+}
+
 impl UserState {
-    fn new(name: &str, uuid: &str, code: i32) -> Self {
+    fn new(name: &str, uuid: &str, code: i32, server: Option<String>) -> Self {
         Self {
             name: name.to_owned(),
             uuid: uuid.to_owned(),
             discord_id: None,
             verify_state: VerifyState::NEW,
             verify_message: None,
+            pending_since: None,
+            approved_at: None,
+            approved_by: None,
+            approvals: Vec::new(),
+            slo_alerts: 0,
+            reminded_at: None,
+            priority: Priority::Normal,
+            playtime_seconds: 0,
+            server,
+            servers: Vec::new(),
+            guild_id: None,
+            inherited: Vec::new(),
             verify_code: Some(code),
             code_expires: Some(
                 SystemTime::now()
                     .duration_since(UNIX_EPOCH)
                     .expect("Time went backwards")
                     .as_millis()
-                    + (1000 * 30),
+                    + CODE_LIFETIME,
             ),
         }
     }
 
-    fn complete(name: &str, uuid: &str, discord_id: u64, message_id: u64) -> Self {
+    fn complete(name: &str, uuid: &str, discord_id: u64, message_id: u64, priority: Priority) -> Self {
         Self {
             name: name.to_string(),
             uuid: uuid.to_string(),
             discord_id: Some(discord_id),
             verify_state: VerifyState::PENDING,
             verify_message: Some(message_id),
+            pending_since: Some(timestamp()),
+            approved_at: None,
+            approved_by: None,
+            approvals: Vec::new(),
+            slo_alerts: 0,
+            reminded_at: None,
+            priority,
+            playtime_seconds: 0,
+            server: None,
+            servers: Vec::new(),
+            guild_id: None,
+            inherited: Vec::new(),
             verify_code: None,
             code_expires: None,
         }
     }
+
+    fn approved_for(&self, server: &str) -> bool {
+        self.servers.is_empty() || self.servers.iter().any(|approved| approved == server)
+    }
 }
 
-#[derive(Eq, PartialEq, Serialize, Deserialize)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Serialize, Deserialize)]
 enum VerifyState {
     NEW,
     PENDING,
     APPROVED,
+    BANNED,
+}
+
+// What moves a user from one verify state to the next.
+#[derive(Clone, Copy, Debug)]
+enum VerifyAction {
+    // A player entered their code in discord.
+    Link,
+    // A moderator approved the account.
+    Approve,
+    // A moderator denied the account, which is forgotten until the player next joins.
+    Deny,
+    // A player asked for a fresh code after theirs ran out.
+    RegenerateCode,
+    Ban,
+    // A moderator lifted a ban, putting the account back in the queue.
+    Unban,
+}
+
+#[derive(Debug)]
+struct TransitionError {
+    from: VerifyState,
+    action: VerifyAction,
+}
+
+impl Display for TransitionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} is not allowed for a user who is {:?}", self.action, self.from)
+    }
+}
+
+impl std::error::Error for TransitionError {}
+
+impl VerifyState {
+    // Every legal transition is listed here, and everything else is refused, so state changes go through this
+    // rather than assigning the next state directly.
+    fn transition(self, action: VerifyAction) -> Result<Self, TransitionError> {
+        match (self, action) {
+            (Self::NEW, VerifyAction::Link) => Ok(Self::PENDING),
+            (Self::PENDING, VerifyAction::Approve) => Ok(Self::APPROVED),
+            (Self::PENDING, VerifyAction::Deny) => Ok(Self::NEW),
+            (Self::NEW, VerifyAction::RegenerateCode) => Ok(Self::NEW),
+            (Self::PENDING | Self::APPROVED, VerifyAction::Ban) => Ok(Self::BANNED),
+            (Self::BANNED, VerifyAction::Unban) => Ok(Self::PENDING),
+            (from, action) => Err(TransitionError { from, action }),
+        }
+    }
+}
+
+// Whether a player may join. The values are what gets sent to the plugin.
+#[derive(Clone, Copy, Debug)]
+enum ConnectResult {
+    Allowed = 0,
+    NeedsCode = 1,
+    Pending = 2,
+    Denied = 3,
 }
 
 #[derive(Debug)]
 enum Packet {
-    ConnectQuery(String, String),
-    ConnectResponse(String),
-    DiscordCode(i32, u64),
-    DiscordApproval(String),
-    VerifyPending(String, String),
-    LinkVerifyMessage(u64),
+    // Name, uuid, locale and the game server asking.
+    ConnectQuery(String, String, Option<String>, Option<String>),
+    // Result, message for the player and verification code if they need one.
+    ConnectResponse(ConnectResult, String, Option<i32>),
+    // Name, uuid and locale of each player, and the game server asking.
+    ConnectQueryBatch(Vec<(String, String, Option<String>)>, Option<String>),
+    // One connect response per player, in the order they were asked about.
+    ConnectBatchResponse(Vec<(ConnectResult, String, Option<i32>)>),
+    // Code, discord ID, how many accounts one discord user may link and the linked guild the code was entered in, None
+    // for the main guild or a DM.
+    DiscordCode(i32, u64, u8, Option<u64>),
+    // UUID, the game server the approval is for and the discord ID of the approving moderator.
+    DiscordApproval(String, Option<String>, u64),
+    // UUID and the discord ID of a moderator signing off on it.
+    RecordApproval(String, u64),
+    // Every moderator who has signed off so far, empty if the user is gone.
+    Approvals(Vec<u64>),
+    // UUID, the reason a moderator gave for denying the account and the moderator's discord ID.
+    DiscordDenial(String, String, u64),
+    // Whether the user was still pending and has been removed.
+    DenialResult(bool),
+    // UUID and the discord ID of the moderator banning (or unbanning) the account.
+    BanUser(String, u64),
+    UnbanUser(String, u64),
+    // The member message ID of the user, None if they couldn't be banned (or unbanned).
+    BanResult(Option<u64>),
+    // Uuid, name, whether the account was approved before, the game server it's for, the names of the other accounts
+    // the discord user has linked and the linked guild the link is for, None for the main guild.
+    VerifyPending(String, String, bool, Option<String>, Vec<String>, Option<u64>),
+    LinkVerifyMessage(u64, Priority),
     AlreadyLinked,
     VerifyCodeInvalid,
-    RemoveUser(u64),
-    RemoveMessage(u64),
-    ApprovalSuccess,
+    VerifyCodeExpired(u64),
+    // When the user's lockout for entering invalid codes ends, and whether to tell them about it.
+    LockedOut(u64, bool),
+    // Discord ID of a user to lift the lockout of.
+    ClearLockout(u64),
+    // Whether the user had entered any invalid codes to forget.
+    LockoutCleared(bool),
+    // Discord ID of a user asking for a fresh code.
+    RegenerateCode(u64),
+    // The name of the account and its new code, None if the user has no expired code to replace.
+    RegeneratedCode(Option<(String, i32)>),
+    LinkRejected(String),
+    // Discord ID and who asked for the removal.
+    RemoveUser(u64, Actor),
+    // Discord ID and the discord ID of whoever asked to forget them.
+    ForgetUser(u64, u64),
+    // Uuid and the game server asking.
+    RemovePlayer(String, Option<String>),
+    // Whether the player was found.
+    RemovePlayerResult(bool),
+    // Discord ID of an account unlinked outside of discord, its member messages by the linked guild whose member
+    // channel each is in (None for the main guild's), and the guilds the user no longer has an approved account in
+    // and so loses the verified role in.
+    UnlinkMember(u64, Vec<(Option<u64>, u64)>, Vec<Option<u64>>),
+    // Member messages of every account that was unlinked, by the linked guild whose member channel each is in.
+    RemoveMessages(Vec<(Option<u64>, u64)>),
+    // Whether the approval had breached the approval SLO, the user's Minecraft name, whether this was their first
+    // approval rather than one for another server, and the linked guild to give the verified role in.
+    ApprovalSuccess(bool, String, bool, Option<u64>),
+    // Discord ID of an approved user and the linked guild to carry their link into.
+    InheritLink(u64, u64),
+    // The name and uuid of the account being inherited, None if there's nothing to inherit.
+    Inheritable(Option<(String, String)>),
+    // The member message posted in the linked guild for the inherited link.
+    InheritedMessage(u64),
     ApprovalFailure,
-    AddUserManually(String, String, u64, u64),
-    UserQuery(String, u64),
-    UserResponse(bool),
+    // Name, uuid, discord ID, member message ID, priority and the discord ID of the moderator linking them.
+    AddUserManually(String, String, u64, u64, Priority, u64),
+    // A verification step for the audit channel.
+    Audit(AuditEntry),
+    // Uuid, discord ID and how many accounts one discord user may link.
+    UserQuery(String, u64, u8),
+    // Whether the user can be added, whether the account was approved before and the names of the other accounts
+    // the discord user has linked.
+    UserResponse(bool, bool, Vec<String>),
+    WhitelistQuery,
+    WhitelistResponse(Vec<(String, String)>),
+    ListPending,
+    PendingList(Vec<PendingUser>),
+    // Every known user, for reconciling with the guild.
+    ListAll,
+    AllUsers(Vec<LinkDetails>),
+    // Whether the discord user has an approved account giving them the verified role in the main guild.
+    ApprovedQuery(u64),
+    ApprovedResponse(bool),
+    LookupByDiscord(u64),
+    // Minecraft name, matched case-insensitively.
+    LookupByName(String),
+    LookupResult(Option<LinkDetails>),
+    SloAlerted(String, u8),
+    // Uuids of the pending users a reminder digest just listed.
+    Reminded(Vec<String>),
+    ApprovalTimesQuery(u64),
+    ApprovalTimesResponse(Vec<(u64, u64)>),
+    // The approvals and denials moderators made in a range, for the staff leaderboard.
+    DecisionsQuery(u64, u64),
+    DecisionsResponse(Vec<Decision>),
+    CodeTtlQuery(String),
+    // Milliseconds until the player's code expires, if they have one.
+    CodeTtlResponse(Option<u64>),
+    RoundTripQuery,
+    // The server name or address of each connection that answers pings, and its latest ping round trip.
+    RoundTripResponse(Vec<(String, Duration)>),
+    PlayerJoined(String),
+    PlayerLeft(String),
+    // Name, uuid, linked discord ID and whether the player joined (or left).
+    PlayerPresence(String, String, Option<u64>, bool),
+    // A game server that relayed players went away, taking its players offline with it.
+    ServerDisconnected,
+    ChatMessage(String, String),
+    RelayChat(String, String),
+    // UUID and length of a finished play session in seconds.
+    SessionEnd(String, u64),
+    // Member message ID and the player's total playtime in seconds.
+    UpdatePlaytime(u64, u64),
+    // Member message ID, discord ID, and the player's new Minecraft name and uuid.
+    RenameMember(u64, u64, String, String),
+    // Member message ID, discord ID, uuid and game server of an approved user asking to join another server.
+    RequestServerAccess(u64, u64, String, String),
+    WhitelistDiffQuery,
+    // None when no game server was connected to ask.
+    WhitelistDiffResponse(Option<WhitelistDiff>),
+    // Whitelisted uuids and the game server that uploaded them.
+    WhitelistUpload(Vec<String>, Option<String>),
+    // Names and uuids to add to every connected game server's whitelist.
+    PushWhitelist(Vec<(String, String)>),
+    // How many game servers the push reached.
+    PushedWhitelist(usize),
+    // Uuid of the player to look up.
+    WhoisQuery(String),
+    // Verify state, discord ID and discord username, or None for players the bot has never seen.
+    WhoisResponse(Option<(VerifyState, Option<u64>, Option<String>)>),
+    // Discord ID to look up the username of, asked of the discord thread.
+    DiscordUserQuery(u64),
+    DiscordUserResponse(Option<String>),
+    // A staff announcement for every connected game server's chat.
+    Announce(String),
+    // How many game servers the announcement reached.
+    Announced(usize),
+}
+
+/// How a game server's whitelist differs from the users approved for it.
+#[derive(Clone, Debug)]
+struct WhitelistDiff {
+    server: Option<String>,
+    // Names and uuids approved but not whitelisted.
+    missing: Vec<(String, String)>,
+    // Whitelisted uuids nobody approved.
+    unknown: Vec<String>,
+    matching: usize,
+}
+
+fn whitelist_diff(user_states: &[UserState], uuids: Vec<String>, server: Option<String>) -> WhitelistDiff {
+    let whitelisted = uuids.into_iter().collect::<HashSet<_>>();
+    let approved = user_states
+        .iter()
+        .filter(|state| state.verify_state == VerifyState::APPROVED)
+        .filter(|state| server.as_deref().is_none_or(|server| state.approved_for(server)))
+        .collect::<Vec<_>>();
+
+    let missing = approved
+        .iter()
+        .filter(|state| !whitelisted.contains(&state.uuid))
+        .map(|state| (state.name.to_owned(), state.uuid.to_owned()))
+        .collect::<Vec<_>>();
+    let approved_uuids = approved.iter().map(|state| &state.uuid).collect::<HashSet<_>>();
+    let mut unknown = whitelisted
+        .iter()
+        .filter(|uuid| !approved_uuids.contains(uuid))
+        .cloned()
+        .collect::<Vec<_>>();
+    unknown.sort();
+
+    WhitelistDiff {
+        matching: approved.len() - missing.len(),
+        server,
+        missing,
+        unknown,
+    }
+}
+
+/// Everything staff see about a user when looking them up.
+#[derive(Debug)]
+struct LinkDetails {
+    name: String,
+    uuid: String,
+    discord_id: Option<u64>,
+    verify_state: VerifyState,
+    pending_since: Option<u64>,
+    approved_at: Option<u64>,
+    approved_by: Option<u64>,
+    servers: Vec<String>,
+    guild_id: Option<u64>,
+}
+
+impl LinkDetails {
+    fn of(state: &UserState) -> Self {
+        Self {
+            name: state.name.to_owned(),
+            uuid: state.uuid.to_owned(),
+            discord_id: state.discord_id,
+            verify_state: state.verify_state,
+            pending_since: state.pending_since,
+            approved_at: state.approved_at,
+            approved_by: state.approved_by,
+            servers: state.servers.clone(),
+            guild_id: state.guild_id,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PendingUser {
+    name: String,
+    uuid: String,
+    discord_id: Option<u64>,
+    verify_message: Option<u64>,
+    pending_since: Option<u64>,
+    slo_alerts: u8,
+    reminded_at: Option<u64>,
+    priority: Priority,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    const STATES: [VerifyState; 4] = [VerifyState::NEW, VerifyState::PENDING, VerifyState::APPROVED, VerifyState::BANNED];
+    const ACTIONS: [VerifyAction; 6] = [VerifyAction::Link, VerifyAction::Approve, VerifyAction::Deny, VerifyAction::RegenerateCode, VerifyAction::Ban, VerifyAction::Unban];
+
+    // The whole matrix, one row per state in STATES order and one column per action in ACTIONS order.
+    const LEGAL: [[Option<VerifyState>; 6]; 4] = [
+        [Some(VerifyState::PENDING), None, None, Some(VerifyState::NEW), None, None],
+        [None, Some(VerifyState::APPROVED), Some(VerifyState::NEW), None, Some(VerifyState::BANNED), None],
+        [None, None, None, None, Some(VerifyState::BANNED), None],
+        [None, None, None, None, None, Some(VerifyState::PENDING)],
+    ];
+
+    #[test]
+    fn transitions_follow_the_matrix() {
+        for (from, row) in STATES.into_iter().zip(LEGAL) {
+            for (action, expected) in ACTIONS.into_iter().zip(row) {
+                match (from.transition(action), expected) {
+                    (Ok(to), Some(expected)) => assert_eq!(to, expected, "{from:?} {action:?}"),
+                    (Err(why), None) => assert_eq!(why.to_string(), format!("{action:?} is not allowed for a user who is {from:?}")),
+                    (result, expected) => panic!("{from:?} {action:?} gave {result:?}, expected {expected:?}"),
+                }
+            }
+        }
+    }
+
+    // However the actions arrive, a user only ever moves along a legal edge, and a refused action leaves them
+    // where they were.
+    #[test]
+    fn random_action_sequences_stay_legal() {
+        let mut random = StdRng::seed_from_u64(532);
+        for _ in 0..1000 {
+            let mut state = VerifyState::NEW;
+            for _ in 0..20 {
+                let action = ACTIONS[random.random_range(0..ACTIONS.len())];
+                if let Ok(next) = state.transition(action) {
+                    let row = STATES.iter().position(|known| *known == state).unwrap();
+                    let column = ACTIONS.iter().position(|known| std::mem::discriminant(known) == std::mem::discriminant(&action)).unwrap();
+                    assert_eq!(LEGAL[row][column], Some(next));
+                    // Nobody is approved without going through the queue, and only a denial sends anyone in it back
+                    // to needing a code.
+                    assert!(next != VerifyState::APPROVED || state == VerifyState::PENDING);
+                    assert!(next != VerifyState::NEW || state == VerifyState::NEW || matches!(action, VerifyAction::Deny));
+                    state = next;
+                }
+            }
+        }
+    }
+
+    fn linked(name: &str, discord_id: u64, verify_state: VerifyState, guild_id: Option<u64>) -> UserState {
+        let mut state = UserState::complete(name, &format!("{name}-uuid"), discord_id, discord_id * 10, Priority::Normal);
+        state.verify_state = verify_state;
+        state.guild_id = guild_id;
+        state
+    }
+
+    #[test]
+    fn per_guild_links_only_count_towards_their_guild() {
+        let states = [linked("Notch", 1, VerifyState::APPROVED, None), linked("jeb_", 1, VerifyState::PENDING, Some(7)), linked("Dinnerbone", 2, VerifyState::APPROVED, None)];
+        assert_eq!(linked_names(&states, 1, LinkScope::Global, None), ["Notch", "jeb_"]);
+        assert_eq!(linked_names(&states, 1, LinkScope::PerGuild, None), ["Notch"]);
+        assert_eq!(linked_names(&states, 1, LinkScope::PerGuild, Some(7)), ["jeb_"]);
+        assert!(linked_names(&states, 1, LinkScope::PerGuild, Some(8)).is_empty());
+    }
+
+    #[test]
+    fn the_role_is_held_where_an_approved_account_reaches() {
+        let mut inherited = linked("Notch", 1, VerifyState::APPROVED, None);
+        inherited.inherited.push(InheritedLink { guild_id: 7, verify_message: 70, inherited_at: 0 });
+        let states = [inherited, linked("jeb_", 2, VerifyState::PENDING, None), linked("Dinnerbone", 3, VerifyState::APPROVED, Some(8))];
+        assert!(holds_role(&states, 1, None));
+        assert!(holds_role(&states, 1, Some(7)));
+        assert!(!holds_role(&states, 1, Some(8)));
+        assert!(!holds_role(&states, 2, None));
+        assert!(holds_role(&states, 3, Some(8)));
+        assert!(!holds_role(&states, 3, None));
+    }
+
+    #[test]
+    fn only_approved_global_links_are_inherited_once() {
+        let mut states = vec![linked("jeb_", 1, VerifyState::PENDING, None), linked("Notch", 1, VerifyState::APPROVED, None), linked("Dinnerbone", 2, VerifyState::PENDING, None)];
+        assert!(inheritable(&mut states, LinkScope::PerGuild, 1, 7).is_none());
+        assert!(inheritable(&mut states, LinkScope::Global, 2, 7).is_none());
+
+        let state = inheritable(&mut states, LinkScope::Global, 1, 7).unwrap();
+        assert_eq!(state.name, "Notch");
+        state.inherited.push(InheritedLink { guild_id: 7, verify_message: 70, inherited_at: 0 });
+        assert!(inheritable(&mut states, LinkScope::Global, 1, 7).is_none());
+        assert_eq!(inheritable(&mut states, LinkScope::Global, 1, 8).unwrap().name, "Notch");
+    }
 }
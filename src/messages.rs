@@ -0,0 +1,70 @@
+use crate::config;
+use anyhow::Result;
+use std::collections::HashMap;
+
+const MESSAGES_PATH: &str = "./messages.json";
+
+// The text used for any key messages.json doesn't have.
+const BUILT_IN: &[(&str, &str)] = &[
+    ("title", "CloverCraft SMP"),
+    ("stats.title", "CloverCraft SMP Stats"),
+    ("status.updated", "Your whitelist status has been updated."),
+    ("status.fallback", "<@{discord_id}>, I couldn't DM you about your verification, so here it is instead."),
+    ("status.trying_again", "Join the Minecraft server again to get a fresh code, then enter it with /verify or in <#{channel_id}>."),
+    ("code.invalid", "You did not send a valid verification code. Please ensure that you have typed the code exactly as it appeared in Minecraft."),
+    ("code.expired", "That verification code expired {expired}. Press \"I need a new code\" on the verification panel, or reconnect to the Minecraft server, to get a fresh code."),
+    ("code.regenerated", "Here is a fresh verification code for {name}: **{code}**. Enter it in <#{channel_id}> or with /verify within 30 seconds."),
+    ("code.how_to", "Join the Minecraft server to be given a verification code, then enter it in this channel, with /verify or in a DM to me. If your code runs out before you enter it, enter it anyway and then press this button again for a fresh one."),
+    ("code.locked_out", "You have entered too many invalid verification codes. You can try again {until}."),
+    ("code.already_linked", "You cannot link any more Minecraft accounts."),
+    ("link.inherited", "{name} is already approved for you, so it has been linked in this server too."),
+    ("erasure.done", "Everything the bot stored about you has been deleted. Your reference is `{reference}`."),
+    ("ticket.title", "CloverCraft Ticket"),
+    ("ticket.description", "Thank you for opening a ticket. Please describe your issue below. A staff member will reach out to help as soon as possible."),
+    ("survey.title", "CloverCraft Tickets"),
+    ("survey.description", "Your ticket has been closed. How satisfied were you with the help you received? Please rate it from 1 (poor) to 5 (great)."),
+    ("panel.verification.title", "CloverCraft SMP"),
+    ("panel.verification.description", "Welcome to the CloverCraft SMP! To verify your account, please join the Minecraft server and type the code it gives you into this channel. You will not be able to play until you have verified your account and an admin has approved it. The bot will DM you in order to confirm your verification statuses."),
+    ("panel.ticket.title", "CloverCraft Tickets"),
+    ("panel.ticket.description", "If you need to discuss something in private with the team, this is the place. Simply press the 'Create Ticket' button below to open a new ticket. Be prepared to describe your issue once the ticket is open."),
+    ("role.reverify", "Your verified role was removed, as no approved Minecraft account is linked to you. To verify again, join the Minecraft server for a code and enter it in <#{channel_id}>, with /verify or in a DM to me."),
+    ("kick.unlinked", "Your account has been unlinked from discord."),
+];
+
+/// The text of the bot's user-facing embeds and kicks, loaded from `./messages.json` so a community can rebrand
+/// the bot without editing source. Placeholders are written as `{name}`; messages about a user are given `{name}`,
+/// `{uuid}` and `{discord_id}` where they're known, and `{code}` where a code was entered.
+pub(crate) struct Messages {
+    templates: HashMap<String, String>,
+}
+
+impl Messages {
+    pub(crate) fn load() -> Result<Self> {
+        Ok(Self { templates: config::open_json(MESSAGES_PATH, built_in)? })
+    }
+
+    /// Render a message, falling back to the built-in text for keys the file doesn't have.
+    pub(crate) fn render(&self, key: &str, args: &[(&str, &str)]) -> String {
+        let template = self.templates
+            .get(key)
+            .cloned()
+            .or_else(|| BUILT_IN.iter().find(|(built_in, _)| *built_in == key).map(|(_, template)| (*template).to_owned()))
+            .unwrap_or(key.to_owned());
+
+        args.iter().fold(template, |message, (name, value)| message.replace(&format!("{{{name}}}"), value))
+    }
+
+    pub(crate) fn get(&self, key: &str) -> String {
+        self.render(key, &[])
+    }
+
+    // The built-in text, without reading a messages file.
+    #[cfg(test)]
+    pub(crate) fn built_in() -> Self {
+        Self { templates: built_in() }
+    }
+}
+
+fn built_in() -> HashMap<String, String> {
+    BUILT_IN.iter().map(|(key, template)| ((*key).to_owned(), (*template).to_owned())).collect()
+}
@@ -0,0 +1,205 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+
+const NOTIFICATIONS_FILE: &str = "./notifications.json";
+
+/// Which bot DMs a user has opted into.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum NotificationLevel {
+    #[default]
+    All,
+    ImportantOnly,
+    None,
+}
+
+impl NotificationLevel {
+    pub(crate) fn from_id(id: &str) -> Option<Self> {
+        match id {
+            "all" => Some(Self::All),
+            "important_only" => Some(Self::ImportantOnly),
+            "none" => Some(Self::None),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn label(&self) -> &'static str {
+        match self {
+            Self::All => "All notifications",
+            Self::ImportantOnly => "Important only",
+            Self::None => "No DMs",
+        }
+    }
+
+    pub(crate) fn allows(&self, importance: Importance) -> bool {
+        match self {
+            Self::All => true,
+            Self::ImportantOnly => importance == Importance::Important,
+            Self::None => false,
+        }
+    }
+}
+
+/// How much a user needs to see a given DM.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub(crate) enum Importance {
+    // Decisions about the user's account and replies to something they just did.
+    Important,
+    // Status acknowledgements and reminders.
+    Optional,
+}
+
+/// Notification preferences keyed by discord ID. These are kept apart from the user states so
+/// they survive unlinking and relinking.
+#[derive(Default, Serialize, Deserialize)]
+pub(crate) struct NotificationPreferences {
+    levels: HashMap<u64, NotificationLevel>,
+    // Users discord won't let the bot DM, usually because they blocked it or closed their DMs.
+    #[serde(default)]
+    blocked: HashSet<u64>,
+}
+
+impl NotificationPreferences {
+    pub(crate) fn load() -> Result<Self> {
+        match File::open(NOTIFICATIONS_FILE) {
+            Ok(mut file) => Ok(serde_json::from_reader(&mut file)?),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub(crate) fn get(&self, discord_id: u64) -> NotificationLevel {
+        self.levels.get(&discord_id).copied().unwrap_or_default()
+    }
+
+    pub(crate) fn set(&mut self, discord_id: u64, level: NotificationLevel) -> Result<()> {
+        match level {
+            NotificationLevel::All => self.levels.remove(&discord_id),
+            _ => self.levels.insert(discord_id, level),
+        };
+        self.save()
+    }
+
+    /// Whether a DM of this importance should be sent to the user. Users discord won't deliver to are still sent
+    /// important DMs, since one getting through is how the block is noticed to have lifted.
+    pub(crate) fn should_send(&self, discord_id: u64, importance: Importance) -> bool {
+        self.get(discord_id).allows(importance) && !(self.is_blocked(discord_id) && importance == Importance::Optional)
+    }
+
+    pub(crate) fn is_blocked(&self, discord_id: u64) -> bool {
+        self.blocked.contains(&discord_id)
+    }
+
+    /// Record whether DMs to a user are getting through, returning whether that changed.
+    pub(crate) fn set_blocked(&mut self, discord_id: u64, blocked: bool) -> Result<bool> {
+        let changed = self.mark_blocked(discord_id, blocked);
+        if changed {
+            self.save()?;
+        }
+        Ok(changed)
+    }
+
+    fn mark_blocked(&mut self, discord_id: u64, blocked: bool) -> bool {
+        match blocked {
+            true => self.blocked.insert(discord_id),
+            false => self.blocked.remove(&discord_id),
+        }
+    }
+
+    pub(crate) fn forget(&mut self, discord_id: u64) -> Result<()> {
+        if self.remove_user(discord_id) {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn remove_user(&mut self, discord_id: u64) -> bool {
+        self.levels.remove(&discord_id).is_some() | self.blocked.remove(&discord_id)
+    }
+
+    fn save(&self) -> Result<()> {
+        let _ = std::fs::remove_file(NOTIFICATIONS_FILE);
+        let mut file = File::create_new(NOTIFICATIONS_FILE)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER: u64 = 1;
+
+    fn preferences(level: NotificationLevel) -> NotificationPreferences {
+        let mut preferences = NotificationPreferences::default();
+        preferences.levels.insert(USER, level);
+        preferences
+    }
+
+    #[test]
+    fn users_get_everything_by_default() {
+        let preferences = NotificationPreferences::default();
+        assert_eq!(preferences.get(USER), NotificationLevel::All);
+        assert!(preferences.should_send(USER, Importance::Important));
+        assert!(preferences.should_send(USER, Importance::Optional));
+    }
+
+    // Approvals, denials and code errors are important. Pending acknowledgements and reminders are optional.
+    #[test]
+    fn each_level_lets_through_what_it_names() {
+        let cases = [
+            (NotificationLevel::All, true, true),
+            (NotificationLevel::ImportantOnly, true, false),
+            (NotificationLevel::None, false, false),
+        ];
+        for (level, important, optional) in cases {
+            let preferences = preferences(level);
+            assert_eq!(preferences.should_send(USER, Importance::Important), important, "{level:?}");
+            assert_eq!(preferences.should_send(USER, Importance::Optional), optional, "{level:?}");
+            // Other users keep the default.
+            assert!(preferences.should_send(USER + 1, Importance::Optional));
+        }
+    }
+
+    // A failed DM marks the user blocked, which stops optional DMs until an important one gets through again.
+    #[test]
+    fn blocked_flag_lifecycle() {
+        let mut preferences = NotificationPreferences::default();
+        assert!(preferences.mark_blocked(USER, true));
+        assert!(!preferences.mark_blocked(USER, true));
+        assert!(preferences.is_blocked(USER));
+        assert!(!preferences.should_send(USER, Importance::Optional));
+        assert!(preferences.should_send(USER, Importance::Important));
+
+        assert!(preferences.mark_blocked(USER, false));
+        assert!(!preferences.mark_blocked(USER, false));
+        assert!(preferences.should_send(USER, Importance::Optional));
+    }
+
+    #[test]
+    fn levels_are_read_from_select_menu_ids() {
+        for level in [NotificationLevel::All, NotificationLevel::ImportantOnly, NotificationLevel::None] {
+            let id = serde_json::to_value(level).unwrap();
+            assert_eq!(NotificationLevel::from_id(id.as_str().unwrap()), Some(level));
+        }
+        assert_eq!(NotificationLevel::from_id("some"), None);
+    }
+
+    #[test]
+    fn forgetting_a_user_leaves_nothing_behind() {
+        let forgotten = 123456789012345678;
+        let mut preferences = preferences(NotificationLevel::None);
+        preferences.levels.insert(forgotten, NotificationLevel::ImportantOnly);
+        preferences.mark_blocked(forgotten, true);
+        assert!(preferences.remove_user(forgotten));
+
+        let saved = serde_json::to_string(&preferences).unwrap();
+        assert!(!saved.contains(&forgotten.to_string()), "{saved}");
+        assert_eq!(preferences.get(USER), NotificationLevel::None);
+
+        // A second erasure finds nothing, so nothing is saved again.
+        assert!(!preferences.remove_user(forgotten));
+    }
+}
@@ -0,0 +1,52 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+
+const PANELS_FILE: &str = "./panels.json";
+
+/// The setup messages the bot posts and keeps up to date.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum PanelKind {
+    Verification,
+    Ticket,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct PanelRecord {
+    pub(crate) channel_id: u64,
+    pub(crate) message_id: u64,
+    // Hash of the rendered embed and components, used to tell when the posted message is stale.
+    pub(crate) hash: u64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub(crate) struct Panels {
+    panels: HashMap<PanelKind, PanelRecord>,
+}
+
+impl Panels {
+    pub(crate) fn load() -> Result<Self> {
+        match File::open(PANELS_FILE) {
+            Ok(mut file) => Ok(serde_json::from_reader(&mut file)?),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub(crate) fn get(&self, kind: PanelKind) -> Option<PanelRecord> {
+        self.panels.get(&kind).cloned()
+    }
+
+    pub(crate) fn set(&mut self, kind: PanelKind, record: PanelRecord) -> Result<()> {
+        self.panels.insert(kind, record);
+        self.save()
+    }
+
+    fn save(&self) -> Result<()> {
+        let _ = std::fs::remove_file(PANELS_FILE);
+        let mut file = File::create_new(PANELS_FILE)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+use crate::{log, timestamp};
+use futures_util::FutureExt;
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// What came of running a handler under a `PanicGuard`.
+pub(crate) enum Outcome<T> {
+    Finished(T),
+    // The reference is logged with the panic, so a report from staff can be matched to it. Tripped is set when
+    // this panic switched the handler off.
+    Panicked { reference: String, message: String, tripped: bool },
+    // The handler panicked too often and has been switched off until the bot restarts.
+    Disabled,
+}
+
+/// Catches panics in event handlers and scheduled tasks, so one buggy handler can't take the rest of the bot
+/// with it. A handler that panics more than `limit` times within `window` is switched off until restart.
+pub(crate) struct PanicGuard {
+    limit: usize,
+    window: Duration,
+    // When each handler last panicked, within the window.
+    recent: Mutex<HashMap<&'static str, Vec<u64>>>,
+    totals: Mutex<HashMap<&'static str, u64>>,
+    disabled: Mutex<HashSet<&'static str>>,
+}
+
+impl PanicGuard {
+    pub(crate) fn new(limit: usize, window: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            recent: Mutex::new(HashMap::new()),
+            totals: Mutex::new(HashMap::new()),
+            disabled: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub(crate) async fn run<T>(&self, handler: &'static str, future: impl Future<Output = T>) -> Outcome<T> {
+        if self.disabled.lock().unwrap().contains(handler) {
+            return Outcome::Disabled;
+        }
+
+        let payload = match AssertUnwindSafe(future).catch_unwind().await {
+            Ok(value) => return Outcome::Finished(value),
+            Err(payload) => payload,
+        };
+
+        let reference = format!("{:08x}", rand::random::<u32>());
+        let message = panic_message(payload.as_ref());
+        log!("Panic in {handler} handler [{reference}]: {message}");
+        *self.totals.lock().unwrap().entry(handler).or_default() += 1;
+
+        let now = timestamp();
+        let mut recent = self.recent.lock().unwrap();
+        let panics = recent.entry(handler).or_default();
+        panics.retain(|panicked| now.saturating_sub(*panicked) < self.window.as_millis() as u64);
+        panics.push(now);
+        let tripped = panics.len() > self.limit;
+        if tripped {
+            log!("Disabling the {handler} handler after {} panics in {} minutes", panics.len(), self.window.as_secs() / 60);
+            self.disabled.lock().unwrap().insert(handler);
+        }
+        Outcome::Panicked { reference, message, tripped }
+    }
+
+    /// Panics since startup for each handler that has panicked, with the ones switched off marked.
+    pub(crate) fn summary(&self) -> Option<String> {
+        let totals = self.totals.lock().unwrap();
+        if totals.is_empty() {
+            return None;
+        }
+        let disabled = self.disabled.lock().unwrap();
+        let mut lines = totals
+            .iter()
+            .map(|(handler, count)| format!("{handler}: {count}{}", if disabled.contains(handler) { " (disabled)" } else { "" }))
+            .collect::<Vec<_>>();
+        lines.sort();
+        Some(lines.join("\n"))
+    }
+}
+
+// Panics carry a &str for literal messages and a String for formatted ones.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic payload".to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(600);
+
+    async fn panicking(guard: &PanicGuard, handler: &'static str) -> Outcome<()> {
+        guard.run(handler, async { panic!("index out of bounds in {handler}") }).await
+    }
+
+    #[tokio::test]
+    async fn panics_are_caught_with_their_message() {
+        let guard = PanicGuard::new(3, WINDOW);
+        let Outcome::Panicked { reference, message, tripped } = panicking(&guard, "message").await else { panic!("The panic wasn't caught") };
+        assert_eq!(reference.len(), 8);
+        assert_eq!(message, "index out of bounds in message");
+        assert!(!tripped);
+
+        let Outcome::Panicked { message, .. } = guard.run("message", async { std::panic::panic_any(7) }).await else { panic!("The panic wasn't caught") };
+        assert_eq!(message, "unknown panic payload");
+    }
+
+    #[tokio::test]
+    async fn other_handlers_keep_running() {
+        let guard = PanicGuard::new(0, WINDOW);
+        assert!(matches!(panicking(&guard, "message").await, Outcome::Panicked { tripped: true, .. }));
+        assert!(matches!(guard.run("interaction", async { 5 }).await, Outcome::Finished(5)));
+        assert!(matches!(guard.run("message", async { 5 }).await, Outcome::Disabled));
+        assert_eq!(guard.summary().as_deref(), Some("message: 1 (disabled)"));
+    }
+
+    #[tokio::test]
+    async fn handlers_are_disabled_after_too_many_panics() {
+        let guard = PanicGuard::new(2, WINDOW);
+        assert_eq!(guard.summary(), None);
+        for _ in 0..2 {
+            assert!(matches!(panicking(&guard, "member add").await, Outcome::Panicked { tripped: false, .. }));
+            assert!(matches!(guard.run("member add", async {}).await, Outcome::Finished(())));
+        }
+        assert!(matches!(panicking(&guard, "member add").await, Outcome::Panicked { tripped: true, .. }));
+        assert!(matches!(guard.run("member add", async {}).await, Outcome::Disabled));
+        assert_eq!(guard.summary().as_deref(), Some("member add: 3 (disabled)"));
+    }
+
+    #[tokio::test]
+    async fn panics_outside_the_window_are_forgotten() {
+        let guard = PanicGuard::new(1, Duration::from_millis(20));
+        assert!(matches!(panicking(&guard, "reminders").await, Outcome::Panicked { tripped: false, .. }));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(matches!(panicking(&guard, "reminders").await, Outcome::Panicked { tripped: false, .. }));
+        assert_eq!(guard.summary().as_deref(), Some("reminders: 2"));
+    }
+}
@@ -0,0 +1,122 @@
+// Declares Capability together with its ALL list, so a capability can't be added without the report and the
+// tests below seeing it.
+macro_rules! capabilities {
+    ($(#[$meta:meta])* $vis:vis enum $name:ident { $($capability:ident),* $(,)? }) => {
+        $(#[$meta])*
+        $vis enum $name {
+            $($capability),*
+        }
+
+        impl $name {
+            pub(crate) const ALL: [$name; [$($name::$capability),*].len()] = [$($name::$capability),*];
+        }
+    };
+}
+
+capabilities! {
+    /// Everything the bot only lets some users do. Both the runtime checks and the permission report read the
+    /// gates from here, so the two can't disagree.
+    #[derive(Clone, Copy, Eq, PartialEq, Debug)]
+    pub(crate) enum Capability {
+        PostPanels,
+        ViewQueue,
+        LinkAccounts,
+        ApproveAccounts,
+        UnlinkAccounts,
+        ViewPermissions,
+        ForgetUsers,
+        ExportAnalytics,
+        ViewStaffActivity,
+        AuditWhitelist,
+        Announce,
+        CloseTickets,
+        LookupUsers,
+        BanUsers,
+        ListMembers,
+        ClearLockouts,
+    }
+}
+
+/// What a user needs before the bot lets them use a capability.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub(crate) enum Gate {
+    // Holding one of the configured staff roles.
+    StaffRole,
+    // Acting from the members channel, so access is whatever discord's channel permissions allow.
+    MemberChannel,
+}
+
+impl Capability {
+    // This match is exhaustive on purpose: a new capability won't compile until it has been given a gate.
+    pub(crate) fn gate(&self) -> Gate {
+        match self {
+            Self::PostPanels => Gate::StaffRole,
+            Self::ViewQueue => Gate::StaffRole,
+            Self::LinkAccounts => Gate::MemberChannel,
+            Self::ApproveAccounts => Gate::StaffRole,
+            Self::UnlinkAccounts => Gate::StaffRole,
+            Self::ViewPermissions => Gate::StaffRole,
+            Self::ForgetUsers => Gate::StaffRole,
+            Self::ExportAnalytics => Gate::StaffRole,
+            Self::ViewStaffActivity => Gate::StaffRole,
+            Self::AuditWhitelist => Gate::StaffRole,
+            Self::Announce => Gate::StaffRole,
+            Self::CloseTickets => Gate::StaffRole,
+            Self::LookupUsers => Gate::StaffRole,
+            Self::BanUsers => Gate::StaffRole,
+            Self::ListMembers => Gate::StaffRole,
+            Self::ClearLockouts => Gate::StaffRole,
+        }
+    }
+
+    pub(crate) fn description(&self) -> &'static str {
+        match self {
+            Self::PostPanels => "Post the verification and ticket panels with /setup",
+            Self::ViewQueue => "View the approval queue with /pending",
+            Self::LinkAccounts => "Manually link accounts with !link",
+            Self::ApproveAccounts => "Approve or deny pending accounts",
+            Self::UnlinkAccounts => "Unlink accounts with the Unlink button",
+            Self::ViewPermissions => "View this report with /permissions report",
+            Self::ForgetUsers => "Erase another user's data with /forget",
+            Self::ExportAnalytics => "Download analytics rollups with /stats export-range",
+            Self::ViewStaffActivity => "View the staff leaderboard with /stats staff",
+            Self::AuditWhitelist => "Compare the game server whitelist with approved users using /whitelist diff",
+            Self::Announce => "Post in game server chat with /announce",
+            Self::CloseTickets => "Close other members' tickets. Openers can always close their own",
+            Self::LookupUsers => "Look up linked accounts with /lookup",
+            Self::BanUsers => "Ban and unban accounts with the Ban button",
+            Self::ListMembers => "List approved members with /members",
+            Self::ClearLockouts => "Let users locked out for invalid codes try again with /clearlockout",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_capability_is_registered_once() {
+        for (index, capability) in Capability::ALL.iter().enumerate() {
+            assert_eq!(*capability as usize, index, "{capability:?}");
+        }
+    }
+
+    #[test]
+    fn every_capability_is_described() {
+        for capability in Capability::ALL {
+            assert!(!capability.description().is_empty(), "{capability:?}");
+            assert_eq!(Capability::ALL.iter().filter(|other| other.description() == capability.description()).count(), 1, "{capability:?}");
+        }
+    }
+
+    // The report lists every capability, so each one has to be checked somewhere or the report would overstate
+    // what's enforced.
+    #[test]
+    fn every_capability_is_checked() {
+        let discord = include_str!("discord.rs");
+        for capability in Capability::ALL {
+            assert!(discord.contains(&format!("Capability::{capability:?}).await")), "{capability:?} is never checked");
+        }
+    }
+}
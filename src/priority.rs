@@ -0,0 +1,208 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fs::File;
+
+const APPROVAL_HISTORY_FILE: &str = "./approval_history.json";
+
+/// How urgently a pending user should be looked at. Higher levels are reviewed first.
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Priority {
+    #[default]
+    Normal,
+    Elevated,
+    High,
+}
+
+impl Priority {
+    pub(crate) fn label(&self) -> &'static str {
+        match self {
+            Self::Normal => "Normal",
+            Self::Elevated => "Elevated",
+            Self::High => "High",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum PriorityCondition {
+    // The Minecraft account was approved at some point before.
+    PreviouslyApproved,
+    // The discord user holds this role.
+    Role(u64),
+}
+
+/// Grants `level` to anyone matching `condition`.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct PriorityRule {
+    pub(crate) condition: PriorityCondition,
+    pub(crate) level: Priority,
+}
+
+/// What the priority rules are evaluated against.
+pub(crate) struct PriorityFacts<'a> {
+    pub(crate) previously_approved: bool,
+    pub(crate) roles: &'a [u64],
+}
+
+/// The highest level granted by any matching rule, regardless of rule order.
+pub(crate) fn evaluate(rules: &[PriorityRule], facts: &PriorityFacts) -> Priority {
+    rules
+        .iter()
+        .filter(|rule| match rule.condition {
+            PriorityCondition::PreviouslyApproved => facts.previously_approved,
+            PriorityCondition::Role(role) => facts.roles.contains(&role),
+        })
+        .map(|rule| rule.level)
+        .max()
+        .unwrap_or_default()
+}
+
+// Sort key for the approval queue: highest priority first, then whoever has waited longest.
+pub(crate) fn queue_key(priority: Priority, pending_since: Option<u64>) -> (Reverse<Priority>, u64) {
+    (Reverse(priority), pending_since.unwrap_or(u64::MAX))
+}
+
+/// A moderator approving or denying an account, kept for the staff leaderboard.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Decision {
+    pub(crate) uuid: String,
+    pub(crate) moderator_id: u64,
+    pub(crate) approved: bool,
+    // Milliseconds since the unix epoch.
+    pub(crate) at: u64,
+}
+
+/// Every Minecraft UUID that has ever been approved, and each approval and denial moderators made. This outlives
+/// unlinking so returning players can be recognised.
+#[derive(Default, Serialize, Deserialize)]
+pub(crate) struct ApprovalHistory {
+    uuids: HashSet<String>,
+    #[serde(default)]
+    decisions: Vec<Decision>,
+}
+
+impl ApprovalHistory {
+    pub(crate) fn load() -> Result<Self> {
+        match File::open(APPROVAL_HISTORY_FILE) {
+            Ok(mut file) => Ok(serde_json::from_reader(&mut file)?),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub(crate) fn contains(&self, uuid: &str) -> bool {
+        self.uuids.contains(uuid)
+    }
+
+    pub(crate) fn record(&mut self, uuid: &str, moderator_id: u64, at: u64) -> Result<()> {
+        self.uuids.insert(uuid.to_owned());
+        self.decisions.push(Decision { uuid: uuid.to_owned(), moderator_id, approved: true, at });
+        self.save()
+    }
+
+    pub(crate) fn record_denial(&mut self, uuid: &str, moderator_id: u64, at: u64) -> Result<()> {
+        self.decisions.push(Decision { uuid: uuid.to_owned(), moderator_id, approved: false, at });
+        self.save()
+    }
+
+    // Approvals made before decisions were kept, taken from the approver and time stored with each linked account.
+    // Ones already recorded are skipped, so this can run at every startup.
+    pub(crate) fn backfill(&mut self, approvals: impl Iterator<Item = (String, u64, u64)>) -> Result<()> {
+        let count = self.decisions.len();
+        for (uuid, moderator_id, at) in approvals {
+            if !self.decisions.iter().any(|decision| decision.approved && decision.uuid == uuid && decision.at == at) {
+                self.decisions.push(Decision { uuid, moderator_id, approved: true, at });
+            }
+        }
+        if self.decisions.len() != count {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Decisions made in `[from, to)`.
+    pub(crate) fn decisions(&self, from: u64, to: u64) -> Vec<Decision> {
+        self.decisions.iter().filter(|decision| (from..to).contains(&decision.at)).cloned().collect()
+    }
+
+    pub(crate) fn forget(&mut self, uuid: &str) -> Result<()> {
+        let count = self.decisions.len();
+        self.decisions.retain(|decision| decision.uuid != uuid);
+        if self.uuids.remove(uuid) || self.decisions.len() != count {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    // Drop every decision a moderator made.
+    pub(crate) fn forget_moderator(&mut self, moderator_id: u64) -> Result<()> {
+        let count = self.decisions.len();
+        self.decisions.retain(|decision| decision.moderator_id != moderator_id);
+        if self.decisions.len() != count {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> Result<()> {
+        let _ = std::fs::remove_file(APPROVAL_HISTORY_FILE);
+        let mut file = File::create_new(APPROVAL_HISTORY_FILE)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VETERAN_ROLE: u64 = 10;
+    const BOOSTER_ROLE: u64 = 20;
+
+    fn rules() -> Vec<PriorityRule> {
+        vec![
+            PriorityRule { condition: PriorityCondition::Role(BOOSTER_ROLE), level: Priority::Elevated },
+            PriorityRule { condition: PriorityCondition::PreviouslyApproved, level: Priority::High },
+            PriorityRule { condition: PriorityCondition::Role(VETERAN_ROLE), level: Priority::Elevated },
+        ]
+    }
+
+    #[test]
+    fn no_matching_rule_is_normal() {
+        assert_eq!(evaluate(&rules(), &PriorityFacts { previously_approved: false, roles: &[] }), Priority::Normal);
+        assert_eq!(evaluate(&[], &PriorityFacts { previously_approved: true, roles: &[VETERAN_ROLE] }), Priority::Normal);
+    }
+
+    #[test]
+    fn the_highest_matching_level_wins() {
+        assert_eq!(evaluate(&rules(), &PriorityFacts { previously_approved: false, roles: &[BOOSTER_ROLE] }), Priority::Elevated);
+        assert_eq!(evaluate(&rules(), &PriorityFacts { previously_approved: true, roles: &[BOOSTER_ROLE, VETERAN_ROLE] }), Priority::High);
+    }
+
+    #[test]
+    fn rule_order_doesnt_matter() {
+        let mut reversed = rules();
+        reversed.reverse();
+        for (previously_approved, roles) in [(false, &[][..]), (true, &[][..]), (false, &[VETERAN_ROLE][..]), (true, &[BOOSTER_ROLE][..])] {
+            let facts = PriorityFacts { previously_approved, roles };
+            assert_eq!(evaluate(&rules(), &facts), evaluate(&reversed, &facts));
+        }
+    }
+
+    #[test]
+    fn queue_is_ordered_by_priority_then_age() {
+        let mut queue = [
+            ("new", Priority::Normal, Some(1)),
+            ("waiting", Priority::Normal, Some(0)),
+            ("veteran", Priority::High, Some(5)),
+            ("booster", Priority::Elevated, Some(2)),
+            ("unknown", Priority::High, None),
+        ];
+        queue.sort_by_key(|(_, priority, pending_since)| queue_key(*priority, *pending_since));
+        let order: Vec<&str> = queue.iter().map(|(name, _, _)| *name).collect();
+        assert_eq!(order, ["veteran", "unknown", "booster", "waiting", "new"]);
+    }
+}
@@ -0,0 +1,168 @@
+use anyhow::{anyhow, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: [u8; 12] = [0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A];
+// The longest a version 1 header may be, including its CRLF.
+const V1_MAX_LENGTH: usize = 107;
+// Version 2 headers can carry extra TLVs after the addresses. Proxies send a few hundred bytes at most.
+const V2_MAX_LENGTH: usize = 4096;
+
+/// Read the PROXY protocol header a front proxy sends ahead of everything else, in either version, and return the
+/// client address it names. None for connections the proxy opened itself, such as health checks, and for
+/// protocols without an address, which keep the proxy's own address. Nothing past the header is read.
+pub(crate) async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Option<SocketAddr>> {
+    // Every header of either version is at least this long, so this can't read into the client's first frame.
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, &start).await
+    } else {
+        Err(anyhow!("Connection did not start with a PROXY header"))
+    }
+}
+
+// `PROXY TCP4 <source> <destination> <source port> <destination port>\r\n`, or `PROXY UNKNOWN ...\r\n`.
+async fn read_v1<R: AsyncRead + Unpin>(stream: &mut R, start: &[u8]) -> Result<Option<SocketAddr>> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(anyhow!("PROXY header is longer than {V1_MAX_LENGTH} bytes"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])?;
+    let fields = line.split(' ').collect::<Vec<_>>();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), source, _, source_port, _] => {
+            let ip = IpAddr::from_str(source)?;
+            if ip.is_ipv4() != (*protocol == "TCP4") {
+                return Err(anyhow!("PROXY header address {source} doesn't match {protocol}"));
+            }
+            Ok(Some(SocketAddr::new(ip, u16::from_str(source_port)?)))
+        }
+        _ => Err(anyhow!("Malformed PROXY header {line:?}")),
+    }
+}
+
+// The signature is followed by [u8 version and command][u8 family and protocol][u16 length] and `length` bytes
+// of addresses and TLVs.
+async fn read_v2<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Option<SocketAddr>> {
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let len = stream.read_u16().await? as usize;
+    if version_command >> 4 != 2 {
+        return Err(anyhow!("Unsupported PROXY header version {}", version_command >> 4));
+    }
+    if len > V2_MAX_LENGTH {
+        return Err(anyhow!("PROXY header claims {len} bytes of addresses"));
+    }
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await?;
+
+    match version_command & 0x0F {
+        // LOCAL: the proxy's own connection.
+        0 => return Ok(None),
+        1 => {}
+        command => return Err(anyhow!("Unknown PROXY header command {command}")),
+    }
+
+    // Only TCP over IPv4 and IPv6 carry a usable address. Source addresses come first, then destinations,
+    // then the source and destination ports.
+    Ok(match family {
+        0x11 if len >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&body[0..4])?);
+            Some(SocketAddr::new(ip.into(), u16::from_be_bytes([body[8], body[9]])))
+        }
+        0x21 if len >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&body[0..16])?);
+            Some(SocketAddr::new(ip.into(), u16::from_be_bytes([body[32], body[33]])))
+        }
+        0x11 | 0x21 => return Err(anyhow!("PROXY header is too short for its addresses")),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A version 2 header with the given command and family, followed by `body`.
+    fn v2(command: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x20 | command, family]);
+        header.extend((body.len() as u16).to_be_bytes());
+        header.extend(body);
+        header
+    }
+
+    async fn read(mut header: &[u8]) -> Result<Option<SocketAddr>> {
+        read_header(&mut header).await
+    }
+
+    #[tokio::test]
+    async fn version_1_names_the_client() {
+        let client = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 25687\r\n").await.unwrap();
+        assert_eq!(client, Some("192.0.2.1:56324".parse().unwrap()));
+        let client = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 25687\r\n").await.unwrap();
+        assert_eq!(client, Some("[2001:db8::1]:56324".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn version_2_names_the_client() {
+        let mut ipv4 = vec![192, 0, 2, 1, 198, 51, 100, 1];
+        ipv4.extend(56324u16.to_be_bytes());
+        ipv4.extend(25687u16.to_be_bytes());
+        assert_eq!(read(&v2(1, 0x11, &ipv4)).await.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+
+        let mut ipv6 = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        ipv6.extend("2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        ipv6.extend(56324u16.to_be_bytes());
+        ipv6.extend(25687u16.to_be_bytes());
+        assert_eq!(read(&v2(1, 0x21, &ipv6)).await.unwrap(), Some("[2001:db8::1]:56324".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn headers_without_a_client_address_are_none() {
+        assert_eq!(read(b"PROXY UNKNOWN\r\n").await.unwrap(), None);
+        assert_eq!(read(b"PROXY UNKNOWN ffff:f...f:ffff ffff:f...f:ffff 65535 65535\r\n").await.unwrap(), None);
+        // LOCAL, as health checks from the proxy itself send.
+        assert_eq!(read(&v2(0, 0x00, &[])).await.unwrap(), None);
+        assert_eq!(read(&v2(0, 0x11, &[0; 12])).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn nothing_past_the_header_is_read() {
+        let mut stream = &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 25687\r\nfirst frame"[..];
+        read_header(&mut stream).await.unwrap();
+        assert_eq!(stream, b"first frame");
+    }
+
+    #[tokio::test]
+    async fn invalid_headers_are_errors() {
+        // Anything else first, such as a client that isn't behind the proxy.
+        assert!(read(b"\x00\x05hello, bot!").await.is_err());
+        assert!(read(b"GET / HTTP/1.1\r\n\r\n").await.is_err());
+        // Cut short.
+        assert!(read(b"PROXY TCP4").await.is_err());
+        assert!(read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324").await.is_err());
+        assert!(read(&v2(1, 0x11, &[0; 12])[..20]).await.is_err());
+        // Longer than either version allows.
+        assert!(read(format!("PROXY TCP4 {}\r\n", "1".repeat(V1_MAX_LENGTH)).as_bytes()).await.is_err());
+        assert!(read(&v2(1, 0x11, &[0; V2_MAX_LENGTH + 1])).await.is_err());
+        // Addresses that don't match the family they're sent as.
+        assert!(read(b"PROXY TCP4 2001:db8::1 2001:db8::2 56324 25687\r\n").await.is_err());
+        assert!(read(b"PROXY TCP6 192.0.2.1 198.51.100.1 56324 25687\r\n").await.is_err());
+        assert!(read(&v2(1, 0x11, &[0; 8])).await.is_err());
+        assert!(read(&v2(1, 0x21, &[0; 12])).await.is_err());
+        // Malformed otherwise.
+        assert!(read(b"PROXY TCP4 192.0.2.1 56324\r\n").await.is_err());
+        assert!(read(&[&V2_SIGNATURE[..], &[0x11, 0x11, 0, 0]].concat()).await.is_err());
+        assert!(read(&v2(2, 0x11, &[0; 12])).await.is_err());
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::time::Instant;
+
+// Buckets refill over this window, and a limited address is logged at most once per window.
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// What to do with a connection attempt.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub(crate) enum Decision {
+    Allow,
+    // Drop it, and whether this is the first drop for the address this window.
+    Drop { log: bool },
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    logged: Option<Instant>,
+}
+
+/// A token bucket per peer address, allowing bursts of up to `per_window` connections and refilling at the same
+/// rate over a minute.
+pub(crate) struct RateLimiter {
+    per_window: u32,
+    buckets: HashMap<IpAddr, Bucket>,
+    pruned: Instant,
+}
+
+impl RateLimiter {
+    // Zero turns the limit off.
+    pub(crate) fn new(per_window: u32) -> Self {
+        Self {
+            per_window,
+            buckets: HashMap::new(),
+            pruned: Instant::now(),
+        }
+    }
+
+    pub(crate) fn per_window(&self) -> u32 {
+        self.per_window
+    }
+
+    pub(crate) fn check(&mut self, ip: IpAddr, now: Instant) -> Decision {
+        if self.per_window == 0 {
+            return Decision::Allow;
+        }
+        self.prune(now);
+
+        let capacity = self.per_window as f64;
+        let bucket = self.buckets.entry(ip.to_canonical()).or_insert(Bucket { tokens: capacity, updated: now, logged: None });
+        bucket.tokens = (bucket.tokens + refill(capacity, now - bucket.updated)).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Decision::Allow;
+        }
+
+        let log = bucket.logged.is_none_or(|logged| now - logged >= WINDOW);
+        if log {
+            bucket.logged = Some(now);
+        }
+        Decision::Drop { log }
+    }
+
+    // Forget addresses whose bucket would have refilled by now, since they behave exactly like a new one.
+    fn prune(&mut self, now: Instant) {
+        if now - self.pruned < WINDOW {
+            return;
+        }
+        self.pruned = now;
+
+        let capacity = self.per_window as f64;
+        self.buckets.retain(|_, bucket| bucket.tokens + refill(capacity, now - bucket.updated) < capacity);
+    }
+}
+
+fn refill(capacity: f64, elapsed: Duration) -> f64 {
+    capacity * elapsed.as_secs_f64() / WINDOW.as_secs_f64()
+}
@@ -0,0 +1,101 @@
+use regex::Regex;
+use std::sync::LazyLock;
+
+static ONLINE_NAME: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^[A-Za-z0-9_]{3,16}$").unwrap());
+static UUID: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^[0-9a-f]{8}-([0-9a-f]{4}-){3}[0-9a-f]{12}$").unwrap());
+static SERVER_NAME: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^[a-z0-9_]{1,16}$").unwrap());
+
+/// Strip control, bidi-override and zero-width characters so user supplied text can't hide or reorder parts of
+/// embeds, channel names and log lines. Newlines are kept since our own messages use them.
+pub(crate) fn sanitize_display(text: &str) -> String {
+    text.chars().filter(|c| *c == '\n' || !is_hidden(*c)).collect()
+}
+
+fn is_hidden(c: char) -> bool {
+    c.is_control()
+        || matches!(c, '\u{061C}' | '\u{200B}'..='\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2060}'..='\u{2064}' | '\u{2066}'..='\u{2069}' | '\u{FEFF}')
+}
+
+/// Escape Discord markdown and mention syntax so relayed text is shown as typed.
+pub(crate) fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '*' | '_' | '~' | '`' | '|' | '>' | '#' | '[' | ']' | '@' | '<') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Online-mode names follow Mojang's rules. Offline-mode servers accept anything printable up to the
+/// configured length.
+pub(crate) fn is_valid_name(name: &str, offline_mode: bool, max_length: usize) -> bool {
+    if offline_mode {
+        !name.is_empty() && name.chars().count() <= max_length && !name.chars().any(is_hidden)
+    } else {
+        ONLINE_NAME.is_match(name)
+    }
+}
+
+/// Normalize a dashed UUID to lowercase, or return None if it isn't one.
+pub(crate) fn normalize_uuid(uuid: &str) -> Option<String> {
+    let uuid = uuid.to_ascii_lowercase();
+    UUID.is_match(&uuid).then_some(uuid)
+}
+
+/// Normalize a game server name to lowercase, or return None if it isn't 1-16 letters, digits or underscores.
+/// The length limit keeps approve button IDs within discord's 100 characters.
+pub(crate) fn normalize_server_name(server: &str) -> Option<String> {
+    let server = server.to_ascii_lowercase();
+    SERVER_NAME.is_match(&server).then_some(server)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bidi_overrides_are_stripped() {
+        // Right-to-left override makes "Steve\u{202E}gnp.exe" render as "Steveexe.png".
+        assert_eq!(sanitize_display("Steve\u{202E}gnp.exe"), "Stevegnp.exe");
+        assert_eq!(sanitize_display("\u{2066}a\u{2069}\u{061C}b"), "ab");
+    }
+
+    #[test]
+    fn zero_width_characters_are_stripped() {
+        assert_eq!(sanitize_display("No\u{200D}tch\u{200B}\u{FEFF}"), "Notch");
+        // Joined emoji lose their joiners but keep their parts.
+        assert_eq!(sanitize_display("👨\u{200D}👩"), "👨👩");
+    }
+
+    #[test]
+    fn control_characters_are_stripped_but_newlines_kept() {
+        assert_eq!(sanitize_display("line\r\none\u{7}\u{1B}[31m\ttwo"), "line\none[31mtwo");
+    }
+
+    #[test]
+    fn online_names_follow_mojang_rules() {
+        assert!(is_valid_name("Notch", false, 16));
+        assert!(is_valid_name("jeb_", false, 16));
+        assert!(!is_valid_name("ab", false, 16));
+        assert!(!is_valid_name("ThisNameIsTooLong", false, 16));
+        assert!(!is_valid_name("Not ch", false, 16));
+        assert!(!is_valid_name("Nötch", false, 16));
+    }
+
+    #[test]
+    fn offline_names_are_limited_by_length_and_hidden_characters() {
+        assert!(is_valid_name("Nötch ☘", true, 16));
+        assert!(!is_valid_name("", true, 16));
+        assert!(!is_valid_name(&"😀".repeat(20), true, 16));
+        assert!(is_valid_name(&"😀".repeat(16), true, 16));
+        assert!(!is_valid_name("Steve\u{202E}", true, 16));
+        assert!(!is_valid_name("No\u{200D}tch", true, 16));
+    }
+
+    #[test]
+    fn markdown_and_mentions_are_escaped() {
+        assert_eq!(escape_markdown("**hi** @everyone <@1>"), "\\*\\*hi\\*\\* \\@everyone \\<\\@1\\>");
+    }
+}
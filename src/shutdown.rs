@@ -0,0 +1,142 @@
+use crate::log;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::time::{sleep, Instant};
+
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Lets in-flight discord handlers and tcp clients finish before the bot exits, so neither is cut off between
+/// the main loop changing state and the matching side effect.
+pub(crate) struct Shutdown {
+    stopping: AtomicBool,
+    finished: AtomicBool,
+    active: AtomicUsize,
+    // Flips to true when shutdown begins, for tasks that need to stop waiting on something else.
+    signal: watch::Sender<bool>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            stopping: AtomicBool::new(false),
+            finished: AtomicBool::new(false),
+            active: AtomicUsize::new(0),
+            signal: watch::Sender::new(false),
+        }
+    }
+}
+
+/// Held by a handler while it runs.
+pub(crate) struct HandlerGuard(Arc<Shutdown>);
+
+impl Drop for HandlerGuard {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Shutdown {
+    /// Register a handler, or None once shutdown has begun and new work should be turned away.
+    pub(crate) fn enter(self: &Arc<Self>) -> Option<HandlerGuard> {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        let guard = HandlerGuard(self.clone());
+        (!self.stopping.load(Ordering::SeqCst)).then_some(guard)
+    }
+
+    /// A receiver that changes once shutdown begins.
+    pub(crate) fn subscribe(&self) -> watch::Receiver<bool> {
+        self.signal.subscribe()
+    }
+
+    /// Whether handlers have drained and the main loop should flush its state and exit.
+    pub(crate) fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::SeqCst)
+    }
+
+    /// Wait for SIGINT or SIGTERM, stop taking new handlers, then give running ones up to `grace` to finish.
+    pub(crate) async fn run(self: Arc<Self>, grace: Duration) -> std::io::Result<()> {
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            _ = interrupt.recv() => {}
+            _ = terminate.recv() => {}
+        }
+        self.stop(grace).await;
+        Ok(())
+    }
+
+    // Stop taking new handlers and wait for running ones, returning how many finished and how many were cut off.
+    async fn stop(&self, grace: Duration) -> (usize, usize) {
+        self.stopping.store(true, Ordering::SeqCst);
+        self.signal.send_replace(true);
+        let in_flight = self.active.load(Ordering::SeqCst);
+        log!("Shutting down, waiting up to {}s for {in_flight} handlers", grace.as_secs());
+
+        let deadline = Instant::now() + grace;
+        while self.active.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            sleep(DRAIN_POLL_INTERVAL).await;
+        }
+
+        let cancelled = self.active.load(Ordering::SeqCst);
+        let drained = in_flight.saturating_sub(cancelled);
+        log!("Drained {drained} handlers, cancelling {cancelled}");
+        self.finished.store(true, Ordering::SeqCst);
+        (drained, cancelled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GRACE: Duration = Duration::from_secs(10);
+
+    // A handler that holds its guard for `takes`.
+    fn slow_handler(shutdown: &Arc<Shutdown>, takes: Duration) -> tokio::task::JoinHandle<()> {
+        let guard = shutdown.enter().unwrap();
+        tokio::spawn(async move {
+            sleep(takes).await;
+            drop(guard);
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn handlers_that_finish_in_time_are_drained() {
+        let shutdown = Arc::new(Shutdown::default());
+        let stopping = shutdown.subscribe();
+        let handler = slow_handler(&shutdown, GRACE / 2);
+
+        let started = Instant::now();
+        assert_eq!(shutdown.stop(GRACE).await, (1, 0));
+        assert!(started.elapsed() < GRACE);
+        assert!(handler.is_finished());
+        assert!(*stopping.borrow());
+        assert!(shutdown.is_finished());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn handlers_still_running_after_the_grace_period_are_cancelled() {
+        let shutdown = Arc::new(Shutdown::default());
+        let _quick = slow_handler(&shutdown, GRACE / 2);
+        let _slow = slow_handler(&shutdown, GRACE * 2);
+
+        let started = Instant::now();
+        assert_eq!(shutdown.stop(GRACE).await, (1, 1));
+        assert!(started.elapsed() >= GRACE);
+        assert!(shutdown.is_finished());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn new_handlers_are_turned_away_once_stopping() {
+        let shutdown = Arc::new(Shutdown::default());
+        assert!(shutdown.enter().is_some());
+        assert!(!shutdown.is_finished());
+        assert_eq!(shutdown.stop(GRACE).await, (0, 0));
+        assert!(shutdown.enter().is_none());
+        // Turned away handlers don't count as running.
+        assert_eq!(shutdown.active.load(Ordering::SeqCst), 0);
+    }
+}
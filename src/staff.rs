@@ -0,0 +1,235 @@
+use crate::priority::Decision;
+use crate::tickets::TicketRecord;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+
+const STAFF_FILE: &str = "./staff_activity.json";
+
+/// One moderator's counts over a range.
+#[derive(Default)]
+pub(crate) struct StaffTotals {
+    pub(crate) moderator_id: u64,
+    pub(crate) name: String,
+    pub(crate) approvals: u64,
+    pub(crate) denials: u64,
+    pub(crate) tickets_claimed: u64,
+    pub(crate) tickets_closed: u64,
+    // Milliseconds from a ticket being opened to their first answer, averaged over the tickets they claimed.
+    pub(crate) average_first_response: Option<u64>,
+}
+
+/// Each moderator's name as it was when they last acted, so they can still be listed after leaving the guild, and
+/// when the leaderboard was last posted. What they did is read from the approval and ticket histories.
+#[derive(Default, Serialize, Deserialize)]
+pub(crate) struct StaffActivity {
+    names: HashMap<u64, String>,
+    // The last month the leaderboard was posted for, as YYYY-MM.
+    #[serde(default)]
+    last_report: Option<String>,
+}
+
+impl StaffActivity {
+    pub(crate) fn load() -> Result<Self> {
+        match File::open(STAFF_FILE) {
+            Ok(mut file) => Ok(serde_json::from_reader(&mut file)?),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub(crate) fn remember(&mut self, moderator_id: u64, name: &str) -> Result<()> {
+        if self.names.get(&moderator_id).map(String::as_str) != Some(name) {
+            self.names.insert(moderator_id, name.to_owned());
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Totals per moderator for actions in `[from, to)`, most active first. Tickets count towards the range they
+    /// were claimed or closed in, and tickets closed by their opener don't count at all.
+    pub(crate) fn leaderboard(&self, decisions: &[Decision], tickets: &[TicketRecord], from: u64, to: u64) -> Vec<StaffTotals> {
+        let range = from..to;
+        let mut totals = HashMap::<u64, StaffTotals>::new();
+        let mut response_times = HashMap::<u64, Vec<u64>>::new();
+        for decision in decisions.iter().filter(|decision| range.contains(&decision.at)) {
+            let entry = self.totals(&mut totals, decision.moderator_id);
+            if decision.approved {
+                entry.approvals += 1;
+            } else {
+                entry.denials += 1;
+            }
+        }
+        for ticket in tickets {
+            if let (Some(moderator_id), Some(claimed_at)) = (ticket.claimed_by, ticket.claimed_at) && range.contains(&claimed_at) {
+                self.totals(&mut totals, moderator_id).tickets_claimed += 1;
+                if let Some(opened_at) = ticket.opened_at {
+                    response_times.entry(moderator_id).or_default().push(claimed_at.saturating_sub(opened_at));
+                }
+            }
+            if let Some(moderator_id) = ticket.closed_by && range.contains(&ticket.closed_at) {
+                self.totals(&mut totals, moderator_id).tickets_closed += 1;
+            }
+        }
+        for (moderator_id, times) in response_times {
+            if let Some(entry) = totals.get_mut(&moderator_id) {
+                entry.average_first_response = Some(times.iter().sum::<u64>() / times.len() as u64);
+            }
+        }
+
+        let mut totals = totals.into_values().collect::<Vec<_>>();
+        totals.sort_by_key(|totals| (std::cmp::Reverse(totals.approvals + totals.denials + totals.tickets_claimed + totals.tickets_closed), totals.moderator_id));
+        totals
+    }
+
+    fn totals<'a>(&self, totals: &'a mut HashMap<u64, StaffTotals>, moderator_id: u64) -> &'a mut StaffTotals {
+        totals.entry(moderator_id).or_insert_with(|| StaffTotals {
+            moderator_id,
+            name: self.names.get(&moderator_id).cloned().unwrap_or_default(),
+            ..StaffTotals::default()
+        })
+    }
+
+    pub(crate) fn last_report(&self) -> Option<&str> {
+        self.last_report.as_deref()
+    }
+
+    pub(crate) fn set_last_report(&mut self, month: String) -> Result<()> {
+        self.last_report = Some(month);
+        self.save()
+    }
+
+    // Drop a moderator's stored name. Their actions are forgotten with the approval and ticket histories.
+    pub(crate) fn forget(&mut self, moderator_id: u64) -> Result<()> {
+        if self.remove_moderator(moderator_id) {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn remove_moderator(&mut self, moderator_id: u64) -> bool {
+        self.names.remove(&moderator_id).is_some()
+    }
+
+    fn save(&self) -> Result<()> {
+        let _ = std::fs::remove_file(STAFF_FILE);
+        let mut file = File::create_new(STAFF_FILE)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODERATOR: u64 = 123456789012345678;
+    const OTHER: u64 = 876543210987654321;
+    const OPENER: u64 = 555555555555555555;
+
+    fn activity() -> StaffActivity {
+        let mut activity = StaffActivity::default();
+        activity.names.insert(MODERATOR, "Forgotten".to_owned());
+        activity.names.insert(OTHER, "Remembered".to_owned());
+        activity
+    }
+
+    fn decision(moderator_id: u64, approved: bool, at: u64) -> Decision {
+        Decision { uuid: format!("uuid-{at}"), moderator_id, approved, at }
+    }
+
+    fn ticket(opened_at: u64, claimed: Option<(u64, u64)>, closed_by: Option<u64>, closed_at: u64) -> TicketRecord {
+        TicketRecord {
+            channel_id: opened_at,
+            opener_id: OPENER,
+            closed_at,
+            rating: None,
+            comment: None,
+            opened_at: Some(opened_at),
+            claimed_by: claimed.map(|(moderator_id, _)| moderator_id),
+            claimed_at: claimed.map(|(_, at)| at),
+            closed_by,
+        }
+    }
+
+    // Each moderator approves at 10, denies at 20, claims a ticket at 30 and closes it at 40.
+    fn history() -> (Vec<Decision>, Vec<TicketRecord>) {
+        let mut decisions = vec![];
+        let mut tickets = vec![];
+        for moderator_id in [MODERATOR, OTHER] {
+            decisions.push(decision(moderator_id, true, 10));
+            decisions.push(decision(moderator_id, false, 20));
+            tickets.push(ticket(25, Some((moderator_id, 30)), Some(moderator_id), 40));
+        }
+        (decisions, tickets)
+    }
+
+    fn ranking(totals: &[StaffTotals]) -> Vec<(u64, u64, u64, u64, u64)> {
+        totals.iter().map(|totals| (totals.moderator_id, totals.approvals, totals.denials, totals.tickets_claimed, totals.tickets_closed)).collect()
+    }
+
+    #[test]
+    fn ranges_include_their_start_but_not_their_end() {
+        let (decisions, tickets) = history();
+        let activity = activity();
+        assert_eq!(ranking(&activity.leaderboard(&decisions, &tickets, 10, 20)), [(MODERATOR, 1, 0, 0, 0), (OTHER, 1, 0, 0, 0)]);
+        assert_eq!(ranking(&activity.leaderboard(&decisions, &tickets, 11, 21)), [(MODERATOR, 0, 1, 0, 0), (OTHER, 0, 1, 0, 0)]);
+        assert_eq!(ranking(&activity.leaderboard(&decisions, &tickets, 30, 40)), [(MODERATOR, 0, 0, 1, 0), (OTHER, 0, 0, 1, 0)]);
+        assert_eq!(ranking(&activity.leaderboard(&decisions, &tickets, 31, 41)), [(MODERATOR, 0, 0, 0, 1), (OTHER, 0, 0, 0, 1)]);
+        assert!(activity.leaderboard(&decisions, &tickets, 0, 10).is_empty());
+        assert!(activity.leaderboard(&decisions, &tickets, 41, u64::MAX).is_empty());
+    }
+
+    #[test]
+    fn the_most_active_moderator_is_ranked_first() {
+        let (mut decisions, tickets) = history();
+        decisions.push(decision(OTHER, false, 15));
+        let activity = activity();
+        assert_eq!(ranking(&activity.leaderboard(&decisions, &tickets, 0, u64::MAX)), [(OTHER, 1, 2, 1, 1), (MODERATOR, 1, 1, 1, 1)]);
+        // Ties are broken by ID, so the order doesn't change between runs.
+        assert_eq!(ranking(&activity.leaderboard(&decisions, &tickets, 0, 15)), [(MODERATOR, 1, 0, 0, 0), (OTHER, 1, 0, 0, 0)]);
+    }
+
+    #[test]
+    fn openers_closing_their_own_ticket_are_not_counted() {
+        let tickets = [ticket(0, None, None, 10), ticket(0, Some((MODERATOR, 5)), None, 10)];
+        assert_eq!(ranking(&activity().leaderboard(&[], &tickets, 0, u64::MAX)), [(MODERATOR, 0, 0, 1, 0)]);
+    }
+
+    #[test]
+    fn first_response_times_are_averaged_over_claimed_tickets() {
+        let tickets = [
+            ticket(0, Some((MODERATOR, 1000)), Some(OTHER), 5000),
+            ticket(10_000, Some((MODERATOR, 13_000)), Some(MODERATOR), 20_000),
+            // Tickets opened before opening times were kept count as claimed, but not towards the average.
+            TicketRecord { opened_at: None, ..ticket(0, Some((MODERATOR, 2000)), None, 3000) },
+        ];
+        let totals = activity().leaderboard(&[], &tickets, 0, u64::MAX);
+        let moderator = totals.iter().find(|totals| totals.moderator_id == MODERATOR).unwrap();
+        assert_eq!((moderator.tickets_claimed, moderator.average_first_response), (3, Some(2000)));
+        let other = totals.iter().find(|totals| totals.moderator_id == OTHER).unwrap();
+        assert_eq!((other.tickets_closed, other.average_first_response), (1, None));
+    }
+
+    #[test]
+    fn moderators_who_left_keep_their_stored_name() {
+        let (decisions, tickets) = history();
+        let totals = activity().leaderboard(&decisions, &tickets, 0, u64::MAX);
+        let names = totals.iter().map(|totals| totals.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["Forgotten", "Remembered"]);
+    }
+
+    #[test]
+    fn forgetting_a_moderator_leaves_nothing_behind() {
+        let mut activity = activity();
+        assert!(activity.remove_moderator(MODERATOR));
+
+        let saved = serde_json::to_string(&activity).unwrap();
+        assert!(!saved.contains(&MODERATOR.to_string()), "{saved}");
+        assert!(!saved.contains("Forgotten"), "{saved}");
+        assert!(saved.contains("Remembered"), "{saved}");
+
+        // A second erasure finds nothing, so nothing is saved again.
+        assert!(!activity.remove_moderator(MODERATOR));
+    }
+}
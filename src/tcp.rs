@@ -1,151 +1,1868 @@
-use crate::{log, ChannelPair, Packet};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc::UnboundedSender;
+use crate::cidr::Cidr;
+use crate::events::{observer_view, EventSender};
+use crate::proxy_protocol;
+use crate::rate_limit::{Decision, RateLimiter};
+use crate::sanitize::{is_valid_name, normalize_server_name, normalize_uuid};
+use crate::shutdown::Shutdown;
+use crate::tls::{self, TlsConfig};
+use crate::websocket;
+use crate::wire::{Buffer, ClientPacket, ServerPacket, StringTooLong, CODE_TTL_NONE, COMPRESSION_PROTOCOL_VERSION, WHOIS_APPROVED, WHOIS_BANNED, WHOIS_NEW, WHOIS_PENDING, WHOIS_UNKNOWN, ERROR_BUSY, LIVENESS_PROTOCOL_VERSION, ERROR_INTERNAL, ERROR_INVALID_PAYLOAD, ERROR_PACKET_PROTOCOL_VERSION, ERROR_UNAUTHORIZED, ERROR_UNKNOWN_PACKET, HELLO_ACCEPTED, HELLO_UNSUPPORTED, LEGACY_PROTOCOL_VERSION, REMOVE_PLAYER_NOT_FOUND, REMOVE_PLAYER_REMOVED};
+use crate::{build_info, config, log, ChannelPair, ConnectResult, Packet, VerifyState};
+use futures_util::future::select_all;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
+use tokio::net::{lookup_host, TcpListener, UnixListener};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{watch, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, timeout, Instant};
 
 use anyhow::{anyhow, Result};
 
 const TCP_PORT: u16 = 25687;
+const SOCKET_PERMISSIONS: u32 = 0o660;
+const TCP_CONFIG_PATH: &str = "./tcp_config.toml";
 
-macro_rules! impl_next {
-    ($ty:ty,$id:ident) => {
-        fn $id(&mut self) -> Result<$ty> {
-            let len = size_of::<$ty>();
-            if self.read_cursor + len > self.write_cursor {
-                return Err(anyhow!("Ran out of room while reading!"));
+// Protocol versions the bot can still talk. Clients that never send a hello are treated as version 1.
+// Version 2 clients get structured connect results instead of a single message. Each later version adds:
+// 3, the player's locale in connect queries; 4, the game server's name in the hello; 5, UUIDs as 16 raw bytes;
+// 6, the answered packet's ID in error frames; 7, compressed frames; 8, pings from the bot. The matching
+// `*_PROTOCOL_VERSION` constants in wire.rs are what the code checks.
+const SUPPORTED_PROTOCOL_VERSIONS: &[u8] = &[1, 2, 3, 4, 5, 6, 7, 8];
+
+// Most uuids one whitelist upload may add up to across all of its chunks.
+const MAX_WHITELIST_UPLOAD: usize = 100_000;
+
+// Pings in a row a connection may leave unanswered before it's dropped.
+const MAX_MISSED_PONGS: u8 = 2;
+
+const BUSY_MESSAGE: &str = "Too many connections, try again later";
+// Shown to players whose connect query the main thread didn't answer in time.
+const CONNECT_BUSY_MESSAGE: &str = "The verification service is busy, please try again in a moment.";
+// How often a main thread too slow to answer connect queries is logged, however many queries it leaves waiting.
+const BUSY_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Frames pushed to connected game servers without them asking.
+#[derive(Clone, Debug)]
+pub(crate) enum Outbound {
+    // Uuid and the reason shown to the player.
+    KickPlayer(String, String),
+    // The listen address the bot moved to after a reload.
+    Reconnect(String),
+    // Ask the game server to upload its whitelist.
+    RequestWhitelist,
+    // Names and uuids to add to the game server's whitelist.
+    AddToWhitelist(Vec<(String, String)>),
+    // A message from staff for the game server's chat.
+    Announce(String),
+}
+
+/// Every connected game server session, so the main loop can push frames to them.
+#[derive(Clone, Default)]
+pub(crate) struct Sessions {
+    next_id: Arc<AtomicU64>,
+    senders: Arc<Mutex<HashMap<u64, UnboundedSender<Outbound>>>>,
+    // The latest ping round trip of each connection that answers pings, with the server or address it's from.
+    round_trips: Arc<Mutex<HashMap<u64, (String, Duration)>>>,
+}
+
+impl Sessions {
+    fn register(&self) -> (Session, UnboundedReceiver<Outbound>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = unbounded_channel();
+        self.senders.lock().unwrap().insert(id, sender);
+        (Session { id, sessions: self.clone(), relays_players: AtomicBool::new(false) }, receiver)
+    }
+
+    fn unregister(&self, id: u64) {
+        self.senders.lock().unwrap().remove(&id);
+        self.round_trips.lock().unwrap().remove(&id);
+    }
+
+    /// The latest ping round trip of every connection that answers pings, by server name or address.
+    pub(crate) fn round_trips(&self) -> Vec<(String, Duration)> {
+        let mut round_trips = self.round_trips.lock().unwrap().values().cloned().collect::<Vec<_>>();
+        round_trips.sort();
+        round_trips
+    }
+
+    /// Send a frame to every connected session, returning how many it reached.
+    pub(crate) fn broadcast(&self, outbound: Outbound) -> usize {
+        let mut senders = self.senders.lock().unwrap();
+        senders.retain(|_, sender| sender.send(outbound.clone()).is_ok());
+        senders.len()
+    }
+}
+
+/// One connection's place in `Sessions`, which it leaves when dropped.
+pub(crate) struct Session {
+    id: u64,
+    sessions: Sessions,
+    // Set once the connection relays players joining or leaving, which makes it a game server whose players go
+    // offline with it.
+    relays_players: AtomicBool,
+}
+
+impl Session {
+    fn record_round_trip(&self, label: &str, round_trip: Duration) {
+        self.sessions.round_trips.lock().unwrap().insert(self.id, (label.to_owned(), round_trip));
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.sessions.unregister(self.id);
+    }
+}
+
+// Pings a persistent connection every so often, and notices when it stops answering.
+struct Liveness {
+    interval: Duration,
+    timeout: Duration,
+    deadline: Instant,
+    ping_sent: Option<Instant>,
+    missed: u8,
+}
+
+impl Liveness {
+    fn new(config: &TcpConfig) -> Self {
+        let interval = Duration::from_secs(config.ping_interval_secs);
+        Self {
+            interval,
+            timeout: Duration::from_secs(config.pong_timeout_secs),
+            deadline: Instant::now() + interval,
+            ping_sent: None,
+            missed: 0,
+        }
+    }
+
+    // Called once the deadline passes, either to send the next ping or because the last one went unanswered.
+    // Returns false once too many pings in a row have gone unanswered, and otherwise a ping should be sent.
+    fn ping_due(&mut self) -> bool {
+        if self.ping_sent.take().is_some() {
+            self.missed += 1;
+            if self.missed >= MAX_MISSED_PONGS {
+                return false;
             }
-            let data = <$ty>::from_be_bytes(self.data[self.read_cursor..self.read_cursor + len].try_into()?);
-            self.read_cursor += len;
-            Ok(data)
         }
-    };
+        self.ping_sent = Some(Instant::now());
+        self.deadline = Instant::now() + self.timeout;
+        true
+    }
+
+    // The round trip of the ping a pong answers. Unprompted pongs are ignored.
+    fn pong(&mut self) -> Option<Duration> {
+        let sent = self.ping_sent.take()?;
+        self.missed = 0;
+        self.deadline = Instant::now() + self.interval;
+        Some(sent.elapsed())
+    }
+}
+
+// Connect answers recently given on any connection, by packet type and uuid. Server list pings and proxy health
+// checks send bursts of the same query, often over several connections, which are answered from here instead of
+// going through the main thread each time.
+#[derive(Default)]
+struct RecentConnects(Mutex<HashMap<(u8, String), RecentConnect>>);
+
+struct RecentConnect {
+    answered: Instant,
+    // The server, name and locale the answer was for. A query differing in any of them is asked again.
+    query: (Option<String>, String, Option<String>),
+    result: ConnectResult,
+    message: String,
 }
 
-macro_rules! impl_put {
-    ($ty:ty,$id:ident) => {
-        fn $id(&mut self, val: $ty) -> Result<()> {
-            let len = size_of::<$ty>();
-            if self.write_cursor + len > BUFFER_SIZE {
-                return Err(anyhow!("Ran into end of buffer while writing!"));
+impl RecentConnects {
+    // The answer given to an identical query within the window. Expired answers are dropped on the way.
+    fn get(&self, key: &(u8, String), query: &(Option<String>, String, Option<String>), window: Duration) -> Option<(ConnectResult, String)> {
+        let mut answers = self.0.lock().unwrap();
+        answers.retain(|_, answer| answer.answered.elapsed() < window);
+        answers.get(key).filter(|answer| answer.query == *query).map(|answer| (answer.result, answer.message.clone()))
+    }
+
+    fn insert(&self, key: (u8, String), query: (Option<String>, String, Option<String>), result: ConnectResult, message: String) {
+        self.0.lock().unwrap().insert(key, RecentConnect { answered: Instant::now(), query, result, message });
+    }
+}
+
+/// Counters for how the integration is behaving. Shared into every handler so anything that wants to report on
+/// them can read the same values.
+pub(crate) struct TcpMetrics {
+    connections_accepted: AtomicU64,
+    connections_rejected: AtomicU64,
+    // Indexed by packet ID.
+    packets: [AtomicU64; 256],
+    decode_errors: AtomicU64,
+    // Connect queries answered from a recent identical one without asking the main thread.
+    collapsed: AtomicU64,
+    replies: AtomicU64,
+    reply_micros: AtomicU64,
+    // Observers currently subscribed.
+    subscribers: AtomicU64,
+    // Connections dropped for not answering pings.
+    evicted: AtomicU64,
+    // Connect queries answered busy because the main thread was too slow.
+    busy: AtomicU64,
+    // When a slow main thread was last logged, and the busy answers since.
+    busy_logged: Mutex<(Option<Instant>, u64)>,
+}
+
+impl TcpMetrics {
+    fn new() -> Self {
+        Self {
+            connections_accepted: AtomicU64::new(0),
+            connections_rejected: AtomicU64::new(0),
+            packets: std::array::from_fn(|_| AtomicU64::new(0)),
+            decode_errors: AtomicU64::new(0),
+            collapsed: AtomicU64::new(0),
+            replies: AtomicU64::new(0),
+            reply_micros: AtomicU64::new(0),
+            subscribers: AtomicU64::new(0),
+            evicted: AtomicU64::new(0),
+            busy: AtomicU64::new(0),
+            busy_logged: Mutex::new((None, 0)),
+        }
+    }
+
+    fn record_busy(&self, waited: Duration) {
+        self.busy.fetch_add(1, Ordering::Relaxed);
+        let mut busy_logged = self.busy_logged.lock().unwrap();
+        let (logged_at, unlogged) = &mut *busy_logged;
+        *unlogged += 1;
+        if logged_at.is_none_or(|logged_at| logged_at.elapsed() >= BUSY_LOG_INTERVAL) {
+            log!("Main thread didn't answer a connect query within {}ms, {unlogged} players told to retry", waited.as_millis());
+            *logged_at = Some(Instant::now());
+            *unlogged = 0;
+        }
+    }
+
+    fn record_reply(&self, latency: Duration) {
+        self.replies.fetch_add(1, Ordering::Relaxed);
+        self.reply_micros.fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// The mean time the main thread took to answer, or zero before anything has been asked.
+    pub(crate) fn average_reply_latency(&self) -> Duration {
+        let replies = self.replies.load(Ordering::Relaxed);
+        Duration::from_micros(self.reply_micros.load(Ordering::Relaxed).checked_div(replies).unwrap_or_default())
+    }
+
+    /// Describe every counter on one line, with packets listed as `id:count` for the IDs that have been seen.
+    pub(crate) fn summary(&self) -> String {
+        let packets = self
+            .packets
+            .iter()
+            .enumerate()
+            .filter_map(|(id, count)| Some(count.load(Ordering::Relaxed)).filter(|count| *count > 0).map(|count| format!("{id}:{count}")))
+            .collect::<Vec<_>>();
+        format!(
+            "{} connections accepted, {} rejected, packets [{}], {} decode errors, {} collapsed connect queries, {:?} average reply latency, {} subscribers, {} evicted for missing pongs, {} connect queries answered busy",
+            self.connections_accepted.load(Ordering::Relaxed),
+            self.connections_rejected.load(Ordering::Relaxed),
+            packets.join(" "),
+            self.decode_errors.load(Ordering::Relaxed),
+            self.collapsed.load(Ordering::Relaxed),
+            self.average_reply_latency(),
+            self.subscribers.load(Ordering::Relaxed),
+            self.evicted.load(Ordering::Relaxed),
+            self.busy.load(Ordering::Relaxed)
+        )
+    }
+}
+
+// Log the counters since startup every so often, along with how long each connection takes to answer a ping.
+async fn log_metrics(metrics: Arc<TcpMetrics>, sessions: Sessions, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        log!("Tcp metrics: {}", metrics.summary());
+        let round_trips = sessions.round_trips();
+        if !round_trips.is_empty() {
+            log!("Ping round trips: {}", round_trips.iter().map(|(label, round_trip)| format!("{label} {round_trip:?}")).collect::<Vec<_>>().join(", "));
+        }
+    }
+}
+
+// Clients can connect over either transport, and are handled the same way from then on.
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+enum Listener {
+    // One socket per address, or a single dual-stack socket for `*`.
+    Tcp(Vec<TcpListener>),
+    Unix(UnixListener),
+}
+
+// Listen addresses are either `tcp:host:port` or `unix:/path/to/socket`. Tcp addresses may list several
+// comma separated hosts, each bound on every address it resolves to, and `*` as the host listens on every IPv4
+// and IPv6 address through one socket.
+async fn bind(listen: &str) -> Result<Listener> {
+    if let Some(addrs) = listen.strip_prefix("tcp:") {
+        let mut listeners = vec![];
+        for addr in addrs.split(',').map(str::trim) {
+            if let Some(port) = addr.strip_prefix("*:") {
+                listeners.push(bind_dual_stack(u16::from_str(port)?)?);
+                continue;
+            }
+            for addr in lookup_host(addr).await? {
+                listeners.push(bind_tcp(addr, true)?);
             }
-            self.data[self.write_cursor..self.write_cursor + len].copy_from_slice(&val.to_be_bytes());
-            self.write_cursor += len;
-            Ok(())
         }
-    };
+        if listeners.is_empty() {
+            return Err(anyhow!("Listen address {listen} didn't resolve to anything!"));
+        }
+        for listener in &listeners {
+            let local = listener.local_addr()?;
+            let families = match local.ip() {
+                IpAddr::V4(_) => "IPv4",
+                IpAddr::V6(_) if socket2::SockRef::from(listener).only_v6()? => "IPv6",
+                IpAddr::V6(_) => "IPv4 and IPv6",
+            };
+            log!("Listening on {local} for {families}");
+        }
+        return Ok(Listener::Tcp(listeners));
+    }
+
+    let path = listen.strip_prefix("unix:").ok_or(anyhow!("Listen address {listen} must start with tcp: or unix:"))?;
+
+    // Clear out a socket left behind by a previous run, but never one that is still in use.
+    if Path::new(path).exists() {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(anyhow!("Socket {path} is already in use!"));
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(SOCKET_PERMISSIONS))?;
+    Ok(Listener::Unix(listener))
+}
+
+// IPv6 sockets are kept to IPv6 when bound alongside others, so an IPv4 socket on the same port doesn't clash.
+fn bind_tcp(addr: SocketAddr, only_v6: bool) -> Result<TcpListener> {
+    let socket = socket2::Socket::new(socket2::Domain::for_address(addr), socket2::Type::STREAM, Some(socket2::Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+// Hosts without IPv6 can't open the dual-stack socket, so they fall back to IPv4 alone.
+fn bind_dual_stack(port: u16) -> Result<TcpListener> {
+    bind_tcp(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port), false).or_else(|why| {
+        log!("Couldn't listen on IPv6, falling back to IPv4 only: {why}");
+        bind_tcp(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port), false)
+    })
+}
+
+// IPv4 clients of a dual-stack socket arrive as v4-mapped IPv6 addresses, which are logged, rate limited and
+// checked against the allowlist as the IPv4 address they are.
+fn canonical_peer(peer: SocketAddr) -> SocketAddr {
+    SocketAddr::new(peer.ip().to_canonical(), peer.port())
+}
+
+impl Listener {
+    // Unix socket peers have no address, so they're numbered instead.
+    async fn accept(&self, unix_clients: &mut u64) -> Result<(Box<dyn Stream>, String, Option<IpAddr>)> {
+        match self {
+            Listener::Tcp(listeners) => {
+                let (accepted, _, _) = select_all(listeners.iter().map(|listener| Box::pin(listener.accept()))).await;
+                let (stream, peer) = accepted?;
+                let peer = canonical_peer(peer);
+                Ok((Box::new(stream), peer.to_string(), Some(peer.ip())))
+            }
+
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                *unix_clients += 1;
+                Ok((Box::new(stream), format!("unix#{unix_clients}"), None))
+            }
+        }
+    }
 }
 
-pub async fn start_tcp(tx: UnboundedSender<ChannelPair<Packet>>) -> Result<()> {
-    let listener = TcpListener::bind(format!("0.0.0.0:{TCP_PORT}")).await?;
-    log!("Successfully started tcp listener on port {TCP_PORT}");
+pub async fn start_tcp(tx: UnboundedSender<ChannelPair<Packet>>, events: EventSender, sessions: Sessions, shutdown: Arc<Shutdown>) -> Result<()> {
+    let mut config = Arc::new(open_config()?);
+    let mut allowlist = allowed_ranges(&config)?;
+    let mut limiter = RateLimiter::new(config.connections_per_minute);
+    let mut listener = bind(&config.listen).await?;
+    log!("Successfully started listener on {}", config.listen);
+    let websocket_listener = match config.websocket_listen.is_empty() {
+        true => None,
+        false => {
+            let listener = TcpListener::bind(&config.websocket_listen).await?;
+            log!("Successfully started websocket listener on {}", config.websocket_listen);
+            Some(listener)
+        }
+    };
+    let connections = Arc::new(Semaphore::new(config.max_connections));
+    let mut unix_clients = 0u64;
+    let mut reload = signal(SignalKind::hangup())?;
+    let mut stopping = shutdown.subscribe();
+    let tls_acceptor = tls::acceptor(&config.tls)?;
+    if tls_acceptor.is_some() {
+        log!("Tcp connections are encrypted{}", if config.tls.client_ca_path.is_empty() { "" } else { " and need a client certificate" });
+    }
+    let metrics = Arc::new(TcpMetrics::new());
+    let recent_connects = Arc::new(RecentConnects::default());
+    if config.metrics_interval_mins > 0 {
+        tokio::spawn(log_metrics(metrics.clone(), sessions.clone(), Duration::from_secs(config.metrics_interval_mins * 60)));
+    }
+    // Connections whose PROXY header has been read, with the client address it named.
+    let (proxied_tx, mut proxied_rx) = unbounded_channel::<(Box<dyn Stream>, String, Option<IpAddr>)>();
     loop {
-        let (stream, _) = listener.accept().await?;
+        let (mut stream, peer, ip, is_websocket) = tokio::select! {
+            accepted = listener.accept(&mut unix_clients) => {
+                let (stream, peer, ip) = accepted?;
+                // Headers are read off the accept loop, so a slow proxy can't hold up everyone else.
+                if config.proxy_protocol && ip.is_some() {
+                    tokio::spawn(read_proxy_header(stream, peer, ip, Duration::from_secs(config.io_timeout_secs), proxied_tx.clone(), metrics.clone()));
+                    continue;
+                }
+                (stream, peer, ip, false)
+            }
+
+            Some((stream, peer, ip)) = proxied_rx.recv() => (stream, peer, ip, false),
+
+            accepted = accept_websocket(websocket_listener.as_ref()) => {
+                let (stream, peer) = accepted?;
+                let peer = canonical_peer(peer);
+                (Box::new(stream) as Box<dyn Stream>, format!("ws:{peer}"), Some(peer.ip()), true)
+            }
+
+            // Connected clients notice the same signal and close after their current exchange.
+            Ok(()) = stopping.changed() => {
+                close_listener(listener, &config.listen);
+                log!("Stopped accepting tcp connections");
+                return Ok(());
+            }
+
+            _ = reload.recv() => {
+                match reload_listener(&config, &sessions).await {
+                    Ok((new_config, new_allowlist, new_listener)) => {
+                        if let Some(new_listener) = new_listener {
+                            let old = std::mem::replace(&mut listener, new_listener);
+                            close_listener(old, &config.listen);
+                        }
+                        if new_config.connections_per_minute != limiter.per_window() {
+                            limiter = RateLimiter::new(new_config.connections_per_minute);
+                        }
+                        config = Arc::new(new_config);
+                        allowlist = new_allowlist;
+                    }
+                    Err(why) => log!("Error reloading tcp config, keeping the current listener: {why:?}"),
+                }
+                continue;
+            }
+        };
+
+        // Unix socket peers are already limited by the socket's file permissions.
+        if let Some(ip) = ip && !allowlist.is_empty() && !allowlist.iter().any(|range| range.contains(ip)) {
+            log!("Dropping connection from {peer}: address is not in allowed_ips");
+            metrics.connections_rejected.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+
+        // Drop floods before they cost a task, logging each address once a minute at most.
+        if let Some(ip) = ip && let Decision::Drop { log } = limiter.check(ip, Instant::now()) {
+            if log {
+                log!("Dropping connections from {ip}: more than {} per minute", config.connections_per_minute);
+            }
+            metrics.connections_rejected.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+
+        // Refuse connections over the limit with an error frame so the plugin can log why.
+        // Websocket clients haven't finished their handshake yet, so they can't be sent a frame.
+        let Ok(permit) = connections.clone().try_acquire_owned() else {
+            log!("Refusing connection from {peer}: all {} connections are in use", config.max_connections);
+            metrics.connections_rejected.fetch_add(1, Ordering::Relaxed);
+            if is_websocket {
+                continue;
+            }
+            let io_timeout = Duration::from_secs(config.io_timeout_secs);
+            tokio::spawn(async move {
+                let _ = send(&mut stream, BUSY_MESSAGE.len() + 16, io_timeout, None, ServerPacket::Error { code: ERROR_BUSY, message: BUSY_MESSAGE.to_owned(), packet: None }).await;
+            });
+            continue;
+        };
+
+        // Subscribe before entering so a shutdown starting in between is never missed.
+        let thread_stopping = shutdown.subscribe();
+        let Some(guard) = shutdown.enter() else {
+            continue;
+        };
+
+        metrics.connections_accepted.fetch_add(1, Ordering::Relaxed);
+        let active = config.max_connections - connections.available_permits();
+        log!("Client {peer} connected ({active}/{} connections)", config.max_connections);
+
         let thread_tx = tx.clone();
+        let thread_config = config.clone();
+        let thread_events = events.clone();
+        let thread_sessions = sessions.clone();
+        let thread_metrics = metrics.clone();
+        let thread_recent_connects = recent_connects.clone();
+        let thread_tls_acceptor = tls_acceptor.clone();
         tokio::spawn(async move {
-            if let Err(why) = handle_tcp_client(stream, thread_tx).await {
-                log!("Error handling client: {why:?}");
+            let io_timeout = Duration::from_secs(thread_config.io_timeout_secs);
+            let (stream, certified_server): (Box<dyn Stream>, _) = if is_websocket {
+                match websocket::accept(stream, peer.clone(), thread_config.max_frame_size, io_timeout).await {
+                    Ok(stream) => (Box::new(stream), None),
+                    Err(why) => {
+                        log!("Error accepting websocket client {peer}: {why:?}");
+                        return;
+                    }
+                }
+            } else if let Some(acceptor) = thread_tls_acceptor {
+                // Clients without a valid certificate fail here, before a single frame is read.
+                let stream = match timeout(io_timeout, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(why)) => {
+                        log!("Rejecting client {peer}: TLS handshake failed: {why}");
+                        return;
+                    }
+                    Err(_) => {
+                        log!("Rejecting client {peer}: TLS handshake timed out");
+                        return;
+                    }
+                };
+                let certified_server = match tls::peer_common_name(&stream) {
+                    Some(name) => match normalize_server_name(&name) {
+                        Some(server) => {
+                            log!("Client {peer} presented a certificate for server {server}");
+                            Some(server)
+                        }
+                        None => {
+                            log!("Rejecting client {peer}: certificate common name {name} is not a valid server name");
+                            return;
+                        }
+                    },
+                    None => None,
+                };
+                (Box::new(stream), certified_server)
+            } else {
+                (stream, None)
+            };
+            if let Err(why) = handle_tcp_client(stream, &peer, certified_server, thread_tx, thread_config, thread_events, thread_sessions, thread_stopping, thread_metrics, thread_recent_connects).await {
+                log!("Error handling client {peer}: {why:?}");
             }
+            drop(permit);
+            drop(guard);
         });
     }
 }
 
-async fn handle_tcp_client(mut client: TcpStream, tx: UnboundedSender<ChannelPair<Packet>>) -> Result<()> {
-    let mut local_pair = ChannelPair::new();
+// Re-read the config on SIGHUP. If the listen address changed, the new address is bound before the old one is
+// given up, so a failed bind leaves everything as it was. Existing connections are left to drain on their own
+// and are told where to reconnect.
+async fn reload_listener(config: &TcpConfig, sessions: &Sessions) -> Result<(TcpConfig, Vec<Cidr>, Option<Listener>)> {
+    let new_config = read_config()?;
+    let allowlist = allowed_ranges(&new_config)?;
+    if new_config.max_connections != config.max_connections {
+        log!("The connection limit only changes after a restart");
+    }
+    if new_config.websocket_listen != config.websocket_listen {
+        log!("The websocket listener only changes after a restart");
+    }
+    if new_config.tls != config.tls {
+        log!("TLS settings only change after a restart");
+    }
+    if new_config.listen == config.listen {
+        log!("Reloaded tcp config");
+        return Ok((new_config, allowlist, None));
+    }
 
-    let mut buf = Buffer::new();
-    buf.read_from_tcp(&mut client).await?;
-    let id = buf.next_u8()?;
+    let listener = rebind(&config.listen, &new_config.listen, sessions).await?;
+    Ok((new_config, allowlist, Some(listener)))
+}
+
+// Bind the new address, then point connected clients at it. They keep being served where they are until they
+// move over, so nothing they were in the middle of is lost.
+async fn rebind(old_listen: &str, new_listen: &str, sessions: &Sessions) -> Result<Listener> {
+    let listener = bind(new_listen).await?;
+    let reached = sessions.broadcast(Outbound::Reconnect(new_listen.to_owned()));
+    log!("Moved listener from {old_listen} to {new_listen}, asked {reached} connected clients to reconnect");
+    Ok(listener)
+}
 
-    match id {
-        0 => {
-            let uuid = buf.next_string()?;
-            let name = buf.next_string()?;
-            tx.send(local_pair.entangle())?;
-            local_pair.sender.send(Packet::ConnectQuery(name, uuid))?;
-            let Packet::ConnectResponse(response) = local_pair.receiver.recv().await.ok_or(anyhow!("Main thread did not respond!"))? else { return Err(anyhow!("Unexpected packet received in tcp client!")) };
+// An empty allowlist lets every address connect.
+fn allowed_ranges(config: &TcpConfig) -> Result<Vec<Cidr>> {
+    config
+        .allowed_ips
+        .iter()
+        .map(|range| Cidr::from_str(range).map_err(|why| anyhow!("Invalid allowed_ips entry {range}: {why}")))
+        .collect()
+}
 
-            buf.reset();
-            buf.put_u8(0)?;
-            buf.put_string(response)?;
-            buf.write_to_tcp(&mut client).await?;
+// The websocket listener is optional, so without one this never yields a connection.
+async fn accept_websocket(listener: Option<&TcpListener>) -> std::io::Result<(tokio::net::TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Swap the proxy's address for the client's before the connection goes through the allowlist and rate limiter.
+// Connections without a valid header are dropped, since their first frame can't be trusted either.
+async fn read_proxy_header(
+    mut stream: Box<dyn Stream>,
+    peer: String,
+    ip: Option<IpAddr>,
+    io_timeout: Duration,
+    proxied: UnboundedSender<(Box<dyn Stream>, String, Option<IpAddr>)>,
+    metrics: Arc<TcpMetrics>,
+) {
+    let client = match timeout(io_timeout, proxy_protocol::read_header(&mut stream)).await {
+        Ok(Ok(Some(client))) => canonical_peer(client),
+        Ok(Ok(None)) => {
+            let _ = proxied.send((stream, peer, ip));
+            return;
+        }
+        Ok(Err(why)) => {
+            log!("Dropping connection from {peer}: invalid PROXY header: {why}");
+            metrics.connections_rejected.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        Err(_) => {
+            log!("Dropping connection from {peer}: no PROXY header after {}s", io_timeout.as_secs());
+            metrics.connections_rejected.fetch_add(1, Ordering::Relaxed);
+            return;
         }
+    };
+    let _ = proxied.send((stream, client.to_string(), Some(client.ip())));
+}
+
+// Stop accepting on an old listener, cleaning up its socket file if it had one.
+fn close_listener(listener: Listener, listen: &str) {
+    drop(listener);
+    if let Some(path) = listen.strip_prefix("unix:") {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_tcp_client(
+    client: Box<dyn Stream>,
+    peer: &str,
+    certified_server: Option<String>,
+    tx: UnboundedSender<ChannelPair<Packet>>,
+    config: Arc<TcpConfig>,
+    events: EventSender,
+    sessions: Sessions,
+    stopping: watch::Receiver<bool>,
+    metrics: Arc<TcpMetrics>,
+    recent_connects: Arc<RecentConnects>,
+) -> Result<()> {
+    let (reader, writer) = tokio::io::split(client);
+    let (frames, reader_task) = spawn_reader(reader, config.max_frame_size, Duration::from_secs(config.io_timeout_secs));
+    let (session, outbound) = sessions.register();
+
+    let result = serve_client(writer, peer, certified_server, frames, outbound, &session, tx.clone(), config, events, stopping, metrics, recent_connects).await;
+
+    let relayed_players = session.relays_players.load(Ordering::Relaxed);
+    drop(session);
+    reader_task.abort();
+    if relayed_players {
+        let mut local_pair = ChannelPair::new();
+        tx.send(local_pair.entangle())?;
+        local_pair.sender.send(Packet::ServerDisconnected)?;
+    }
+    result
+}
+
+// Read frames on a separate task, since a frame read can't be safely cancelled halfway through. The channel
+// closes once the client disconnects.
+fn spawn_reader(mut reader: ReadHalf<Box<dyn Stream>>, max_frame_size: usize, io_timeout: Duration) -> (UnboundedReceiver<Result<Buffer>>, JoinHandle<()>) {
+    let (frames_tx, frames_rx) = unbounded_channel();
+    let task = tokio::spawn(async move {
+        loop {
+            let mut buf = Buffer::new(max_frame_size);
+            match buf.read_from_tcp(&mut reader, io_timeout).await {
+                Ok(true) => if frames_tx.send(Ok(buf)).is_err() {
+                    break;
+                },
+                Ok(false) => break,
+                Err(why) => {
+                    let _ = frames_tx.send(Err(why));
+                    break;
+                }
+            }
+        }
+    });
+    (frames_rx, task)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn serve_client(
+    mut client: WriteHalf<Box<dyn Stream>>,
+    peer: &str,
+    certified_server: Option<String>,
+    mut frames: UnboundedReceiver<Result<Buffer>>,
+    mut outbound: UnboundedReceiver<Outbound>,
+    session: &Session,
+    tx: UnboundedSender<ChannelPair<Packet>>,
+    config: Arc<TcpConfig>,
+    events: EventSender,
+    mut stopping: watch::Receiver<bool>,
+    metrics: Arc<TcpMetrics>,
+    recent_connects: Arc<RecentConnects>,
+) -> Result<()> {
+    let idle_timeout = Duration::from_secs(config.idle_timeout_secs);
+    let io_timeout = Duration::from_secs(config.io_timeout_secs);
+    let response_timeout = Duration::from_secs(config.response_timeout_secs);
+    let connect_timeout = Duration::from_millis(config.connect_timeout_millis);
+    let collapse_window = Duration::from_millis(config.collapse_window_millis);
+    let mut active = false;
+    let mut idle_deadline = Instant::now() + idle_timeout;
+
+    let max_frame_size = config.max_frame_size;
+    let mut protocol_version = LEGACY_PROTOCOL_VERSION;
+    let mut authenticated = false;
+    // Frames over this many bytes are deflated, once the client has said it can inflate them.
+    let mut compress_over = None;
+    // Set once the client has said it answers pings.
+    let mut liveness = None::<Liveness>;
+    // Which game server this is, for bots shared between several. None for single server setups. A client
+    // certificate names the server for it, and can't be overridden by the hello.
+    let mut server_name = certified_server.clone();
+    // Whitelist chunks uploaded so far, until the last one arrives.
+    let mut whitelist_upload = Vec::<String>::new();
+
+    loop {
+        let mut buf = tokio::select! {
+            frame = frames.recv() => match frame {
+                Some(frame) => frame?,
+                None => return Ok(()),
+            },
+
+            Some(frame) = outbound.recv() => {
+                let packet = match frame {
+                    Outbound::KickPlayer(uuid, reason) => ServerPacket::KickPlayer { uuid, reason },
+                    Outbound::Reconnect(listen) => ServerPacket::Reconnect(listen),
+                    Outbound::RequestWhitelist => ServerPacket::RequestWhitelist,
+                    Outbound::AddToWhitelist(users) => ServerPacket::AddToWhitelist(users),
+                    Outbound::Announce(message) => ServerPacket::Announcement(message),
+                };
+                send(&mut client, max_frame_size, io_timeout, compress_over, packet).await?;
+                continue;
+            }
+
+            // Exchanges are handled outside this select, so the last one has finished by the time we get here.
+            Ok(()) = stopping.changed() => {
+                log!("Closing connection to {peer} for shutdown");
+                return Ok(());
+            }
+
+            // Ping clients that answer pings, and drop them once they stop.
+            _ = sleep_until(liveness.as_ref().map_or(idle_deadline, |liveness| liveness.deadline)), if liveness.is_some() => {
+                let Some(liveness) = &mut liveness else { continue };
+                if !liveness.ping_due() {
+                    log!("Client {peer} missed {MAX_MISSED_PONGS} pongs in a row, closing connection");
+                    metrics.evicted.fetch_add(1, Ordering::Relaxed);
+                    return Ok(());
+                }
+                send(&mut client, max_frame_size, io_timeout, compress_over, ServerPacket::Ping).await?;
+                continue;
+            }
+
+            // Drop connections that have gone quiet, logging it if the client had been talking to us.
+            _ = sleep_until(idle_deadline) => {
+                if active {
+                    log!("Client {peer} has been silent for {}s, closing connection", config.idle_timeout_secs);
+                }
+                return Ok(());
+            }
+        };
+        active = true;
+        idle_deadline = Instant::now() + idle_timeout;
+        let started = Instant::now();
+
+        // The packet ID is inside the compressed payload, so a frame that can't be inflated can't say which packet it
+        // was. Clients that expect an ID in error frames get 0, like an empty frame would.
+        if let Err(why) = buf.decompress(protocol_version) {
+            metrics.decode_errors.fetch_add(1, Ordering::Relaxed);
+            log_exchange(peer, 0, "unknown player", &format!("decode error: {why}"), started);
+            let packet = (protocol_version >= ERROR_PACKET_PROTOCOL_VERSION).then_some(0);
+            send(&mut client, max_frame_size, io_timeout, compress_over, ServerPacket::Error { code: ERROR_INVALID_PAYLOAD, message: why.to_string(), packet }).await?;
+            return Err(why);
+        }
+
+        // Errors name the packet they answer, so a plugin can tell which of its requests went wrong.
+        let packet_id = buf.packet_id().unwrap_or_default();
+        metrics.packets[packet_id as usize].fetch_add(1, Ordering::Relaxed);
+        let error_packet = (protocol_version >= ERROR_PACKET_PROTOCOL_VERSION).then_some(packet_id);
+
+        // Tell the client why its packet couldn't be read before giving up on it, since that's a plugin bug worth fixing.
+        let packet = match ClientPacket::decode(&mut buf, protocol_version) {
+            Ok(packet) => packet,
+            Err(why) => {
+                metrics.decode_errors.fetch_add(1, Ordering::Relaxed);
+                let message = match why.downcast_ref::<StringTooLong>() {
+                    Some(too_long) => too_long.to_string(),
+                    None => format!("Malformed packet {packet_id}"),
+                };
+                log_exchange(peer, packet_id, "unknown player", &format!("decode error: {why}"), started);
+                send(&mut client, max_frame_size, io_timeout, compress_over, ServerPacket::Error { code: ERROR_INVALID_PAYLOAD, message, packet: error_packet }).await?;
+                return Err(why);
+            }
+        };
+
+        match packet {
+            ClientPacket::ConnectQuery { uuid, name, locale } => {
+                let Some(uuid) = normalize_uuid(&uuid).filter(|_| is_valid_name(&name, config.offline_mode, config.max_name_length)) else {
+                    log_exchange(peer, packet_id, &format!("{name:?} [{uuid:?}]"), "invalid player name or UUID", started);
+                    send(&mut client, max_frame_size, io_timeout, compress_over, ServerPacket::Error { code: ERROR_INVALID_PAYLOAD, message: "Invalid player name or UUID".to_owned(), packet: error_packet }).await?;
+                    continue;
+                };
+
+                let key = (packet_id, uuid.clone());
+                let query = (server_name.clone(), name.clone(), locale.clone());
+                let player = format!("{name} [{uuid}]");
+                let (result, message, code, outcome) = match recent_connects.get(&key, &query, collapse_window) {
+                    Some((result, message)) => {
+                        metrics.collapsed.fetch_add(1, Ordering::Relaxed);
+                        (result, message, None, format!("{result:?} (collapsed)"))
+                    }
+                    None => {
+                        let mut local_pair = ChannelPair::new();
+                        tx.send(local_pair.entangle())?;
+                        local_pair.sender.send(Packet::ConnectQuery(name, uuid, locale, server_name.clone()))?;
+                        match recv_connect_reply(&mut local_pair, connect_timeout, &metrics).await {
+                            Ok(Some(Packet::ConnectResponse(result, message, code))) => {
+                                // Answers that hand out a code are never reused.
+                                if code.is_none() && !collapse_window.is_zero() {
+                                    recent_connects.insert(key, query, result, message.clone());
+                                }
+                                (result, message, code, format!("{result:?}"))
+                            }
+                            Ok(Some(_)) => return Err(anyhow!("Unexpected packet received in tcp client!")),
+                            Ok(None) => (ConnectResult::Denied, CONNECT_BUSY_MESSAGE.to_owned(), None, "busy".to_owned()),
+                            Err(why) => {
+                                log_exchange(peer, packet_id, &player, &format!("failed: {why}"), started);
+                                return Err(why);
+                            }
+                        }
+                    }
+                };
+                let response = if protocol_version == LEGACY_PROTOCOL_VERSION {
+                    ServerPacket::ConnectResponse(message)
+                } else {
+                    ServerPacket::ConnectResult { result: result as u8, message, code: code.map(|code| code.to_string()).unwrap_or_default() }
+                };
+                let sent = send(&mut client, max_frame_size, io_timeout, compress_over, response).await;
+                match &sent {
+                    Ok(()) => log_exchange(peer, packet_id, &player, &outcome, started),
+                    Err(why) => log_exchange(peer, packet_id, &player, &format!("{outcome}, but the answer couldn't be sent: {why}"), started),
+                }
+                sent?;
+            }
+
+            // Ask about every player at once, in a single round trip to the main thread.
+            ClientPacket::ConnectQueryBatch(queries) => {
+                let valid = queries
+                    .into_iter()
+                    .map(|(uuid, name, locale)| normalize_uuid(&uuid).filter(|_| is_valid_name(&name, config.offline_mode, config.max_name_length)).map(|uuid| (name, uuid, locale)))
+                    .collect::<Option<Vec<_>>>();
+                let Some(valid) = valid else {
+                    log_exchange(peer, packet_id, "batch", "invalid player name or UUID", started);
+                    send(&mut client, max_frame_size, io_timeout, compress_over, ServerPacket::Error { code: ERROR_INVALID_PAYLOAD, message: "Invalid player name or UUID".to_owned(), packet: error_packet }).await?;
+                    continue;
+                };
+
+                let count = valid.len();
+                let players = format!("batch of {count} players");
+                let mut local_pair = ChannelPair::new();
+                tx.send(local_pair.entangle())?;
+                local_pair.sender.send(Packet::ConnectQueryBatch(valid, server_name.clone()))?;
+                let (responses, outcome) = match recv_connect_reply(&mut local_pair, connect_timeout, &metrics).await {
+                    Ok(Some(Packet::ConnectBatchResponse(responses))) => {
+                        let outcome = batch_outcome(&responses);
+                        (responses, outcome)
+                    }
+                    Ok(Some(_)) => return Err(anyhow!("Unexpected packet received in tcp client!")),
+                    Ok(None) => ((0..count).map(|_| (ConnectResult::Denied, CONNECT_BUSY_MESSAGE.to_owned(), None)).collect(), "busy".to_owned()),
+                    Err(why) => {
+                        log_exchange(peer, packet_id, &players, &format!("failed: {why}"), started);
+                        return Err(why);
+                    }
+                };
+                let results = responses
+                    .into_iter()
+                    .map(|(result, message, code)| (result as u8, message, code.map(|code| code.to_string()).unwrap_or_default()))
+                    .collect();
+                let sent = send(&mut client, max_frame_size, io_timeout, compress_over, ServerPacket::ConnectBatchResult(results)).await;
+                match &sent {
+                    Ok(()) => log_exchange(peer, packet_id, &players, &outcome, started),
+                    Err(why) => log_exchange(peer, packet_id, &players, &format!("{outcome}, but the answer couldn't be sent: {why}"), started),
+                }
+                sent?;
+            }
+
+            // The client sends its protocol version, and we reply with whether we accept it followed by the bot's
+            // version, commit and build time so the plugin can log what it's paired with.
+            ClientPacket::Hello { version, server } => {
+                let supported = SUPPORTED_PROTOCOL_VERSIONS.contains(&version);
+                let normalized = server.as_deref().map(normalize_server_name);
+                let (status, message) = if !supported {
+                    log!("Client {peer} attempted to connect with unsupported protocol version {version}");
+                    (HELLO_UNSUPPORTED, format!("Unsupported protocol version {version}, please update the plugin."))
+                } else if normalized == Some(None) {
+                    log!("Client {peer} sent an invalid server name");
+                    (HELLO_UNSUPPORTED, "Server names must be 1-16 letters, digits or underscores.".to_owned())
+                } else {
+                    (HELLO_ACCEPTED, String::new())
+                };
+
+                send(&mut client, max_frame_size, io_timeout, compress_over, ServerPacket::Hello {
+                    status,
+                    message,
+                    version: build_info::VERSION.to_owned(),
+                    commit: build_info::GIT_COMMIT.to_owned(),
+                    build_time: build_info::build_time(),
+                }).await?;
+
+                if status != HELLO_ACCEPTED {
+                    return Ok(());
+                }
+                protocol_version = version;
+                liveness = (version >= LIVENESS_PROTOCOL_VERSION && config.ping_interval_secs > 0).then(|| Liveness::new(&config));
+                compress_over = (version >= COMPRESSION_PROTOCOL_VERSION && config.compress_over_bytes > 0).then_some(config.compress_over_bytes);
+                match (&certified_server, normalized.flatten()) {
+                    (Some(certified), Some(server)) if *certified != server => log!("Client {peer} claimed to be server {server}, but its certificate is for {certified}"),
+                    (Some(_), _) => {}
+                    (None, server) => {
+                        if let Some(server) = &server {
+                            log!("Client {peer} identified as server {server}");
+                        }
+                        server_name = server;
+                    }
+                }
+            }
+
+            // Reply with every approved user in a single frame.
+            ClientPacket::WhitelistQuery => {
+                let mut local_pair = ChannelPair::new();
+                tx.send(local_pair.entangle())?;
+                local_pair.sender.send(Packet::WhitelistQuery)?;
+                let Packet::WhitelistResponse(users) = recv_reply(&mut client, max_frame_size, io_timeout, error_packet, &mut local_pair, response_timeout, &metrics).await? else { return Err(anyhow!("Unexpected packet received in tcp client!")) };
+                send(&mut client, max_frame_size, io_timeout, compress_over, ServerPacket::Whitelist(users)).await?;
+            }
+
+            // Let the plugin tell a kicked player how long their code has left.
+            ClientPacket::CodeTtlQuery { uuid } => {
+                let Some(uuid) = normalize_uuid(&uuid) else {
+                    send(&mut client, max_frame_size, io_timeout, compress_over, ServerPacket::Error { code: ERROR_INVALID_PAYLOAD, message: "Invalid UUID".to_owned(), packet: error_packet }).await?;
+                    continue;
+                };
+
+                let mut local_pair = ChannelPair::new();
+                tx.send(local_pair.entangle())?;
+                local_pair.sender.send(Packet::CodeTtlQuery(uuid))?;
+                let Packet::CodeTtlResponse(remaining) = recv_reply(&mut client, max_frame_size, io_timeout, error_packet, &mut local_pair, response_timeout, &metrics).await? else { return Err(anyhow!("Unexpected packet received in tcp client!")) };
+                send(&mut client, max_frame_size, io_timeout, compress_over, ServerPacket::CodeTtl(remaining.unwrap_or(CODE_TTL_NONE))).await?;
+            }
+
+            // Tell in-game admins which discord account a player is linked to.
+            ClientPacket::WhoisQuery { uuid } => {
+                if !authenticated {
+                    send(&mut client, max_frame_size, io_timeout, compress_over, ServerPacket::Error { code: ERROR_UNAUTHORIZED, message: "Authenticate first".to_owned(), packet: error_packet }).await?;
+                    continue;
+                }
+                let Some(uuid) = normalize_uuid(&uuid) else {
+                    send(&mut client, max_frame_size, io_timeout, compress_over, ServerPacket::Error { code: ERROR_INVALID_PAYLOAD, message: "Invalid UUID".to_owned(), packet: error_packet }).await?;
+                    continue;
+                };
+
+                let mut local_pair = ChannelPair::new();
+                tx.send(local_pair.entangle())?;
+                local_pair.sender.send(Packet::WhoisQuery(uuid))?;
+                let Packet::WhoisResponse(whois) = recv_reply(&mut client, max_frame_size, io_timeout, error_packet, &mut local_pair, response_timeout, &metrics).await? else { return Err(anyhow!("Unexpected packet received in tcp client!")) };
+                let packet = match whois {
+                    Some((verify_state, discord_id, discord_name)) => ServerPacket::Whois {
+                        state: match verify_state {
+                            VerifyState::NEW => WHOIS_NEW,
+                            VerifyState::PENDING => WHOIS_PENDING,
+                            VerifyState::APPROVED => WHOIS_APPROVED,
+                            VerifyState::BANNED => WHOIS_BANNED,
+                        },
+                        discord_id: discord_id.unwrap_or_default(),
+                        discord_name: discord_name.unwrap_or_default(),
+                    },
+                    None => ServerPacket::Whois { state: WHOIS_UNKNOWN, discord_id: 0, discord_name: String::new() },
+                };
+                send(&mut client, max_frame_size, io_timeout, compress_over, packet).await?;
+            }
+
+            // Answer with a pong so the client knows we're still alive.
+            ClientPacket::Ping => send(&mut client, max_frame_size, io_timeout, compress_over, ServerPacket::Pong).await?,
+
+            ClientPacket::Pong => {
+                if let Some(liveness) = &mut liveness && let Some(round_trip) = liveness.pong() {
+                    session.record_round_trip(server_name.as_deref().unwrap_or(peer), round_trip);
+                }
+            }
+
+            // Presence and chat updates get no reply.
+            ClientPacket::PlayerJoined { uuid } => {
+                session.relays_players.store(true, Ordering::Relaxed);
+                forward_player(&mut client, max_frame_size, io_timeout, error_packet, &tx, &uuid, Packet::PlayerJoined).await?
+            }
+            ClientPacket::PlayerLeft { uuid } => {
+                session.relays_players.store(true, Ordering::Relaxed);
+                forward_player(&mut client, max_frame_size, io_timeout, error_packet, &tx, &uuid, Packet::PlayerLeft).await?
+            }
+            ClientPacket::Chat { uuid, message } => {
+                if !authenticated {
+                    send(&mut client, max_frame_size, io_timeout, compress_over, ServerPacket::Error { code: ERROR_UNAUTHORIZED, message: "Authenticate first".to_owned(), packet: error_packet }).await?;
+                    continue;
+                }
+                forward_player(&mut client, max_frame_size, io_timeout, error_packet, &tx, &uuid, |uuid| Packet::ChatMessage(uuid, message)).await?;
+            }
+            ClientPacket::SessionEnd { uuid, seconds } => {
+                if !authenticated {
+                    send(&mut client, max_frame_size, io_timeout, compress_over, ServerPacket::Error { code: ERROR_UNAUTHORIZED, message: "Authenticate first".to_owned(), packet: error_packet }).await?;
+                    continue;
+                }
+                forward_player(&mut client, max_frame_size, io_timeout, error_packet, &tx, &uuid, |uuid| Packet::SessionEnd(uuid, seconds)).await?;
+            }
+
+            // Unlock admin packets for this connection.
+            ClientPacket::Authenticate { token } => {
+                if config.server_token.is_empty() || !tokens_match(&token, &config.server_token) {
+                    log!("Client {peer} attempted to authenticate with an invalid token");
+                    send(&mut client, max_frame_size, io_timeout, compress_over, ServerPacket::Error { code: ERROR_UNAUTHORIZED, message: "Invalid server token".to_owned(), packet: error_packet }).await?;
+                    return Ok(());
+                }
+
+                log!("Client {peer} authenticated");
+                authenticated = true;
+                send(&mut client, max_frame_size, io_timeout, compress_over, ServerPacket::Authenticated).await?;
+            }
+
+            // Unlink a player by UUID from in-game.
+            ClientPacket::RemovePlayer { uuid } => {
+                if !authenticated {
+                    send(&mut client, max_frame_size, io_timeout, compress_over, ServerPacket::Error { code: ERROR_UNAUTHORIZED, message: "Authenticate first".to_owned(), packet: error_packet }).await?;
+                    continue;
+                }
+                let Some(uuid) = normalize_uuid(&uuid) else {
+                    send(&mut client, max_frame_size, io_timeout, compress_over, ServerPacket::Error { code: ERROR_INVALID_PAYLOAD, message: "Invalid UUID".to_owned(), packet: error_packet }).await?;
+                    continue;
+                };
+
+                log!("Client {peer} is removing player {uuid}");
+                let mut local_pair = ChannelPair::new();
+                tx.send(local_pair.entangle())?;
+                local_pair.sender.send(Packet::RemovePlayer(uuid, server_name.clone()))?;
+                let Packet::RemovePlayerResult(found) = recv_reply(&mut client, max_frame_size, io_timeout, error_packet, &mut local_pair, response_timeout, &metrics).await? else { return Err(anyhow!("Unexpected packet received in tcp client!")) };
+                let status = if found { REMOVE_PLAYER_REMOVED } else { REMOVE_PLAYER_NOT_FOUND };
+                send(&mut client, max_frame_size, io_timeout, compress_over, ServerPacket::RemovePlayer(status)).await?;
+            }
+
+            // Collect the game server's whitelist, which may arrive over several frames, then hand it over whole.
+            ClientPacket::WhitelistUpload { uuids, last } => {
+                if !authenticated {
+                    send(&mut client, max_frame_size, io_timeout, compress_over, ServerPacket::Error { code: ERROR_UNAUTHORIZED, message: "Authenticate first".to_owned(), packet: error_packet }).await?;
+                    continue;
+                }
+                let Some(uuids) = uuids.iter().map(|uuid| normalize_uuid(uuid)).collect::<Option<Vec<_>>>() else {
+                    whitelist_upload.clear();
+                    send(&mut client, max_frame_size, io_timeout, compress_over, ServerPacket::Error { code: ERROR_INVALID_PAYLOAD, message: "Invalid UUID".to_owned(), packet: error_packet }).await?;
+                    continue;
+                };
+                if whitelist_upload.len() + uuids.len() > MAX_WHITELIST_UPLOAD {
+                    log!("Client {peer} uploaded a whitelist of more than {MAX_WHITELIST_UPLOAD} entries");
+                    whitelist_upload.clear();
+                    send(&mut client, max_frame_size, io_timeout, compress_over, ServerPacket::Error { code: ERROR_INVALID_PAYLOAD, message: "Whitelist too large".to_owned(), packet: error_packet }).await?;
+                    continue;
+                }
+
+                whitelist_upload.extend(uuids);
+                if last {
+                    log!("Client {peer} uploaded a whitelist of {} players", whitelist_upload.len());
+                    let mut local_pair = ChannelPair::new();
+                    tx.send(local_pair.entangle())?;
+                    local_pair.sender.send(Packet::WhitelistUpload(std::mem::take(&mut whitelist_upload), server_name.clone()))?;
+                }
+            }
+
+            // On success the connection only receives events from then on.
+            ClientPacket::Subscribe { token } => {
+                if config.observer_token.is_empty() || !tokens_match(&token, &config.observer_token) {
+                    log!("Client {peer} attempted to subscribe as an observer with an invalid token");
+                    send(&mut client, max_frame_size, io_timeout, compress_over, ServerPacket::Error { code: ERROR_UNAUTHORIZED, message: "Invalid observer token".to_owned(), packet: error_packet }).await?;
+                    return Ok(());
+                }
 
-        _ => {}
+                log!("Client {peer} subscribed as an observer");
+                // Observers aren't game servers, so they stop receiving outbound frames. Broadcasts drop closed sessions.
+                drop(outbound);
+                return run_observer(client, frames, peer, protocol_version, session, events, config, stopping, metrics).await;
+            }
+
+            // Answer rather than ignore, or a plugin speaking a newer protocol would wait for a reply forever.
+            ClientPacket::Unknown(id) => {
+                log!("Client {peer} sent unknown packet {id}");
+                send(&mut client, max_frame_size, io_timeout, compress_over, ServerPacket::Error { code: ERROR_UNKNOWN_PACKET, message: format!("Unknown packet {id}"), packet: error_packet }).await?;
+            }
+        }
     }
+}
+
+// Push events to a read-only observer. Observers may ping and answer pings, but every other packet is refused.
+// Event frames carry empty strings when the player is stripped or the event has none.
+#[allow(clippy::too_many_arguments)]
+async fn run_observer(
+    mut writer: WriteHalf<Box<dyn Stream>>,
+    mut frames: UnboundedReceiver<Result<Buffer>>,
+    peer: &str,
+    protocol_version: u8,
+    session: &Session,
+    events: EventSender,
+    config: Arc<TcpConfig>,
+    mut stopping: watch::Receiver<bool>,
+    metrics: Arc<TcpMetrics>,
+) -> Result<()> {
+    let io_timeout = Duration::from_secs(config.io_timeout_secs);
+    let mut events = events.subscribe();
+    // Observers sit on a connection for hours without sending anything, so a dead one is only noticed by pinging it.
+    let mut liveness = (protocol_version >= LIVENESS_PROTOCOL_VERSION && config.ping_interval_secs > 0).then(|| Liveness::new(&config));
+    let label = format!("observer {peer}");
+    metrics.subscribers.fetch_add(1, Ordering::Relaxed);
+
+    let max_frame_size = config.max_frame_size;
+    let result = loop {
+        tokio::select! {
+            frame = frames.recv() => match frame.map(|frame| frame.and_then(|frame| decode_observer_frame(frame, protocol_version))) {
+                None | Some(Err(_)) => break Ok(()),
+
+                Some(Ok((_, ClientPacket::Ping))) => send(&mut writer, max_frame_size, io_timeout, None, ServerPacket::Pong).await?,
+
+                Some(Ok((_, ClientPacket::Pong))) => {
+                    if let Some(liveness) = &mut liveness && let Some(round_trip) = liveness.pong() {
+                        session.record_round_trip(&label, round_trip);
+                    }
+                }
+
+                Some(Ok((packet_id, packet))) => {
+                    log!("Observer {peer} attempted to send packet {packet:?}");
+                    let packet = (protocol_version >= ERROR_PACKET_PROTOCOL_VERSION).then_some(packet_id);
+                    send(&mut writer, max_frame_size, io_timeout, None, ServerPacket::Error { code: ERROR_UNAUTHORIZED, message: "Observers cannot send packets".to_owned(), packet }).await?;
+                }
+            },
+
+            event = events.recv() => match event {
+                Ok(event) => {
+                    let Some(view) = observer_view(&event, config.observer_include_identifiers) else { continue };
+                    let (name, uuid) = view.player.unwrap_or_default();
+                    if let Err(why) = send(&mut writer, max_frame_size, io_timeout, None, ServerPacket::ObserverEvent { kind: view.kind, name, uuid }).await {
+                        break Err(why);
+                    }
+                }
 
+                Err(RecvError::Lagged(missed)) => log!("Observer {peer} fell behind and missed {missed} events"),
+                Err(RecvError::Closed) => break Ok(()),
+            },
+
+            _ = sleep_until(liveness.as_ref().map_or_else(Instant::now, |liveness| liveness.deadline)), if liveness.is_some() => {
+                let Some(liveness) = &mut liveness else { continue };
+                if !liveness.ping_due() {
+                    log!("Observer {peer} missed {MAX_MISSED_PONGS} pongs in a row, closing connection");
+                    metrics.evicted.fetch_add(1, Ordering::Relaxed);
+                    break Ok(());
+                }
+                if let Err(why) = send(&mut writer, max_frame_size, io_timeout, None, ServerPacket::Ping).await {
+                    break Err(why);
+                }
+            }
+
+            Ok(()) = stopping.changed() => break Ok(()),
+        }
+    };
+
+    metrics.subscribers.fetch_sub(1, Ordering::Relaxed);
+    log!("Observer {peer} disconnected");
+    result
+}
+
+// An observer's frame with the ID it was sent as, read with the protocol version the observer said hello with.
+fn decode_observer_frame(mut frame: Buffer, protocol_version: u8) -> Result<(u8, ClientPacket)> {
+    frame.decompress(protocol_version)?;
+    let packet_id = frame.packet_id().unwrap_or_default();
+    Ok((packet_id, ClientPacket::decode(&mut frame, protocol_version)?))
+}
+
+async fn send<W: AsyncWrite + Unpin>(client: &mut W, max_frame_size: usize, io_timeout: Duration, compress_over: Option<usize>, packet: ServerPacket) -> Result<()> {
+    let mut buf = Buffer::new(max_frame_size);
+    packet.encode(&mut buf)?;
+    buf.write_to_tcp(client, io_timeout, compress_over).await
+}
+
+// Pass a player update on to the main thread, or tell the client its UUID was invalid.
+async fn forward_player<W: AsyncWrite + Unpin>(
+    client: &mut W,
+    max_frame_size: usize,
+    io_timeout: Duration,
+    error_packet: Option<u8>,
+    tx: &UnboundedSender<ChannelPair<Packet>>,
+    uuid: &str,
+    packet: impl FnOnce(String) -> Packet,
+) -> Result<()> {
+    let Some(uuid) = normalize_uuid(uuid) else {
+        return send(client, max_frame_size, io_timeout, None, ServerPacket::Error { code: ERROR_INVALID_PAYLOAD, message: "Invalid UUID".to_owned(), packet: error_packet }).await;
+    };
+
+    let mut local_pair = ChannelPair::new();
+    tx.send(local_pair.entangle())?;
+    local_pair.sender.send(packet(uuid))?;
     Ok(())
 }
 
-const BUFFER_SIZE: usize = 128;
+// Wait for the main thread to answer, giving up rather than leaving the socket dangling. The client is told
+// before the connection is dropped, so it doesn't sit waiting for a reply that isn't coming.
+async fn recv_reply<W: AsyncWrite + Unpin>(
+    client: &mut W,
+    max_frame_size: usize,
+    io_timeout: Duration,
+    error_packet: Option<u8>,
+    pair: &mut ChannelPair<Packet>,
+    response_timeout: Duration,
+    metrics: &TcpMetrics,
+) -> Result<Packet> {
+    let started = Instant::now();
+    let reply = timeout(response_timeout, pair.receiver.recv())
+        .await
+        .map_err(|_| anyhow!("Timed out waiting {}s for the main thread to respond!", response_timeout.as_secs()))
+        .and_then(|reply| reply.ok_or(anyhow!("Main thread did not respond!")));
 
-struct Buffer {
-    read_cursor: usize,
-    write_cursor: usize,
-    data: Box<[u8]>,
+    match reply {
+        Ok(_) => metrics.record_reply(started.elapsed()),
+        Err(_) => send(client, max_frame_size, io_timeout, None, ServerPacket::Error { code: ERROR_INTERNAL, message: "The bot failed to answer in time".to_owned(), packet: error_packet }).await?,
+    }
+    reply
 }
 
-impl Buffer {
-    fn new() -> Self {
+// Compare a token a client sent with the configured one in time that doesn't depend on where they first differ, so
+// response times don't give the token away a byte at a time. Only the length can be learnt this way.
+fn tokens_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len() && given.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// One line per connect exchange with the peer, packet, players and how it ended, so a login can be matched up
+// with the game server's own logs.
+fn log_exchange(peer: &str, packet_id: u8, player: &str, outcome: &str, started: Instant) {
+    log!("Exchange with {peer}: packet {packet_id}, {player}, {outcome}, took {}ms", started.elapsed().as_millis());
+}
+
+// How many players in a batch got each result, e.g. `Allowed x3, Pending x1`.
+fn batch_outcome(responses: &[(ConnectResult, String, Option<i32>)]) -> String {
+    let mut counts = Vec::<(String, usize)>::new();
+    for (result, _, _) in responses {
+        let result = format!("{result:?}");
+        match counts.iter_mut().find(|(seen, _)| *seen == result) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((result, 1)),
+        }
+    }
+    if counts.is_empty() {
+        return "no players".to_owned();
+    }
+    counts.iter().map(|(result, count)| format!("{result} x{count}")).collect::<Vec<_>>().join(", ")
+}
+
+// Wait for the main thread to answer a connect query. Players are waiting on these, so rather than the login
+// timing out, None tells the caller to have them retry once the main thread takes too long.
+async fn recv_connect_reply(pair: &mut ChannelPair<Packet>, connect_timeout: Duration, metrics: &TcpMetrics) -> Result<Option<Packet>> {
+    let started = Instant::now();
+    match timeout(connect_timeout, pair.receiver.recv()).await {
+        Ok(Some(reply)) => {
+            metrics.record_reply(started.elapsed());
+            Ok(Some(reply))
+        }
+        Ok(None) => Err(anyhow!("Main thread did not respond!")),
+        Err(_) => {
+            metrics.record_busy(connect_timeout);
+            Ok(None)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct TcpConfig {
+    // Either `tcp:host:port[,host:port...]` or `unix:/path/to/socket`. A host of `*` listens on IPv4 and IPv6.
+    #[serde(default = "default_listen")]
+    listen: String,
+    // A `host:port` to also accept websocket clients on, for hosts that only expose http ports. Leave empty to
+    // disable it.
+    #[serde(default)]
+    websocket_listen: String,
+    #[serde(default = "default_idle_timeout_secs")]
+    idle_timeout_secs: u64,
+    #[serde(default = "default_max_frame_size")]
+    max_frame_size: usize,
+    #[serde(default = "default_io_timeout_secs")]
+    io_timeout_secs: u64,
+    #[serde(default = "default_response_timeout_secs")]
+    response_timeout_secs: u64,
+    // How long a connect query waits for the main thread before the player is told to try again.
+    #[serde(default = "default_connect_timeout_millis")]
+    connect_timeout_millis: u64,
+    #[serde(default)]
+    offline_mode: bool,
+    #[serde(default = "default_max_name_length")]
+    max_name_length: usize,
+    #[serde(default = "default_max_connections")]
+    max_connections: usize,
+    // Addresses or CIDR ranges that may connect over tcp. Leave empty to allow everyone.
+    #[serde(default)]
+    allowed_ips: Vec<String>,
+    // Token for read-only observer connections. Leave empty to disable observers.
+    #[serde(default)]
+    observer_token: String,
+    #[serde(default)]
+    observer_include_identifiers: bool,
+    // Token game servers authenticate with before sending admin packets. Leave empty to disable them.
+    #[serde(default)]
+    server_token: String,
+    // New connections each address may open per minute. Zero, the default, turns the limit off. Game servers on
+    // protocol versions that open a connection per login send every login from the one address, so leave room for
+    // the busiest login rush, or keep it off when game servers are already limited by allowed_ips.
+    #[serde(default)]
+    connections_per_minute: u32,
+    // How often to log the connection and packet counters. Zero turns the summary off.
+    #[serde(default = "default_metrics_interval_mins")]
+    metrics_interval_mins: u64,
+    #[serde(default)]
+    tls: TlsConfig,
+    // How long a connect answer is reused for identical queries, on any connection. Zero turns it off.
+    #[serde(default = "default_collapse_window_millis")]
+    collapse_window_millis: u64,
+    // Frames larger than this are compressed for clients that support it. Zero turns compression off.
+    #[serde(default = "default_compress_over_bytes")]
+    compress_over_bytes: usize,
+    // Read a PROXY protocol header at the start of each tcp connection, and use the client address it names
+    // instead of the proxy's. Connections without one are dropped.
+    #[serde(default)]
+    proxy_protocol: bool,
+    // How often to ping connections that answer pings. Zero turns pings off.
+    #[serde(default = "default_ping_interval_secs")]
+    ping_interval_secs: u64,
+    // How long a ping may go unanswered before it counts as missed.
+    #[serde(default = "default_pong_timeout_secs")]
+    pong_timeout_secs: u64,
+}
+
+fn default_listen() -> String {
+    format!("tcp:*:{TCP_PORT}")
+}
+
+fn default_idle_timeout_secs() -> u64 {
+    60
+}
+
+fn default_max_frame_size() -> usize {
+    64 * 1024
+}
+
+fn default_io_timeout_secs() -> u64 {
+    10
+}
+
+fn default_response_timeout_secs() -> u64 {
+    10
+}
+
+fn default_connect_timeout_millis() -> u64 {
+    2000
+}
+
+fn default_max_name_length() -> usize {
+    16
+}
+
+fn default_max_connections() -> usize {
+    64
+}
+
+fn default_metrics_interval_mins() -> u64 {
+    15
+}
+
+fn default_collapse_window_millis() -> u64 {
+    500
+}
+
+fn default_compress_over_bytes() -> usize {
+    4 * 1024
+}
+
+fn default_ping_interval_secs() -> u64 {
+    30
+}
+
+fn default_pong_timeout_secs() -> u64 {
+    10
+}
+
+impl TcpConfig {
+    fn default() -> Self {
         Self {
-            read_cursor: 0,
-            write_cursor: 0,
-            data: vec![0u8; BUFFER_SIZE].into_boxed_slice(),
+            listen: default_listen(),
+            websocket_listen: String::new(),
+            idle_timeout_secs: default_idle_timeout_secs(),
+            max_frame_size: default_max_frame_size(),
+            io_timeout_secs: default_io_timeout_secs(),
+            response_timeout_secs: default_response_timeout_secs(),
+            connect_timeout_millis: default_connect_timeout_millis(),
+            offline_mode: false,
+            max_name_length: default_max_name_length(),
+            max_connections: default_max_connections(),
+            allowed_ips: Vec::new(),
+            observer_token: String::new(),
+            observer_include_identifiers: false,
+            server_token: String::new(),
+            connections_per_minute: 0,
+            metrics_interval_mins: default_metrics_interval_mins(),
+            tls: TlsConfig::default(),
+            collapse_window_millis: default_collapse_window_millis(),
+            compress_over_bytes: default_compress_over_bytes(),
+            proxy_protocol: false,
+            ping_interval_secs: default_ping_interval_secs(),
+            pong_timeout_secs: default_pong_timeout_secs(),
         }
     }
+}
+
+impl config::Documented for TcpConfig {
+    const COMMENTS: &'static [(&'static str, &'static str)] = &[
+        ("listen", "Either `tcp:host:port[,host:port...]` or `unix:/path/to/socket`. A host of `*` listens on IPv4 and IPv6."),
+        ("websocket_listen", "A `host:port` to also accept websocket clients on, for hosts that only expose http ports. Leave empty to disable it."),
+        ("idle_timeout_secs", "Connections that send nothing for this long are closed."),
+        ("max_frame_size", "The largest frame in bytes a client may send. Connections sending larger ones are closed."),
+        ("io_timeout_secs", "How long a single read or write may take before the connection is closed."),
+        ("response_timeout_secs", "How long a game server's request, such as a whitelist or whois lookup, waits for the bot to look up the answer before the game server is sent an error and disconnected."),
+        ("connect_timeout_millis", "How long a connect query waits for the main thread before the player is told to try again."),
+        ("offline_mode", "Accept any visible name up to max_name_length, for offline mode servers, rather than only names Mojang allows."),
+        ("max_name_length", "The longest name accepted in offline mode."),
+        ("max_connections", "The most connections open at once. Further ones are turned away."),
+        ("allowed_ips", "Addresses or CIDR ranges that may connect over tcp. Leave empty to allow everyone."),
+        ("observer_token", "Token for read-only observer connections. Leave empty to disable observers."),
+        ("observer_include_identifiers", "Show observers the names, UUIDs and discord IDs in events rather than leaving them out."),
+        ("server_token", "Token game servers authenticate with before sending admin packets. Leave empty to disable them."),
+        ("connections_per_minute", "New connections each address may open per minute. Zero turns the limit off. Game servers on protocol versions that open a connection per login send every login from the one address, so leave room for the busiest login rush."),
+        ("metrics_interval_mins", "How often to log the connection and packet counters. Zero turns the summary off."),
+        ("tls", "Certificate and key files for encrypting game server connections, all PEM encoded. An empty certificate path leaves connections in plain text."),
+        ("tls.cert_path", "The certificate chain presented to game servers."),
+        ("tls.key_path", "The certificate's private key."),
+        ("tls.client_ca_path", "CA bundle game server certificates must be signed by. Leave empty to not ask for client certificates."),
+        ("collapse_window_millis", "How long a connect answer is reused for identical queries, on any connection. Zero turns it off."),
+        ("compress_over_bytes", "Frames larger than this are compressed for clients that support it. Zero turns compression off."),
+        ("proxy_protocol", "Read a PROXY protocol header at the start of each tcp connection, and use the client address it names instead of the proxy's. Connections without one are dropped."),
+        ("ping_interval_secs", "How often to ping connections that answer pings. Zero turns pings off."),
+        ("pong_timeout_secs", "How long a ping may go unanswered before it counts as missed."),
+    ];
+}
+
+// Unlike open_config, a broken file is reported rather than replaced with the defaults.
+fn read_config() -> Result<TcpConfig> {
+    config::read_config(TCP_CONFIG_PATH, TcpConfig::default)
+}
+
+fn open_config() -> Result<TcpConfig> {
+    config::open_config(TCP_CONFIG_PATH, TcpConfig::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{self, Event};
+    use crate::wire::{LOCALE_PROTOCOL_VERSION, SERVER_PROTOCOL_VERSION};
+    use tokio::io::{duplex, AsyncWriteExt, DuplexStream};
+
+    const UUID: &str = "069a79f4-44e9-4726-a5be-fca90e38aaf5";
+    const NAME: &str = "Notch";
+    const SERVER_TOKEN: &str = "server secret";
+    const IO_TIMEOUT: Duration = Duration::from_secs(5);
 
-    fn reset(&mut self) {
-        self.read_cursor = 0;
-        self.write_cursor = 0;
+    // A game server's end of a connection the bot is serving.
+    struct Client {
+        stream: DuplexStream,
+        version: u8,
+        metrics: Arc<TcpMetrics>,
+        handler: JoinHandle<Result<()>>,
     }
 
-    async fn read_from_tcp(&mut self, stream: &mut TcpStream) -> Result<()> {
-        self.reset();
+    impl Client {
+        async fn send(&mut self, packet: ClientPacket) {
+            let mut buf = Buffer::new(default_max_frame_size());
+            packet.encode(&mut buf, self.version).unwrap();
+            buf.write_to_tcp(&mut self.stream, IO_TIMEOUT, None).await.unwrap();
+        }
+
+        // None once the bot has closed the connection.
+        async fn recv(&mut self) -> Option<ServerPacket> {
+            let mut buf = Buffer::new(default_max_frame_size());
+            if !buf.read_from_tcp(&mut self.stream, IO_TIMEOUT).await.unwrap() {
+                return None;
+            }
+            buf.decompress(self.version).unwrap();
+            Some(ServerPacket::decode(&mut buf, self.version).unwrap())
+        }
+
+        // Deflated whether or not the bot said it could inflate it.
+        async fn send_compressed(&mut self, packet: ClientPacket) {
+            let mut buf = Buffer::new(default_max_frame_size());
+            packet.encode(&mut buf, self.version).unwrap();
+            buf.write_to_tcp(&mut self.stream, IO_TIMEOUT, Some(0)).await.unwrap();
+        }
+
+        async fn hello(&mut self, version: u8) {
+            let server = (version >= SERVER_PROTOCOL_VERSION).then(|| "survival".to_owned());
+            self.send(ClientPacket::Hello { version, server }).await;
+            self.version = version;
+            let reply = self.recv().await;
+            assert!(matches!(reply, Some(ServerPacket::Hello { status: HELLO_ACCEPTED, .. })), "{reply:?}");
+        }
+
+        async fn authenticate(&mut self) {
+            self.send(ClientPacket::Authenticate { token: SERVER_TOKEN.to_owned() }).await;
+            let reply = self.recv().await;
+            assert!(matches!(reply, Some(ServerPacket::Authenticated)), "{reply:?}");
+        }
 
-        // Read the length as an integer
-        stream.read_exact(&mut self.data[0..4]).await?;
-        let len = u32::from_be_bytes(self.data[0..4].try_into()?) as usize;
+        async fn query(&mut self) {
+            let locale = (self.version >= LOCALE_PROTOCOL_VERSION).then(String::new);
+            self.send(ClientPacket::ConnectQuery { uuid: UUID.to_owned(), name: NAME.to_owned(), locale }).await;
+        }
 
-        if len > BUFFER_SIZE {
-            return Err(anyhow!("Attempted to read packet with length {len}!"));
+        async fn close(self) -> Result<()> {
+            drop(self.stream);
+            self.handler.await.unwrap()
         }
+    }
+
+    // Serve a new connection, returning the client's end and what the bot asks the main loop.
+    fn connect(config: TcpConfig, sessions: &Sessions, events: &EventSender) -> (Client, UnboundedReceiver<ChannelPair<Packet>>) {
+        connect_sharing(config, sessions, events, &Arc::default())
+    }
+
+    // Serve a new connection that shares its recent connect answers with others.
+    fn connect_sharing(config: TcpConfig, sessions: &Sessions, events: &EventSender, recent_connects: &Arc<RecentConnects>) -> (Client, UnboundedReceiver<ChannelPair<Packet>>) {
+        let (stream, bot_end) = duplex(64 * 1024);
+        let (tx, requests) = unbounded_channel();
+        let metrics = Arc::new(TcpMetrics::new());
+        // Dropping the sender leaves the connection never told to stop.
+        let (_, stopping) = watch::channel(false);
+        let handler = tokio::spawn({
+            let (sessions, events, metrics, recent_connects) = (sessions.clone(), events.clone(), metrics.clone(), recent_connects.clone());
+            async move { handle_tcp_client(Box::new(bot_end), "test", None, tx, Arc::new(config), events, sessions, stopping, metrics, recent_connects).await }
+        });
+        (Client { stream, version: LEGACY_PROTOCOL_VERSION, metrics, handler }, requests)
+    }
+
+    // Stand in for the main loop, answering every connect query once `release` lets it. Returns how many it saw.
+    fn answer_connects(mut requests: UnboundedReceiver<ChannelPair<Packet>>, code: Option<i32>, mut release: watch::Receiver<bool>) -> JoinHandle<usize> {
+        tokio::spawn(async move {
+            let mut seen = 0;
+            while let Some(mut pair) = requests.recv().await {
+                let Some(Packet::ConnectQuery(..)) = pair.receiver.recv().await else { continue };
+                seen += 1;
+                let _ = release.wait_for(|released| *released).await;
+                pair.sender.send(Packet::ConnectResponse(ConnectResult::Pending, "Waiting for approval".to_owned(), code)).unwrap();
+            }
+            seen
+        })
+    }
+
+    // Long and repetitive enough to be worth compressing.
+    fn chat() -> ClientPacket {
+        ClientPacket::Chat { uuid: UUID.to_owned(), message: "hello ".repeat(50) }
+    }
+
+    fn released() -> watch::Receiver<bool> {
+        watch::channel(true).1
+    }
 
-        stream.read_exact(&mut self.data[0..len]).await?;
-        self.write_cursor += len;
-        Ok(())
+    fn is_connect_result(reply: &Option<ServerPacket>) -> bool {
+        matches!(reply, Some(ServerPacket::ConnectResult { result, .. }) if *result == ConnectResult::Pending as u8)
     }
 
-    async fn write_to_tcp(&mut self, stream: &mut TcpStream) -> Result<()> {
-        stream.write_all(&(self.write_cursor as u32).to_be_bytes()).await?;
-        stream.write_all(&self.data[0..self.write_cursor]).await?;
-        self.reset();
-        Ok(())
+    fn test_socket(name: &str) -> String {
+        format!("unix:{}", std::env::temp_dir().join(format!("ccbot-{name}-{}.sock", std::process::id())).display())
     }
 
-    impl_next!(u8, next_u8);
-    impl_next!(u32, next_u32);
+    #[tokio::test]
+    async fn rebinding_keeps_serving_existing_connections() {
+        let sessions = Sessions::default();
+        let events = events::channel();
+        // Both queries should reach the main loop rather than the second being answered from the first.
+        let config = TcpConfig { collapse_window_millis: 0, ..TcpConfig::default() };
+        let (mut client, requests) = connect(config, &sessions, &events);
+        let (release, release_rx) = watch::channel(false);
+        let main_loop = answer_connects(requests, None, release_rx);
+        client.hello(ERROR_PACKET_PROTOCOL_VERSION).await;
 
-    fn next_string(&mut self) -> Result<String> {
-        let len = self.next_u32()? as usize;
-        if self.read_cursor + len > self.write_cursor {
-            return Err(anyhow!("Ran out of room while reading!"));
+        // A query the main loop is still answering when the listener moves.
+        client.query().await;
+        while client.metrics.packets[0].load(Ordering::Relaxed) == 0 {
+            tokio::task::yield_now().await;
         }
-        let data = &self.data[self.read_cursor..self.read_cursor + len];
-        self.read_cursor += len;
-        Ok(String::from_utf8(Vec::from(data))?)
+        let (old_listen, new_listen) = (test_socket("old"), test_socket("new"));
+        let old = bind(&old_listen).await.unwrap();
+        let new = rebind(&old_listen, &new_listen, &sessions).await.unwrap();
+        close_listener(old, &old_listen);
+        release.send(true).unwrap();
+
+        // The answer isn't lost, and the client is told where to go once it's sent.
+        assert!(is_connect_result(&client.recv().await));
+        assert!(matches!(client.recv().await, Some(ServerPacket::Reconnect(listen)) if listen == new_listen));
+        client.query().await;
+        assert!(is_connect_result(&client.recv().await));
+
+        // New connections arrive on the new address.
+        let path = new_listen.strip_prefix("unix:").unwrap();
+        let _stream = tokio::net::UnixStream::connect(path).await.unwrap();
+        let (_, peer, ip) = new.accept(&mut 0).await.unwrap();
+        assert_eq!(peer, "unix#1");
+        assert_eq!(ip, None);
+        assert!(!Path::new(old_listen.strip_prefix("unix:").unwrap()).exists());
+
+        close_listener(new, &new_listen);
+        client.close().await.unwrap();
+        assert_eq!(main_loop.await.unwrap(), 2);
     }
 
-    impl_put!(u8, put_u8);
-    impl_put!(u32, put_u32);
+    #[tokio::test]
+    async fn a_failed_rebind_leaves_clients_where_they_are() {
+        let sessions = Sessions::default();
+        let events = events::channel();
+        let (mut client, requests) = connect(TcpConfig::default(), &sessions, &events);
+        let main_loop = answer_connects(requests, None, released());
+        client.hello(ERROR_PACKET_PROTOCOL_VERSION).await;
+
+        assert!(rebind("tcp:*:25687", "nonsense", &sessions).await.is_err());
+        client.query().await;
+        assert!(is_connect_result(&client.recv().await));
+        client.close().await.unwrap();
+        assert_eq!(main_loop.await.unwrap(), 1);
+    }
 
-    fn put_string(&mut self, val: String) -> Result<()> {
-        let len = val.len();
-        self.put_u32(len as u32)?;
-        if self.write_cursor + len > BUFFER_SIZE {
-            return Err(anyhow!("Ran into end of buffer while writing!"));
+    #[tokio::test]
+    async fn identical_queries_are_collapsed() {
+        let (mut client, requests) = connect(TcpConfig::default(), &Sessions::default(), &events::channel());
+        let main_loop = answer_connects(requests, None, released());
+        client.hello(ERROR_PACKET_PROTOCOL_VERSION).await;
+
+        for _ in 0..100 {
+            client.query().await;
+            assert!(is_connect_result(&client.recv().await));
+        }
+        assert_eq!(client.metrics.collapsed.load(Ordering::Relaxed), 99);
+        client.close().await.unwrap();
+        assert_eq!(main_loop.await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn identical_queries_are_collapsed_across_connections() {
+        let recent_connects = Arc::default();
+        let (mut first, requests) = connect_sharing(TcpConfig::default(), &Sessions::default(), &events::channel(), &recent_connects);
+        let first_loop = answer_connects(requests, None, released());
+        first.hello(ERROR_PACKET_PROTOCOL_VERSION).await;
+        first.query().await;
+        assert!(is_connect_result(&first.recv().await));
+
+        let (mut second, requests) = connect_sharing(TcpConfig::default(), &Sessions::default(), &events::channel(), &recent_connects);
+        let second_loop = answer_connects(requests, None, released());
+        second.hello(ERROR_PACKET_PROTOCOL_VERSION).await;
+        second.query().await;
+        assert!(is_connect_result(&second.recv().await));
+        assert_eq!(second.metrics.collapsed.load(Ordering::Relaxed), 1);
+
+        first.close().await.unwrap();
+        second.close().await.unwrap();
+        assert_eq!((first_loop.await.unwrap(), second_loop.await.unwrap()), (1, 0));
+    }
+
+    #[tokio::test]
+    async fn answers_with_a_code_are_never_collapsed() {
+        let (mut client, requests) = connect(TcpConfig::default(), &Sessions::default(), &events::channel());
+        let main_loop = answer_connects(requests, Some(123456), released());
+        client.hello(ERROR_PACKET_PROTOCOL_VERSION).await;
+
+        for _ in 0..3 {
+            client.query().await;
+            assert!(matches!(client.recv().await, Some(ServerPacket::ConnectResult { code, .. }) if code == "123456"));
+        }
+        client.close().await.unwrap();
+        assert_eq!(main_loop.await.unwrap(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn clients_that_stop_answering_pings_are_evicted() {
+        let (mut client, _requests) = connect(TcpConfig::default(), &Sessions::default(), &events::channel());
+        client.hello(LIVENESS_PROTOCOL_VERSION).await;
+
+        let mut pings = 0;
+        while let Some(packet) = client.recv().await {
+            assert!(matches!(packet, ServerPacket::Ping), "{packet:?}");
+            pings += 1;
+        }
+        assert_eq!(pings, MAX_MISSED_PONGS);
+        assert_eq!(client.metrics.evicted.load(Ordering::Relaxed), 1);
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn clients_that_answer_pings_stay_connected() {
+        let sessions = Sessions::default();
+        let (mut client, _requests) = connect(TcpConfig::default(), &sessions, &events::channel());
+        client.hello(LIVENESS_PROTOCOL_VERSION).await;
+
+        for _ in 0..5 {
+            assert!(matches!(client.recv().await, Some(ServerPacket::Ping)));
+            client.send(ClientPacket::Pong).await;
         }
-        self.data[self.write_cursor..self.write_cursor + len].copy_from_slice(val.as_bytes());
-        self.write_cursor += len;
-        Ok(())
+        tokio::task::yield_now().await;
+        assert_eq!(client.metrics.evicted.load(Ordering::Relaxed), 0);
+        assert_eq!(sessions.round_trips().len(), 1);
+        assert_eq!(sessions.round_trips()[0].0, "survival");
+        client.close().await.unwrap();
     }
 
+    #[tokio::test]
+    async fn compressed_frames_need_a_protocol_that_supports_them() {
+        let (mut client, _requests) = connect(TcpConfig::default(), &Sessions::default(), &events::channel());
+        client.hello(ERROR_PACKET_PROTOCOL_VERSION).await;
+
+        client.send_compressed(chat()).await;
+        assert!(matches!(client.recv().await, Some(ServerPacket::Error { code: ERROR_INVALID_PAYLOAD, packet: Some(0), .. })));
+        assert!(client.recv().await.is_none());
+        assert!(client.close().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn compressed_frames_are_inflated_once_negotiated() {
+        let config = TcpConfig { server_token: SERVER_TOKEN.to_owned(), ..TcpConfig::default() };
+        let (mut client, mut requests) = connect(config, &Sessions::default(), &events::channel());
+        client.hello(COMPRESSION_PROTOCOL_VERSION).await;
+        client.authenticate().await;
+
+        client.send_compressed(chat()).await;
+        let mut pair = requests.recv().await.unwrap();
+        let ClientPacket::Chat { message, .. } = chat() else { unreachable!() };
+        assert!(matches!(pair.receiver.recv().await, Some(Packet::ChatMessage(uuid, sent)) if uuid == UUID && sent == message));
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn player_packets_need_authentication() {
+        let config = TcpConfig { server_token: SERVER_TOKEN.to_owned(), ..TcpConfig::default() };
+        let (mut client, mut requests) = connect(config, &Sessions::default(), &events::channel());
+        client.hello(ERROR_PACKET_PROTOCOL_VERSION).await;
+
+        client.send(chat()).await;
+        assert!(matches!(client.recv().await, Some(ServerPacket::Error { code: ERROR_UNAUTHORIZED, .. })));
+        client.send(ClientPacket::SessionEnd { uuid: UUID.to_owned(), seconds: 60 }).await;
+        assert!(matches!(client.recv().await, Some(ServerPacket::Error { code: ERROR_UNAUTHORIZED, .. })));
+        client.close().await.unwrap();
+        assert!(requests.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn observers_only_receive_events() {
+        let events = events::channel();
+        let config = TcpConfig { observer_token: "secret".to_owned(), ..TcpConfig::default() };
+        let (mut client, mut requests) = connect(config, &Sessions::default(), &events);
+        client.hello(ERROR_PACKET_PROTOCOL_VERSION).await;
+        client.send(ClientPacket::Subscribe { token: "secret".to_owned() }).await;
+        client.send(ClientPacket::Ping).await;
+        assert!(matches!(client.recv().await, Some(ServerPacket::Pong)));
+
+        client.query().await;
+        assert!(matches!(client.recv().await, Some(ServerPacket::Error { code: ERROR_UNAUTHORIZED, packet: Some(0), .. })));
+        client.send(ClientPacket::RemovePlayer { uuid: UUID.to_owned() }).await;
+        assert!(matches!(client.recv().await, Some(ServerPacket::Error { code: ERROR_UNAUTHORIZED, packet: Some(11), .. })));
+
+        events.send(Event::Approved { name: NAME.to_owned(), uuid: UUID.to_owned() }).unwrap();
+        let event = client.recv().await;
+        assert!(matches!(&event, Some(ServerPacket::ObserverEvent { kind: 0, name, uuid }) if name.is_empty() && uuid.is_empty()), "{event:?}");
+
+        client.close().await.unwrap();
+        assert!(requests.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn observers_need_the_token() {
+        let config = TcpConfig { observer_token: "secret".to_owned(), ..TcpConfig::default() };
+        let (mut client, _requests) = connect(config, &Sessions::default(), &events::channel());
+        client.send(ClientPacket::Subscribe { token: "secreT".to_owned() }).await;
+        assert!(matches!(client.recv().await, Some(ServerPacket::Error { code: ERROR_UNAUTHORIZED, .. })));
+        assert!(client.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn collapsed_answers_expire() {
+        let config = TcpConfig { collapse_window_millis: 20, ..TcpConfig::default() };
+        let (mut client, requests) = connect(config, &Sessions::default(), &events::channel());
+        let main_loop = answer_connects(requests, None, released());
+        client.hello(ERROR_PACKET_PROTOCOL_VERSION).await;
+
+        client.query().await;
+        assert!(is_connect_result(&client.recv().await));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        client.query().await;
+        assert!(is_connect_result(&client.recv().await));
+        client.close().await.unwrap();
+        assert_eq!(main_loop.await.unwrap(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connections_without_a_proxy_header_are_dropped() {
+        let metrics = Arc::new(TcpMetrics::new());
+        let (proxied_tx, mut proxied) = unbounded_channel();
+        let (mut stream, bot_end) = duplex(1024);
+        let reader = tokio::spawn(read_proxy_header(Box::new(bot_end), "proxy".to_owned(), None, IO_TIMEOUT, proxied_tx, metrics.clone()));
+        // A client that isn't behind the proxy starts with its hello.
+        let mut hello = Buffer::new(1024);
+        ClientPacket::Hello { version: LIVENESS_PROTOCOL_VERSION, server: None }.encode(&mut hello, LEGACY_PROTOCOL_VERSION).unwrap();
+        hello.write_to_tcp(&mut stream, IO_TIMEOUT, None).await.unwrap();
+
+        reader.await.unwrap();
+        assert!(proxied.recv().await.is_none());
+        assert_eq!(metrics.connections_rejected.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn proxied_connections_take_the_client_address() {
+        let (proxied_tx, mut proxied) = unbounded_channel();
+        let (mut stream, bot_end) = duplex(1024);
+        tokio::spawn(read_proxy_header(Box::new(bot_end), "proxy".to_owned(), None, IO_TIMEOUT, proxied_tx, Arc::new(TcpMetrics::new())));
+        stream.write_all(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 25687\r\n").await.unwrap();
+
+        let (_, peer, ip) = proxied.recv().await.unwrap();
+        assert_eq!((peer.as_str(), ip), ("192.0.2.1:56324", Some(IpAddr::from([192, 0, 2, 1]))));
+    }
+
+    #[test]
+    fn every_setting_is_documented() {
+        assert_eq!(config::undocumented(&TcpConfig::default()), Vec::<String>::new());
+    }
 }
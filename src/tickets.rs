@@ -0,0 +1,191 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+
+const TICKETS_FILE: &str = "./tickets.json";
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct TicketRecord {
+    pub(crate) channel_id: u64,
+    pub(crate) opener_id: u64,
+    pub(crate) closed_at: u64,
+    pub(crate) rating: Option<u8>,
+    pub(crate) comment: Option<String>,
+    // When the ticket was opened, unknown for tickets opened before this was kept.
+    #[serde(default)]
+    pub(crate) opened_at: Option<u64>,
+    // The staff member who answered the ticket first, and when.
+    #[serde(default)]
+    pub(crate) claimed_by: Option<u64>,
+    #[serde(default)]
+    pub(crate) claimed_at: Option<u64>,
+    // The staff member who closed the ticket, None when the opener closed it themselves.
+    #[serde(default)]
+    pub(crate) closed_by: Option<u64>,
+}
+
+// A ticket that hasn't been closed yet.
+#[derive(Serialize, Deserialize)]
+struct OpenTicket {
+    channel_id: u64,
+    opener_id: u64,
+    opened_at: u64,
+    claimed_by: Option<u64>,
+    claimed_at: Option<u64>,
+}
+
+/// Open tickets, and records of closed tickets with any survey feedback they received.
+#[derive(Default, Serialize, Deserialize)]
+pub(crate) struct TicketHistory {
+    records: Vec<TicketRecord>,
+    #[serde(default)]
+    open: Vec<OpenTicket>,
+}
+
+impl TicketHistory {
+    pub(crate) fn load() -> Result<Self> {
+        match File::open(TICKETS_FILE) {
+            Ok(mut file) => Ok(serde_json::from_reader(&mut file)?),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub(crate) fn records(&self) -> &[TicketRecord] {
+        &self.records
+    }
+
+    pub(crate) fn get_mut(&mut self, channel_id: u64) -> Option<&mut TicketRecord> {
+        self.records.iter_mut().rev().find(|record| record.channel_id == channel_id)
+    }
+
+    pub(crate) fn open(&mut self, channel_id: u64, opener_id: u64, at: u64) -> Result<()> {
+        self.open.push(OpenTicket { channel_id, opener_id, opened_at: at, claimed_by: None, claimed_at: None });
+        self.save()
+    }
+
+    // Whether a message from this author could claim the ticket in this channel: it's open, nobody has answered it
+    // yet and the author isn't the one who opened it.
+    pub(crate) fn claimable(&self, channel_id: u64, author_id: u64) -> bool {
+        self.open.iter().any(|ticket| ticket.channel_id == channel_id && ticket.claimed_by.is_none() && ticket.opener_id != author_id)
+    }
+
+    // The first staff member to answer an open ticket claims it.
+    pub(crate) fn claim(&mut self, channel_id: u64, moderator_id: u64, at: u64) -> Result<()> {
+        if let Some(ticket) = self.open.iter_mut().find(|ticket| ticket.channel_id == channel_id && ticket.claimed_by.is_none()) {
+            ticket.claimed_by = Some(moderator_id);
+            ticket.claimed_at = Some(at);
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    // Move a ticket to the closed records. Tickets opened before open tickets were kept only get what's known now.
+    pub(crate) fn close(&mut self, channel_id: u64, opener_id: u64, closer_id: u64, at: u64) -> Result<()> {
+        let open = self.open.iter().position(|ticket| ticket.channel_id == channel_id).map(|index| self.open.remove(index));
+        self.records.push(TicketRecord {
+            channel_id,
+            opener_id,
+            closed_at: at,
+            rating: None,
+            comment: None,
+            opened_at: open.as_ref().map(|ticket| ticket.opened_at),
+            claimed_by: open.as_ref().and_then(|ticket| ticket.claimed_by),
+            claimed_at: open.as_ref().and_then(|ticket| ticket.claimed_at),
+            closed_by: Some(closer_id).filter(|closer_id| *closer_id != opener_id),
+        });
+        self.save()
+    }
+
+    // Drop every ticket a user opened, along with any feedback they left, and take them off the tickets they
+    // claimed or closed as staff.
+    pub(crate) fn forget(&mut self, user_id: u64) -> Result<()> {
+        if self.remove_user(user_id) {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn remove_user(&mut self, user_id: u64) -> bool {
+        let mut changed = false;
+        let (records, open) = (self.records.len(), self.open.len());
+        self.records.retain(|record| record.opener_id != user_id);
+        self.open.retain(|ticket| ticket.opener_id != user_id);
+        for record in &mut self.records {
+            if record.claimed_by == Some(user_id) {
+                (record.claimed_by, record.claimed_at) = (None, None);
+                changed = true;
+            }
+            if record.closed_by == Some(user_id) {
+                record.closed_by = None;
+                changed = true;
+            }
+        }
+        for ticket in &mut self.open {
+            if ticket.claimed_by == Some(user_id) {
+                (ticket.claimed_by, ticket.claimed_at) = (None, None);
+                changed = true;
+            }
+        }
+        changed || self.records.len() != records || self.open.len() != open
+    }
+
+    pub(crate) fn save(&self) -> Result<()> {
+        let _ = std::fs::remove_file(TICKETS_FILE);
+        let mut file = File::create_new(TICKETS_FILE)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER: u64 = 123456789012345678;
+    const OTHER: u64 = 876543210987654321;
+
+    fn record(channel_id: u64, opener_id: u64, comment: Option<&str>) -> TicketRecord {
+        TicketRecord { channel_id, opener_id, closed_at: 1, rating: Some(5), comment: comment.map(str::to_owned), opened_at: Some(0), claimed_by: None, claimed_at: None, closed_by: None }
+    }
+
+    #[test]
+    fn forgetting_an_opener_leaves_nothing_behind() {
+        let mut history = TicketHistory { records: vec![record(1, USER, Some("Quick and friendly")), record(2, OTHER, None), record(3, USER, None)], open: vec![] };
+        history.open.push(OpenTicket { channel_id: 4, opener_id: USER, opened_at: 2, claimed_by: None, claimed_at: None });
+        assert!(history.remove_user(USER));
+
+        let saved = serde_json::to_string(&history).unwrap();
+        assert!(!saved.contains(&USER.to_string()), "{saved}");
+        assert!(!saved.contains("Quick and friendly"), "{saved}");
+        assert!(saved.contains(&OTHER.to_string()), "{saved}");
+
+        // A second erasure finds nothing, so nothing is saved again.
+        assert!(!history.remove_user(USER));
+    }
+
+    #[test]
+    fn forgetting_a_moderator_keeps_the_tickets_they_handled() {
+        let mut handled = record(1, OTHER, None);
+        (handled.claimed_by, handled.claimed_at, handled.closed_by) = (Some(USER), Some(1), Some(USER));
+        let mut history = TicketHistory { records: vec![handled], open: vec![OpenTicket { channel_id: 2, opener_id: OTHER, opened_at: 2, claimed_by: Some(USER), claimed_at: Some(3) }] };
+        assert!(history.remove_user(USER));
+
+        let saved = serde_json::to_string(&history).unwrap();
+        assert!(!saved.contains(&USER.to_string()), "{saved}");
+        assert_eq!(history.records.len(), 1);
+        assert!(history.claimable(2, USER));
+        assert!(!history.remove_user(USER));
+    }
+
+    #[test]
+    fn the_first_answer_from_someone_else_claims_the_ticket() {
+        let mut history = TicketHistory::default();
+        history.open.push(OpenTicket { channel_id: 1, opener_id: USER, opened_at: 10, claimed_by: None, claimed_at: None });
+        assert!(!history.claimable(1, USER));
+        assert!(history.claimable(1, OTHER));
+        assert!(!history.claimable(2, OTHER));
+
+        history.open[0].claimed_by = Some(OTHER);
+        assert!(!history.claimable(1, OTHER));
+    }
+}
@@ -0,0 +1,105 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio_rustls::rustls::crypto::ring::default_provider;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+// 2.5.4.3, the id-at-commonName attribute.
+const COMMON_NAME_OID: &[u8] = &[0x55, 0x04, 0x03];
+
+/// Certificate and key files for encrypting game server connections, all PEM encoded. An empty certificate path
+/// leaves connections in plain text.
+#[derive(Clone, Eq, PartialEq, Default, Serialize, Deserialize)]
+pub(crate) struct TlsConfig {
+    pub(crate) cert_path: String,
+    pub(crate) key_path: String,
+    // CA bundle game server certificates must be signed by. Leave empty to not ask for client certificates.
+    pub(crate) client_ca_path: String,
+}
+
+/// Build the acceptor for the configured certificates, if TLS is enabled. With a client CA configured, the
+/// handshake fails for any client that doesn't present a certificate signed by it.
+pub(crate) fn acceptor(config: &TlsConfig) -> Result<Option<TlsAcceptor>> {
+    if config.cert_path.is_empty() {
+        return Ok(None);
+    }
+
+    let certs = CertificateDer::pem_file_iter(&config.cert_path)?.collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(&config.key_path)?;
+    let provider = Arc::new(default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone()).with_safe_default_protocol_versions()?;
+    let builder = if config.client_ca_path.is_empty() {
+        builder.with_no_client_auth()
+    } else {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(&config.client_ca_path)? {
+            roots.add(cert?)?;
+        }
+        if roots.is_empty() {
+            return Err(anyhow!("No certificates found in {}!", config.client_ca_path));
+        }
+        builder.with_client_cert_verifier(WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build()?)
+    };
+    Ok(Some(TlsAcceptor::from(Arc::new(builder.with_single_cert(certs, key)?))))
+}
+
+/// The common name on the certificate the client presented, if it was asked for one.
+pub(crate) fn peer_common_name<S>(stream: &TlsStream<S>) -> Option<String> {
+    stream.get_ref().1.peer_certificates()?.first().and_then(|cert| common_name(cert))
+}
+
+// Walk just far enough into the certificate's DER to find the subject's common name.
+fn common_name(cert: &[u8]) -> Option<String> {
+    let (_, certificate, _) = next_element(cert)?;
+    let (_, mut fields, _) = next_element(certificate)?;
+
+    // The version is optional and explicitly tagged, then come the serial number, signature algorithm, issuer and
+    // validity before the subject.
+    let (tag, _, rest) = next_element(fields)?;
+    if tag == 0xA0 {
+        fields = rest;
+    }
+    for _ in 0..4 {
+        fields = next_element(fields)?.2;
+    }
+
+    // The subject is a sequence of sets of (OID, value) attributes.
+    let (_, mut subject, _) = next_element(fields)?;
+    while let Some((_, mut attributes, rest)) = next_element(subject) {
+        subject = rest;
+        while let Some((_, attribute, rest)) = next_element(attributes) {
+            attributes = rest;
+            let (_, oid, value) = next_element(attribute)?;
+            if oid == COMMON_NAME_OID {
+                let (_, name, _) = next_element(value)?;
+                return String::from_utf8(name.to_vec()).ok();
+            }
+        }
+    }
+    None
+}
+
+// Split one DER element off the front, returning its tag, its contents and whatever follows it.
+fn next_element(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = data.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let count = (first & 0x7F) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let len = rest[..count].iter().fold(0usize, |len, byte| len << 8 | *byte as usize);
+        (len, &rest[count..])
+    };
+    if rest.len() < len {
+        return None;
+    }
+    Some((tag, &rest[..len], &rest[len..]))
+}
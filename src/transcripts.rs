@@ -0,0 +1,306 @@
+use crate::log;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serenity::all::{ChannelId, CreateAttachment, CreateMessage, GetMessages, Http, Message, MessageId};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::sleep;
+
+const TRANSCRIPTS_DIR: &str = "./transcripts";
+const PAGE_SIZE: u8 = 100;
+// Discord's attachment limit is 10MiB, leaving room for the request around the file.
+const MAX_PART_BYTES: usize = 9 * 1024 * 1024;
+const RETRIES: u32 = 5;
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// How far a transcript has got, saved after every page and upload so a restart picks up where it stopped.
+#[derive(Serialize, Deserialize)]
+struct Progress {
+    // Where the transcript and its progress are kept.
+    #[serde(skip, default = "transcripts_dir")]
+    dir: String,
+    channel_id: u64,
+    // Newest message written so far, and how long the transcript file was at that point.
+    last_message_id: Option<u64>,
+    written_bytes: u64,
+    messages: u64,
+    fetched: bool,
+    // Messages in the transcript channel's reply chain, starting with the index header.
+    posted: Vec<u64>,
+}
+
+/// Start writing a transcript of a closed ticket.
+pub(crate) async fn start(http: Arc<Http>, ticket: ChannelId, transcript_channel: ChannelId) {
+    let progress = Progress { dir: transcripts_dir(), channel_id: ticket.get(), last_message_id: None, written_bytes: 0, messages: 0, fetched: false, posted: vec![] };
+    if let Err(why) = std::fs::create_dir_all(TRANSCRIPTS_DIR).map_err(anyhow::Error::from).and_then(|_| progress.save()) {
+        log!("Error starting transcript of {ticket}: {why:?}");
+        return;
+    }
+    run(&http, progress, transcript_channel).await;
+}
+
+/// Finish any transcripts a restart interrupted.
+pub(crate) async fn resume_all(http: Arc<Http>, transcript_channel: ChannelId) {
+    let Ok(entries) = std::fs::read_dir(TRANSCRIPTS_DIR) else { return };
+    for path in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).filter(|path| path.extension().is_some_and(|extension| extension == "json")) {
+        match File::open(&path).map_err(anyhow::Error::from).and_then(|file| Ok(serde_json::from_reader::<_, Progress>(file)?)) {
+            Ok(progress) => {
+                log!("Resuming transcript of {} after {} messages", progress.channel_id, progress.messages);
+                run(&http, progress, transcript_channel).await;
+            }
+            Err(why) => log!("Error reading transcript progress {}: {why:?}", path.display()),
+        }
+    }
+}
+
+async fn run(http: &Arc<Http>, mut progress: Progress, transcript_channel: ChannelId) {
+    let ticket = progress.channel_id;
+    let result = async {
+        let channel_id = ChannelId::new(ticket);
+        fetch(&mut progress, |after| retry(move || channel_id.messages(http, GetMessages::new().after(after).limit(PAGE_SIZE)))).await?;
+        post(http, &mut progress, transcript_channel).await
+    }.await;
+
+    match result {
+        Ok(()) => {
+            log!("Posted transcript of {ticket} with {} messages", progress.messages);
+            let _ = std::fs::remove_file(progress.text_path());
+            let _ = std::fs::remove_file(progress.progress_path());
+        }
+        Err(why) => log!("Error writing transcript of {ticket}, it will be resumed after a restart: {why:?}"),
+    }
+}
+
+// Page through the ticket's messages from wherever the transcript got to.
+async fn fetch<F: Future<Output = Result<Vec<Message>>>>(progress: &mut Progress, mut page_after: impl FnMut(MessageId) -> F) -> Result<()> {
+    while !progress.fetched {
+        let page = page_after(MessageId::new(progress.last_message_id.unwrap_or(1))).await?;
+        append_page(progress, page)?;
+    }
+    Ok(())
+}
+
+// Append a page of messages to the transcript file. Anything written after the last saved progress is cut off
+// first, so a crash between the two never duplicates messages.
+fn append_page(progress: &mut Progress, mut page: Vec<Message>) -> Result<()> {
+    page.sort_by_key(|message| message.id);
+
+    let mut file = OpenOptions::new().create(true).truncate(false).write(true).open(progress.text_path())?;
+    file.set_len(progress.written_bytes)?;
+    file.seek(SeekFrom::End(0))?;
+    for message in &page {
+        file.write_all(format_message(message).as_bytes())?;
+    }
+    file.sync_all()?;
+
+    progress.written_bytes = file.metadata()?.len();
+    progress.messages += page.len() as u64;
+    progress.last_message_id = page.last().map(|message| message.id.get()).or(progress.last_message_id);
+    progress.fetched = page.len() < PAGE_SIZE as usize;
+    progress.save()
+}
+
+// Post an index header, then each part as a reply to the one before, skipping whatever was already posted.
+async fn post(http: &Arc<Http>, progress: &mut Progress, transcript_channel: ChannelId) -> Result<()> {
+    let parts = split_parts(&progress.text_path(), progress.written_bytes as usize)?;
+    if progress.posted.is_empty() {
+        let header = format!("**Transcript of <#{}>**\n{} messages in {} parts, each posted as a reply below.", progress.channel_id, progress.messages, parts.len());
+        let message = retry(|| transcript_channel.send_message(http, CreateMessage::new().content(header.clone()))).await?;
+        progress.posted.push(message.id.get());
+        progress.save()?;
+    }
+
+    let ticket = progress.channel_id;
+    for (index, part) in parts.iter().enumerate().skip(progress.posted.len() - 1) {
+        let previous = MessageId::new(*progress.posted.last().unwrap());
+        let name = format!("ticket-{ticket}-part-{}-of-{}.txt", index + 1, parts.len());
+        let message = retry(|| transcript_channel.send_message(http, CreateMessage::new()
+            .content(format!("Part {} of {}", index + 1, parts.len()))
+            .add_file(CreateAttachment::bytes(part.clone(), name.clone()))
+            .reference_message((transcript_channel, previous)))).await?;
+        progress.posted.push(message.id.get());
+        progress.save()?;
+    }
+    Ok(())
+}
+
+// Split the transcript at line breaks into parts under the attachment limit.
+fn split_parts(path: &str, length: usize) -> Result<Vec<Vec<u8>>> {
+    let mut text = Vec::with_capacity(length);
+    File::open(path)?.take(length as u64).read_to_end(&mut text)?;
+    Ok(split_text(&text, MAX_PART_BYTES))
+}
+
+// Lines longer than a whole part are cut wherever the part ends.
+fn split_text(text: &[u8], max_part_bytes: usize) -> Vec<Vec<u8>> {
+    let mut parts = vec![];
+    let mut rest = text;
+    while !rest.is_empty() {
+        let mut end = rest.len().min(max_part_bytes);
+        if end < rest.len() && let Some(newline) = rest[..end].iter().rposition(|byte| *byte == b'\n') {
+            end = newline + 1;
+        }
+        parts.push(rest[..end].to_vec());
+        rest = &rest[end..];
+    }
+    if parts.is_empty() {
+        parts.push(b"No messages.\n".to_vec());
+    }
+    parts
+}
+
+fn format_message(message: &Message) -> String {
+    let time = chrono::DateTime::from_timestamp(message.timestamp.unix_timestamp(), 0).map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string()).unwrap_or_default();
+    let mut line = format!("[{time}] {} ({}): {}", message.author.name, message.author.id, message.content);
+    for attachment in &message.attachments {
+        line.push_str(&format!(" [attachment: {}]", attachment.url));
+    }
+    line.push('\n');
+    line
+}
+
+// Retry a discord request with a growing delay, for rate limits and outages that outlast serenity's own retries.
+async fn retry<T, F: Future<Output = serenity::Result<T>>>(mut request: impl FnMut() -> F) -> Result<T> {
+    let mut attempt = 0;
+    loop {
+        match request().await {
+            Ok(value) => return Ok(value),
+            Err(why) if attempt + 1 >= RETRIES => return Err(anyhow!("Gave up after {RETRIES} attempts: {why}")),
+            Err(why) => {
+                attempt += 1;
+                log!("Transcript request failed, retrying ({attempt}/{RETRIES}): {why}");
+                sleep(RETRY_DELAY * attempt).await;
+            }
+        }
+    }
+}
+
+fn transcripts_dir() -> String {
+    TRANSCRIPTS_DIR.to_owned()
+}
+
+impl Progress {
+    fn text_path(&self) -> String {
+        format!("{}/{}.txt", self.dir, self.channel_id)
+    }
+
+    fn progress_path(&self) -> String {
+        format!("{}/{}.json", self.dir, self.channel_id)
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = self.progress_path();
+        let _ = std::fs::remove_file(&path);
+        let mut file = File::create_new(&path)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICKET: u64 = 42;
+    const MESSAGES: u64 = 5000;
+
+    fn message(id: u64) -> Message {
+        let mut message = Message::default();
+        message.id = MessageId::new(id);
+        message.content = format!("message {id}");
+        message
+    }
+
+    // A ticket channel holding MESSAGES messages, answering like discord does: up to a page of the messages after
+    // `after`, newest first.
+    fn page_after(after: MessageId) -> Vec<Message> {
+        let mut page = (after.get() + 1..=MESSAGES + 1).take(PAGE_SIZE as usize).map(message).collect::<Vec<_>>();
+        page.reverse();
+        page
+    }
+
+    fn test_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("ccbot-transcripts-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.display().to_string()
+    }
+
+    fn new_progress(dir: &str) -> Progress {
+        Progress { dir: dir.to_owned(), channel_id: TICKET, last_message_id: None, written_bytes: 0, messages: 0, fetched: false, posted: vec![] }
+    }
+
+    // Message contents in the order they were written.
+    fn written(progress: &Progress) -> Vec<String> {
+        let text = std::fs::read_to_string(progress.text_path()).unwrap();
+        text.lines().map(|line| line.rsplit(": ").next().unwrap().to_owned()).collect()
+    }
+
+    fn expected() -> Vec<String> {
+        (2..=MESSAGES + 1).map(|id| format!("message {id}")).collect()
+    }
+
+    #[tokio::test]
+    async fn every_message_is_written_once_in_order() {
+        let dir = test_dir("whole");
+        let mut progress = new_progress(&dir);
+        fetch(&mut progress, |after| async move { Ok(page_after(after)) }).await.unwrap();
+
+        assert!(progress.fetched);
+        assert_eq!(progress.messages, MESSAGES);
+        assert_eq!(written(&progress), expected());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_restart_resumes_where_the_last_page_was_saved() {
+        let dir = test_dir("resume");
+        let mut progress = new_progress(&dir);
+        let mut pages = 0;
+        let crashed = fetch(&mut progress, |after| {
+            pages += 1;
+            let page = (pages <= 20).then(|| page_after(after));
+            async move { page.ok_or(anyhow!("Crashed")) }
+        }).await;
+        assert!(crashed.is_err());
+
+        // Half a page made it into the file without the progress being saved.
+        let mut file = OpenOptions::new().append(true).open(progress.text_path()).unwrap();
+        file.write_all(b"[2025-01-01 00:00:00] someone (1): message 2002\n[2025-01-01").unwrap();
+
+        let mut resumed = serde_json::from_reader::<_, Progress>(File::open(progress.progress_path()).unwrap()).unwrap();
+        resumed.dir = dir.clone();
+        assert_eq!(resumed.messages, 20 * PAGE_SIZE as u64);
+        fetch(&mut resumed, |after| async move { Ok(page_after(after)) }).await.unwrap();
+
+        assert_eq!(resumed.messages, MESSAGES);
+        assert_eq!(written(&resumed), expected());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn parts_are_split_at_line_breaks_under_the_limit() {
+        let text = (0..1000).map(|line| format!("line {line}\n")).collect::<String>();
+        let parts = split_text(text.as_bytes(), 100);
+        assert!(parts.len() > 1);
+        for part in &parts {
+            assert!(part.len() <= 100);
+            assert!(part.ends_with(b"\n"));
+        }
+        assert_eq!(parts.concat(), text.as_bytes());
+    }
+
+    #[test]
+    fn lines_longer_than_a_part_are_cut() {
+        let text = "x".repeat(250);
+        let parts = split_text(text.as_bytes(), 100);
+        assert_eq!(parts.iter().map(Vec::len).collect::<Vec<_>>(), [100, 100, 50]);
+        assert_eq!(parts.concat(), text.as_bytes());
+    }
+
+    #[test]
+    fn empty_transcripts_still_have_a_part() {
+        assert_eq!(split_text(b"", 100), [b"No messages.\n".to_vec()]);
+    }
+}
@@ -0,0 +1,134 @@
+use crate::log;
+use anyhow::{anyhow, Result};
+use futures_util::{SinkExt, StreamExt};
+use std::time::Duration;
+use tokio::io::{duplex, split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{accept_async_with_config, WebSocketStream};
+
+/// Finish the websocket handshake on an accepted connection and hand back a stream that looks like a raw tcp
+/// client. Each binary message carries one frame's payload, so the usual length prefix is added on the way in
+/// and stripped on the way out.
+pub(crate) async fn accept<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(stream: S, peer: String, max_frame_size: usize, io_timeout: Duration) -> Result<DuplexStream> {
+    let config = WebSocketConfig {
+        max_message_size: Some(max_frame_size),
+        max_frame_size: Some(max_frame_size),
+        ..Default::default()
+    };
+
+    let socket = timeout(io_timeout, accept_async_with_config(stream, Some(config)))
+        .await
+        .map_err(|_| anyhow!("Timed out waiting for the websocket handshake after {}s!", io_timeout.as_secs()))??;
+
+    let (local, remote) = duplex(max_frame_size + 4);
+    tokio::spawn(async move {
+        if let Err(why) = bridge(socket, remote, max_frame_size).await {
+            log!("Websocket connection {peer} closed: {why:?}");
+        }
+    });
+    Ok(local)
+}
+
+// Shuttle frames between the socket and the client handler until either side goes away.
+async fn bridge<S: AsyncRead + AsyncWrite + Unpin>(socket: WebSocketStream<S>, stream: DuplexStream, max_frame_size: usize) -> Result<()> {
+    let (mut sink, mut messages) = socket.split();
+    let (mut reader, mut writer) = split(stream);
+
+    let inbound = async {
+        while let Some(message) = messages.next().await {
+            match message? {
+                Message::Binary(payload) => {
+                    if payload.len() > max_frame_size {
+                        return Err(anyhow!("Attempted to send a message with length {}!", payload.len()));
+                    }
+                    writer.write_all(&(payload.len() as u32).to_be_bytes()).await?;
+                    writer.write_all(&payload).await?;
+                }
+                Message::Close(_) => break,
+                // Pings are answered by tungstenite itself, and text isn't part of the protocol.
+                _ => {}
+            }
+        }
+        Ok(())
+    };
+
+    let outbound = async {
+        let mut len_bytes = [0u8; 4];
+        while reader.read_exact(&mut len_bytes).await.is_ok() {
+            let mut payload = vec![0u8; u32::from_be_bytes(len_bytes) as usize];
+            reader.read_exact(&mut payload).await?;
+            sink.send(Message::Binary(payload)).await?;
+        }
+        sink.close().await?;
+        Ok(())
+    };
+
+    tokio::select! {
+        result = inbound => result,
+        result = outbound => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::{Buffer, ClientPacket, ServerPacket, HELLO_ACCEPTED, LIVENESS_PROTOCOL_VERSION};
+    use tokio_tungstenite::client_async;
+
+    const MAX_FRAME: usize = 1024;
+    const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+    // A websocket client connected to the bot, and the bot's end as the tcp handler sees it.
+    async fn connect() -> (WebSocketStream<DuplexStream>, DuplexStream) {
+        let (client, server) = duplex(64 * 1024);
+        let accepted = tokio::spawn(accept(server, "test".to_owned(), MAX_FRAME, IO_TIMEOUT));
+        let (socket, _) = client_async("ws://localhost/", client).await.unwrap();
+        (socket, accepted.await.unwrap().unwrap())
+    }
+
+    // A frame as it goes over tcp, length prefix and all.
+    async fn frame(encode: impl FnOnce(&mut Buffer)) -> Vec<u8> {
+        let mut buf = Buffer::new(MAX_FRAME);
+        encode(&mut buf);
+        let mut frame = vec![];
+        buf.write_to_tcp(&mut frame, IO_TIMEOUT, None).await.unwrap();
+        frame
+    }
+
+    #[tokio::test]
+    async fn hello_is_exchanged_over_a_websocket() {
+        let (mut socket, mut stream) = connect().await;
+
+        // Websocket clients send a frame's payload without its length prefix.
+        let hello = frame(|buf| ClientPacket::Hello { version: LIVENESS_PROTOCOL_VERSION, server: Some("survival".to_owned()) }.encode(buf, LIVENESS_PROTOCOL_VERSION).unwrap()).await;
+        socket.send(Message::Binary(hello[4..].to_vec())).await.unwrap();
+        let mut buf = Buffer::new(MAX_FRAME);
+        assert!(buf.read_from_tcp(&mut stream, IO_TIMEOUT).await.unwrap());
+        let packet = ClientPacket::decode(&mut buf, LIVENESS_PROTOCOL_VERSION).unwrap();
+        assert!(matches!(&packet, ClientPacket::Hello { version: LIVENESS_PROTOCOL_VERSION, server: Some(server) } if server == "survival"), "{packet:?}");
+
+        let reply = ServerPacket::Hello { status: HELLO_ACCEPTED, message: String::new(), version: "1.0".to_owned(), commit: "abc".to_owned(), build_time: "now".to_owned() };
+        let reply = frame(|buf| reply.encode(buf).unwrap()).await;
+        stream.write_all(&reply).await.unwrap();
+        let Some(Ok(Message::Binary(payload))) = socket.next().await else { panic!("No reply") };
+        assert_eq!(payload, reply[4..]);
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_close_the_connection() {
+        let (mut socket, mut stream) = connect().await;
+        let _ = socket.send(Message::Binary(vec![0; MAX_FRAME + 1])).await;
+        let mut buf = Buffer::new(MAX_FRAME);
+        assert!(!buf.read_from_tcp(&mut stream, IO_TIMEOUT).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn closing_the_websocket_disconnects_the_client() {
+        let (mut socket, mut stream) = connect().await;
+        socket.close(None).await.unwrap();
+        let mut buf = Buffer::new(MAX_FRAME);
+        assert!(!buf.read_from_tcp(&mut stream, IO_TIMEOUT).await.unwrap());
+    }
+}
@@ -0,0 +1,1078 @@
+use anyhow::{anyhow, Result};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::fmt::{Display, Formatter};
+use std::io::{Read, Write};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+
+// Clients that never send a hello are treated as this version.
+pub(crate) const LEGACY_PROTOCOL_VERSION: u8 = 1;
+// From this version on, connect queries carry the player's locale.
+pub(crate) const LOCALE_PROTOCOL_VERSION: u8 = 3;
+// From this version on, the hello names which game server the client is.
+pub(crate) const SERVER_PROTOCOL_VERSION: u8 = 4;
+// From this version on, game servers send player UUIDs as 16 raw bytes instead of dashed strings.
+pub(crate) const BINARY_UUID_PROTOCOL_VERSION: u8 = 5;
+// From this version on, error frames also carry the ID of the packet they answer.
+pub(crate) const ERROR_PACKET_PROTOCOL_VERSION: u8 = 6;
+// From this version on, either side may deflate large frames.
+pub(crate) const COMPRESSION_PROTOCOL_VERSION: u8 = 7;
+// From this version on, the bot pings persistent connections and expects a pong back.
+pub(crate) const LIVENESS_PROTOCOL_VERSION: u8 = 8;
+
+// Set in the length prefix of a frame whose payload is zlib compressed. Frames are far smaller than 2GiB, so the
+// top bit is never part of a real length.
+const COMPRESSED_FLAG: u32 = 1 << 31;
+
+pub(crate) const HELLO_ACCEPTED: u8 = 0;
+pub(crate) const HELLO_UNSUPPORTED: u8 = 1;
+
+pub(crate) const REMOVE_PLAYER_REMOVED: u8 = 0;
+pub(crate) const REMOVE_PLAYER_NOT_FOUND: u8 = 1;
+
+// Sent in place of a code's remaining time when the player has no code.
+pub(crate) const CODE_TTL_NONE: u64 = u64::MAX;
+
+pub(crate) const WHOIS_UNKNOWN: u8 = 0;
+pub(crate) const WHOIS_NEW: u8 = 1;
+pub(crate) const WHOIS_PENDING: u8 = 2;
+pub(crate) const WHOIS_APPROVED: u8 = 3;
+pub(crate) const WHOIS_BANNED: u8 = 4;
+
+pub(crate) const ERROR_INVALID_PAYLOAD: u8 = 1;
+pub(crate) const ERROR_BUSY: u8 = 2;
+pub(crate) const ERROR_UNAUTHORIZED: u8 = 3;
+pub(crate) const ERROR_UNKNOWN_PACKET: u8 = 4;
+pub(crate) const ERROR_INTERNAL: u8 = 5;
+
+// The most bytes each kind of string field may claim. Names leave room for offline-mode servers.
+const MAX_UUID: usize = 64;
+const MAX_NAME: usize = 64;
+const MAX_LOCALE: usize = 16;
+const MAX_SERVER_NAME: usize = 64;
+const MAX_TOKEN: usize = 256;
+const MAX_CHAT: usize = 1024;
+const MAX_CODE: usize = 16;
+const MAX_MESSAGE: usize = 4096;
+const MAX_BUILD_INFO: usize = 256;
+const MAX_LISTEN: usize = 256;
+// The most players a batched connect query may ask about.
+pub(crate) const MAX_CONNECT_BATCH: usize = 100;
+
+/// A string field claimed to be longer than that field may be.
+#[derive(Debug)]
+pub(crate) struct StringTooLong {
+    pub(crate) field: &'static str,
+    pub(crate) len: usize,
+    pub(crate) max: usize,
+}
+
+impl Display for StringTooLong {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The {} field is {} bytes long, over its limit of {}", self.field, self.len, self.max)
+    }
+}
+
+impl std::error::Error for StringTooLong {}
+
+/// Packets sent by game servers and observers. The first byte of a frame is the packet ID, followed by the
+/// fields in the order listed. A `[uuid]` is a dashed string before protocol version 5 and 16 raw bytes from then on.
+#[derive(Debug)]
+pub(crate) enum ClientPacket {
+    // 0: [uuid][string name], followed by [string locale] from protocol version 3
+    ConnectQuery { uuid: String, name: String, locale: Option<String> },
+    // 1: [u8 protocol version], followed by [string server] from protocol version 4
+    Hello { version: u8, server: Option<String> },
+    // 2: no fields
+    WhitelistQuery,
+    // 3: no fields
+    Ping,
+    // 4: [uuid]
+    PlayerJoined { uuid: String },
+    // 5: [uuid]
+    PlayerLeft { uuid: String },
+    // 6: [string token]
+    Subscribe { token: String },
+    // 8: [uuid][string message]
+    Chat { uuid: String, message: String },
+    // 11: [uuid]
+    RemovePlayer { uuid: String },
+    // 12: [string token]
+    Authenticate { token: String },
+    // 13: [uuid][u64 seconds played]
+    SessionEnd { uuid: String, seconds: u64 },
+    // 14: [u32 count] followed by `count` repetitions of [uuid][string name][string locale], with an empty locale
+    // when the plugin doesn't know it
+    ConnectQueryBatch(Vec<(String, String, Option<String>)>),
+    // 15: [u8 last][u32 count] followed by `count` repetitions of [uuid]. Whitelists too big for one frame are sent
+    // over several, with `last` set to 1 on the final one.
+    WhitelistUpload { uuids: Vec<String>, last: bool },
+    // 16: no fields, answers the bot's ping
+    Pong,
+    // 17: [uuid], asks how long the player's verification code has left
+    CodeTtlQuery { uuid: String },
+    // 18: [uuid], asks which discord account the player is linked to. Needs authentication.
+    WhoisQuery { uuid: String },
+    // Any ID the bot doesn't know. These are answered with an ERROR_UNKNOWN_PACKET error frame naming the ID.
+    Unknown(u8),
+}
+
+impl ClientPacket {
+    pub(crate) fn decode(buf: &mut Buffer, protocol_version: u8) -> Result<Self> {
+        Ok(match buf.next_u8()? {
+            0 => Self::ConnectQuery {
+                uuid: buf.next_player_uuid(protocol_version)?,
+                name: buf.next_string("name", MAX_NAME)?,
+                locale: if protocol_version >= LOCALE_PROTOCOL_VERSION { Some(buf.next_string("locale", MAX_LOCALE)?).filter(|locale| !locale.is_empty()) } else { None },
+            },
+            1 => {
+                let version = buf.next_u8()?;
+                let server = if version >= SERVER_PROTOCOL_VERSION { Some(buf.next_string("server", MAX_SERVER_NAME)?).filter(|server| !server.is_empty()) } else { None };
+                Self::Hello { version, server }
+            }
+            2 => Self::WhitelistQuery,
+            3 => Self::Ping,
+            4 => Self::PlayerJoined { uuid: buf.next_player_uuid(protocol_version)? },
+            5 => Self::PlayerLeft { uuid: buf.next_player_uuid(protocol_version)? },
+            6 => Self::Subscribe { token: buf.next_string("token", MAX_TOKEN)? },
+            8 => Self::Chat { uuid: buf.next_player_uuid(protocol_version)?, message: buf.next_string("message", MAX_CHAT)? },
+            11 => Self::RemovePlayer { uuid: buf.next_player_uuid(protocol_version)? },
+            12 => Self::Authenticate { token: buf.next_string("token", MAX_TOKEN)? },
+            13 => Self::SessionEnd { uuid: buf.next_player_uuid(protocol_version)?, seconds: buf.next_u64()? },
+            14 => {
+                let count = buf.next_u32()? as usize;
+                if count > MAX_CONNECT_BATCH {
+                    return Err(anyhow!("Attempted to query {count} players at once!"));
+                }
+                let mut queries = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let uuid = buf.next_player_uuid(protocol_version)?;
+                    let name = buf.next_string("name", MAX_NAME)?;
+                    let locale = Some(buf.next_string("locale", MAX_LOCALE)?).filter(|locale| !locale.is_empty());
+                    queries.push((uuid, name, locale));
+                }
+                Self::ConnectQueryBatch(queries)
+            }
+            15 => {
+                let last = buf.next_bool()?;
+                let count = buf.next_u32()?;
+                let mut uuids = Vec::new();
+                for _ in 0..count {
+                    uuids.push(buf.next_player_uuid(protocol_version)?);
+                }
+                Self::WhitelistUpload { uuids, last }
+            }
+            16 => Self::Pong,
+            17 => Self::CodeTtlQuery { uuid: buf.next_player_uuid(protocol_version)? },
+            18 => Self::WhoisQuery { uuid: buf.next_player_uuid(protocol_version)? },
+            id => Self::Unknown(id),
+        })
+    }
+
+    pub(crate) fn encode(self, buf: &mut Buffer, protocol_version: u8) -> Result<()> {
+        match self {
+            Self::ConnectQuery { uuid, name, locale } => {
+                buf.put_u8(0)?;
+                buf.put_player_uuid(&uuid, protocol_version)?;
+                buf.put_string(name)?;
+                if let Some(locale) = locale {
+                    buf.put_string(locale)?;
+                }
+            }
+
+            Self::Hello { version, server } => {
+                buf.put_u8(1)?;
+                buf.put_u8(version)?;
+                if let Some(server) = server {
+                    buf.put_string(server)?;
+                }
+            }
+
+            Self::WhitelistQuery => buf.put_u8(2)?,
+            Self::Ping => buf.put_u8(3)?,
+
+            Self::PlayerJoined { uuid } => {
+                buf.put_u8(4)?;
+                buf.put_player_uuid(&uuid, protocol_version)?;
+            }
+
+            Self::PlayerLeft { uuid } => {
+                buf.put_u8(5)?;
+                buf.put_player_uuid(&uuid, protocol_version)?;
+            }
+
+            Self::Subscribe { token } => {
+                buf.put_u8(6)?;
+                buf.put_string(token)?;
+            }
+
+            Self::Chat { uuid, message } => {
+                buf.put_u8(8)?;
+                buf.put_player_uuid(&uuid, protocol_version)?;
+                buf.put_string(message)?;
+            }
+
+            Self::RemovePlayer { uuid } => {
+                buf.put_u8(11)?;
+                buf.put_player_uuid(&uuid, protocol_version)?;
+            }
+
+            Self::Authenticate { token } => {
+                buf.put_u8(12)?;
+                buf.put_string(token)?;
+            }
+
+            Self::SessionEnd { uuid, seconds } => {
+                buf.put_u8(13)?;
+                buf.put_player_uuid(&uuid, protocol_version)?;
+                buf.put_u64(seconds)?;
+            }
+
+            Self::ConnectQueryBatch(queries) => {
+                buf.put_u8(14)?;
+                buf.put_u32(queries.len() as u32)?;
+                for (uuid, name, locale) in queries {
+                    buf.put_player_uuid(&uuid, protocol_version)?;
+                    buf.put_string(name)?;
+                    buf.put_string(locale.unwrap_or_default())?;
+                }
+            }
+
+            Self::WhitelistUpload { uuids, last } => {
+                buf.put_u8(15)?;
+                buf.put_bool(last)?;
+                buf.put_u32(uuids.len() as u32)?;
+                for uuid in uuids {
+                    buf.put_player_uuid(&uuid, protocol_version)?;
+                }
+            }
+
+            Self::Pong => buf.put_u8(16)?,
+
+            Self::CodeTtlQuery { uuid } => {
+                buf.put_u8(17)?;
+                buf.put_player_uuid(&uuid, protocol_version)?;
+            }
+
+            Self::WhoisQuery { uuid } => {
+                buf.put_u8(18)?;
+                buf.put_player_uuid(&uuid, protocol_version)?;
+            }
+
+            Self::Unknown(id) => buf.put_u8(id)?,
+        }
+        Ok(())
+    }
+}
+
+/// Packets sent by the bot. The IDs of replies match the packet they answer. UUIDs here are always dashed strings.
+#[derive(Debug)]
+pub(crate) enum ServerPacket {
+    // 0: [string response], empty if the player may join. Only sent to protocol version 1 clients.
+    ConnectResponse(String),
+    // 0: [u8 result][string message][string code], with an empty code when the player doesn't need one
+    ConnectResult { result: u8, message: String, code: String },
+    // 1: [u8 status][string message][string version][string commit][string build time]
+    Hello { status: u8, message: String, version: String, commit: String, build_time: String },
+    // 2: [u32 count] followed by `count` repetitions of [string name][string uuid]
+    Whitelist(Vec<(String, String)>),
+    // 3: no fields
+    Pong,
+    // 7: [u8 event kind][string name][string uuid]
+    ObserverEvent { kind: u8, name: String, uuid: String },
+    // 9: [string uuid][string reason]
+    KickPlayer { uuid: String, reason: String },
+    // 10: [string listen address]
+    Reconnect(String),
+    // 11: [u8 status]
+    RemovePlayer(u8),
+    // 12: no fields, sent once authenticated
+    Authenticated,
+    // 14: [u32 count] followed by `count` repetitions of [u8 result][string message][string code], in the order
+    // the players were asked about
+    ConnectBatchResult(Vec<(u8, String, String)>),
+    // 15: no fields, asks the plugin to upload the game server's whitelist
+    RequestWhitelist,
+    // 16: [u32 count] followed by `count` repetitions of [string name][string uuid] to add to the game server's whitelist
+    AddToWhitelist(Vec<(String, String)>),
+    // 17: no fields, checks the connection is still alive. Answered with a pong.
+    Ping,
+    // 18: [u64 milliseconds] until the player's verification code expires, or CODE_TTL_NONE if they have none
+    CodeTtl(u64),
+    // 19: [string message] for the game server to show in chat
+    Announcement(String),
+    // 20: [u8 verify state][u64 discord id][string discord username], with a zero ID when the player hasn't linked
+    // an account and an empty username when discord couldn't be asked
+    Whois { state: u8, discord_id: u64, discord_name: String },
+    // 0xFF: [u8 error code][string message], followed by [u8 packet id] from protocol version 6
+    Error { code: u8, message: String, packet: Option<u8> },
+}
+
+impl ServerPacket {
+    // Connect responses share an ID, so decoding them depends on the negotiated protocol version.
+    pub(crate) fn decode(buf: &mut Buffer, protocol_version: u8) -> Result<Self> {
+        Ok(match buf.next_u8()? {
+            0 if protocol_version == LEGACY_PROTOCOL_VERSION => Self::ConnectResponse(buf.next_string("response", MAX_MESSAGE)?),
+            0 => Self::ConnectResult { result: buf.next_u8()?, message: buf.next_string("message", MAX_MESSAGE)?, code: buf.next_string("code", MAX_CODE)? },
+            1 => Self::Hello {
+                status: buf.next_u8()?,
+                message: buf.next_string("message", MAX_MESSAGE)?,
+                version: buf.next_string("version", MAX_BUILD_INFO)?,
+                commit: buf.next_string("commit", MAX_BUILD_INFO)?,
+                build_time: buf.next_string("build time", MAX_BUILD_INFO)?,
+            },
+            2 => {
+                let count = buf.next_u32()?;
+                let mut users = Vec::new();
+                for _ in 0..count {
+                    users.push((buf.next_string("name", MAX_NAME)?, buf.next_string("uuid", MAX_UUID)?));
+                }
+                Self::Whitelist(users)
+            }
+            3 => Self::Pong,
+            7 => Self::ObserverEvent { kind: buf.next_u8()?, name: buf.next_string("name", MAX_NAME)?, uuid: buf.next_string("uuid", MAX_UUID)? },
+            9 => Self::KickPlayer { uuid: buf.next_string("uuid", MAX_UUID)?, reason: buf.next_string("reason", MAX_MESSAGE)? },
+            10 => Self::Reconnect(buf.next_string("listen address", MAX_LISTEN)?),
+            11 => Self::RemovePlayer(buf.next_u8()?),
+            12 => Self::Authenticated,
+            14 => {
+                let count = buf.next_u32()?;
+                let mut results = Vec::new();
+                for _ in 0..count {
+                    results.push((buf.next_u8()?, buf.next_string("message", MAX_MESSAGE)?, buf.next_string("code", MAX_CODE)?));
+                }
+                Self::ConnectBatchResult(results)
+            }
+            15 => Self::RequestWhitelist,
+            16 => {
+                let count = buf.next_u32()?;
+                let mut users = Vec::new();
+                for _ in 0..count {
+                    users.push((buf.next_string("name", MAX_NAME)?, buf.next_string("uuid", MAX_UUID)?));
+                }
+                Self::AddToWhitelist(users)
+            }
+            17 => Self::Ping,
+            18 => Self::CodeTtl(buf.next_u64()?),
+            19 => Self::Announcement(buf.next_string("announcement", MAX_MESSAGE)?),
+            20 => Self::Whois { state: buf.next_u8()?, discord_id: buf.next_u64()?, discord_name: buf.next_string("discord name", MAX_NAME)? },
+            0xFF => Self::Error {
+                code: buf.next_u8()?,
+                message: buf.next_string("message", MAX_MESSAGE)?,
+                packet: if protocol_version >= ERROR_PACKET_PROTOCOL_VERSION { Some(buf.next_u8()?) } else { None },
+            },
+            id => return Err(anyhow!("Unknown packet {id}!")),
+        })
+    }
+
+    pub(crate) fn encode(self, buf: &mut Buffer) -> Result<()> {
+        match self {
+            Self::ConnectResponse(response) => {
+                buf.put_u8(0)?;
+                buf.put_string(response)?;
+            }
+
+            Self::ConnectResult { result, message, code } => {
+                buf.put_u8(0)?;
+                buf.put_u8(result)?;
+                buf.put_string(message)?;
+                buf.put_string(code)?;
+            }
+
+            Self::Hello { status, message, version, commit, build_time } => {
+                buf.put_u8(1)?;
+                buf.put_u8(status)?;
+                buf.put_string(message)?;
+                buf.put_string(version)?;
+                buf.put_string(commit)?;
+                buf.put_string(build_time)?;
+            }
+
+            Self::Whitelist(users) => {
+                buf.put_u8(2)?;
+                buf.put_u32(users.len() as u32)?;
+                for (name, uuid) in users {
+                    buf.put_string(name)?;
+                    buf.put_string(uuid)?;
+                }
+            }
+
+            Self::Pong => buf.put_u8(3)?,
+
+            Self::ObserverEvent { kind, name, uuid } => {
+                buf.put_u8(7)?;
+                buf.put_u8(kind)?;
+                buf.put_string(name)?;
+                buf.put_string(uuid)?;
+            }
+
+            Self::KickPlayer { uuid, reason } => {
+                buf.put_u8(9)?;
+                buf.put_string(uuid)?;
+                buf.put_string(reason)?;
+            }
+
+            Self::Reconnect(listen) => {
+                buf.put_u8(10)?;
+                buf.put_string(listen)?;
+            }
+
+            Self::RemovePlayer(status) => {
+                buf.put_u8(11)?;
+                buf.put_u8(status)?;
+            }
+
+            Self::Authenticated => buf.put_u8(12)?,
+
+            Self::ConnectBatchResult(results) => {
+                buf.put_u8(14)?;
+                buf.put_u32(results.len() as u32)?;
+                for (result, message, code) in results {
+                    buf.put_u8(result)?;
+                    buf.put_string(message)?;
+                    buf.put_string(code)?;
+                }
+            }
+
+            Self::RequestWhitelist => buf.put_u8(15)?,
+
+            Self::AddToWhitelist(users) => {
+                buf.put_u8(16)?;
+                buf.put_u32(users.len() as u32)?;
+                for (name, uuid) in users {
+                    buf.put_string(name)?;
+                    buf.put_string(uuid)?;
+                }
+            }
+
+            Self::Ping => buf.put_u8(17)?,
+
+            Self::CodeTtl(remaining) => {
+                buf.put_u8(18)?;
+                buf.put_u64(remaining)?;
+            }
+
+            Self::Announcement(message) => {
+                buf.put_u8(19)?;
+                buf.put_string(message)?;
+            }
+
+            Self::Whois { state, discord_id, discord_name } => {
+                buf.put_u8(20)?;
+                buf.put_u8(state)?;
+                buf.put_u64(discord_id)?;
+                buf.put_string(discord_name)?;
+            }
+
+            Self::Error { code, message, packet } => {
+                buf.put_u8(0xFF)?;
+                buf.put_u8(code)?;
+                buf.put_string(message)?;
+                if let Some(packet) = packet {
+                    buf.put_u8(packet)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+macro_rules! impl_next {
+    ($ty:ty,$id:ident) => {
+        fn $id(&mut self) -> Result<$ty> {
+            let len = size_of::<$ty>();
+            if self.read_cursor + len > self.data.len() {
+                return Err(anyhow!("Ran out of room while reading!"));
+            }
+            let data = <$ty>::from_be_bytes(self.data[self.read_cursor..self.read_cursor + len].try_into()?);
+            self.read_cursor += len;
+            Ok(data)
+        }
+    };
+}
+
+macro_rules! impl_put {
+    ($ty:ty,$id:ident) => {
+        fn $id(&mut self, val: $ty) -> Result<()> {
+            self.reserve(size_of::<$ty>())?;
+            self.data.extend_from_slice(&val.to_be_bytes());
+            Ok(())
+        }
+    };
+}
+
+// Frames are laid out as a u32 length followed by that many bytes of payload. The buffer grows as
+// values are written, up to the configured maximum frame size. A compressed frame has the top bit of its length
+// set, and is held to the same maximum once inflated.
+pub(crate) struct Buffer {
+    read_cursor: usize,
+    max_size: usize,
+    data: Vec<u8>,
+    // Whether the frame read into the buffer is still deflated.
+    compressed: bool,
+}
+
+impl Buffer {
+    pub(crate) fn new(max_size: usize) -> Self {
+        Self {
+            read_cursor: 0,
+            max_size,
+            data: Vec::new(),
+            compressed: false,
+        }
+    }
+
+    // The ID of the packet in a frame that has been read, even if the rest of it can't be decoded.
+    pub(crate) fn packet_id(&self) -> Option<u8> {
+        self.data.first().copied()
+    }
+
+    fn reset(&mut self) {
+        self.read_cursor = 0;
+        self.data.clear();
+        self.compressed = false;
+    }
+
+    fn reserve(&mut self, len: usize) -> Result<()> {
+        if self.data.len() + len > self.max_size {
+            return Err(anyhow!("Ran into end of buffer while writing!"));
+        }
+        self.data.reserve(len);
+        Ok(())
+    }
+
+    // Returns false if the client closed the connection instead of sending another frame. Compressed frames are left
+    // deflated until `decompress` is given the protocol version the peer negotiated.
+    pub(crate) async fn read_from_tcp<R: AsyncRead + Unpin>(&mut self, stream: &mut R, io_timeout: Duration) -> Result<bool> {
+        self.reset();
+
+        // Read the length as an integer. A connection closed between frames is a clean disconnect, but one closed
+        // partway through a length is a truncated frame.
+        let mut len_bytes = [0u8; 4];
+        if stream.read(&mut len_bytes[..1]).await? == 0 {
+            return Ok(false);
+        }
+        stream.read_exact(&mut len_bytes[1..]).await?;
+        let len_prefix = u32::from_be_bytes(len_bytes);
+        let compressed = len_prefix & COMPRESSED_FLAG != 0;
+        let len = (len_prefix & !COMPRESSED_FLAG) as usize;
+
+        // Check the length before allocating anything for it. Every frame starts with a packet ID, so an empty
+        // one can only be a broken client.
+        if len == 0 {
+            return Err(anyhow!("Attempted to read an empty packet with no ID!"));
+        }
+        if len > self.max_size {
+            return Err(anyhow!("Attempted to read packet with length {len}!"));
+        }
+
+        // Once a frame has started, the rest of it has to arrive promptly
+        self.data.resize(len, 0);
+        timeout(io_timeout, stream.read_exact(&mut self.data))
+            .await
+            .map_err(|_| anyhow!("Timed out reading a {len} byte frame after {}s!", io_timeout.as_secs()))??;
+        self.compressed = compressed;
+        Ok(true)
+    }
+
+    // Inflate the frame if it was compressed. Only peers that negotiated compression may send compressed frames,
+    // so from anyone else the flag is refused rather than honoured.
+    pub(crate) fn decompress(&mut self, protocol_version: u8) -> Result<()> {
+        if !self.compressed {
+            return Ok(());
+        }
+        if protocol_version < COMPRESSION_PROTOCOL_VERSION {
+            return Err(anyhow!("Attempted to read a compressed packet on protocol version {protocol_version}!"));
+        }
+        self.compressed = false;
+        self.inflate()
+    }
+
+    // Inflate a compressed payload in place. Reading stops one byte past the limit, so a frame that inflates to
+    // far more than it claims is refused without ever being held in memory.
+    fn inflate(&mut self) -> Result<()> {
+        let mut inflated = Vec::new();
+        ZlibDecoder::new(self.data.as_slice()).take(self.max_size as u64 + 1).read_to_end(&mut inflated)?;
+        if inflated.len() > self.max_size {
+            return Err(anyhow!("Attempted to read a compressed packet inflating past {} bytes!", self.max_size));
+        }
+        if inflated.is_empty() {
+            return Err(anyhow!("Attempted to read an empty packet with no ID!"));
+        }
+        self.data = inflated;
+        Ok(())
+    }
+
+    // Frames longer than `compress_over` bytes are deflated, unless that wouldn't make them any smaller. Only
+    // pass a threshold to peers that negotiated compression.
+    pub(crate) async fn write_to_tcp<W: AsyncWrite + Unpin>(&mut self, stream: &mut W, io_timeout: Duration, compress_over: Option<usize>) -> Result<()> {
+        let mut len_prefix = self.data.len() as u32;
+        if let Some(threshold) = compress_over && self.data.len() > threshold {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&self.data)?;
+            let deflated = encoder.finish()?;
+            if deflated.len() < self.data.len() {
+                len_prefix = deflated.len() as u32 | COMPRESSED_FLAG;
+                self.data = deflated;
+            }
+        }
+
+        let write = async {
+            stream.write_all(&len_prefix.to_be_bytes()).await?;
+            stream.write_all(&self.data).await
+        };
+        timeout(io_timeout, write)
+            .await
+            .map_err(|_| anyhow!("Timed out writing a frame after {}s!", io_timeout.as_secs()))??;
+        self.reset();
+        Ok(())
+    }
+
+    impl_next!(u8, next_u8);
+    impl_next!(u32, next_u32);
+
+    // The length prefix comes from the peer, so it's checked against the field's own limit before anything else.
+    fn next_string(&mut self, field: &'static str, max: usize) -> Result<String> {
+        let len = self.next_u32()? as usize;
+        if len > max {
+            return Err(StringTooLong { field, len, max }.into());
+        }
+        if self.read_cursor + len > self.data.len() {
+            return Err(anyhow!("Ran out of room while reading!"));
+        }
+        let data = &self.data[self.read_cursor..self.read_cursor + len];
+        self.read_cursor += len;
+        Ok(String::from_utf8(Vec::from(data))?)
+    }
+
+    impl_put!(u8, put_u8);
+    impl_put!(u32, put_u32);
+
+    fn put_string(&mut self, val: String) -> Result<()> {
+        let len = val.len();
+        self.reserve(size_of::<u32>() + len)?;
+        self.put_u32(len as u32)?;
+        self.data.extend_from_slice(val.as_bytes());
+        Ok(())
+    }
+}
+
+// Player UUIDs, kept as canonical dashed strings outside of the wire format.
+impl Buffer {
+    // Two big-endian u64s, most significant half first.
+    fn next_uuid(&mut self) -> Result<String> {
+        let high = self.next_u64()? as u128;
+        let low = self.next_u64()? as u128;
+        Ok(format_uuid(high << 64 | low))
+    }
+
+    fn put_uuid(&mut self, uuid: &str) -> Result<()> {
+        let bits = parse_uuid(uuid).ok_or(anyhow!("Invalid UUID {uuid}!"))?;
+        self.put_u64((bits >> 64) as u64)?;
+        self.put_u64(bits as u64)
+    }
+
+    fn next_player_uuid(&mut self, protocol_version: u8) -> Result<String> {
+        if protocol_version >= BINARY_UUID_PROTOCOL_VERSION {
+            self.next_uuid()
+        } else {
+            self.next_string("uuid", MAX_UUID)
+        }
+    }
+
+    fn put_player_uuid(&mut self, uuid: &str, protocol_version: u8) -> Result<()> {
+        if protocol_version >= BINARY_UUID_PROTOCOL_VERSION {
+            self.put_uuid(uuid)
+        } else {
+            self.put_string(uuid.to_owned())
+        }
+    }
+}
+
+/// Read a UUID with or without dashes, in either case.
+pub(crate) fn parse_uuid(uuid: &str) -> Option<u128> {
+    let dashed = uuid.len() == 36 && [8, 13, 18, 23].iter().all(|index| uuid.as_bytes()[*index] == b'-');
+    let hex = if dashed { uuid.replace('-', "") } else { uuid.to_owned() };
+    if hex.len() != 32 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    u128::from_str_radix(&hex, 16).ok()
+}
+
+/// The canonical lowercase, dashed form of a UUID.
+pub(crate) fn format_uuid(bits: u128) -> String {
+    let hex = format!("{bits:032x}");
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
+// Wider primitives for packets carrying discord IDs, timestamps and durations.
+#[allow(dead_code)]
+impl Buffer {
+    impl_next!(u64, next_u64);
+    impl_next!(i64, next_i64);
+    impl_next!(f64, next_f64);
+
+    // Booleans are a single byte that must be 0 or 1.
+    fn next_bool(&mut self) -> Result<bool> {
+        match self.next_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            x => Err(anyhow!("Invalid boolean value {x}!")),
+        }
+    }
+
+    impl_put!(u64, put_u64);
+    impl_put!(i64, put_i64);
+    impl_put!(f64, put_f64);
+
+    fn put_bool(&mut self, val: bool) -> Result<()> {
+        self.put_u8(val as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_FRAME: usize = 64 * 1024;
+    const UUID: &str = "069a79f4-44e9-4726-a5be-fca90e38aaf5";
+    const UUID_BYTES: [u8; 16] = [0x06, 0x9a, 0x79, 0xf4, 0x44, 0xe9, 0x47, 0x26, 0xa5, 0xbe, 0xfc, 0xa9, 0x0e, 0x38, 0xaa, 0xf5];
+
+    fn frame(bytes: &[u8]) -> Buffer {
+        let mut buf = Buffer::new(MAX_FRAME);
+        buf.data = bytes.to_vec();
+        buf
+    }
+
+    // A string field as it appears on the wire.
+    fn string(text: &str) -> Vec<u8> {
+        let mut bytes = (text.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(text.as_bytes());
+        bytes
+    }
+
+    fn concat(parts: &[&[u8]]) -> Vec<u8> {
+        parts.concat()
+    }
+
+    // The packet encodes to exactly the fixture, and the fixture decodes back to the same packet.
+    fn assert_client_fixture(packet: ClientPacket, protocol_version: u8, fixture: &[u8]) {
+        let expected = format!("{packet:?}");
+        let mut buf = Buffer::new(MAX_FRAME);
+        packet.encode(&mut buf, protocol_version).unwrap();
+        assert_eq!(buf.data, fixture, "encoding {expected}");
+        let decoded = ClientPacket::decode(&mut frame(fixture), protocol_version).unwrap();
+        assert_eq!(format!("{decoded:?}"), expected);
+    }
+
+    fn assert_server_fixture(packet: ServerPacket, protocol_version: u8, fixture: &[u8]) {
+        let expected = format!("{packet:?}");
+        let mut buf = Buffer::new(MAX_FRAME);
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(buf.data, fixture, "encoding {expected}");
+        let decoded = ServerPacket::decode(&mut frame(fixture), protocol_version).unwrap();
+        assert_eq!(format!("{decoded:?}"), expected);
+    }
+
+    // The exchange every plugin build since the first speaks, which has to stay byte for byte the same.
+    #[test]
+    fn legacy_connect_exchange_is_unchanged() {
+        let mut query = vec![0, 0, 0, 0, 36];
+        query.extend_from_slice(UUID.as_bytes());
+        query.extend_from_slice(&[0, 0, 0, 5, b'N', b'o', b't', b'c', b'h']);
+        assert_client_fixture(ClientPacket::ConnectQuery { uuid: UUID.to_owned(), name: "Notch".to_owned(), locale: None }, LEGACY_PROTOCOL_VERSION, &query);
+
+        assert_server_fixture(ServerPacket::ConnectResponse(String::new()), LEGACY_PROTOCOL_VERSION, &[0, 0, 0, 0, 0]);
+        assert_server_fixture(ServerPacket::ConnectResponse("Code 123456".to_owned()), LEGACY_PROTOCOL_VERSION, &[0, 0, 0, 0, 11, b'C', b'o', b'd', b'e', b' ', b'1', b'2', b'3', b'4', b'5', b'6']);
+    }
+
+    #[test]
+    fn client_packets_match_fixtures() {
+        let uuid = || UUID.to_owned();
+        let v4 = SERVER_PROTOCOL_VERSION;
+        let v5 = BINARY_UUID_PROTOCOL_VERSION;
+
+        assert_client_fixture(ClientPacket::ConnectQuery { uuid: uuid(), name: "Notch".to_owned(), locale: Some("en-US".to_owned()) }, v4, &concat(&[&[0], &string(UUID), &string("Notch"), &string("en-US")]));
+        assert_client_fixture(ClientPacket::ConnectQuery { uuid: uuid(), name: "Notch".to_owned(), locale: Some("en-US".to_owned()) }, v5, &concat(&[&[0], &UUID_BYTES, &string("Notch"), &string("en-US")]));
+        assert_client_fixture(ClientPacket::Hello { version: 2, server: None }, LEGACY_PROTOCOL_VERSION, &[1, 2]);
+        assert_client_fixture(ClientPacket::Hello { version: v5, server: Some("survival".to_owned()) }, LEGACY_PROTOCOL_VERSION, &concat(&[&[1, v5], &string("survival")]));
+        assert_client_fixture(ClientPacket::WhitelistQuery, v5, &[2]);
+        assert_client_fixture(ClientPacket::Ping, v5, &[3]);
+        assert_client_fixture(ClientPacket::PlayerJoined { uuid: uuid() }, v5, &concat(&[&[4], &UUID_BYTES]));
+        assert_client_fixture(ClientPacket::PlayerLeft { uuid: uuid() }, v4, &concat(&[&[5], &string(UUID)]));
+        assert_client_fixture(ClientPacket::Subscribe { token: "secret".to_owned() }, v5, &concat(&[&[6], &string("secret")]));
+        assert_client_fixture(ClientPacket::Chat { uuid: uuid(), message: "hi".to_owned() }, v5, &concat(&[&[8], &UUID_BYTES, &string("hi")]));
+        assert_client_fixture(ClientPacket::RemovePlayer { uuid: uuid() }, v5, &concat(&[&[11], &UUID_BYTES]));
+        assert_client_fixture(ClientPacket::Authenticate { token: "secret".to_owned() }, v5, &concat(&[&[12], &string("secret")]));
+        assert_client_fixture(ClientPacket::SessionEnd { uuid: uuid(), seconds: 3600 }, v5, &concat(&[&[13], &UUID_BYTES, &3600u64.to_be_bytes()]));
+        assert_client_fixture(
+            ClientPacket::ConnectQueryBatch(vec![(uuid(), "Notch".to_owned(), None), (uuid(), "jeb_".to_owned(), Some("sv-SE".to_owned()))]),
+            v5,
+            &concat(&[&[14, 0, 0, 0, 2], &UUID_BYTES, &string("Notch"), &string(""), &UUID_BYTES, &string("jeb_"), &string("sv-SE")]),
+        );
+        assert_client_fixture(ClientPacket::WhitelistUpload { uuids: vec![uuid()], last: true }, v5, &concat(&[&[15, 1, 0, 0, 0, 1], &UUID_BYTES]));
+        assert_client_fixture(ClientPacket::Pong, v5, &[16]);
+        assert_client_fixture(ClientPacket::CodeTtlQuery { uuid: uuid() }, v5, &concat(&[&[17], &UUID_BYTES]));
+        assert_client_fixture(ClientPacket::WhoisQuery { uuid: uuid() }, v5, &concat(&[&[18], &UUID_BYTES]));
+        assert_client_fixture(ClientPacket::Unknown(200), v5, &[200]);
+    }
+
+    #[test]
+    fn server_packets_match_fixtures() {
+        let v2 = LEGACY_PROTOCOL_VERSION + 1;
+        let user = || vec![("Notch".to_owned(), UUID.to_owned())];
+
+        assert_server_fixture(ServerPacket::ConnectResult { result: 1, message: "Enter your code".to_owned(), code: "123456".to_owned() }, v2, &concat(&[&[0, 1], &string("Enter your code"), &string("123456")]));
+        assert_server_fixture(
+            ServerPacket::Hello { status: HELLO_ACCEPTED, message: String::new(), version: "1.0.0".to_owned(), commit: "abc123".to_owned(), build_time: "now".to_owned() },
+            v2,
+            &concat(&[&[1, HELLO_ACCEPTED], &string(""), &string("1.0.0"), &string("abc123"), &string("now")]),
+        );
+        assert_server_fixture(ServerPacket::Whitelist(user()), v2, &concat(&[&[2, 0, 0, 0, 1], &string("Notch"), &string(UUID)]));
+        assert_server_fixture(ServerPacket::Pong, v2, &[3]);
+        assert_server_fixture(ServerPacket::ObserverEvent { kind: 2, name: String::new(), uuid: String::new() }, v2, &concat(&[&[7, 2], &string(""), &string("")]));
+        assert_server_fixture(ServerPacket::KickPlayer { uuid: UUID.to_owned(), reason: "Bye".to_owned() }, v2, &concat(&[&[9], &string(UUID), &string("Bye")]));
+        assert_server_fixture(ServerPacket::Reconnect("0.0.0.0:25687".to_owned()), v2, &concat(&[&[10], &string("0.0.0.0:25687")]));
+        assert_server_fixture(ServerPacket::RemovePlayer(REMOVE_PLAYER_NOT_FOUND), v2, &[11, REMOVE_PLAYER_NOT_FOUND]);
+        assert_server_fixture(ServerPacket::Authenticated, v2, &[12]);
+        assert_server_fixture(ServerPacket::ConnectBatchResult(vec![(0, String::new(), String::new())]), v2, &concat(&[&[14, 0, 0, 0, 1, 0], &string(""), &string("")]));
+        assert_server_fixture(ServerPacket::RequestWhitelist, v2, &[15]);
+        assert_server_fixture(ServerPacket::AddToWhitelist(user()), v2, &concat(&[&[16, 0, 0, 0, 1], &string("Notch"), &string(UUID)]));
+        assert_server_fixture(ServerPacket::Ping, v2, &[17]);
+        assert_server_fixture(ServerPacket::CodeTtl(CODE_TTL_NONE), v2, &[18, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_server_fixture(ServerPacket::Announcement("Restarting".to_owned()), v2, &concat(&[&[19], &string("Restarting")]));
+        assert_server_fixture(ServerPacket::Whois { state: WHOIS_UNKNOWN, discord_id: 0, discord_name: String::new() }, v2, &concat(&[&[20, WHOIS_UNKNOWN], &[0; 8], &string("")]));
+        assert_server_fixture(ServerPacket::Error { code: ERROR_BUSY, message: "Busy".to_owned(), packet: None }, v2, &concat(&[&[0xFF, ERROR_BUSY], &string("Busy")]));
+        assert_server_fixture(ServerPacket::Error { code: ERROR_UNKNOWN_PACKET, message: "Unknown packet 200".to_owned(), packet: Some(200) }, ERROR_PACKET_PROTOCOL_VERSION, &concat(&[&[0xFF, ERROR_UNKNOWN_PACKET], &string("Unknown packet 200"), &[200]]));
+    }
+
+    #[test]
+    fn primitives_round_trip() {
+        let mut buf = Buffer::new(MAX_FRAME);
+        buf.put_u8(0xAB).unwrap();
+        buf.put_u32(0xDEAD_BEEF).unwrap();
+        buf.put_u64(u64::MAX - 1).unwrap();
+        buf.put_bool(true).unwrap();
+        buf.put_bool(false).unwrap();
+        buf.put_string("Notch".to_owned()).unwrap();
+        buf.put_u64(1_234_567_890_123_456_789).unwrap();
+
+        assert_eq!(buf.next_u8().unwrap(), 0xAB);
+        assert_eq!(buf.next_u32().unwrap(), 0xDEAD_BEEF);
+        assert_eq!(buf.next_u64().unwrap(), u64::MAX - 1);
+        assert!(buf.next_bool().unwrap());
+        assert!(!buf.next_bool().unwrap());
+        assert_eq!(buf.next_string("name", MAX_NAME).unwrap(), "Notch");
+        assert_eq!(buf.next_u64().unwrap(), 1_234_567_890_123_456_789);
+        assert!(buf.next_u8().is_err());
+    }
+
+    #[test]
+    fn signed_and_float_primitives_round_trip() {
+        for val in [0, 1, -1, i64::MIN, i64::MAX] {
+            let mut buf = Buffer::new(MAX_FRAME);
+            buf.put_i64(val).unwrap();
+            assert_eq!(buf.next_i64().unwrap(), val);
+            assert!(buf.next_u8().is_err());
+        }
+        for val in [0.0, -0.0, 1.5, -273.15, f64::MIN_POSITIVE, f64::MAX, f64::INFINITY, f64::NEG_INFINITY] {
+            let mut buf = Buffer::new(MAX_FRAME);
+            buf.put_f64(val).unwrap();
+            assert_eq!(buf.next_f64().unwrap().to_bits(), val.to_bits());
+            assert!(buf.next_u8().is_err());
+        }
+        let mut buf = Buffer::new(MAX_FRAME);
+        buf.put_f64(f64::NAN).unwrap();
+        assert!(buf.next_f64().unwrap().is_nan());
+    }
+
+    #[test]
+    fn mixed_primitives_round_trip_in_order() {
+        let mut buf = Buffer::new(MAX_FRAME);
+        buf.put_i64(-42).unwrap();
+        buf.put_u8(7).unwrap();
+        buf.put_f64(0.1).unwrap();
+        buf.put_bool(true).unwrap();
+        buf.put_u64(u64::MAX).unwrap();
+        buf.put_i64(i64::MIN).unwrap();
+        buf.put_u32(9).unwrap();
+        buf.put_f64(-1e300).unwrap();
+
+        assert_eq!(buf.next_i64().unwrap(), -42);
+        assert_eq!(buf.next_u8().unwrap(), 7);
+        assert_eq!(buf.next_f64().unwrap(), 0.1);
+        assert!(buf.next_bool().unwrap());
+        assert_eq!(buf.next_u64().unwrap(), u64::MAX);
+        assert_eq!(buf.next_i64().unwrap(), i64::MIN);
+        assert_eq!(buf.next_u32().unwrap(), 9);
+        assert_eq!(buf.next_f64().unwrap(), -1e300);
+        assert!(buf.next_u8().is_err());
+    }
+
+    #[test]
+    fn primitives_are_big_endian() {
+        let mut buf = Buffer::new(MAX_FRAME);
+        buf.put_u32(1).unwrap();
+        buf.put_u64(2).unwrap();
+        assert_eq!(buf.data, [0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 2]);
+    }
+
+    #[test]
+    fn booleans_must_be_zero_or_one() {
+        let mut buf = Buffer::new(MAX_FRAME);
+        buf.put_u8(2).unwrap();
+        assert!(buf.next_bool().is_err());
+    }
+
+    #[test]
+    fn packets_with_wide_fields_round_trip() {
+        let mut buf = Buffer::new(MAX_FRAME);
+        ClientPacket::WhitelistUpload { uuids: vec![], last: true }.encode(&mut buf, LEGACY_PROTOCOL_VERSION).unwrap();
+        assert!(matches!(ClientPacket::decode(&mut buf, LEGACY_PROTOCOL_VERSION).unwrap(), ClientPacket::WhitelistUpload { last: true, .. }));
+
+        let mut buf = Buffer::new(MAX_FRAME);
+        ServerPacket::Whois { state: WHOIS_APPROVED, discord_id: 123_456_789_012_345_678, discord_name: "notch".to_owned() }.encode(&mut buf).unwrap();
+        let ServerPacket::Whois { state, discord_id, discord_name } = ServerPacket::decode(&mut buf, LEGACY_PROTOCOL_VERSION).unwrap() else { panic!("not a whois") };
+        assert_eq!((state, discord_id, discord_name.as_str()), (WHOIS_APPROVED, 123_456_789_012_345_678, "notch"));
+    }
+
+    #[test]
+    fn writes_stop_at_the_maximum_frame_size() {
+        let mut buf = Buffer::new(8);
+        buf.put_u64(7).unwrap();
+        assert!(buf.put_u8(1).is_err());
+        assert!(buf.put_string(String::new()).is_err());
+    }
+
+    #[test]
+    fn empty_strings_are_read() {
+        let mut buf = frame(&[0, 0, 0, 0]);
+        assert_eq!(buf.next_string("name", MAX_NAME).unwrap(), "");
+    }
+
+    #[test]
+    fn over_long_strings_name_their_field() {
+        let mut buf = frame(&concat(&[&(MAX_NAME as u32 + 1).to_be_bytes(), &[b'a'; MAX_NAME + 1]]));
+        let error = buf.next_string("name", MAX_NAME).unwrap_err();
+        let too_long = error.downcast_ref::<StringTooLong>().unwrap();
+        assert_eq!((too_long.field, too_long.len, too_long.max), ("name", MAX_NAME + 1, MAX_NAME));
+
+        // Absurd claims are refused before looking for that many bytes.
+        let mut buf = frame(&u32::MAX.to_be_bytes());
+        assert!(buf.next_string("token", MAX_TOKEN).unwrap_err().downcast_ref::<StringTooLong>().is_some());
+    }
+
+    #[test]
+    fn truncated_strings_are_errors() {
+        // A length with only some of its bytes, and a string cut short of its length.
+        assert!(frame(&[0, 0]).next_string("name", MAX_NAME).is_err());
+        assert!(frame(&[0, 0, 0, 5, b'N', b'o']).next_string("name", MAX_NAME).is_err());
+        // A packet whose last field is cut off.
+        assert!(ClientPacket::decode(&mut frame(&[0, 0, 0, 0, 36, b'0', b'6']), LEGACY_PROTOCOL_VERSION).is_err());
+    }
+
+    #[test]
+    fn invalid_utf8_is_an_error() {
+        let mut buf = frame(&[0, 0, 0, 2, 0xC3, 0x28]);
+        assert!(buf.next_string("name", MAX_NAME).is_err());
+    }
+
+    #[test]
+    fn uuids_parse_with_or_without_dashes_in_either_case() {
+        let bits = u128::from_be_bytes(UUID_BYTES);
+        assert_eq!(parse_uuid(UUID), Some(bits));
+        assert_eq!(parse_uuid("069a79f444e94726a5befca90e38aaf5"), Some(bits));
+        assert_eq!(parse_uuid("069A79F4-44E9-4726-A5BE-FCA90E38AAF5"), Some(bits));
+        assert_eq!(parse_uuid("069A79F444E94726A5BEFCA90E38AAF5"), Some(bits));
+        assert_eq!(format_uuid(bits), UUID);
+    }
+
+    #[test]
+    fn malformed_uuids_are_refused() {
+        for uuid in ["", "069a79f4", "069a79f4-44e9-4726-a5be-fca90e38aaf", "069a79f4-44e9-4726-a5be-fca90e38aafg", "069a79f444e9-4726-a5be-fca90e38aaf5-", "+69a79f444e94726a5befca90e38aaf5"] {
+            assert_eq!(parse_uuid(uuid), None, "{uuid}");
+        }
+    }
+
+    #[test]
+    fn player_uuids_follow_the_protocol_version() {
+        let mut buf = Buffer::new(MAX_FRAME);
+        buf.put_player_uuid("069A79F444E94726A5BEFCA90E38AAF5", BINARY_UUID_PROTOCOL_VERSION).unwrap();
+        assert_eq!(buf.data, UUID_BYTES);
+        assert_eq!(buf.next_player_uuid(BINARY_UUID_PROTOCOL_VERSION).unwrap(), UUID);
+
+        // Older plugins keep sending the dashed string.
+        let mut buf = Buffer::new(MAX_FRAME);
+        buf.put_player_uuid(UUID, BINARY_UUID_PROTOCOL_VERSION - 1).unwrap();
+        assert_eq!(buf.data, string(UUID));
+        assert_eq!(buf.next_player_uuid(BINARY_UUID_PROTOCOL_VERSION - 1).unwrap(), UUID);
+
+        assert!(Buffer::new(MAX_FRAME).put_uuid("not a uuid").is_err());
+    }
+
+    // Read one frame from raw bytes and decode it, as the client handler would.
+    async fn read_and_decode(bytes: &[u8], protocol_version: u8) -> Result<Option<ClientPacket>> {
+        let mut buf = Buffer::new(1024);
+        let mut stream = bytes;
+        if !buf.read_from_tcp(&mut stream, Duration::from_secs(1)).await? {
+            return Ok(None);
+        }
+        buf.decompress(protocol_version)?;
+        ClientPacket::decode(&mut buf, protocol_version).map(Some)
+    }
+
+    #[tokio::test]
+    async fn malformed_frames_are_errors() {
+        // Empty, truncated and oversized frames, and compressed ones that aren't zlib.
+        let frames: [&[u8]; 6] = [
+            &[0, 0, 0, 0],
+            &[0, 0, 0, 4, 3],
+            &[0, 0, 0, 2],
+            &[0, 0, 4, 1, 3],
+            &[0xFF, 0xFF, 0xFF, 0xFF],
+            &[0x80, 0, 0, 2, 1, 2],
+        ];
+        for bytes in frames {
+            assert!(read_and_decode(bytes, COMPRESSION_PROTOCOL_VERSION).await.is_err(), "{bytes:?}");
+        }
+        // A closed connection is not an error.
+        assert!(read_and_decode(&[], COMPRESSION_PROTOCOL_VERSION).await.unwrap().is_none());
+        assert!(read_and_decode(&[0, 0], COMPRESSION_PROTOCOL_VERSION).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn only_one_frame_is_read() {
+        let bytes = [0, 0, 0, 1, 3, 0, 0, 0, 1, 16];
+        let mut stream = &bytes[..];
+        let mut buf = Buffer::new(1024);
+        assert!(buf.read_from_tcp(&mut stream, Duration::from_secs(1)).await.unwrap());
+        assert!(matches!(ClientPacket::decode(&mut buf, LEGACY_PROTOCOL_VERSION).unwrap(), ClientPacket::Ping));
+        assert!(buf.read_from_tcp(&mut stream, Duration::from_secs(1)).await.unwrap());
+        assert!(matches!(ClientPacket::decode(&mut buf, LEGACY_PROTOCOL_VERSION).unwrap(), ClientPacket::Pong));
+    }
+
+    // Random bytes, both raw and behind a valid length prefix, must never panic the reader or the decoder.
+    #[tokio::test]
+    async fn random_frames_never_panic() {
+        use rand::{Rng, SeedableRng};
+        let mut random = rand::rngs::StdRng::seed_from_u64(0x5EED);
+        for round in 0..5000 {
+            let len = random.random_range(0..64);
+            let mut payload = vec![0u8; len];
+            random.fill(&mut payload[..]);
+            // Bias the first byte towards known packet IDs so the decoder gets exercised, not just the framing.
+            if let Some(id) = payload.first_mut() && round % 2 == 0 {
+                *id %= 20;
+            }
+            let version = random.random_range(LEGACY_PROTOCOL_VERSION..=LIVENESS_PROTOCOL_VERSION);
+
+            let _ = read_and_decode(&payload, version).await;
+            let framed = concat(&[&(payload.len() as u32).to_be_bytes(), &payload]);
+            let _ = read_and_decode(&framed, version).await;
+            let _ = ServerPacket::decode(&mut frame(&payload), version);
+        }
+    }
+}